@@ -0,0 +1,651 @@
+//! Geofences from camera GPS telemetry.
+//!
+//! Each camera can have one fence, a circle or a polygon, set through the
+//! API and kept in `geofences.json`, rewritten whole on every change. A
+//! camera reporting a position outside its fence starts an excursion: its
+//! track is recorded until it is back inside, then appended to
+//! `excursions.jsonl`, which is compacted on open to the newest
+//! [`MAX_EXCURSIONS`].
+//!
+//! Positions from readings below the fence's `min_fix_mode` are ignored,
+//! and a fix dropping below it raises an alert of its own.
+//!
+//! [`Geofences::in_memory`] keeps both on the heap only, for when nothing
+//! may be written to disk in the clear.
+
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use kodama::capture::GpsData;
+use serde::{Deserialize, Serialize};
+
+use super::history::write_compacted;
+use super::threshold::duration;
+use super::AlertKind;
+
+/// Finished excursions kept.
+pub const MAX_EXCURSIONS: usize = 100;
+
+/// Points kept per excursion. Longer tracks are thinned to every other
+/// point, and later points recorded half as often.
+const MAX_TRACK_POINTS: usize = 10_000;
+
+/// Spacing of track points before any thinning.
+const TRACK_INTERVAL_MS: u64 = 1000;
+
+const EARTH_RADIUS_M: f64 = 6_371_000.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Point {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "shape", rename_all = "snake_case")]
+pub enum Fence {
+    Circle {
+        center: Point,
+        radius_m: f64,
+    },
+    /// Vertices in order; the last connects back to the first.
+    Polygon {
+        points: Vec<Point>,
+    },
+}
+
+impl Fence {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let points = match self {
+            Fence::Circle { center, radius_m } => {
+                if !radius_m.is_finite() || *radius_m <= 0.0 {
+                    anyhow::bail!("radius_m must be positive");
+                }
+                std::slice::from_ref(center)
+            }
+            Fence::Polygon { points } => {
+                if points.len() < 3 {
+                    anyhow::bail!("a polygon needs at least 3 points");
+                }
+                points
+            }
+        };
+        for p in points {
+            if !(-90.0..=90.0).contains(&p.latitude) || !(-180.0..=180.0).contains(&p.longitude) {
+                anyhow::bail!("invalid coordinates {}, {}", p.latitude, p.longitude);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn contains(&self, p: Point) -> bool {
+        match self {
+            Fence::Circle { center, radius_m } => distance_m(*center, p) <= *radius_m,
+            // Ray casting in plain lat/lon; fences are small enough that
+            // the projection doesn't matter, and none cross the antimeridian
+            Fence::Polygon { points } => {
+                let mut inside = false;
+                let mut j = points.len() - 1;
+                for i in 0..points.len() {
+                    let (a, b) = (points[i], points[j]);
+                    if (a.latitude > p.latitude) != (b.latitude > p.latitude)
+                        && p.longitude
+                            < (b.longitude - a.longitude) * (p.latitude - a.latitude)
+                                / (b.latitude - a.latitude)
+                                + a.longitude
+                    {
+                        inside = !inside;
+                    }
+                    j = i;
+                }
+                inside
+            }
+        }
+    }
+}
+
+/// Great-circle distance.
+fn distance_m(a: Point, b: Point) -> f64 {
+    let (lat1, lat2) = (a.latitude.to_radians(), b.latitude.to_radians());
+    let dlat = lat2 - lat1;
+    let dlon = (b.longitude - a.longitude).to_radians();
+    let h = (dlat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (dlon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_M * h.sqrt().asin()
+}
+
+/// A camera's fence.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Geofence {
+    pub camera_id: String,
+    #[serde(flatten)]
+    pub fence: Fence,
+    /// Lowest GPS `fix_mode` trusted (2 = 2D, 3 = 3D).
+    pub min_fix_mode: u8,
+    /// When the fence was set (epoch ms)
+    #[serde(rename = "updated")]
+    pub updated_ms: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TrackPoint {
+    /// Epoch ms
+    pub time: u64,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub fix_mode: u8,
+}
+
+/// A camera's time outside its fence.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Excursion {
+    pub id: u64,
+    pub camera_id: String,
+    /// Epoch ms
+    #[serde(rename = "start")]
+    pub start_ms: u64,
+    /// Epoch ms; `None` while the camera is still outside.
+    #[serde(rename = "end")]
+    pub end_ms: Option<u64>,
+    pub track: Vec<TrackPoint>,
+    #[serde(skip)]
+    interval_ms: u64,
+}
+
+impl Excursion {
+    fn record(&mut self, point: TrackPoint) {
+        if self
+            .track
+            .last()
+            .is_some_and(|last| point.time < last.time + self.interval_ms)
+        {
+            return;
+        }
+        if self.track.len() >= MAX_TRACK_POINTS {
+            let mut i = 0;
+            self.track.retain(|_| {
+                i += 1;
+                i % 2 == 1
+            });
+            self.interval_ms *= 2;
+        }
+        self.track.push(point);
+    }
+}
+
+#[derive(Debug, Default)]
+struct CameraTrack {
+    /// Whether the last reading met the fence's `min_fix_mode`.
+    fix_ok: Option<bool>,
+    excursion: Option<Excursion>,
+}
+
+struct Tracker {
+    /// `None` when in memory only.
+    log: Option<File>,
+    cameras: HashMap<String, CameraTrack>,
+    /// Finished excursions, oldest first.
+    finished: VecDeque<Excursion>,
+    next_id: u64,
+}
+
+impl Tracker {
+    fn finish(&mut self, mut excursion: Excursion, end_ms: u64) {
+        excursion.end_ms = Some(end_ms);
+        if let Err(e) = self.append(&excursion) {
+            tracing::warn!("Failed to persist excursion: {}", e);
+        }
+        self.finished.push_back(excursion);
+        if self.finished.len() > MAX_EXCURSIONS {
+            self.finished.pop_front();
+        }
+    }
+
+    fn append(&mut self, excursion: &Excursion) -> anyhow::Result<()> {
+        let Some(log) = &mut self.log else {
+            return Ok(());
+        };
+        let mut line = serde_json::to_vec(excursion)?;
+        line.push(b'\n');
+        log.write_all(&line)?;
+        Ok(())
+    }
+}
+
+/// Camera fences and their excursions.
+pub struct Geofences {
+    /// `None` when in memory only.
+    path: Option<PathBuf>,
+    fences: Mutex<HashMap<String, Geofence>>,
+    tracker: Mutex<Tracker>,
+}
+
+impl Geofences {
+    /// Load fences from `path` and finished excursions from
+    /// `excursions_path`, starting empty where they don't exist.
+    pub fn open(
+        path: impl Into<PathBuf>,
+        excursions_path: impl Into<PathBuf>,
+    ) -> anyhow::Result<Self> {
+        let path = path.into();
+        let excursions_path = excursions_path.into();
+        let fences: Vec<Geofence> = match std::fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut finished = VecDeque::new();
+        if excursions_path.exists() {
+            for line in BufReader::new(File::open(&excursions_path)?).lines() {
+                // A torn final line from a crash is expected; skip it
+                let Ok(excursion) = serde_json::from_str::<Excursion>(&line?) else {
+                    continue;
+                };
+                finished.push_back(excursion);
+                if finished.len() > MAX_EXCURSIONS {
+                    finished.pop_front();
+                }
+            }
+        }
+        let next_id = finished.iter().map(|e| e.id).max().unwrap_or(0) + 1;
+        let log = write_compacted(&excursions_path, finished.iter())?;
+
+        Ok(Self {
+            path: Some(path),
+            fences: Mutex::new(
+                fences
+                    .into_iter()
+                    .map(|f| (f.camera_id.clone(), f))
+                    .collect(),
+            ),
+            tracker: Mutex::new(Tracker {
+                log: Some(log),
+                cameras: HashMap::new(),
+                finished,
+                next_id,
+            }),
+        })
+    }
+
+    /// Start empty and never write to disk: fences and excursions last
+    /// until restart.
+    pub fn in_memory() -> Self {
+        Self {
+            path: None,
+            fences: Mutex::new(HashMap::new()),
+            tracker: Mutex::new(Tracker {
+                log: None,
+                cameras: HashMap::new(),
+                finished: VecDeque::new(),
+                next_id: 1,
+            }),
+        }
+    }
+
+    /// Every fence, by camera.
+    pub fn list(&self) -> Vec<Geofence> {
+        let mut fences: Vec<_> = self.fences.lock().unwrap().values().cloned().collect();
+        fences.sort_by(|a, b| a.camera_id.cmp(&b.camera_id));
+        fences
+    }
+
+    /// Set (or replace) a camera's fence. An excursion in progress
+    /// continues against the new fence.
+    pub fn set(
+        &self,
+        camera_id: String,
+        fence: Fence,
+        min_fix_mode: u8,
+        now_ms: u64,
+    ) -> anyhow::Result<Geofence> {
+        fence.validate()?;
+        let geofence = Geofence {
+            camera_id: camera_id.clone(),
+            fence,
+            min_fix_mode,
+            updated_ms: now_ms,
+        };
+        let mut fences = self.fences.lock().unwrap();
+        let previous = fences.insert(camera_id.clone(), geofence.clone());
+        if let Err(e) = self.save(&fences) {
+            match previous {
+                Some(previous) => fences.insert(camera_id, previous),
+                None => fences.remove(&camera_id),
+            };
+            return Err(e);
+        }
+        Ok(geofence)
+    }
+
+    /// Remove a camera's fence, ending any excursion. Returns the fence, or
+    /// `None` if the camera had none.
+    pub fn remove(&self, camera_id: &str, now_ms: u64) -> anyhow::Result<Option<Geofence>> {
+        let mut fences = self.fences.lock().unwrap();
+        let Some(removed) = fences.remove(camera_id) else {
+            return Ok(None);
+        };
+        if let Err(e) = self.save(&fences) {
+            fences.insert(camera_id.to_string(), removed);
+            return Err(e);
+        }
+
+        let mut tracker = self.tracker.lock().unwrap();
+        if let Some(excursion) = tracker
+            .cameras
+            .remove(camera_id)
+            .and_then(|track| track.excursion)
+        {
+            tracker.finish(excursion, now_ms);
+        }
+        Ok(Some(removed))
+    }
+
+    /// Up to `limit` excursions for a camera, newest (or ongoing) first.
+    pub fn excursions(&self, camera_id: &str, limit: usize) -> Vec<Excursion> {
+        let tracker = self.tracker.lock().unwrap();
+        let ongoing = tracker
+            .cameras
+            .get(camera_id)
+            .and_then(|track| track.excursion.clone());
+        ongoing
+            .into_iter()
+            .chain(
+                tracker
+                    .finished
+                    .iter()
+                    .rev()
+                    .filter(|e| e.camera_id == camera_id)
+                    .cloned(),
+            )
+            .take(limit)
+            .collect()
+    }
+
+    /// Check a GPS reading against the camera's fence, returning the
+    /// alerts it raises.
+    pub fn update(&self, camera_id: &str, gps: &GpsData, now_ms: u64) -> Vec<(AlertKind, String)> {
+        let Some(geofence) = self.fences.lock().unwrap().get(camera_id).cloned() else {
+            return Vec::new();
+        };
+        let mut raised = Vec::new();
+        let mut tracker = self.tracker.lock().unwrap();
+        let tracker = &mut *tracker;
+        let track = tracker.cameras.entry(camera_id.to_string()).or_default();
+
+        let fix_ok = gps.fix_mode >= geofence.min_fix_mode;
+        if track.fix_ok == Some(true) && !fix_ok {
+            raised.push((
+                AlertKind::GpsDegraded,
+                format!("GPS fix degraded (mode {})", gps.fix_mode),
+            ));
+        }
+        track.fix_ok = Some(fix_ok);
+        if !fix_ok {
+            return raised;
+        }
+
+        let position = Point {
+            latitude: gps.latitude,
+            longitude: gps.longitude,
+        };
+        let point = TrackPoint {
+            time: now_ms,
+            latitude: gps.latitude,
+            longitude: gps.longitude,
+            fix_mode: gps.fix_mode,
+        };
+        let inside = geofence.fence.contains(position);
+        let returned = match track.excursion.take() {
+            None if inside => None,
+            None => {
+                raised.push((
+                    AlertKind::GeofenceExit,
+                    format!("Left geofence at {:.5}, {:.5}", gps.latitude, gps.longitude),
+                ));
+                let mut excursion = Excursion {
+                    id: tracker.next_id,
+                    camera_id: camera_id.to_string(),
+                    start_ms: now_ms,
+                    end_ms: None,
+                    track: Vec::new(),
+                    interval_ms: TRACK_INTERVAL_MS,
+                };
+                tracker.next_id += 1;
+                excursion.record(point);
+                track.excursion = Some(excursion);
+                None
+            }
+            Some(mut excursion) => {
+                excursion.record(point);
+                if inside {
+                    Some(excursion)
+                } else {
+                    track.excursion = Some(excursion);
+                    None
+                }
+            }
+        };
+        if let Some(excursion) = returned {
+            raised.push((
+                AlertKind::GeofenceReturn,
+                format!(
+                    "Returned to geofence after {}",
+                    duration(now_ms.saturating_sub(excursion.start_ms) / 1000)
+                ),
+            ));
+            tracker.finish(excursion, now_ms);
+        }
+        raised
+    }
+
+    fn save(&self, fences: &HashMap<String, Geofence>) -> anyhow::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let mut list: Vec<_> = fences.values().collect();
+        list.sort_by(|a, b| a.camera_id.cmp(&b.camera_id));
+        let tmp = path.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_vec_pretty(&list)?)?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(latitude: f64, longitude: f64) -> Point {
+        Point {
+            latitude,
+            longitude,
+        }
+    }
+
+    fn gps(latitude: f64, longitude: f64, fix_mode: u8) -> GpsData {
+        GpsData {
+            latitude,
+            longitude,
+            altitude: None,
+            speed: None,
+            heading: None,
+            fix_mode,
+        }
+    }
+
+    fn kinds(raised: Vec<(AlertKind, String)>) -> Vec<AlertKind> {
+        raised.into_iter().map(|(kind, _)| kind).collect()
+    }
+
+    #[test]
+    fn fence_shapes() {
+        let circle = Fence::Circle {
+            center: point(45.0, -122.0),
+            radius_m: 100.0,
+        };
+        // ~0.0009° of latitude is 100 m
+        assert!(circle.contains(point(45.0008, -122.0)));
+        assert!(!circle.contains(point(45.0010, -122.0)));
+
+        let square = Fence::Polygon {
+            points: vec![
+                point(0.0, 0.0),
+                point(0.0, 1.0),
+                point(1.0, 1.0),
+                point(1.0, 0.0),
+            ],
+        };
+        assert!(square.contains(point(0.5, 0.5)));
+        assert!(!square.contains(point(0.5, 1.5)));
+        assert!(!square.contains(point(-0.1, 0.5)));
+
+        assert!(Fence::Polygon {
+            points: vec![point(0.0, 0.0), point(1.0, 1.0)]
+        }
+        .validate()
+        .is_err());
+        assert!(Fence::Circle {
+            center: point(91.0, 0.0),
+            radius_m: 10.0
+        }
+        .validate()
+        .is_err());
+        assert!(Fence::Circle {
+            center: point(0.0, 0.0),
+            radius_m: 0.0
+        }
+        .validate()
+        .is_err());
+    }
+
+    #[test]
+    fn excursions_are_tracked_and_persisted() {
+        let dir = tempfile::tempdir().unwrap();
+        let open = || {
+            Geofences::open(
+                dir.path().join("geofences.json"),
+                dir.path().join("excursions.jsonl"),
+            )
+            .unwrap()
+        };
+        let fences = open();
+        let fence = Fence::Circle {
+            center: point(45.0, -122.0),
+            radius_m: 100.0,
+        };
+        fences.set("cam".into(), fence.clone(), 2, 0).unwrap();
+
+        // Cameras without a fence are ignored
+        assert!(fences.update("other", &gps(0.0, 0.0, 3), 0).is_empty());
+
+        assert!(fences
+            .update("cam", &gps(45.0, -122.0, 3), 1_000)
+            .is_empty());
+        let raised = fences.update("cam", &gps(45.01, -122.0, 3), 2_000);
+        assert_eq!(kinds(raised), vec![AlertKind::GeofenceExit]);
+        assert!(fences
+            .update("cam", &gps(45.02, -122.0, 3), 2_500)
+            .is_empty());
+        assert!(fences
+            .update("cam", &gps(45.03, -122.0, 3), 3_000)
+            .is_empty());
+
+        let ongoing = fences.excursions("cam", 10);
+        assert_eq!(ongoing.len(), 1);
+        assert_eq!(ongoing[0].end_ms, None);
+        // Points closer than the track interval are skipped
+        assert_eq!(ongoing[0].track.len(), 2);
+
+        // An unreliable fix neither moves the camera nor ends the excursion
+        let raised = fences.update("cam", &gps(45.0, -122.0, 1), 4_000);
+        assert_eq!(kinds(raised), vec![AlertKind::GpsDegraded]);
+        assert!(fences
+            .update("cam", &gps(45.0, -122.0, 1), 5_000)
+            .is_empty());
+        assert_eq!(fences.excursions("cam", 10)[0].end_ms, None);
+
+        let raised = fences.update("cam", &gps(45.0, -122.0, 3), 122_000);
+        assert_eq!(raised[0].0, AlertKind::GeofenceReturn);
+        assert_eq!(raised[0].1, "Returned to geofence after 2 min");
+        drop(fences);
+
+        let fences = open();
+        assert_eq!(fences.list()[0].fence, fence);
+        let excursions = fences.excursions("cam", 10);
+        assert_eq!(excursions.len(), 1);
+        assert_eq!(excursions[0].start_ms, 2_000);
+        assert_eq!(excursions[0].end_ms, Some(122_000));
+        assert_eq!(excursions[0].track.len(), 3);
+
+        let raised = fences.update("cam", &gps(46.0, -122.0, 3), 130_000);
+        assert_eq!(kinds(raised), vec![AlertKind::GeofenceExit]);
+        assert_eq!(fences.excursions("cam", 10)[0].id, excursions[0].id + 1);
+        fences.remove("cam", 140_000).unwrap().unwrap();
+        assert!(fences.list().is_empty());
+        assert_eq!(fences.excursions("cam", 10)[0].end_ms, Some(140_000));
+    }
+
+    #[test]
+    fn clock_stepping_back_during_excursion() {
+        let dir = tempfile::tempdir().unwrap();
+        let fences = Geofences::open(
+            dir.path().join("geofences.json"),
+            dir.path().join("excursions.jsonl"),
+        )
+        .unwrap();
+        let fence = Fence::Circle {
+            center: point(45.0, -122.0),
+            radius_m: 100.0,
+        };
+        fences.set("cam".into(), fence, 2, 0).unwrap();
+        fences.update("cam", &gps(45.01, -122.0, 3), 60_000);
+        let raised = fences.update("cam", &gps(45.0, -122.0, 3), 30_000);
+        assert_eq!(raised[0].0, AlertKind::GeofenceReturn);
+        assert_eq!(raised[0].1, "Returned to geofence after 0 min");
+    }
+
+    #[test]
+    fn in_memory_writes_nothing() {
+        let fences = Geofences::in_memory();
+        let fence = Fence::Circle {
+            center: point(45.0, -122.0),
+            radius_m: 100.0,
+        };
+        fences.set("cam".into(), fence, 2, 0).unwrap();
+        fences.update("cam", &gps(45.01, -122.0, 3), 1_000);
+        fences.update("cam", &gps(45.0, -122.0, 3), 61_000);
+        let excursions = fences.excursions("cam", 10);
+        assert_eq!(excursions.len(), 1);
+        assert_eq!(excursions[0].id, 1);
+        assert_eq!(excursions[0].end_ms, Some(61_000));
+        fences.remove("cam", 70_000).unwrap().unwrap();
+        assert!(fences.list().is_empty());
+    }
+
+    #[test]
+    fn long_tracks_are_thinned() {
+        let mut excursion = Excursion {
+            id: 1,
+            camera_id: "cam".into(),
+            start_ms: 0,
+            end_ms: None,
+            track: Vec::new(),
+            interval_ms: TRACK_INTERVAL_MS,
+        };
+        for i in 0..MAX_TRACK_POINTS as u64 + 10 {
+            excursion.record(TrackPoint {
+                time: i * TRACK_INTERVAL_MS,
+                latitude: 0.0,
+                longitude: 0.0,
+                fix_mode: 3,
+            });
+        }
+        assert!(excursion.track.len() <= MAX_TRACK_POINTS);
+        assert_eq!(excursion.track[0].time, 0);
+        assert_eq!(excursion.track[1].time, 2 * TRACK_INTERVAL_MS);
+        assert_eq!(excursion.interval_ms, 2 * TRACK_INTERVAL_MS);
+    }
+}
@@ -0,0 +1,186 @@
+//! Alert history.
+//!
+//! Alerts are appended to a JSON lines file as they are raised. Opening the
+//! history keeps only the newest [`MAX_ALERTS`] and rewrites the file, so it
+//! never grows much past that.
+
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use super::{Alert, AlertKind};
+
+/// Alerts kept in the history.
+pub const MAX_ALERTS: usize = 1000;
+
+/// Recent alerts, oldest first, backed by a log file.
+pub struct AlertHistory {
+    path: PathBuf,
+    log: File,
+    alerts: VecDeque<Alert>,
+    next_id: u64,
+}
+
+impl AlertHistory {
+    /// Open (or create) the history at `path`, compacting its log.
+    pub fn open(path: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let path = path.into();
+        let mut alerts = VecDeque::new();
+
+        if path.exists() {
+            for line in BufReader::new(File::open(&path)?).lines() {
+                let line = line?;
+                // A torn final line from a crash is expected; skip it
+                let Ok(alert) = serde_json::from_str::<Alert>(&line) else {
+                    continue;
+                };
+                alerts.push_back(alert);
+                if alerts.len() > MAX_ALERTS {
+                    alerts.pop_front();
+                }
+            }
+        }
+
+        let next_id = alerts.iter().map(|a| a.id).max().unwrap_or(0) + 1;
+        let log = write_compacted(&path, alerts.iter())?;
+        Ok(Self {
+            path,
+            log,
+            alerts,
+            next_id,
+        })
+    }
+
+    /// Record a new alert and return it.
+    ///
+    /// The alert is kept in memory even if it can't be written, so a full
+    /// disk doesn't silence alerts.
+    pub fn push(
+        &mut self,
+        kind: AlertKind,
+        camera_id: String,
+        message: String,
+        time_ms: u64,
+    ) -> Alert {
+        let alert = Alert {
+            id: self.next_id,
+            kind,
+            camera_id,
+            message,
+            time_ms,
+        };
+        self.next_id += 1;
+
+        if let Err(e) = self.append(&alert) {
+            tracing::warn!("Failed to persist alert: {}", e);
+        }
+        self.alerts.push_back(alert.clone());
+        if self.alerts.len() > MAX_ALERTS {
+            self.alerts.pop_front();
+        }
+        alert
+    }
+
+    /// Up to `limit` alerts, optionally for one camera, newest first.
+    pub fn recent(&self, camera_id: Option<&str>, limit: usize) -> Vec<Alert> {
+        self.alerts
+            .iter()
+            .rev()
+            .filter(|a| camera_id.is_none_or(|id| a.camera_id == id))
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    /// Forget every alert.
+    pub fn clear(&mut self) -> anyhow::Result<()> {
+        self.log = write_compacted::<Alert>(&self.path, std::iter::empty())?;
+        self.alerts.clear();
+        Ok(())
+    }
+
+    fn append(&mut self, alert: &Alert) -> anyhow::Result<()> {
+        let mut line = serde_json::to_vec(alert)?;
+        line.push(b'\n');
+        self.log.write_all(&line)?;
+        Ok(())
+    }
+}
+
+/// Rewrite a JSON lines log with just `items`, returning it opened for
+/// appending.
+pub(super) fn write_compacted<'a, T: Serialize + 'a>(
+    path: &Path,
+    items: impl Iterator<Item = &'a T>,
+) -> anyhow::Result<File> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let tmp = path.with_extension("jsonl.tmp");
+    {
+        let mut file = File::create(&tmp)?;
+        for item in items {
+            let mut line = serde_json::to_vec(item)?;
+            line.push(b'\n');
+            file.write_all(&line)?;
+        }
+        file.sync_all()?;
+    }
+    std::fs::rename(&tmp, path)?;
+    Ok(OpenOptions::new().append(true).open(path)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn persists_and_caps() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("alerts.jsonl");
+
+        let mut history = AlertHistory::open(&path).unwrap();
+        for i in 0..MAX_ALERTS as u64 + 5 {
+            history.push(AlertKind::Motion, "cam".into(), format!("#{}", i), i);
+        }
+        history.push(
+            AlertKind::Disconnect,
+            "other".into(),
+            "Disconnected".into(),
+            9_999,
+        );
+        drop(history);
+
+        // Torn write from a crash
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"{\"id\":").unwrap();
+        drop(file);
+
+        let mut history = AlertHistory::open(&path).unwrap();
+        let all = history.recent(None, usize::MAX);
+        assert_eq!(all.len(), MAX_ALERTS);
+        assert_eq!(all[0].camera_id, "other");
+        assert_eq!(all[1].message, format!("#{}", MAX_ALERTS + 4));
+
+        let cam = history.recent(Some("other"), 10);
+        assert_eq!(cam.len(), 1);
+        assert_eq!(cam[0].kind, AlertKind::Disconnect);
+
+        let next = history.push(
+            AlertKind::Reconnect,
+            "other".into(),
+            "Reconnected".into(),
+            10_000,
+        );
+        assert_eq!(next.id, all[0].id + 1);
+
+        history.clear().unwrap();
+        assert!(history.recent(None, 10).is_empty());
+        drop(history);
+        let history = AlertHistory::open(&path).unwrap();
+        assert!(history.recent(None, 10).is_empty());
+    }
+}
@@ -0,0 +1,609 @@
+//! Server-side alerts.
+//!
+//! The engine watches the Router broadcast, evaluates each camera's
+//! [`AlertRules`](crate::config::AlertRules) and records the alerts it
+//! raises in a persistent history. New alerts are pushed to the UI as
+//! `alert` app events, and to notifiers, along with camera connects and
+//! disconnects, as [`AlertEvent`]s from [`AlertEngine::subscribe`].
+//!
+//! Telemetry feeds the motion rule and the device health
+//! [`threshold`] rules, which raise an alert both when they fire and when
+//! they clear. GPS readings are checked against the camera's
+//! [`geofence`], if it has one.
+//!
+//! Cameras can be disarmed, which silences their motion and geofence
+//! alerts; health and connectivity alerts are still raised. The arming
+//! [`Modes`], when set, silence them the same way for cameras whose
+//! effect for the current mode has alerts off.
+//!
+//! Connectivity is judged from frames: a camera is connected while it keeps
+//! sending them, and disconnected once it has been silent for the rule's
+//! `after_secs`.
+
+pub mod geofence;
+pub mod history;
+pub mod threshold;
+
+pub use geofence::Geofences;
+pub use history::AlertHistory;
+
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use kodama::capture::TelemetryData;
+use kodama::server::RouterHandle;
+use kodama::{Channel, Frame};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+use crate::config::{AlertRules, AlertsConfig};
+use crate::events::{AppEvent, EventBus};
+use crate::modes::Modes;
+use crate::storage::now_ms;
+use threshold::{ThresholdState, Transition};
+
+/// How often silent cameras are checked for disconnects.
+const TICK: Duration = Duration::from_secs(1);
+
+/// Events buffered per subscriber before slow ones start missing them.
+const CAPACITY: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertKind {
+    Motion,
+    Disconnect,
+    Reconnect,
+    /// A telemetry threshold rule fired.
+    Threshold,
+    /// A firing threshold rule cleared.
+    ThresholdCleared,
+    /// A camera reported a position outside its geofence.
+    GeofenceExit,
+    GeofenceReturn,
+    /// A fenced camera's GPS fix dropped below the fence's minimum.
+    GpsDegraded,
+}
+
+/// An alert, as listed by `/api/alerts` and pushed to the UI.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Alert {
+    pub id: u64,
+    #[serde(rename = "type")]
+    pub kind: AlertKind,
+    pub camera_id: String,
+    pub message: String,
+    /// When the alert was raised (epoch ms)
+    #[serde(rename = "time")]
+    pub time_ms: u64,
+}
+
+/// What the engine tells notifiers about.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AlertEvent {
+    Alert {
+        alert: Alert,
+    },
+    /// A camera's first frame, or its first after a disconnect.
+    CameraConnected {
+        camera_id: String,
+        #[serde(rename = "time")]
+        time_ms: u64,
+    },
+    CameraDisconnected {
+        camera_id: String,
+        #[serde(rename = "time")]
+        time_ms: u64,
+    },
+}
+
+impl AlertEvent {
+    pub fn camera_id(&self) -> &str {
+        match self {
+            AlertEvent::Alert { alert } => &alert.camera_id,
+            AlertEvent::CameraConnected { camera_id, .. }
+            | AlertEvent::CameraDisconnected { camera_id, .. } => camera_id,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct CameraState {
+    last_frame_ms: u64,
+    connected: bool,
+    last_motion_ms: Option<u64>,
+    /// Parallel to the camera's threshold rules.
+    thresholds: Vec<ThresholdState>,
+}
+
+/// Evaluates alert rules and keeps the alert history.
+pub struct AlertEngine {
+    config: AlertsConfig,
+    history: Mutex<AlertHistory>,
+    cameras: Mutex<HashMap<String, CameraState>>,
+    geofences: Option<Arc<Geofences>>,
+    modes: Option<Arc<Modes>>,
+    disarmed: Mutex<HashSet<String>>,
+    events: EventBus,
+    tx: broadcast::Sender<AlertEvent>,
+}
+
+impl AlertEngine {
+    pub fn new(config: AlertsConfig, history: AlertHistory, events: EventBus) -> Self {
+        Self {
+            config,
+            history: Mutex::new(history),
+            cameras: Mutex::new(HashMap::new()),
+            geofences: None,
+            modes: None,
+            disarmed: Mutex::new(HashSet::new()),
+            events,
+            tx: broadcast::channel(CAPACITY).0,
+        }
+    }
+
+    /// Check GPS readings against `geofences`. Call before the engine is
+    /// shared.
+    pub fn set_geofences(&mut self, geofences: Arc<Geofences>) {
+        self.geofences = Some(geofences);
+    }
+
+    pub fn geofences(&self) -> Option<&Arc<Geofences>> {
+        self.geofences.as_ref()
+    }
+
+    /// Follow the arming `modes`. Call before the engine is shared.
+    pub fn set_modes(&mut self, modes: Arc<Modes>) {
+        self.modes = Some(modes);
+    }
+
+    pub fn modes(&self) -> Option<&Arc<Modes>> {
+        self.modes.as_ref()
+    }
+
+    /// The rules in effect for a camera.
+    pub fn rules(&self, camera_id: &str) -> &AlertRules {
+        self.config.for_camera(camera_id)
+    }
+
+    /// Arm or disarm a camera's motion and geofence alerts. Cameras start
+    /// armed.
+    pub fn set_armed(&self, camera_id: &str, armed: bool) {
+        let mut disarmed = self.disarmed.lock().unwrap();
+        if armed {
+            disarmed.remove(camera_id);
+        } else {
+            disarmed.insert(camera_id.to_string());
+        }
+    }
+
+    /// Whether a camera raises motion and geofence alerts: it's not
+    /// disarmed, and the current mode has its alerts on.
+    pub fn is_armed(&self, camera_id: &str) -> bool {
+        !self.disarmed.lock().unwrap().contains(camera_id)
+            && self
+                .modes
+                .as_ref()
+                .is_none_or(|m| m.effect(camera_id).alerts)
+    }
+
+    /// Evaluate the rules for a frame received at `now_ms`.
+    pub fn observe(&self, frame: &Frame, now_ms: u64) {
+        let camera_id = frame.source.to_string();
+        let rules = self.config.for_camera(&camera_id);
+        let armed = self.is_armed(&camera_id);
+        let mut raised = Vec::new();
+        let mut came_online = false;
+        {
+            let mut cameras = self.cameras.lock().unwrap();
+            // The first frame from a camera isn't a reconnect
+            let state = match cameras.entry(camera_id.clone()) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    came_online = true;
+                    entry.insert(CameraState {
+                        last_frame_ms: now_ms,
+                        connected: true,
+                        last_motion_ms: None,
+                        thresholds: Vec::new(),
+                    })
+                }
+            };
+            state.last_frame_ms = now_ms;
+            if !state.connected {
+                state.connected = true;
+                came_online = true;
+                if rules.disconnect.enabled {
+                    raised.push((AlertKind::Reconnect, "Reconnected".to_string()));
+                }
+            }
+
+            if let Some(telemetry) = telemetry(frame) {
+                let motion = &rules.motion;
+                if let Some(level) = telemetry.motion_level {
+                    let cooled_down = state
+                        .last_motion_ms
+                        .is_none_or(|last| now_ms >= last + motion.cooldown_secs * 1000);
+                    if armed && motion.enabled && level >= motion.threshold && cooled_down {
+                        state.last_motion_ms = Some(now_ms);
+                        raised.push((
+                            AlertKind::Motion,
+                            format!("Motion detected ({:.0}%)", level * 100.0),
+                        ));
+                    }
+                }
+
+                state
+                    .thresholds
+                    .resize_with(rules.thresholds.len(), Default::default);
+                for (rule, rule_state) in rules.thresholds.iter().zip(&mut state.thresholds) {
+                    let Some(transition) = rule_state.update(rule, &telemetry, now_ms) else {
+                        continue;
+                    };
+                    let kind = match transition {
+                        Transition::Fired(_) => AlertKind::Threshold,
+                        Transition::Cleared(_) => AlertKind::ThresholdCleared,
+                    };
+                    raised.push((kind, threshold::message(rule, transition)));
+                }
+
+                if let (Some(geofences), Some(gps)) = (&self.geofences, &telemetry.gps) {
+                    // Excursions are tracked either way
+                    let changes = geofences.update(&camera_id, gps, now_ms);
+                    if armed {
+                        raised.extend(changes);
+                    }
+                }
+            }
+        }
+
+        if came_online {
+            self.notify(AlertEvent::CameraConnected {
+                camera_id: camera_id.clone(),
+                time_ms: now_ms,
+            });
+        }
+        for (kind, message) in raised {
+            self.raise(kind, camera_id.clone(), message, now_ms);
+        }
+    }
+
+    /// Mark cameras silent for longer than their rule allows as disconnected.
+    pub fn tick(&self, now_ms: u64) {
+        let mut went_offline = Vec::new();
+        {
+            let mut cameras = self.cameras.lock().unwrap();
+            for (camera_id, state) in cameras.iter_mut() {
+                let rule = &self.config.for_camera(camera_id).disconnect;
+                if state.connected && now_ms >= state.last_frame_ms + rule.after_secs * 1000 {
+                    state.connected = false;
+                    went_offline.push((camera_id.clone(), rule.enabled));
+                }
+            }
+        }
+
+        for (camera_id, alert) in went_offline {
+            self.notify(AlertEvent::CameraDisconnected {
+                camera_id: camera_id.clone(),
+                time_ms: now_ms,
+            });
+            if alert {
+                self.raise(
+                    AlertKind::Disconnect,
+                    camera_id,
+                    "Disconnected".into(),
+                    now_ms,
+                );
+            }
+        }
+    }
+
+    /// Record an alert and notify subscribers.
+    fn raise(&self, kind: AlertKind, camera_id: String, message: String, now_ms: u64) {
+        let alert = self
+            .history
+            .lock()
+            .unwrap()
+            .push(kind, camera_id, message, now_ms);
+        tracing::info!(
+            camera = %alert.camera_id,
+            kind = ?alert.kind,
+            "Alert: {}",
+            alert.message
+        );
+        self.events.publish(AppEvent::Alert(alert.clone()));
+        self.notify(AlertEvent::Alert { alert });
+    }
+
+    fn notify(&self, event: AlertEvent) {
+        // No subscribers is fine
+        let _ = self.tx.send(event);
+    }
+
+    /// Alerts and camera connects and disconnects, as they happen.
+    pub fn subscribe(&self) -> broadcast::Receiver<AlertEvent> {
+        self.tx.subscribe()
+    }
+
+    /// Up to `limit` recent alerts, optionally for one camera, newest first.
+    pub fn recent(&self, camera_id: Option<&str>, limit: usize) -> Vec<Alert> {
+        self.history.lock().unwrap().recent(camera_id, limit)
+    }
+
+    /// Clear the alert history.
+    pub fn clear(&self) -> anyhow::Result<()> {
+        self.history.lock().unwrap().clear()
+    }
+}
+
+/// Decoded telemetry, if `frame` carries some.
+pub fn telemetry(frame: &Frame) -> Option<TelemetryData> {
+    if !matches!(frame.channel, Channel::Telemetry) {
+        return None;
+    }
+    kodama::capture::decode_telemetry(&frame.payload).ok()
+}
+
+/// A telemetry reading with everything nominal, for tests.
+#[cfg(test)]
+pub fn test_telemetry(motion_level: Option<f32>) -> TelemetryData {
+    TelemetryData {
+        cpu_usage: 10.0,
+        cpu_temp: Some(50.0),
+        memory_usage: 30.0,
+        disk_usage: 40.0,
+        network_tx_bytes: 0,
+        network_rx_bytes: 0,
+        uptime_secs: 0,
+        load_average: [0.1, 0.1, 0.1],
+        gps: None,
+        motion_level,
+    }
+}
+
+/// Feed the engine from the Router broadcast until it closes.
+pub fn spawn(handle: RouterHandle, engine: Arc<AlertEngine>) {
+    tokio::spawn(async move {
+        let mut rx = handle.subscribe();
+        let mut ticker = tokio::time::interval(TICK);
+
+        loop {
+            tokio::select! {
+                frame = rx.recv() => match frame {
+                    Ok(frame) => engine.observe(&frame, now_ms()),
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        tracing::warn!("Alert broadcast lagged, missed {} frames", n);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+                _ = ticker.tick() => engine.tick(now_ms()),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bytes::Bytes;
+    use kodama::{FrameFlags, SourceId};
+
+    use crate::config::{AlertRules, ArmingMode};
+
+    fn source() -> SourceId {
+        SourceId::from_node_id_bytes(&[1; 32])
+    }
+
+    fn frame(channel: Channel, payload: Bytes) -> Frame {
+        Frame {
+            source: source(),
+            channel,
+            flags: FrameFlags::default(),
+            timestamp_us: 0,
+            payload,
+        }
+    }
+
+    fn engine(config: AlertsConfig) -> (AlertEngine, tempfile::TempDir) {
+        let dir = tempfile::tempdir().unwrap();
+        let history = AlertHistory::open(dir.path().join("alerts.jsonl")).unwrap();
+        (AlertEngine::new(config, history, EventBus::default()), dir)
+    }
+
+    fn kinds(engine: &AlertEngine) -> Vec<AlertKind> {
+        let mut alerts = engine.recent(None, usize::MAX);
+        alerts.reverse();
+        alerts.into_iter().map(|a| a.kind).collect()
+    }
+
+    #[test]
+    fn disconnect_and_reconnect() {
+        let (engine, _dir) = engine(AlertsConfig::default());
+        let mut events = engine.events.subscribe();
+        let mut notifications = engine.subscribe();
+        let video = frame(Channel::Video, Bytes::new());
+
+        engine.observe(&video, 0);
+        engine.tick(9_000);
+        assert!(kinds(&engine).is_empty());
+
+        engine.tick(10_000);
+        engine.tick(11_000);
+        assert_eq!(kinds(&engine), vec![AlertKind::Disconnect]);
+
+        engine.observe(&video, 20_000);
+        engine.observe(&video, 20_040);
+        assert_eq!(
+            kinds(&engine),
+            vec![AlertKind::Disconnect, AlertKind::Reconnect]
+        );
+
+        let event: serde_json::Value = serde_json::from_str(&events.try_recv().unwrap()).unwrap();
+        assert_eq!(event["type"], "alert");
+        assert_eq!(event["payload"]["type"], "disconnect");
+        assert_eq!(event["payload"]["camera_id"], source().to_string());
+        assert_eq!(event["payload"]["time"], 10_000);
+
+        let camera_id = source().to_string();
+        let connected = AlertEvent::CameraConnected {
+            camera_id: camera_id.clone(),
+            time_ms: 0,
+        };
+        assert_eq!(notifications.try_recv().unwrap(), connected);
+        assert_eq!(
+            notifications.try_recv().unwrap(),
+            AlertEvent::CameraDisconnected {
+                camera_id: camera_id.clone(),
+                time_ms: 10_000
+            }
+        );
+        let AlertEvent::Alert { alert } = notifications.try_recv().unwrap() else {
+            panic!("expected the disconnect alert");
+        };
+        assert_eq!(alert.kind, AlertKind::Disconnect);
+        assert!(matches!(
+            notifications.try_recv().unwrap(),
+            AlertEvent::CameraConnected {
+                time_ms: 20_000,
+                ..
+            }
+        ));
+        assert!(matches!(
+            notifications.try_recv().unwrap(),
+            AlertEvent::Alert { .. }
+        ));
+        assert!(notifications.try_recv().is_err());
+
+        let json = serde_json::to_value(&connected).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({"event": "camera_connected", "camera_id": camera_id, "time": 0})
+        );
+    }
+
+    #[test]
+    fn motion_threshold_and_cooldown() {
+        let mut config = AlertsConfig::default();
+        config.cameras.insert(source().to_string(), {
+            let mut rules = AlertRules::default();
+            rules.motion.threshold = 0.5;
+            rules.disconnect.enabled = false;
+            rules
+        });
+        let (engine, _dir) = engine(config);
+        let mut events = engine.events.subscribe();
+
+        let motion = |level: f32| {
+            frame(
+                Channel::Telemetry,
+                kodama::capture::encode_telemetry(&test_telemetry(Some(level))).unwrap(),
+            )
+        };
+        engine.observe(&motion(0.4), 0);
+        engine.observe(&motion(0.7), 1_000);
+        engine.observe(&motion(0.9), 20_000);
+        engine.observe(&motion(0.9), 31_000);
+        assert_eq!(kinds(&engine), vec![AlertKind::Motion, AlertKind::Motion]);
+
+        let event: serde_json::Value = serde_json::from_str(&events.try_recv().unwrap()).unwrap();
+        assert_eq!(event["payload"]["message"], "Motion detected (70%)");
+        assert_eq!(event["payload"]["time"], 1_000);
+
+        // Disabled disconnect rule: silence passes without alerts
+        engine.tick(60_000);
+        engine.observe(&motion(0.0), 61_000);
+        assert_eq!(kinds(&engine).len(), 2);
+    }
+
+    #[test]
+    fn disarmed_cameras_skip_motion_alerts() {
+        let (engine, _dir) = engine(AlertsConfig::default());
+        let camera_id = source().to_string();
+        let motion = frame(
+            Channel::Telemetry,
+            kodama::capture::encode_telemetry(&test_telemetry(Some(0.9))).unwrap(),
+        );
+
+        engine.set_armed(&camera_id, false);
+        assert!(!engine.is_armed(&camera_id));
+        engine.observe(&motion, 0);
+        assert!(kinds(&engine).is_empty());
+
+        engine.set_armed(&camera_id, true);
+        engine.observe(&motion, 1_000);
+        assert_eq!(kinds(&engine), vec![AlertKind::Motion]);
+    }
+
+    #[test]
+    fn modes_without_alerts_skip_motion_alerts() {
+        let (mut engine, dir) = engine(AlertsConfig::default());
+        let modes = Arc::new(
+            Modes::open(
+                Default::default(),
+                dir.path().join("mode.json"),
+                EventBus::default(),
+            )
+            .unwrap(),
+        );
+        engine.set_modes(modes.clone());
+        let motion = frame(
+            Channel::Telemetry,
+            kodama::capture::encode_telemetry(&test_telemetry(Some(0.9))).unwrap(),
+        );
+
+        modes.set(ArmingMode::Home, 0).unwrap();
+        assert!(!engine.is_armed(&source().to_string()));
+        engine.observe(&motion, 0);
+        assert!(kinds(&engine).is_empty());
+
+        modes.set(ArmingMode::Night, 1_000).unwrap();
+        engine.observe(&motion, 1_000);
+        assert_eq!(kinds(&engine), vec![AlertKind::Motion]);
+    }
+
+    #[test]
+    fn threshold_alerts_fire_and_clear() {
+        let config: AlertsConfig = serde_json::from_str(
+            r#"{
+                "default": {
+                    "thresholds": [
+                        { "metric": "cpu_temp", "above": 75, "clear_below": 70, "for_secs": 60 }
+                    ]
+                }
+            }"#,
+        )
+        .unwrap();
+        let (engine, _dir) = engine(config);
+        let reading = |temp: f32| {
+            let telemetry = TelemetryData {
+                cpu_temp: Some(temp),
+                ..test_telemetry(None)
+            };
+            frame(
+                Channel::Telemetry,
+                kodama::capture::encode_telemetry(&telemetry).unwrap(),
+            )
+        };
+
+        for (temp, ms) in [(80.0, 0), (81.0, 30_000), (82.0, 60_000), (73.0, 90_000)] {
+            engine.observe(&reading(temp), ms);
+        }
+        engine.observe(&reading(69.5), 120_000);
+        engine.observe(&reading(76.0), 150_000);
+
+        let alerts = engine.recent(None, usize::MAX);
+        assert_eq!(alerts.len(), 2);
+        assert_eq!(alerts[1].kind, AlertKind::Threshold);
+        assert_eq!(alerts[1].time_ms, 60_000);
+        assert_eq!(
+            alerts[1].message,
+            "CPU temperature 82.0°C, above 75°C for 1 min"
+        );
+        assert_eq!(alerts[0].kind, AlertKind::ThresholdCleared);
+        assert_eq!(alerts[0].message, "CPU temperature back to 69.5°C");
+    }
+}
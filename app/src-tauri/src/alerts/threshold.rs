@@ -0,0 +1,166 @@
+//! Telemetry threshold rules.
+//!
+//! A rule fires once its reading has stayed above `above` for `for_secs`,
+//! and clears when the reading drops to `clear_below`. Readings the camera
+//! doesn't report leave the rule where it was.
+
+use kodama::capture::TelemetryData;
+
+use crate::config::{TelemetryMetric, ThresholdRule};
+
+/// A threshold rule changing state.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Transition {
+    /// The reading that fired the rule.
+    Fired(f32),
+    /// The reading that cleared it.
+    Cleared(f32),
+}
+
+/// One camera's progress through one threshold rule.
+#[derive(Debug, Default)]
+pub struct ThresholdState {
+    above_since_ms: Option<u64>,
+    firing: bool,
+}
+
+impl ThresholdState {
+    /// Feed a telemetry reading taken at `now_ms`.
+    pub fn update(
+        &mut self,
+        rule: &ThresholdRule,
+        telemetry: &TelemetryData,
+        now_ms: u64,
+    ) -> Option<Transition> {
+        let value = reading(rule.metric, telemetry)?;
+
+        if self.firing {
+            if value <= rule.clear_below.unwrap_or(rule.above) {
+                self.firing = false;
+                self.above_since_ms = None;
+                return Some(Transition::Cleared(value));
+            }
+            return None;
+        }
+
+        if value <= rule.above {
+            self.above_since_ms = None;
+            return None;
+        }
+        let since = *self.above_since_ms.get_or_insert(now_ms);
+        if now_ms >= since + rule.for_secs * 1000 {
+            self.firing = true;
+            return Some(Transition::Fired(value));
+        }
+        None
+    }
+}
+
+/// Alert message for a transition of `rule`.
+pub fn message(rule: &ThresholdRule, transition: Transition) -> String {
+    let (label, unit) = match rule.metric {
+        TelemetryMetric::CpuTemp => ("CPU temperature", "°C"),
+        TelemetryMetric::DiskUsage => ("Disk usage", "%"),
+        TelemetryMetric::MemoryUsage => ("Memory usage", "%"),
+        TelemetryMetric::LoadAverage => ("Load average", ""),
+    };
+    match transition {
+        Transition::Fired(value) => {
+            let mut message = format!(
+                "{} {:.1}{}, above {}{}",
+                label, value, unit, rule.above, unit
+            );
+            if rule.for_secs > 0 {
+                message.push_str(&format!(" for {}", duration(rule.for_secs)));
+            }
+            message
+        }
+        Transition::Cleared(value) => format!("{} back to {:.1}{}", label, value, unit),
+    }
+}
+
+fn reading(metric: TelemetryMetric, telemetry: &TelemetryData) -> Option<f32> {
+    match metric {
+        TelemetryMetric::CpuTemp => telemetry.cpu_temp,
+        TelemetryMetric::DiskUsage => Some(telemetry.disk_usage),
+        TelemetryMetric::MemoryUsage => Some(telemetry.memory_usage),
+        TelemetryMetric::LoadAverage => Some(telemetry.load_average[0]),
+    }
+}
+
+pub(super) fn duration(secs: u64) -> String {
+    if secs.is_multiple_of(60) {
+        format!("{} min", secs / 60)
+    } else {
+        format!("{} s", secs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::alerts::test_telemetry;
+
+    fn cpu_temp(temp: Option<f32>) -> TelemetryData {
+        TelemetryData {
+            cpu_temp: temp,
+            ..test_telemetry(None)
+        }
+    }
+
+    #[test]
+    fn fires_after_duration_and_clears_with_hysteresis() {
+        let rule = ThresholdRule {
+            metric: TelemetryMetric::CpuTemp,
+            above: 75.0,
+            clear_below: Some(70.0),
+            for_secs: 300,
+        };
+        let mut state = ThresholdState::default();
+        let mut at = |temp, ms| state.update(&rule, &cpu_temp(temp), ms);
+
+        assert_eq!(at(Some(80.0), 0), None);
+        // Dipping under the limit restarts the clock
+        assert_eq!(at(Some(74.0), 100_000), None);
+        assert_eq!(at(Some(80.0), 200_000), None);
+        assert_eq!(at(None, 400_000), None);
+        assert_eq!(at(Some(78.0), 499_000), None);
+        assert_eq!(at(Some(78.0), 500_000), Some(Transition::Fired(78.0)));
+        assert_eq!(at(Some(90.0), 600_000), None);
+
+        assert_eq!(at(Some(72.0), 700_000), None);
+        assert_eq!(at(Some(70.0), 800_000), Some(Transition::Cleared(70.0)));
+        assert_eq!(at(Some(72.0), 900_000), None);
+    }
+
+    #[test]
+    fn fires_immediately_without_duration() {
+        let rule = ThresholdRule {
+            metric: TelemetryMetric::DiskUsage,
+            above: 90.0,
+            clear_below: None,
+            for_secs: 0,
+        };
+        let mut state = ThresholdState::default();
+        let disk = |usage| TelemetryData {
+            disk_usage: usage,
+            ..test_telemetry(None)
+        };
+
+        let fired = state.update(&rule, &disk(93.5), 0).unwrap();
+        assert_eq!(fired, Transition::Fired(93.5));
+        assert_eq!(message(&rule, fired), "Disk usage 93.5%, above 90%");
+        let cleared = state.update(&rule, &disk(90.0), 1_000).unwrap();
+        assert_eq!(message(&rule, cleared), "Disk usage back to 90.0%");
+
+        let rule = ThresholdRule {
+            for_secs: 300,
+            ..rule
+        };
+        assert_eq!(
+            message(&rule, Transition::Fired(95.0)),
+            "Disk usage 95.0%, above 90% for 5 min"
+        );
+    }
+}
@@ -0,0 +1,1161 @@
+//! App REST API.
+//!
+//! Endpoints the app adds on top of the embedded Kodama web server
+//! (recordings, ...) are served by this router on the web port, with CORS
+//! limited to the bundled UI's origins. Everything else, the core `/api/*`
+//! routes included, is forwarded to the core server, and the core's live
+//! `/ws` stream is relayed with app events added as text messages.
+//! Prometheus metrics are served on `/metrics`.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use axum::body::Body;
+use axum::extract::{Path, Query, Request, State};
+use axum::http::header::{
+    ACCEPT_RANGES, CACHE_CONTROL, CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_RANGE,
+    CONTENT_TYPE, RANGE, RETRY_AFTER,
+};
+use axum::http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode};
+use axum::response::Response;
+use axum::routing::{delete, get, post, put};
+use axum::{Json, Router};
+use serde::Deserialize;
+use tower_http::cors::CorsLayer;
+
+use crate::alerts::geofence::{Excursion, Fence, Geofence, MAX_EXCURSIONS};
+use crate::alerts::{Alert, AlertEngine, Geofences};
+use crate::config::ArmingMode;
+use crate::events::EventBus;
+use crate::export::{ExportJob, ExportManager};
+use crate::metrics::{self, Metrics};
+use crate::modes::{ModeStatus, Modes};
+use crate::notify::webhook::{Delivery, MAX_DELIVERIES};
+use crate::notify::Webhooks;
+use crate::proxy;
+use crate::snapshot::{SnapshotError, Snapshots};
+use crate::storage::health::StorageStatus;
+use crate::storage::playback::{PlaybackCache, MAX_WINDOW_MS};
+use crate::storage::replay::{self, TelemetrySample, MAX_REPLAY_MS};
+use crate::storage::{now_ms, ProtectedClip, ProtectedClips, SegmentEntry, StorageManager};
+use crate::timeseries::{self, Point, Resolution, TelemetryStore};
+
+const PLAYBACK_START: HeaderName = HeaderName::from_static("x-playback-start");
+const PLAYBACK_END: HeaderName = HeaderName::from_static("x-playback-end");
+const THUMBNAIL_TIME: HeaderName = HeaderName::from_static("x-thumbnail-time");
+const SNAPSHOT_TIME: HeaderName = HeaderName::from_static("x-snapshot-time");
+
+/// Shared state for API handlers.
+#[derive(Clone, Default)]
+pub struct ApiState {
+    /// `None` when recording is disabled.
+    pub storage: Option<Arc<StorageManager>>,
+    pub playback: Arc<PlaybackCache>,
+    /// `None` when recording is disabled.
+    pub exports: Option<Arc<ExportManager>>,
+    /// `None` if the alert history couldn't be opened.
+    pub alerts: Option<Arc<AlertEngine>>,
+    /// `None` without configured webhooks.
+    pub webhooks: Option<Arc<Webhooks>>,
+    /// `None` until the server has started.
+    pub snapshots: Option<Arc<Snapshots>>,
+    /// `None` if the saved mode couldn't be opened.
+    pub modes: Option<Arc<Modes>>,
+    /// `None` if the telemetry history is disabled or couldn't be opened.
+    pub telemetry: Option<Arc<TelemetryStore>>,
+    /// `None` until the server has started.
+    pub metrics: Option<Arc<Metrics>>,
+    pub events: EventBus,
+}
+
+type ApiResult<T> = Result<T, (StatusCode, String)>;
+
+/// Build the API router.
+pub fn router(state: ApiState) -> Router {
+    Router::new()
+        .route("/api/recordings", get(list_recordings))
+        .route("/api/recordings/{camera}/play", get(play_recording))
+        .route("/api/recordings/{camera}/thumbnail", get(thumbnail))
+        .route("/api/recordings/{camera}/telemetry", get(replay_telemetry))
+        .route("/api/protected", get(list_protected).post(create_protected))
+        .route("/api/protected/{id}", delete(remove_protected))
+        .route("/api/exports", get(list_exports).post(create_export))
+        .route("/api/exports/timelapse", post(create_timelapse))
+        .route("/api/exports/{id}", get(get_export))
+        .route("/api/exports/{id}/download", get(download_export))
+        .route("/api/storage", get(storage_status))
+        .route("/api/alerts", get(list_alerts).delete(clear_alerts))
+        .route("/api/geofences", get(list_geofences))
+        .route(
+            "/api/geofences/{camera}",
+            put(set_geofence).delete(remove_geofence),
+        )
+        .route("/api/geofences/{camera}/excursions", get(list_excursions))
+        .route("/api/webhooks/deliveries", get(list_deliveries))
+        .route("/api/cameras/{camera}/snapshot.jpg", get(snapshot))
+        .route("/api/cameras/{camera}/telemetry", get(telemetry_history))
+        .route("/api/mode", get(get_mode).put(set_mode))
+        .route("/metrics", get(prometheus_metrics))
+        .with_state(state)
+}
+
+/// Serve the API on `bind`, accepting browser requests from the UI at `web_port`.
+pub async fn serve(state: ApiState, bind: SocketAddr, core: SocketAddr) -> anyhow::Result<()> {
+    // The UI served from the web port itself is same-origin
+    let origins = [
+        HeaderValue::from_static("tauri://localhost"),
+        HeaderValue::from_static("http://tauri.localhost"),
+    ];
+
+    let cors = CorsLayer::new()
+        .allow_origin(origins)
+        .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE])
+        .allow_headers([RANGE, CONTENT_TYPE])
+        .expose_headers([
+            CONTENT_RANGE,
+            ACCEPT_RANGES,
+            CONTENT_DISPOSITION,
+            PLAYBACK_START,
+            PLAYBACK_END,
+            THUMBNAIL_TIME,
+            SNAPSHOT_TIME,
+        ]);
+    let events = state.events.clone();
+    let app = router(state)
+        .layer(cors)
+        .route(
+            "/ws",
+            get(move |ws, uri, headers| proxy::live(core, events.clone(), ws, uri, headers)),
+        )
+        .fallback(move |req: Request| proxy::forward(core, req));
+    let listener = tokio::net::TcpListener::bind(bind).await?;
+    tracing::info!("Web server listening on http://{}", bind);
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+fn storage(state: &ApiState) -> ApiResult<&Arc<StorageManager>> {
+    state.storage.as_ref().ok_or((
+        StatusCode::SERVICE_UNAVAILABLE,
+        "recording is disabled".into(),
+    ))
+}
+
+fn protected(state: &ApiState) -> ApiResult<&Arc<ProtectedClips>> {
+    storage(state)?.protected_clips().ok_or((
+        StatusCode::SERVICE_UNAVAILABLE,
+        "protected clips are unavailable".into(),
+    ))
+}
+
+fn exports(state: &ApiState) -> ApiResult<&Arc<ExportManager>> {
+    state.exports.as_ref().ok_or((
+        StatusCode::SERVICE_UNAVAILABLE,
+        "recording is disabled".into(),
+    ))
+}
+
+fn alerts(state: &ApiState) -> ApiResult<&Arc<AlertEngine>> {
+    state.alerts.as_ref().ok_or((
+        StatusCode::SERVICE_UNAVAILABLE,
+        "alerts are unavailable".into(),
+    ))
+}
+
+fn geofences(state: &ApiState) -> ApiResult<&Arc<Geofences>> {
+    alerts(state)?.geofences().ok_or((
+        StatusCode::SERVICE_UNAVAILABLE,
+        "geofences are unavailable".into(),
+    ))
+}
+
+fn snapshots(state: &ApiState) -> ApiResult<&Arc<Snapshots>> {
+    state.snapshots.as_ref().ok_or((
+        StatusCode::SERVICE_UNAVAILABLE,
+        "snapshots are unavailable".into(),
+    ))
+}
+
+fn modes(state: &ApiState) -> ApiResult<&Arc<Modes>> {
+    state.modes.as_ref().ok_or((
+        StatusCode::SERVICE_UNAVAILABLE,
+        "arming modes are unavailable".into(),
+    ))
+}
+
+fn telemetry(state: &ApiState) -> ApiResult<&Arc<TelemetryStore>> {
+    state.telemetry.as_ref().ok_or((
+        StatusCode::SERVICE_UNAVAILABLE,
+        "telemetry history is unavailable".into(),
+    ))
+}
+
+fn metrics(state: &ApiState) -> ApiResult<&Arc<Metrics>> {
+    state.metrics.as_ref().ok_or((
+        StatusCode::SERVICE_UNAVAILABLE,
+        "metrics are unavailable".into(),
+    ))
+}
+
+fn webhooks(state: &ApiState) -> ApiResult<&Arc<Webhooks>> {
+    state.webhooks.as_ref().ok_or((
+        StatusCode::SERVICE_UNAVAILABLE,
+        "no webhooks are configured".into(),
+    ))
+}
+
+#[derive(Debug, Deserialize)]
+struct RecordingsQuery {
+    camera: Option<String>,
+    /// Epoch ms
+    from: Option<u64>,
+    /// Epoch ms
+    to: Option<u64>,
+}
+
+/// `GET /api/recordings?camera=&from=&to=`
+async fn list_recordings(
+    State(state): State<ApiState>,
+    Query(q): Query<RecordingsQuery>,
+) -> ApiResult<Json<Vec<SegmentEntry>>> {
+    let storage = storage(&state)?;
+    Ok(Json(storage.list(q.camera.as_deref(), q.from, q.to)))
+}
+
+#[derive(Debug, Deserialize)]
+struct PlayQuery {
+    /// Epoch ms
+    from: u64,
+    /// Epoch ms, at most [`MAX_WINDOW_MS`] after `from`
+    to: Option<u64>,
+}
+
+/// `GET /api/recordings/{camera}/play?from=&to=`
+///
+/// Recorded video as fragmented MP4, starting at the keyframe at or before
+/// `from`. Supports single byte ranges so players can seek. The actual
+/// span is reported in `X-Playback-Start` / `X-Playback-End` (epoch ms).
+async fn play_recording(
+    State(state): State<ApiState>,
+    Path(camera_id): Path<String>,
+    Query(q): Query<PlayQuery>,
+    headers: HeaderMap,
+) -> ApiResult<Response> {
+    let storage = storage(&state)?;
+    let max_to = q.from.saturating_add(MAX_WINDOW_MS);
+    let to = q.to.unwrap_or(max_to).min(max_to);
+    if to < q.from {
+        return Err((StatusCode::BAD_REQUEST, "`to` is before `from`".into()));
+    }
+
+    let playback = state
+        .playback
+        .get_or_render(storage, &camera_id, q.from, to)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or((StatusCode::NOT_FOUND, "no recorded video in range".into()))?;
+
+    let len = playback.len;
+    let response = Response::builder()
+        .header(CONTENT_TYPE, "video/mp4")
+        .header(ACCEPT_RANGES, "bytes")
+        .header(PLAYBACK_START, playback.start_ms)
+        .header(PLAYBACK_END, playback.end_ms);
+    let range = headers
+        .get(RANGE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| parse_range(v, len));
+
+    // Fragments are rendered as the body is sent, not held in memory
+    let body = |start: u64, end: u64| {
+        let (mut writer, reader) = tokio::io::duplex(64 * 1024);
+        let storage = storage.clone();
+        let playback = playback.clone();
+        tokio::spawn(async move {
+            if let Err(e) = playback.write_range(&storage, start, end, &mut writer).await {
+                tracing::debug!("Playback of {} ended early: {}", camera_id, e);
+            }
+        });
+        Body::from_stream(tokio_util::io::ReaderStream::new(reader))
+    };
+    let response = match range {
+        None => response
+            .header(CONTENT_LENGTH, len)
+            .body(body(0, len - 1)),
+        Some(Some((start, end))) => response
+            .status(StatusCode::PARTIAL_CONTENT)
+            .header(CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, len))
+            .header(CONTENT_LENGTH, end - start + 1)
+            .body(body(start, end)),
+        Some(None) => response
+            .status(StatusCode::RANGE_NOT_SATISFIABLE)
+            .header(CONTENT_RANGE, format!("bytes */{}", len))
+            .body(Body::empty()),
+    };
+    response.map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+#[derive(Debug, Deserialize)]
+struct ThumbnailQuery {
+    /// Epoch ms
+    at: u64,
+}
+
+/// `GET /api/recordings/{camera}/thumbnail?at=`
+///
+/// The keyframe thumbnail nearest `at`, as JPEG. Its time is reported in
+/// `X-Thumbnail-Time` (epoch ms).
+async fn thumbnail(
+    State(state): State<ApiState>,
+    Path(camera_id): Path<String>,
+    Query(q): Query<ThumbnailQuery>,
+) -> ApiResult<Response> {
+    let (ts, path) = storage(&state)?
+        .thumbnails()
+        .ok_or((StatusCode::NOT_FOUND, "thumbnails are disabled".into()))?
+        .nearest(&camera_id, q.at)
+        .ok_or((StatusCode::NOT_FOUND, "no thumbnail near that time".into()))?;
+    let jpeg = tokio::fs::read(&path)
+        .await
+        .map_err(|e| (StatusCode::NOT_FOUND, e.to_string()))?;
+    Response::builder()
+        .header(CONTENT_TYPE, "image/jpeg")
+        .header(THUMBNAIL_TIME, ts)
+        .body(Body::from(jpeg))
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+#[derive(Debug, Deserialize)]
+struct SnapshotQuery {
+    /// Pixels; the height keeps the aspect ratio. Defaults to the camera's.
+    width: Option<u32>,
+    /// JPEG quality, 1 to 100
+    quality: Option<u8>,
+}
+
+/// `GET /api/cameras/{camera}/snapshot.jpg?width=&quality=`
+///
+/// The camera's latest keyframe as JPEG, with its time in `X-Snapshot-Time`
+/// (epoch ms). `429` with `Retry-After` if the camera was decoded too
+/// recently to decode it again.
+async fn snapshot(
+    State(state): State<ApiState>,
+    Path(camera_id): Path<String>,
+    Query(q): Query<SnapshotQuery>,
+) -> ApiResult<Response> {
+    let quality = q.quality.unwrap_or(80);
+    match snapshots(&state)?.get(&camera_id, q.width, quality).await {
+        Ok(snapshot) => Response::builder()
+            .header(CONTENT_TYPE, "image/jpeg")
+            .header(CACHE_CONTROL, "no-cache")
+            .header(SNAPSHOT_TIME, snapshot.time_ms)
+            .body(Body::from(snapshot.jpeg))
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+        Err(SnapshotError::NoKeyframe) => Err((
+            StatusCode::NOT_FOUND,
+            "no keyframe received from this camera".into(),
+        )),
+        Err(SnapshotError::RateLimited { retry_after }) => Response::builder()
+            .status(StatusCode::TOO_MANY_REQUESTS)
+            .header(RETRY_AFTER, retry_after.as_secs().max(1))
+            .body(Body::from("snapshot rate limited"))
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+        Err(SnapshotError::Decode(e)) => {
+            tracing::warn!(camera = %camera_id, "Snapshot failed: {:#}", e);
+            Err((StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e)))
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct TelemetryQuery {
+    /// Epoch ms
+    from: u64,
+    /// Epoch ms, at most [`MAX_REPLAY_MS`] after `from`
+    to: Option<u64>,
+    /// Minimum spacing between samples, ms
+    step: Option<u64>,
+}
+
+/// `GET /api/recordings/{camera}/telemetry?from=&to=&step=`
+///
+/// Recorded telemetry samples in range, oldest first, each with its
+/// capture time (epoch ms).
+async fn replay_telemetry(
+    State(state): State<ApiState>,
+    Path(camera_id): Path<String>,
+    Query(q): Query<TelemetryQuery>,
+) -> ApiResult<Json<Vec<TelemetrySample>>> {
+    let storage = storage(&state)?;
+    let max_to = q.from.saturating_add(MAX_REPLAY_MS);
+    let to = q.to.unwrap_or(max_to).min(max_to);
+    if to < q.from {
+        return Err((StatusCode::BAD_REQUEST, "`to` is before `from`".into()));
+    }
+    replay::telemetry(storage, &camera_id, q.from, to, q.step)
+        .await
+        .map(Json)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+#[derive(Debug, Deserialize)]
+struct HistoryQuery {
+    /// Epoch ms, default an hour before `to`
+    from: Option<u64>,
+    /// Epoch ms, default now
+    to: Option<u64>,
+    /// `raw`, `1m` or `1h`; picked from the range if not given
+    resolution: Option<Resolution>,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct History {
+    resolution: Resolution,
+    points: Vec<Point>,
+}
+
+/// `GET /api/cameras/{camera}/telemetry?from=&to=&resolution=`
+async fn telemetry_history(
+    State(state): State<ApiState>,
+    Path(camera_id): Path<String>,
+    Query(q): Query<HistoryQuery>,
+) -> ApiResult<Json<History>> {
+    let store = telemetry(&state)?;
+    let now = now_ms();
+    let to = q.to.unwrap_or(now);
+    let from = q.from.unwrap_or(to.saturating_sub(60 * 60 * 1000));
+    if to < from {
+        return Err((StatusCode::BAD_REQUEST, "`to` is before `from`".into()));
+    }
+    let resolution = q
+        .resolution
+        .unwrap_or_else(|| Resolution::auto(from, to, now));
+    if !resolution.fits(from, to) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!(
+                "range is over {} points at this resolution",
+                timeseries::MAX_POINTS
+            ),
+        ));
+    }
+    let points = store
+        .query(&camera_id, resolution, from, to)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(Json(History { resolution, points }))
+}
+
+/// Parse a `Range` header against a body of `len` bytes.
+///
+/// `None` means serve the whole body (absent, malformed or multi-range
+/// requests), `Some(None)` an unsatisfiable range, and `Some(Some((a, b)))`
+/// the inclusive byte range to send.
+fn parse_range(header: &str, len: u64) -> Option<Option<(u64, u64)>> {
+    let spec = header.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+
+    let range = if start.is_empty() {
+        // Suffix: the last `end` bytes
+        let n: u64 = end.parse().ok()?;
+        (n > 0 && len > 0).then(|| (len.saturating_sub(n), len - 1))
+    } else {
+        let start: u64 = start.parse().ok()?;
+        let end = if end.is_empty() {
+            len.saturating_sub(1)
+        } else {
+            end.parse::<u64>().ok()?.min(len.saturating_sub(1))
+        };
+        (start < len && start <= end).then_some((start, end))
+    };
+    Some(range)
+}
+
+#[derive(Debug, Deserialize)]
+struct ProtectedQuery {
+    camera: Option<String>,
+}
+
+/// `GET /api/protected?camera=`
+async fn list_protected(
+    State(state): State<ApiState>,
+    Query(q): Query<ProtectedQuery>,
+) -> ApiResult<Json<Vec<ProtectedClip>>> {
+    Ok(Json(protected(&state)?.list(q.camera.as_deref())))
+}
+
+#[derive(Debug, Deserialize)]
+struct ProtectRequest {
+    camera: String,
+    /// Epoch ms
+    start: u64,
+    /// Epoch ms
+    end: u64,
+    label: String,
+    #[serde(default)]
+    note: String,
+}
+
+/// `POST /api/protected` with `{camera, start, end, label, note}`
+async fn create_protected(
+    State(state): State<ApiState>,
+    Json(req): Json<ProtectRequest>,
+) -> ApiResult<(StatusCode, Json<ProtectedClip>)> {
+    let clip = protected(&state)?
+        .add(req.camera, req.start, req.end, req.label, req.note)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    Ok((StatusCode::CREATED, Json(clip)))
+}
+
+/// `DELETE /api/protected/{id}`: release a protected clip. Its segments
+/// fall back under normal retention.
+async fn remove_protected(
+    State(state): State<ApiState>,
+    Path(id): Path<String>,
+) -> ApiResult<Json<ProtectedClip>> {
+    protected(&state)?
+        .remove(&id)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map(Json)
+        .ok_or((StatusCode::NOT_FOUND, "unknown protected clip".into()))
+}
+
+#[derive(Debug, Deserialize)]
+struct ExportRequest {
+    camera: String,
+    /// Epoch ms
+    start: u64,
+    /// Epoch ms
+    end: u64,
+}
+
+/// `POST /api/exports` with `{camera, start, end}`
+async fn create_export(
+    State(state): State<ApiState>,
+    Json(req): Json<ExportRequest>,
+) -> ApiResult<(StatusCode, Json<ExportJob>)> {
+    let job = exports(&state)?
+        .start(req.camera, req.start, req.end)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    Ok((StatusCode::ACCEPTED, Json(job)))
+}
+
+#[derive(Debug, Deserialize)]
+struct TimelapseRequest {
+    camera: String,
+    /// Epoch ms
+    start: u64,
+    /// Epoch ms
+    end: u64,
+    /// Take one keyframe per this many ms
+    interval: u64,
+}
+
+/// `POST /api/exports/timelapse` with `{camera, start, end, interval}`
+async fn create_timelapse(
+    State(state): State<ApiState>,
+    Json(req): Json<TimelapseRequest>,
+) -> ApiResult<(StatusCode, Json<ExportJob>)> {
+    let job = exports(&state)?
+        .start_timelapse(req.camera, req.start, req.end, req.interval)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    Ok((StatusCode::ACCEPTED, Json(job)))
+}
+
+/// `GET /api/exports`
+async fn list_exports(State(state): State<ApiState>) -> ApiResult<Json<Vec<ExportJob>>> {
+    Ok(Json(exports(&state)?.jobs()))
+}
+
+/// `GET /api/exports/{id}`
+async fn get_export(
+    State(state): State<ApiState>,
+    Path(id): Path<String>,
+) -> ApiResult<Json<ExportJob>> {
+    exports(&state)?
+        .job(&id)
+        .map(Json)
+        .ok_or((StatusCode::NOT_FOUND, "unknown export".into()))
+}
+
+/// `GET /api/exports/{id}/download`
+async fn download_export(
+    State(state): State<ApiState>,
+    Path(id): Path<String>,
+) -> ApiResult<Response> {
+    let (path, file_name) = exports(&state)?
+        .file(&id)
+        .ok_or((StatusCode::NOT_FOUND, "export not ready".into()))?;
+    let file = tokio::fs::File::open(&path)
+        .await
+        .map_err(|e| (StatusCode::NOT_FOUND, e.to_string()))?;
+    let len = file
+        .metadata()
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .len();
+
+    Response::builder()
+        .header(CONTENT_TYPE, "video/mp4")
+        .header(CONTENT_LENGTH, len)
+        .header(
+            CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{}\"", file_name),
+        )
+        .body(Body::from_stream(tokio_util::io::ReaderStream::new(file)))
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+/// `GET /api/storage`
+async fn storage_status(State(state): State<ApiState>) -> ApiResult<Json<StorageStatus>> {
+    Ok(Json(storage(&state)?.status()))
+}
+
+/// Alerts listed when no `limit` is given.
+const DEFAULT_ALERT_LIMIT: usize = 100;
+
+#[derive(Debug, Deserialize)]
+struct AlertsQuery {
+    camera: Option<String>,
+    limit: Option<usize>,
+}
+
+/// `GET /api/alerts?camera=&limit=`, newest first
+async fn list_alerts(
+    State(state): State<ApiState>,
+    Query(q): Query<AlertsQuery>,
+) -> ApiResult<Json<Vec<Alert>>> {
+    let limit = q.limit.unwrap_or(DEFAULT_ALERT_LIMIT);
+    Ok(Json(alerts(&state)?.recent(q.camera.as_deref(), limit)))
+}
+
+/// `DELETE /api/alerts`
+async fn clear_alerts(State(state): State<ApiState>) -> ApiResult<StatusCode> {
+    alerts(&state)?
+        .clear()
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// `GET /api/geofences`
+async fn list_geofences(State(state): State<ApiState>) -> ApiResult<Json<Vec<Geofence>>> {
+    Ok(Json(geofences(&state)?.list()))
+}
+
+#[derive(Debug, Deserialize)]
+struct GeofenceRequest {
+    #[serde(flatten)]
+    fence: Fence,
+    #[serde(default = "default_min_fix_mode")]
+    min_fix_mode: u8,
+}
+
+/// 2D fix
+fn default_min_fix_mode() -> u8 {
+    2
+}
+
+/// `PUT /api/geofences/{camera}` with a circle
+/// `{shape: "circle", center: {latitude, longitude}, radius_m}` or a polygon
+/// `{shape: "polygon", points: [{latitude, longitude}, ...]}`, and optionally
+/// `min_fix_mode`
+async fn set_geofence(
+    State(state): State<ApiState>,
+    Path(camera_id): Path<String>,
+    Json(req): Json<GeofenceRequest>,
+) -> ApiResult<Json<Geofence>> {
+    let geofences = geofences(&state)?;
+    req.fence
+        .validate()
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    geofences
+        .set(camera_id, req.fence, req.min_fix_mode, now_ms())
+        .map(Json)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+/// `DELETE /api/geofences/{camera}`
+async fn remove_geofence(
+    State(state): State<ApiState>,
+    Path(camera_id): Path<String>,
+) -> ApiResult<Json<Geofence>> {
+    geofences(&state)?
+        .remove(&camera_id, now_ms())
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map(Json)
+        .ok_or((StatusCode::NOT_FOUND, "camera has no geofence".into()))
+}
+
+#[derive(Debug, Deserialize)]
+struct ExcursionsQuery {
+    limit: Option<usize>,
+}
+
+/// `GET /api/geofences/{camera}/excursions?limit=`, newest first
+async fn list_excursions(
+    State(state): State<ApiState>,
+    Path(camera_id): Path<String>,
+    Query(q): Query<ExcursionsQuery>,
+) -> ApiResult<Json<Vec<Excursion>>> {
+    let limit = q.limit.unwrap_or(MAX_EXCURSIONS);
+    Ok(Json(geofences(&state)?.excursions(&camera_id, limit)))
+}
+
+#[derive(Debug, Deserialize)]
+struct DeliveriesQuery {
+    limit: Option<usize>,
+}
+
+/// `GET /api/webhooks/deliveries?limit=`, newest first
+async fn list_deliveries(
+    State(state): State<ApiState>,
+    Query(q): Query<DeliveriesQuery>,
+) -> ApiResult<Json<Vec<Delivery>>> {
+    let limit = q.limit.unwrap_or(MAX_DELIVERIES);
+    Ok(Json(webhooks(&state)?.deliveries(limit)))
+}
+
+/// `GET /api/mode`
+async fn get_mode(State(state): State<ApiState>) -> ApiResult<Json<ModeStatus>> {
+    Ok(Json(modes(&state)?.status(now_ms())))
+}
+
+#[derive(Debug, Deserialize)]
+struct ModeRequest {
+    mode: ArmingMode,
+}
+
+/// `PUT /api/mode` with `{mode}`; holds until the next scheduled switch
+async fn set_mode(
+    State(state): State<ApiState>,
+    Json(req): Json<ModeRequest>,
+) -> ApiResult<Json<ModeStatus>> {
+    modes(&state)?
+        .set(req.mode, now_ms())
+        .map(Json)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+/// `GET /metrics` in the Prometheus text format
+async fn prometheus_metrics(State(state): State<ApiState>) -> ApiResult<Response> {
+    let text = metrics(&state)?.render(state.storage.as_deref(), &state.events);
+    Response::builder()
+        .header(CONTENT_TYPE, metrics::CONTENT_TYPE)
+        .body(Body::from(text))
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use axum::http::Request;
+    use bytes::Bytes;
+    use http_body_util::BodyExt;
+    use kodama::{Channel, Frame, FrameFlags, SourceId};
+    use tower::ServiceExt;
+
+    use crate::alerts::{AlertHistory, AlertKind};
+    use crate::storage::manager::{test_config, test_manager};
+    use crate::storage::playback::record_test_video;
+    use crate::storage::SegmentKind;
+
+    async fn get_json(app: Router, uri: &str) -> (StatusCode, serde_json::Value) {
+        let res = app
+            .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let status = res.status();
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        (
+            status,
+            serde_json::from_slice(&body).unwrap_or(serde_json::Value::Null),
+        )
+    }
+
+    #[tokio::test]
+    async fn recordings_unavailable_without_storage() {
+        let (status, _) = get_json(router(ApiState::default()), "/api/recordings").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn storage_reports_health() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = test_manager(dir.path(), test_config());
+        record_test_video(&manager, "cam", 10_000, 0..2).await;
+        manager
+            .health()
+            .store_failed("cam", &anyhow::anyhow!("disk full"));
+        let app = router(ApiState {
+            storage: Some(Arc::new(manager)),
+            ..Default::default()
+        });
+
+        let (status, body) = get_json(app, "/api/storage").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["state"], "failing");
+        assert_eq!(body["segments"], 1);
+        assert_eq!(body["cameras"]["cam"]["store_errors"], 1);
+        assert_eq!(body["cameras"]["cam"]["last_error"], "disk full");
+    }
+
+    #[tokio::test]
+    async fn recordings_lists_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = test_manager(dir.path(), test_config());
+        for (camera_id, start_ms) in [("a", 0), ("a", 60_000), ("b", 0)] {
+            let mut rec = manager.recorder(camera_id.into());
+            let frame = Frame {
+                source: SourceId::from_node_id_bytes(&[1; 32]),
+                channel: Channel::Video,
+                flags: FrameFlags::keyframe(),
+                timestamp_us: 0,
+                payload: Bytes::from_static(b"idr"),
+            };
+            rec.store(&frame, start_ms, SegmentKind::Continuous)
+                .await
+                .unwrap();
+            rec.finish().await.unwrap();
+        }
+        let state = ApiState {
+            storage: Some(Arc::new(manager)),
+            ..Default::default()
+        };
+
+        let (status, body) = get_json(router(state), "/api/recordings?camera=a&from=30000").await;
+        assert_eq!(status, StatusCode::OK);
+        let starts: Vec<u64> = body
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|s| s["start"].as_u64().unwrap())
+            .collect();
+        assert_eq!(starts, vec![60_000]);
+    }
+
+    async fn get_range(app: Router, uri: &str, range: &str) -> (StatusCode, HeaderMap, Bytes) {
+        let res = app
+            .oneshot(
+                Request::get(uri)
+                    .header(RANGE, range)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let status = res.status();
+        let headers = res.headers().clone();
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        (status, headers, body)
+    }
+
+    #[test]
+    fn range_parsing() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Some(Some((0, 99))));
+        assert_eq!(parse_range("bytes=900-", 1000), Some(Some((900, 999))));
+        assert_eq!(parse_range("bytes=-100", 1000), Some(Some((900, 999))));
+        assert_eq!(parse_range("bytes=500-5000", 1000), Some(Some((500, 999))));
+        assert_eq!(parse_range("bytes=1000-", 1000), Some(None));
+        assert_eq!(parse_range("bytes=0-1,5-6", 1000), None);
+        assert_eq!(parse_range("items=0-1", 1000), None);
+    }
+
+    #[tokio::test]
+    async fn play_serves_byte_ranges() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = test_manager(dir.path(), test_config());
+        record_test_video(&manager, "cam", 10_000, 0..3).await;
+        let app = router(ApiState {
+            storage: Some(Arc::new(manager)),
+            ..Default::default()
+        });
+        let uri = "/api/recordings/cam/play?from=11500&to=13000";
+
+        let (status, headers, full) = get_range(app.clone(), uri, "bytes=0-").await;
+        assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(headers[CONTENT_TYPE], "video/mp4");
+        assert_eq!(headers[PLAYBACK_START], "11000");
+        assert_eq!(&full[4..8], b"ftyp");
+
+        let (status, headers, part) = get_range(app.clone(), uri, "bytes=4-7").await;
+        assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            headers[CONTENT_RANGE],
+            format!("bytes 4-7/{}", full.len()).as_str()
+        );
+        assert_eq!(&part[..], b"ftyp");
+
+        let past_end = format!("bytes={}-", full.len());
+        let (status, _, _) = get_range(app.clone(), uri, &past_end).await;
+        assert_eq!(status, StatusCode::RANGE_NOT_SATISFIABLE);
+
+        let (status, _) = get_json(app, "/api/recordings/cam/play?from=99000").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn export_lifecycle() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = Arc::new(test_manager(&dir.path().join("rec"), test_config()));
+        record_test_video(&manager, "cam", 10_000, 0..3).await;
+        let exports = ExportManager::new(manager.clone(), dir.path().join("exports")).unwrap();
+        let app = router(ApiState {
+            storage: Some(manager),
+            exports: Some(Arc::new(exports)),
+            ..Default::default()
+        });
+
+        let res = app
+            .clone()
+            .oneshot(
+                Request::post("/api/exports")
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from(r#"{"camera":"cam","start":10000,"end":12000}"#))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::ACCEPTED);
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        let job: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let id = job["id"].as_str().unwrap().to_string();
+
+        let mut status = String::new();
+        for _ in 0..100 {
+            let (_, job) = get_json(app.clone(), &format!("/api/exports/{}", id)).await;
+            status = job["status"].as_str().unwrap().to_string();
+            if status != "queued" && status != "running" {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(status, "done");
+
+        let res = app
+            .oneshot(
+                Request::get(format!("/api/exports/{}/download", id))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res.headers()[CONTENT_DISPOSITION]
+            .to_str()
+            .unwrap()
+            .starts_with("attachment; filename=\"cam_"));
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[4..8], b"ftyp");
+    }
+
+    #[tokio::test]
+    async fn snapshot_needs_a_keyframe() {
+        let state = ApiState {
+            snapshots: Some(Arc::new(Snapshots::new("ffmpeg".into()))),
+            ..Default::default()
+        };
+        let (status, _) = get_json(router(state), "/api/cameras/cam/snapshot.jpg?width=320").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, _) =
+            get_json(router(ApiState::default()), "/api/cameras/cam/snapshot.jpg").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn alerts_list_and_clear() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut history = AlertHistory::open(dir.path().join("alerts.jsonl")).unwrap();
+        history.push(AlertKind::Disconnect, "a".into(), "Disconnected".into(), 1_000);
+        history.push(AlertKind::Motion, "b".into(), "Motion detected (80%)".into(), 2_000);
+        history.push(AlertKind::Reconnect, "a".into(), "Reconnected".into(), 3_000);
+        let engine = AlertEngine::new(Default::default(), history, EventBus::default());
+        let app = router(ApiState {
+            alerts: Some(Arc::new(engine)),
+            ..Default::default()
+        });
+
+        let (status, alerts) = get_json(app.clone(), "/api/alerts?camera=a&limit=1").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(alerts.as_array().unwrap().len(), 1);
+        assert_eq!(alerts[0]["type"], "reconnect");
+        assert_eq!(alerts[0]["time"], 3_000);
+
+        let res = app
+            .clone()
+            .oneshot(Request::delete("/api/alerts").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let (_, alerts) = get_json(app, "/api/alerts").await;
+        assert_eq!(alerts, serde_json::json!([]));
+    }
+
+    #[tokio::test]
+    async fn geofence_lifecycle() {
+        let dir = tempfile::tempdir().unwrap();
+        let history = AlertHistory::open(dir.path().join("alerts.jsonl")).unwrap();
+        let mut engine = AlertEngine::new(Default::default(), history, EventBus::default());
+        let geofences = Geofences::open(
+            dir.path().join("geofences.json"),
+            dir.path().join("excursions.jsonl"),
+        )
+        .unwrap();
+        engine.set_geofences(Arc::new(geofences));
+        let app = router(ApiState {
+            alerts: Some(Arc::new(engine)),
+            ..Default::default()
+        });
+        let put = |body: &'static str| {
+            Request::put("/api/geofences/cam")
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(body))
+                .unwrap()
+        };
+
+        let res = app
+            .clone()
+            .oneshot(put(r#"{"shape":"polygon","points":[{"latitude":0,"longitude":0}]}"#))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        let res = app
+            .clone()
+            .oneshot(put(
+                r#"{"shape":"circle","center":{"latitude":45,"longitude":-122},"radius_m":50}"#,
+            ))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let (_, fences) = get_json(app.clone(), "/api/geofences").await;
+        assert_eq!(fences[0]["camera_id"], "cam");
+        assert_eq!(fences[0]["shape"], "circle");
+        assert_eq!(fences[0]["radius_m"], 50.0);
+        assert_eq!(fences[0]["min_fix_mode"], 2);
+        let (status, excursions) = get_json(app.clone(), "/api/geofences/cam/excursions").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(excursions, serde_json::json!([]));
+
+        let delete = || {
+            Request::delete("/api/geofences/cam")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let res = app.clone().oneshot(delete()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = app.oneshot(delete()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn mode_get_and_set() {
+        let dir = tempfile::tempdir().unwrap();
+        let (status, _) = get_json(router(ApiState::default()), "/api/mode").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+
+        let modes = Modes::open(
+            Default::default(),
+            dir.path().join("mode.json"),
+            EventBus::default(),
+        )
+        .unwrap();
+        let app = router(ApiState {
+            modes: Some(Arc::new(modes)),
+            ..Default::default()
+        });
+        let (status, body) = get_json(app.clone(), "/api/mode").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["mode"], "away");
+        assert_eq!(body["next"], serde_json::Value::Null);
+
+        let put = |body: &'static str| {
+            Request::put("/api/mode")
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(body))
+                .unwrap()
+        };
+        let res = app.clone().oneshot(put(r#"{"mode":"vacation"}"#)).await.unwrap();
+        assert!(res.status().is_client_error());
+        let res = app.clone().oneshot(put(r#"{"mode":"night"}"#)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let (_, body) = get_json(app, "/api/mode").await;
+        assert_eq!(body["mode"], "night");
+        assert_eq!(body["source"], "manual");
+    }
+
+    #[tokio::test]
+    async fn telemetry_history_by_resolution() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = TelemetryStore::open(dir.path()).unwrap();
+        let now = now_ms();
+        let start = now - now % 60_000 - 120_000;
+        for (i, cpu) in [10.0, 20.0, 30.0].into_iter().enumerate() {
+            let telemetry = kodama::capture::TelemetryData {
+                cpu_usage: cpu,
+                ..crate::alerts::test_telemetry(None)
+            };
+            store
+                .record("cam", &telemetry, start + i as u64 * 60_000)
+                .unwrap();
+        }
+        let app = router(ApiState {
+            telemetry: Some(Arc::new(store)),
+            ..Default::default()
+        });
+
+        // The last hour, by minute
+        let (status, body) = get_json(app.clone(), "/api/cameras/cam/telemetry").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["resolution"], "1m");
+        assert_eq!(body["points"].as_array().unwrap().len(), 2);
+        assert_eq!(body["points"][1]["count"], 1);
+        assert_eq!(body["points"][1]["max"]["cpu_usage"], 20.0);
+
+        let uri = format!("/api/cameras/cam/telemetry?from={}&resolution=raw", start);
+        let (_, body) = get_json(app.clone(), &uri).await;
+        assert_eq!(body["resolution"], "raw");
+        assert_eq!(body["points"].as_array().unwrap().len(), 3);
+        assert_eq!(body["points"][0]["cpu_usage"], 10.0);
+        assert_eq!(body["points"][0]["cpu_temp"], 50.0);
+        assert!(body["points"][0].get("max").is_none());
+
+        let (status, _) = get_json(
+            app.clone(),
+            "/api/cameras/cam/telemetry?from=0&to=86400000&resolution=raw",
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = get_json(app, "/api/cameras/cam/telemetry?resolution=5m").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn metrics_text_format() {
+        let res = router(ApiState::default())
+            .oneshot(Request::get("/metrics").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        let app = router(ApiState {
+            metrics: Some(Arc::new(Metrics::default())),
+            ..Default::default()
+        });
+        let res = app
+            .oneshot(Request::get("/metrics").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[CONTENT_TYPE], metrics::CONTENT_TYPE);
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        let text = std::str::from_utf8(&body).unwrap();
+        assert!(text.contains("# TYPE kodama_uptime_seconds gauge\n"));
+        assert!(text.contains("kodama_peers{kind=\"camera\"} 0\n"));
+    }
+}
@@ -0,0 +1,730 @@
+//! Persistent app configuration.
+//!
+//! Settings that don't fit in a single environment variable (per-camera
+//! overrides, rule lists, ...) live in a JSON file at `KODAMA_CONFIG_PATH`,
+//! defaulting to `<data dir>/kodama/config.json`. A missing file yields the
+//! defaults, so the app runs unconfigured out of the box.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::storage::Container;
+
+/// Top-level app configuration file.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AppConfig {
+    pub recording: RecordingConfig,
+    pub storage: StorageSettings,
+    pub alerts: AlertsConfig,
+    pub modes: ModesConfig,
+    pub notifications: NotificationsConfig,
+}
+
+impl AppConfig {
+    /// Load the config from `path`, falling back to defaults if it doesn't exist.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let raw = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&raw)?)
+    }
+}
+
+/// Recording behavior, with optional per-camera overrides keyed by source ID.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RecordingConfig {
+    pub default: CameraRecordingConfig,
+    pub cameras: HashMap<String, CameraRecordingConfig>,
+}
+
+impl RecordingConfig {
+    /// Settings for a camera, falling back to the global default.
+    pub fn for_camera(&self, camera_id: &str) -> &CameraRecordingConfig {
+        self.cameras.get(camera_id).unwrap_or(&self.default)
+    }
+}
+
+/// When a camera's frames are written to storage.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RecordingMode {
+    /// Record everything.
+    #[default]
+    Continuous,
+    /// Record only around events, padded by pre-roll and post-roll.
+    Event,
+}
+
+/// Per-camera recording settings.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CameraRecordingConfig {
+    pub mode: RecordingMode,
+    /// Seconds of footage kept in memory and flushed when an event fires.
+    pub pre_roll_secs: u64,
+    /// Seconds to keep recording after an event ends.
+    pub post_roll_secs: u64,
+    /// Telemetry `motion_level` at or above which a motion event is active.
+    pub motion_threshold: f32,
+    /// Target segment length. Segments roll at the next keyframe after it,
+    /// so longer ones mean fewer files but more footage lost on a crash.
+    pub segment_secs: u64,
+    /// File format for new segments.
+    pub container: Container,
+}
+
+impl Default for CameraRecordingConfig {
+    fn default() -> Self {
+        Self {
+            mode: RecordingMode::Continuous,
+            pre_roll_secs: 10,
+            post_roll_secs: 30,
+            motion_threshold: 0.3,
+            segment_secs: 60,
+            container: Container::Native,
+        }
+    }
+}
+
+/// Storage locations beyond `KODAMA_STORAGE_PATH`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StorageSettings {
+    /// Extra roots, after the storage path, in spillover order.
+    pub roots: Vec<StorageRootConfig>,
+}
+
+/// How a storage root takes new segments.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RootMode {
+    /// Used once the roots before it reach their caps.
+    #[default]
+    Spillover,
+    /// Receives a copy of every segment.
+    Mirror,
+}
+
+/// One storage root.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StorageRootConfig {
+    pub path: PathBuf,
+    #[serde(default)]
+    pub mode: RootMode,
+    /// Size at which spillover moves on to the next root. Ignored for
+    /// mirrors, which hold everything.
+    #[serde(default = "default_root_max_gb")]
+    pub max_gb: u64,
+}
+
+fn default_root_max_gb() -> u64 {
+    10
+}
+
+/// Server-side alert rules, with optional per-camera overrides keyed by
+/// source ID.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AlertsConfig {
+    pub default: AlertRules,
+    pub cameras: HashMap<String, AlertRules>,
+}
+
+impl AlertsConfig {
+    /// Rules for a camera, falling back to the global default.
+    pub fn for_camera(&self, camera_id: &str) -> &AlertRules {
+        self.cameras.get(camera_id).unwrap_or(&self.default)
+    }
+}
+
+/// Alert rules for one camera.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AlertRules {
+    pub motion: MotionAlertRule,
+    pub disconnect: DisconnectAlertRule,
+    pub thresholds: Vec<ThresholdRule>,
+}
+
+/// Alert when telemetry `motion_level` crosses a threshold.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MotionAlertRule {
+    pub enabled: bool,
+    pub threshold: f32,
+    /// Minimum time between motion alerts for the same camera.
+    pub cooldown_secs: u64,
+}
+
+impl Default for MotionAlertRule {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            threshold: 0.6,
+            cooldown_secs: 30,
+        }
+    }
+}
+
+/// Alert when a camera stops sending frames, and when it comes back.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DisconnectAlertRule {
+    pub enabled: bool,
+    /// Seconds without a frame before a camera counts as disconnected.
+    pub after_secs: u64,
+}
+
+impl Default for DisconnectAlertRule {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            after_secs: 10,
+        }
+    }
+}
+
+/// Device health reading a threshold rule watches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TelemetryMetric {
+    /// °C; cameras without a sensor never trigger it.
+    CpuTemp,
+    /// Percent
+    DiskUsage,
+    /// Percent
+    MemoryUsage,
+    /// One-minute load average
+    LoadAverage,
+}
+
+/// Alert while a telemetry reading stays above a limit.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ThresholdRule {
+    pub metric: TelemetryMetric,
+    pub above: f32,
+    /// Value the reading must drop to before the alert clears, so a reading
+    /// hovering at the limit doesn't flap. Defaults to `above`.
+    #[serde(default)]
+    pub clear_below: Option<f32>,
+    /// How long the reading must stay above the limit before firing.
+    #[serde(default)]
+    pub for_secs: u64,
+}
+
+/// Arming modes: what each one does per camera, and when they switch.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ModesConfig {
+    pub default: ModeEffects,
+    pub cameras: HashMap<String, ModeEffects>,
+    pub schedule: Vec<ScheduleEntry>,
+}
+
+impl ModesConfig {
+    /// Effects for a camera, falling back to the global default.
+    pub fn for_camera(&self, camera_id: &str) -> &ModeEffects {
+        self.cameras.get(camera_id).unwrap_or(&self.default)
+    }
+}
+
+/// The server's arming mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ArmingMode {
+    Home,
+    Away,
+    Night,
+    Disarmed,
+}
+
+impl ArmingMode {
+    pub const ALL: [ArmingMode; 4] = [
+        ArmingMode::Home,
+        ArmingMode::Away,
+        ArmingMode::Night,
+        ArmingMode::Disarmed,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ArmingMode::Home => "home",
+            ArmingMode::Away => "away",
+            ArmingMode::Night => "night",
+            ArmingMode::Disarmed => "disarmed",
+        }
+    }
+}
+
+/// What each mode does to a camera.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ModeEffects {
+    pub home: ModeEffect,
+    pub away: ModeEffect,
+    pub night: ModeEffect,
+    pub disarmed: ModeEffect,
+}
+
+impl ModeEffects {
+    pub fn get(&self, mode: ArmingMode) -> &ModeEffect {
+        match mode {
+            ArmingMode::Home => &self.home,
+            ArmingMode::Away => &self.away,
+            ArmingMode::Night => &self.night,
+            ArmingMode::Disarmed => &self.disarmed,
+        }
+    }
+}
+
+impl Default for ModeEffects {
+    fn default() -> Self {
+        let quiet = ModeEffect {
+            alerts: false,
+            recording: ModeRecording::Default,
+        };
+        Self {
+            home: quiet.clone(),
+            away: ModeEffect::default(),
+            night: ModeEffect::default(),
+            disarmed: quiet,
+        }
+    }
+}
+
+/// A mode's effect on one camera.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ModeEffect {
+    /// Raise motion and geofence alerts. Health and connectivity alerts are
+    /// raised regardless.
+    pub alerts: bool,
+    pub recording: ModeRecording,
+}
+
+impl Default for ModeEffect {
+    fn default() -> Self {
+        Self {
+            alerts: true,
+            recording: ModeRecording::Default,
+        }
+    }
+}
+
+/// How a mode changes a camera's recording.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ModeRecording {
+    /// As the camera's recording settings say.
+    #[default]
+    Default,
+    Continuous,
+    /// Motion-only, with the camera's pre-roll and post-roll.
+    Event,
+    Off,
+}
+
+/// Switch to `mode` at `at` (local time) on `days`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScheduleEntry {
+    /// Empty means every day.
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+    pub at: TimeOfDay,
+    pub mode: ArmingMode,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Weekday {
+    Mon,
+    Tue,
+    Wed,
+    Thu,
+    Fri,
+    Sat,
+    Sun,
+}
+
+/// `HH:MM`, 24-hour.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeOfDay {
+    pub hour: u8,
+    pub minute: u8,
+}
+
+impl TryFrom<String> for TimeOfDay {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        let parsed = s
+            .split_once(':')
+            .and_then(|(h, m)| Some((h.parse::<u8>().ok()?, m.parse::<u8>().ok()?)));
+        match parsed {
+            Some((hour, minute)) if hour < 24 && minute < 60 => Ok(Self { hour, minute }),
+            _ => Err(format!("invalid time {:?}, expected HH:MM", s)),
+        }
+    }
+}
+
+impl From<TimeOfDay> for String {
+    fn from(t: TimeOfDay) -> Self {
+        format!("{:02}:{:02}", t.hour, t.minute)
+    }
+}
+
+/// Where alerts and camera events are sent.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NotificationsConfig {
+    pub webhooks: Vec<WebhookConfig>,
+    pub mqtt: Option<MqttConfig>,
+    pub email: Option<EmailConfig>,
+}
+
+/// An outgoing webhook.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookConfig {
+    pub url: String,
+    /// Shared secret; requests are signed with an HMAC-SHA256 of the body.
+    #[serde(default)]
+    pub secret: Option<String>,
+    /// Event names (`alert`, `camera_connected`, `camera_disconnected`) or
+    /// alert types (`motion`, ...) to send. Empty sends everything.
+    #[serde(default)]
+    pub events: Vec<String>,
+    /// Cameras to send events for. Empty sends every camera's.
+    #[serde(default)]
+    pub cameras: Vec<String>,
+}
+
+/// An MQTT broker to publish camera state to, with Home Assistant discovery.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MqttConfig {
+    pub host: String,
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub client_id: String,
+    /// Prefix of the topics the app publishes and listens on.
+    pub topic_prefix: String,
+    /// Home Assistant's discovery prefix. Empty disables discovery.
+    pub discovery_prefix: String,
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        Self {
+            host: "localhost".into(),
+            port: 1883,
+            username: None,
+            password: None,
+            client_id: "kodama".into(),
+            topic_prefix: "kodama".into(),
+            discovery_prefix: "homeassistant".into(),
+        }
+    }
+}
+
+/// Alert emails, sent through an SMTP server.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmailConfig {
+    pub host: String,
+    /// Defaults to the usual port for `tls`.
+    #[serde(default)]
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub tls: SmtpTls,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    /// Sender address, e.g. `Kodama <kodama@example.com>`.
+    pub from: String,
+    pub recipients: Vec<EmailRecipient>,
+    /// Emails per recipient per hour; alerts beyond it are counted and
+    /// mentioned in the next email instead.
+    #[serde(default = "default_max_per_hour")]
+    pub max_per_hour: u32,
+    /// Attach the camera's thumbnail nearest the alert, if there is one.
+    #[serde(default = "default_true")]
+    pub snapshot: bool,
+}
+
+fn default_max_per_hour() -> u32 {
+    10
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// How the SMTP connection is secured.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SmtpTls {
+    /// Plain connection upgraded with STARTTLS (port 587).
+    #[default]
+    Starttls,
+    /// TLS from the start (port 465).
+    Tls,
+    /// Unencrypted (port 25), for relays on a trusted network.
+    None,
+}
+
+impl SmtpTls {
+    pub fn default_port(self) -> u16 {
+        match self {
+            SmtpTls::Starttls => 587,
+            SmtpTls::Tls => 465,
+            SmtpTls::None => 25,
+        }
+    }
+}
+
+/// Who alert emails go to, and which ones.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmailRecipient {
+    pub address: String,
+    /// Alert types (`motion`, ...) to send. Empty sends every alert.
+    #[serde(default)]
+    pub alerts: Vec<String>,
+    /// Cameras to send alerts for. Empty sends every camera's.
+    #[serde(default)]
+    pub cameras: Vec<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_file_yields_defaults() {
+        let config = AppConfig::load(Path::new("/nonexistent/kodama/config.json")).unwrap();
+        assert_eq!(config.recording.default.mode, RecordingMode::Continuous);
+        assert_eq!(config.recording.default.pre_roll_secs, 10);
+    }
+
+    #[test]
+    fn camera_override_falls_back_to_default() {
+        let config: AppConfig = serde_json::from_str(
+            r#"{
+                "recording": {
+                    "default": { "mode": "event", "pre_roll_secs": 5 },
+                    "cameras": { "0102030405060708": { "mode": "continuous" } }
+                }
+            }"#,
+        )
+        .unwrap();
+
+        let default = config.recording.for_camera("ffffffffffffffff");
+        assert_eq!(default.mode, RecordingMode::Event);
+        assert_eq!(default.pre_roll_secs, 5);
+        assert_eq!(default.post_roll_secs, 30);
+
+        let cam = config.recording.for_camera("0102030405060708");
+        assert_eq!(cam.mode, RecordingMode::Continuous);
+    }
+
+    #[test]
+    fn storage_roots() {
+        let config: AppConfig = serde_json::from_str(
+            r#"{
+                "storage": {
+                    "roots": [
+                        { "path": "/mnt/a", "max_gb": 500 },
+                        { "path": "/mnt/b", "mode": "mirror" }
+                    ]
+                }
+            }"#,
+        )
+        .unwrap();
+
+        let roots = &config.storage.roots;
+        assert_eq!(roots[0].path, PathBuf::from("/mnt/a"));
+        assert_eq!(roots[0].mode, RootMode::Spillover);
+        assert_eq!(roots[0].max_gb, 500);
+        assert_eq!(roots[1].mode, RootMode::Mirror);
+        assert_eq!(roots[1].max_gb, 10);
+        assert!(AppConfig::default().storage.roots.is_empty());
+    }
+
+    #[test]
+    fn segment_settings_per_camera() {
+        let config: AppConfig = serde_json::from_str(
+            r#"{
+                "recording": {
+                    "cameras": {
+                        "0102030405060708": { "segment_secs": 10, "container": "mpegts" },
+                        "1112131415161718": { "container": "fmp4" }
+                    }
+                }
+            }"#,
+        )
+        .unwrap();
+
+        let default = config.recording.for_camera("ffffffffffffffff");
+        assert_eq!(default.segment_secs, 60);
+        assert_eq!(default.container, Container::Native);
+
+        let cam = config.recording.for_camera("0102030405060708");
+        assert_eq!(cam.segment_secs, 10);
+        assert_eq!(cam.container, Container::MpegTs);
+        assert_eq!(
+            config.recording.for_camera("1112131415161718").container,
+            Container::Fmp4
+        );
+    }
+
+    #[test]
+    fn alert_rules_per_camera() {
+        let config: AppConfig = serde_json::from_str(
+            r#"{
+                "alerts": {
+                    "default": { "motion": { "threshold": 0.8 } },
+                    "cameras": {
+                        "0102030405060708": { "disconnect": { "enabled": false } }
+                    }
+                }
+            }"#,
+        )
+        .unwrap();
+
+        let default = config.alerts.for_camera("ffffffffffffffff");
+        assert_eq!(default.motion.threshold, 0.8);
+        assert_eq!(default.motion.cooldown_secs, 30);
+        assert!(default.disconnect.enabled);
+
+        let cam = config.alerts.for_camera("0102030405060708");
+        assert!(!cam.disconnect.enabled);
+        assert_eq!(cam.disconnect.after_secs, 10);
+        assert_eq!(cam.motion.threshold, 0.6);
+    }
+
+    #[test]
+    fn threshold_rules() {
+        let config: AppConfig = serde_json::from_str(
+            r#"{
+                "alerts": {
+                    "default": {
+                        "thresholds": [
+                            { "metric": "cpu_temp", "above": 75, "clear_below": 70, "for_secs": 300 },
+                            { "metric": "disk_usage", "above": 90 }
+                        ]
+                    }
+                }
+            }"#,
+        )
+        .unwrap();
+
+        let rules = &config.alerts.default.thresholds;
+        assert_eq!(rules[0].metric, TelemetryMetric::CpuTemp);
+        assert_eq!(rules[0].clear_below, Some(70.0));
+        assert_eq!(rules[0].for_secs, 300);
+        assert_eq!(rules[1].metric, TelemetryMetric::DiskUsage);
+        assert_eq!(rules[1].clear_below, None);
+        assert_eq!(rules[1].for_secs, 0);
+        assert!(AppConfig::default().alerts.default.thresholds.is_empty());
+    }
+
+    #[test]
+    fn modes_and_schedule() {
+        let config: AppConfig = serde_json::from_str(
+            r#"{
+                "modes": {
+                    "cameras": {
+                        "0102030405060708": { "night": { "recording": "event" } }
+                    },
+                    "schedule": [
+                        { "days": ["mon", "tue"], "at": "22:30", "mode": "night" },
+                        { "at": "7:05", "mode": "home" }
+                    ]
+                }
+            }"#,
+        )
+        .unwrap();
+
+        let modes = &config.modes;
+        assert!(!modes.default.home.alerts);
+        assert!(modes.default.away.alerts);
+        let night = modes.for_camera("0102030405060708").get(ArmingMode::Night);
+        assert!(night.alerts);
+        assert_eq!(night.recording, ModeRecording::Event);
+        assert_eq!(modes.schedule[0].days, vec![Weekday::Mon, Weekday::Tue]);
+        assert_eq!(String::from(modes.schedule[1].at), "07:05");
+        assert!(modes.schedule[1].days.is_empty());
+
+        let bad = serde_json::from_str::<ModesConfig>(
+            r#"{ "schedule": [{ "at": "25:00", "mode": "away" }] }"#,
+        );
+        assert!(bad.is_err());
+    }
+
+    #[test]
+    fn webhooks() {
+        let config: AppConfig = serde_json::from_str(
+            r#"{
+                "notifications": {
+                    "webhooks": [
+                        { "url": "https://example.com/hook", "secret": "s3cret", "events": ["motion"] },
+                        { "url": "http://10.0.0.2/kodama" }
+                    ]
+                }
+            }"#,
+        )
+        .unwrap();
+
+        let hooks = &config.notifications.webhooks;
+        assert_eq!(hooks[0].secret.as_deref(), Some("s3cret"));
+        assert_eq!(hooks[0].events, vec!["motion"]);
+        assert!(hooks[1].secret.is_none());
+        assert!(hooks[1].events.is_empty());
+        assert!(hooks[1].cameras.is_empty());
+    }
+
+    #[test]
+    fn mqtt_defaults() {
+        let config: AppConfig =
+            serde_json::from_str(r#"{ "notifications": { "mqtt": { "host": "10.0.0.5" } } }"#)
+                .unwrap();
+
+        let mqtt = config.notifications.mqtt.unwrap();
+        assert_eq!(mqtt.host, "10.0.0.5");
+        assert_eq!(mqtt.port, 1883);
+        assert_eq!(mqtt.topic_prefix, "kodama");
+        assert_eq!(mqtt.discovery_prefix, "homeassistant");
+        assert!(AppConfig::default().notifications.mqtt.is_none());
+    }
+
+    #[test]
+    fn email() {
+        let config: AppConfig = serde_json::from_str(
+            r#"{
+                "notifications": {
+                    "email": {
+                        "host": "smtp.example.com",
+                        "tls": "tls",
+                        "from": "kodama@example.com",
+                        "recipients": [{ "address": "me@example.com", "alerts": ["motion"] }]
+                    }
+                }
+            }"#,
+        )
+        .unwrap();
+
+        let email = config.notifications.email.unwrap();
+        assert_eq!(email.tls, SmtpTls::Tls);
+        assert_eq!(email.port.unwrap_or(email.tls.default_port()), 465);
+        assert_eq!(email.max_per_hour, 10);
+        assert!(email.snapshot);
+        assert_eq!(email.recipients[0].alerts, vec!["motion"]);
+        assert!(email.recipients[0].cameras.is_empty());
+    }
+}
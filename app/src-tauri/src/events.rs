@@ -0,0 +1,60 @@
+//! App events pushed to the UI.
+//!
+//! Events are sent as text messages on the live `/ws` stream, relayed from
+//! the core server, in the `{type, payload}` JSON envelope the UI transport
+//! accepts for text messages.
+
+use std::sync::Arc;
+
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+use crate::alerts::Alert;
+use crate::modes::ModeStatus;
+use crate::storage::health::StorageStatus;
+
+/// Events buffered per subscriber before slow clients start missing them.
+const CAPACITY: usize = 64;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", content = "payload", rename_all = "kebab-case")]
+pub enum AppEvent {
+    StorageStatus(StorageStatus),
+    Alert(Alert),
+    Mode(ModeStatus),
+}
+
+/// Fan-out of serialized [`AppEvent`]s to connected clients.
+#[derive(Clone)]
+pub struct EventBus {
+    tx: broadcast::Sender<Arc<str>>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self {
+            tx: broadcast::channel(CAPACITY).0,
+        }
+    }
+}
+
+impl EventBus {
+    pub fn publish(&self, event: AppEvent) {
+        match serde_json::to_string(&event) {
+            // No subscribers is fine
+            Ok(json) => {
+                let _ = self.tx.send(json.into());
+            }
+            Err(e) => tracing::warn!("Failed to serialize event: {}", e),
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<str>> {
+        self.tx.subscribe()
+    }
+
+    /// Connected clients.
+    pub fn subscribers(&self) -> usize {
+        self.tx.receiver_count()
+    }
+}
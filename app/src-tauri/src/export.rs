@@ -0,0 +1,542 @@
+//! Clip export: remux a camera's recordings between two times into a
+//! standalone MP4, or condense them into a time-lapse of sampled keyframes.
+//!
+//! Jobs run in the background, one at a time, writing to `<dir>/<id>.mp4`.
+//! Finished files are served by the REST API and, in the desktop app,
+//! copied to a path the user picks. Jobs live in memory only; files left by
+//! a previous run are removed at startup.
+
+use std::collections::VecDeque;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use serde::Serialize;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::Semaphore;
+
+use crate::media::audio::PcmFrame;
+use crate::media::mp4::{iso8601, Finished, Mp4Writer};
+use crate::storage::segment::Track;
+use crate::storage::StorageManager;
+
+/// Longest clip a single export may cover.
+pub const MAX_EXPORT_MS: u64 = 60 * 60 * 1000;
+
+/// Longest range a single time-lapse may cover.
+pub const MAX_TIMELAPSE_MS: u64 = 7 * 24 * 60 * 60 * 1000;
+
+/// Frame rate of time-lapse output.
+pub const TIMELAPSE_FPS: u64 = 30;
+
+/// Most frames a time-lapse may hold (five minutes of output).
+const MAX_TIMELAPSE_FRAMES: u64 = 5 * 60 * TIMELAPSE_FPS;
+
+/// Finished and failed jobs beyond this many are forgotten, oldest first,
+/// and their files deleted.
+const MAX_JOBS: usize = 16;
+
+/// An export and its progress.
+#[derive(Debug, Clone, Serialize)]
+pub struct ExportJob {
+    pub id: String,
+    pub camera_id: String,
+    /// Requested range (epoch ms)
+    #[serde(rename = "start")]
+    pub start_ms: u64,
+    #[serde(rename = "end")]
+    pub end_ms: u64,
+    #[serde(flatten)]
+    pub kind: ExportKind,
+    #[serde(flatten)]
+    pub state: ExportState,
+}
+
+/// What an export produces.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ExportKind {
+    /// The recording as is, with audio.
+    Clip,
+    /// One keyframe per interval, played back at [`TIMELAPSE_FPS`].
+    Timelapse {
+        #[serde(rename = "interval")]
+        interval_ms: u64,
+    },
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum ExportState {
+    Queued,
+    Running {
+        /// 0.0 - 1.0
+        progress: f32,
+    },
+    Done {
+        size: u64,
+        /// Actual clip span (epoch ms): starts at the keyframe at or before
+        /// the requested start. For time-lapses, the first and last
+        /// sampled keyframes.
+        clip_start: u64,
+        clip_end: u64,
+        file_name: String,
+    },
+    Failed {
+        error: String,
+    },
+}
+
+impl ExportState {
+    fn is_finished(&self) -> bool {
+        matches!(self, Self::Done { .. } | Self::Failed { .. })
+    }
+}
+
+/// Runs export jobs against the recordings of a [`StorageManager`].
+pub struct ExportManager {
+    storage: Arc<StorageManager>,
+    dir: PathBuf,
+    jobs: Mutex<VecDeque<ExportJob>>,
+    next_id: AtomicU64,
+    running: Semaphore,
+}
+
+impl ExportManager {
+    pub fn new(storage: Arc<StorageManager>, dir: PathBuf) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        for entry in std::fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.is_file() {
+                let _ = std::fs::remove_file(&path);
+            }
+        }
+        Ok(Self {
+            storage,
+            dir,
+            jobs: Mutex::new(VecDeque::new()),
+            next_id: AtomicU64::new(1),
+            running: Semaphore::new(1),
+        })
+    }
+
+    /// Queue an export of `camera_id` between `start_ms` and `end_ms`.
+    pub fn start(
+        self: &Arc<Self>,
+        camera_id: String,
+        start_ms: u64,
+        end_ms: u64,
+    ) -> anyhow::Result<ExportJob> {
+        if end_ms <= start_ms {
+            anyhow::bail!("end must be after start");
+        }
+        if end_ms - start_ms > MAX_EXPORT_MS {
+            anyhow::bail!("clips are limited to {} minutes", MAX_EXPORT_MS / 60_000);
+        }
+        self.queue(camera_id, start_ms, end_ms, ExportKind::Clip)
+    }
+
+    /// Queue a time-lapse of `camera_id` between `start_ms` and `end_ms`,
+    /// taking the first keyframe in each `interval_ms`.
+    pub fn start_timelapse(
+        self: &Arc<Self>,
+        camera_id: String,
+        start_ms: u64,
+        end_ms: u64,
+        interval_ms: u64,
+    ) -> anyhow::Result<ExportJob> {
+        if end_ms <= start_ms {
+            anyhow::bail!("end must be after start");
+        }
+        if end_ms - start_ms > MAX_TIMELAPSE_MS {
+            anyhow::bail!(
+                "time-lapses are limited to {} days",
+                MAX_TIMELAPSE_MS / 86_400_000
+            );
+        }
+        if interval_ms < 1000 {
+            anyhow::bail!("interval must be at least one second");
+        }
+        if (end_ms - start_ms) / interval_ms > MAX_TIMELAPSE_FRAMES {
+            anyhow::bail!(
+                "interval too short: time-lapses are limited to {} frames",
+                MAX_TIMELAPSE_FRAMES
+            );
+        }
+        let kind = ExportKind::Timelapse { interval_ms };
+        self.queue(camera_id, start_ms, end_ms, kind)
+    }
+
+    fn queue(
+        self: &Arc<Self>,
+        camera_id: String,
+        start_ms: u64,
+        end_ms: u64,
+        kind: ExportKind,
+    ) -> anyhow::Result<ExportJob> {
+        if self
+            .storage
+            .list(Some(&camera_id), Some(start_ms), Some(end_ms))
+            .is_empty()
+        {
+            anyhow::bail!("no recordings in range");
+        }
+
+        let job = ExportJob {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed).to_string(),
+            camera_id,
+            start_ms,
+            end_ms,
+            kind,
+            state: ExportState::Queued,
+        };
+        self.insert(job.clone());
+
+        let manager = self.clone();
+        let id = job.id.clone();
+        tokio::spawn(async move {
+            let _permit = manager.running.acquire().await;
+            manager.set_state(&id, ExportState::Running { progress: 0.0 });
+            let state = match manager.run(&id).await {
+                Ok(state) => state,
+                Err(e) => {
+                    tracing::warn!(export = %id, error = %e, "Export failed");
+                    let _ = tokio::fs::remove_file(manager.path(&id)).await;
+                    ExportState::Failed {
+                        error: e.to_string(),
+                    }
+                }
+            };
+            manager.set_state(&id, state);
+        });
+        Ok(job)
+    }
+
+    /// All known jobs, oldest first.
+    pub fn jobs(&self) -> Vec<ExportJob> {
+        self.jobs.lock().unwrap().iter().cloned().collect()
+    }
+
+    pub fn job(&self, id: &str) -> Option<ExportJob> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|j| j.id == id)
+            .cloned()
+    }
+
+    /// Path and suggested file name of a finished export.
+    pub fn file(&self, id: &str) -> Option<(PathBuf, String)> {
+        match self.job(id)?.state {
+            ExportState::Done { file_name, .. } => Some((self.path(id), file_name)),
+            _ => None,
+        }
+    }
+
+    fn path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.mp4", id))
+    }
+
+    fn insert(&self, job: ExportJob) {
+        let mut jobs = self.jobs.lock().unwrap();
+        jobs.push_back(job);
+        while jobs.len() > MAX_JOBS {
+            let Some(i) = jobs.iter().position(|j| j.state.is_finished()) else {
+                break;
+            };
+            let old = jobs.remove(i).expect("index from position");
+            let _ = std::fs::remove_file(self.path(&old.id));
+        }
+    }
+
+    fn set_state(&self, id: &str, state: ExportState) {
+        if let Some(job) = self.jobs.lock().unwrap().iter_mut().find(|j| j.id == id) {
+            job.state = state;
+        }
+    }
+
+    async fn run(&self, id: &str) -> anyhow::Result<ExportState> {
+        let job = self
+            .job(id)
+            .ok_or_else(|| anyhow::anyhow!("unknown export"))?;
+        let path = self.path(id);
+        let progress = |progress| self.set_state(id, ExportState::Running { progress });
+        let (camera_id, start_ms, end_ms) = (&job.camera_id, job.start_ms, job.end_ms);
+        let ((size, clip_start, clip_end), suffix) = match job.kind {
+            ExportKind::Clip => (
+                export_clip(&self.storage, camera_id, start_ms, end_ms, &path, progress).await?,
+                "",
+            ),
+            ExportKind::Timelapse { interval_ms } => (
+                export_timelapse(
+                    &self.storage,
+                    camera_id,
+                    start_ms,
+                    end_ms,
+                    interval_ms,
+                    &path,
+                    progress,
+                )
+                .await?,
+                "_timelapse",
+            ),
+        };
+        tracing::info!(export = %id, camera = %job.camera_id, size, "Export finished");
+
+        let stamp = iso8601(clip_start)[..19].replace(':', "-");
+        Ok(ExportState::Done {
+            size,
+            clip_start,
+            clip_end,
+            file_name: format!("{}_{}{}.mp4", job.camera_id, stamp, suffix),
+        })
+    }
+}
+
+/// Remux `[start_ms, end_ms]` of a camera's recordings into an MP4 at
+/// `path`. Returns the file size and the clip's wall-clock span (epoch ms).
+async fn export_clip(
+    storage: &StorageManager,
+    camera_id: &str,
+    start_ms: u64,
+    end_ms: u64,
+    path: &Path,
+    progress: impl Fn(f32),
+) -> anyhow::Result<(u64, u64, u64)> {
+    let entries = storage.list(Some(camera_id), Some(start_ms), Some(end_ms));
+    let (from_us, to_us) = (start_ms * 1000, end_ms * 1000);
+
+    let mut file = tokio::io::BufWriter::new(tokio::fs::File::create(path).await?);
+    let (mut writer, head) = Mp4Writer::start();
+    file.write_all(&head).await?;
+
+    'segments: for (i, entry) in entries.iter().enumerate() {
+        let segment = storage.read(&entry.key()).await?;
+        let header = segment.header;
+
+        // Segments open on a keyframe, so the one at or before `start` is
+        // in the first segment that reaches it
+        let seek = segment
+            .frames
+            .iter()
+            .rposition(|f| f.keyframe && header.wall_us(f.timestamp_us) <= from_us)
+            .unwrap_or(0);
+
+        for f in &segment.frames[seek..] {
+            let wall_us = header.wall_us(f.timestamp_us);
+            if wall_us > to_us {
+                break 'segments;
+            }
+            let data = match f.track {
+                Track::Video => match writer.push_video(wall_us, f.keyframe, &f.payload) {
+                    Some(data) => data,
+                    None => {
+                        tracing::warn!(camera = %camera_id, "Encoder parameters changed, ending clip early");
+                        break 'segments;
+                    }
+                },
+                Track::Audio => match PcmFrame::parse(&f.payload) {
+                    Some(pcm) => writer.push_audio(wall_us, &pcm),
+                    None => continue,
+                },
+                Track::Telemetry => continue,
+            };
+            file.write_all(&data).await?;
+        }
+        progress((i + 1) as f32 / entries.len() as f32);
+    }
+
+    let (size, finished) = finish(file, writer).await?;
+    Ok((size, finished.start_us / 1000, finished.end_us / 1000))
+}
+
+/// Write the first video keyframe in each `interval_ms` slot of
+/// `[start_ms, end_ms]` to an MP4 at `path`, one frame each at
+/// [`TIMELAPSE_FPS`]. Returns the file size and the wall-clock times of the
+/// first and last frames (epoch ms).
+async fn export_timelapse(
+    storage: &StorageManager,
+    camera_id: &str,
+    start_ms: u64,
+    end_ms: u64,
+    interval_ms: u64,
+    path: &Path,
+    progress: impl Fn(f32),
+) -> anyhow::Result<(u64, u64, u64)> {
+    let entries = storage.list(Some(camera_id), Some(start_ms), Some(end_ms));
+    let frame_us = 1_000_000 / TIMELAPSE_FPS;
+
+    let mut file = tokio::io::BufWriter::new(tokio::fs::File::create(path).await?);
+    let (mut writer, head) = Mp4Writer::start();
+    file.write_all(&head).await?;
+
+    let mut next_ms = start_ms;
+    let mut frames = 0;
+    let mut span: Option<(u64, u64)> = None;
+    for (i, entry) in entries.iter().enumerate() {
+        // Only read segments that reach the next slot
+        if entry.end_ms >= next_ms {
+            let segment = storage.read(&entry.key()).await?;
+            let header = segment.header;
+            for f in &segment.frames {
+                if f.track != Track::Video || !f.keyframe {
+                    continue;
+                }
+                let wall_ms = header.wall_ms(f.timestamp_us);
+                if wall_ms > end_ms {
+                    break;
+                }
+                if wall_ms < next_ms {
+                    continue;
+                }
+                // Output time: the first frame's wall time, then one frame
+                // period apart, so the file keeps the recording date
+                let first_ms = span.map_or(wall_ms, |(first, _)| first);
+                let time_us = first_ms * 1000 + frames * frame_us;
+                match writer.push_video(time_us, true, &f.payload) {
+                    Some(data) if !data.is_empty() => {
+                        file.write_all(&data).await?;
+                        frames += 1;
+                        span = Some((first_ms, wall_ms));
+                        next_ms = wall_ms - (wall_ms - start_ms) % interval_ms + interval_ms;
+                    }
+                    // Without parameter sets, or after the encoder changed:
+                    // try the next keyframe
+                    _ => continue,
+                }
+            }
+        }
+        progress((i + 1) as f32 / entries.len() as f32);
+    }
+
+    let (size, _) = finish(file, writer).await?;
+    let (first_ms, last_ms) = span.ok_or_else(|| anyhow::anyhow!("no video in range"))?;
+    Ok((size, first_ms, last_ms))
+}
+
+/// Write the tail of an MP4 begun with [`Mp4Writer::start`]. Returns the
+/// file size along with the tail.
+async fn finish(
+    mut file: tokio::io::BufWriter<tokio::fs::File>,
+    writer: Mp4Writer,
+) -> anyhow::Result<(u64, Finished)> {
+    let finished = writer
+        .finish()
+        .ok_or_else(|| anyhow::anyhow!("no video in range"))?;
+    file.write_all(&finished.moov).await?;
+    file.seek(SeekFrom::Start(finished.mdat_size_offset))
+        .await?;
+    file.write_all(&finished.mdat_size).await?;
+    file.flush().await?;
+    let size = file.into_inner().metadata().await?.len();
+    Ok((size, finished))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::storage::manager::{test_config, test_manager};
+    use crate::storage::playback::record_test_video;
+
+    async fn wait_done(exports: &ExportManager, id: &str) -> ExportJob {
+        for _ in 0..100 {
+            let job = exports.job(id).unwrap();
+            if job.state.is_finished() {
+                return job;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        panic!("export did not finish");
+    }
+
+    #[tokio::test]
+    async fn exports_clip_from_preceding_keyframe() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = Arc::new(test_manager(&dir.path().join("rec"), test_config()));
+        record_test_video(&storage, "cam", 1_700_000_000_000, 0..4).await;
+        let exports = Arc::new(ExportManager::new(storage, dir.path().join("exports")).unwrap());
+
+        let job = exports
+            .start("cam".into(), 1_700_000_001_500, 1_700_000_003_000)
+            .unwrap();
+        let job = wait_done(&exports, &job.id).await;
+        let ExportState::Done {
+            size,
+            clip_start,
+            file_name,
+            ..
+        } = job.state
+        else {
+            panic!("export failed: {:?}", job.state);
+        };
+        assert_eq!(clip_start, 1_700_000_001_000);
+        assert_eq!(file_name, "cam_2023-11-14T22-13-21.mp4");
+
+        let (path, _) = exports.file(&job.id).unwrap();
+        let data = std::fs::read(path).unwrap();
+        assert_eq!(data.len() as u64, size);
+        assert_eq!(&data[4..8], b"ftyp");
+        assert!(data.windows(4).any(|w| w == b"moov"));
+    }
+
+    #[tokio::test]
+    async fn timelapse_samples_one_keyframe_per_interval() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = Arc::new(test_manager(&dir.path().join("rec"), test_config()));
+        record_test_video(&storage, "cam", 1_700_000_000_000, 0..20).await;
+        let exports = Arc::new(ExportManager::new(storage, dir.path().join("exports")).unwrap());
+
+        let job = exports
+            .start_timelapse("cam".into(), 1_700_000_000_500, 1_700_000_030_000, 5000)
+            .unwrap();
+        assert_eq!(job.kind, ExportKind::Timelapse { interval_ms: 5000 });
+        let job = wait_done(&exports, &job.id).await;
+        let ExportState::Done {
+            clip_start,
+            clip_end,
+            ref file_name,
+            ..
+        } = job.state
+        else {
+            panic!("time-lapse failed: {:?}", job.state);
+        };
+        // Slots start at 0.5s, 5.5s, ...; keyframes fall on whole seconds
+        assert_eq!(clip_start, 1_700_000_001_000);
+        assert_eq!(clip_end, 1_700_000_016_000);
+        assert_eq!(file_name, "cam_2023-11-14T22-13-21_timelapse.mp4");
+
+        let json = serde_json::to_value(&job).unwrap();
+        assert_eq!(json["kind"], "timelapse");
+        assert_eq!(json["interval"], 5000);
+        assert_eq!(json["status"], "done");
+
+        // Four frames, every one a sync sample
+        let (path, _) = exports.file(&job.id).unwrap();
+        let data = std::fs::read(path).unwrap();
+        let stss = data.windows(4).position(|w| w == b"stss").unwrap();
+        assert_eq!(&data[stss + 8..stss + 12], &4u32.to_be_bytes());
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_ranges() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = Arc::new(test_manager(&dir.path().join("rec"), test_config()));
+        record_test_video(&storage, "cam", 0, 0..2).await;
+        let exports = Arc::new(ExportManager::new(storage, dir.path().join("exports")).unwrap());
+
+        assert!(exports.start("cam".into(), 1000, 1000).is_err());
+        assert!(exports.start("cam".into(), 0, MAX_EXPORT_MS + 1).is_err());
+        assert!(exports.start("cam".into(), 60_000, 70_000).is_err());
+        assert!(exports.start_timelapse("cam".into(), 0, 2000, 999).is_err());
+        assert!(exports
+            .start_timelapse("cam".into(), 0, MAX_TIMELAPSE_MS + 1, 60_000)
+            .is_err());
+        assert!(exports
+            .start_timelapse("cam".into(), 0, MAX_TIMELAPSE_MS, 1000)
+            .is_err());
+        assert!(exports.jobs().is_empty());
+    }
+}
@@ -4,20 +4,70 @@
 //! The Svelte UI communicates entirely over WebSocket and REST — no Tauri IPC
 //! is needed for video, audio, or telemetry.
 
-use std::collections::HashMap;
+mod alerts;
+mod api;
+mod config;
+mod events;
+mod export;
+mod media;
+mod metrics;
+mod modes;
+mod notify;
+mod proxy;
+mod snapshot;
+mod storage;
+mod timeseries;
+
 use std::net::SocketAddr;
 use std::path::PathBuf;
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock};
 
 use tauri::Manager;
+use tauri_plugin_dialog::DialogExt;
 use tokio::time::Duration;
 use tracing_subscriber::EnvFilter;
 
-use kodama::server::{
-    LocalStorage, LocalStorageConfig, Relay, Router, StorageBackend, StorageConfig,
-    StorageManager,
+use kodama::server::{Relay, Router};
+
+use config::{RootMode, StorageRootConfig};
+use storage::{
+    EncryptedStorage, Keyring, LocalStorage, LocalStorageConfig, MultiRootStorage, ProtectedClips,
+    RecordingIndex, S3Config, S3Storage, StorageBackend, StorageConfig, StorageManager,
+    ThumbnailConfig, Thumbnails, TieredStorage,
 };
 
+/// Export jobs, set once the embedded server has opened storage.
+type SharedExports = Arc<OnceLock<Arc<export::ExportManager>>>;
+
+/// Copy a finished export to a location the user picks.
+///
+/// Returns the chosen path, or `None` if the dialog was cancelled.
+#[tauri::command]
+async fn save_export(
+    app: tauri::AppHandle,
+    exports: tauri::State<'_, SharedExports>,
+    id: String,
+) -> Result<Option<String>, String> {
+    let exports = exports.get().ok_or("recording is disabled")?;
+    let (src, file_name) = exports.file(&id).ok_or("export not ready")?;
+
+    let Some(dest) = app
+        .dialog()
+        .file()
+        .add_filter("MP4 video", &["mp4"])
+        .set_file_name(file_name)
+        .blocking_save_file()
+    else {
+        return Ok(None);
+    };
+    let dest = dest.into_path().map_err(|e| e.to_string())?;
+    tokio::fs::copy(&src, &dest)
+        .await
+        .map_err(|e| e.to_string())?;
+    tracing::info!(export = %id, path = ?dest, "Export saved");
+    Ok(Some(dest.display().to_string()))
+}
+
 /// Initialize and run the Tauri application.
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
@@ -31,8 +81,14 @@ pub fn run() {
 
     tracing::info!("Kodama starting");
 
+    let exports = SharedExports::default();
+    let server_exports = exports.clone();
+
     tauri::Builder::default()
-        .setup(|app| {
+        .plugin(tauri_plugin_dialog::init())
+        .manage(exports)
+        .invoke_handler(tauri::generate_handler![save_export])
+        .setup(move |app| {
             // Resolve UI path from bundled resources (production only)
             let ui_path = app
                 .path()
@@ -43,7 +99,7 @@ pub fn run() {
 
             // Start embedded server in background
             tauri::async_runtime::spawn(async move {
-                if let Err(e) = start_embedded_server(ui_path).await {
+                if let Err(e) = start_embedded_server(ui_path, server_exports).await {
                     tracing::error!("Embedded server error: {}", e);
                 }
             });
@@ -61,7 +117,10 @@ pub fn run() {
 }
 
 /// Start the embedded Kodama server (Iroh + axum web).
-async fn start_embedded_server(ui_path: Option<PathBuf>) -> anyhow::Result<()> {
+async fn start_embedded_server(
+    ui_path: Option<PathBuf>,
+    exports: SharedExports,
+) -> anyhow::Result<()> {
     // --- Config ---
     let key_path = std::env::var("KODAMA_KEY_PATH")
         .map(PathBuf::from)
@@ -81,13 +140,23 @@ async fn start_embedded_server(ui_path: Option<PathBuf>) -> anyhow::Result<()> {
         .and_then(|s| s.parse().ok())
         .unwrap_or(3000);
 
+
     let storage_path = std::env::var("KODAMA_STORAGE_PATH")
         .map(PathBuf::from)
         .ok();
 
+    let config_path = std::env::var("KODAMA_CONFIG_PATH")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| {
+            let base = dirs_next::data_dir().unwrap_or_else(|| PathBuf::from("."));
+            base.join("kodama").join("config.json")
+        });
+    let config = config::AppConfig::load(&config_path)?;
+
     tracing::info!("  Key path: {:?}", key_path);
     tracing::info!("  Buffer capacity: {}", buffer_capacity);
     tracing::info!("  Web port: {}", web_port);
+    tracing::info!("  Config path: {:?}", config_path);
 
     // --- Key directory ---
     if let Some(parent) = key_path.parent() {
@@ -105,6 +174,95 @@ async fn start_embedded_server(ui_path: Option<PathBuf>) -> anyhow::Result<()> {
     let router = Router::new(buffer_capacity);
     let handle = router.handle();
 
+    let mut api_state = api::ApiState::default();
+
+    // --- Metrics ---
+    let metrics = Arc::new(metrics::Metrics::default());
+    metrics::spawn(handle.clone(), metrics.clone());
+    api_state.metrics = Some(metrics.clone());
+
+    // --- Snapshots ---
+    let ffmpeg = media::jpeg::ffmpeg_path();
+    tracing::info!("Decoding snapshots with {:?}", ffmpeg);
+    let snapshots = Arc::new(snapshot::Snapshots::new(ffmpeg.clone()));
+    snapshot::spawn(handle.clone(), snapshots.clone());
+    api_state.snapshots = Some(snapshots.clone());
+
+    // --- Arming modes ---
+    let modes_path = config_path.with_file_name("mode.json");
+    match modes::Modes::open(config.modes.clone(), modes_path, api_state.events.clone()) {
+        Ok(modes) => {
+            let modes = Arc::new(modes);
+            tracing::info!("Arming mode: {}", modes.mode().as_str());
+            modes::spawn(modes.clone());
+            api_state.modes = Some(modes);
+        }
+        Err(e) => tracing::warn!("Failed to open arming mode: {}. Everything stays armed.", e),
+    }
+
+    // --- Telemetry history ---
+    let telemetry_path = config_path.with_file_name("telemetry");
+    if std::env::var("KODAMA_STORAGE_SECRET").is_ok() {
+        // History would be stored in the clear
+        tracing::info!("Telemetry history disabled with encryption at rest");
+    } else {
+        match timeseries::TelemetryStore::open(&telemetry_path) {
+            Ok(store) => {
+                let store = Arc::new(store);
+                timeseries::spawn(handle.clone(), store.clone());
+                api_state.telemetry = Some(store);
+            }
+            Err(e) => {
+                tracing::warn!("Failed to open telemetry history {:?}: {}", telemetry_path, e);
+            }
+        }
+    }
+
+    // --- Alerts ---
+    let alerts_path = config_path.with_file_name("alerts.jsonl");
+    match alerts::AlertHistory::open(&alerts_path) {
+        Ok(history) => {
+            let mut engine = alerts::AlertEngine::new(
+                config.alerts.clone(),
+                history,
+                api_state.events.clone(),
+            );
+            if std::env::var("KODAMA_STORAGE_SECRET").is_ok() {
+                // Fences and tracks would be stored in the clear
+                tracing::info!("Geofences kept in memory only with encryption at rest");
+                engine.set_geofences(Arc::new(alerts::Geofences::in_memory()));
+            } else {
+                match alerts::Geofences::open(
+                    config_path.with_file_name("geofences.json"),
+                    config_path.with_file_name("excursions.jsonl"),
+                ) {
+                    Ok(geofences) => engine.set_geofences(Arc::new(geofences)),
+                    Err(e) => tracing::warn!("Failed to open geofences: {}", e),
+                }
+            }
+            if let Some(modes) = &api_state.modes {
+                engine.set_modes(modes.clone());
+            }
+            let engine = Arc::new(engine);
+            let webhooks = config.notifications.webhooks.clone();
+            if !webhooks.is_empty() {
+                match notify::Webhooks::new(webhooks) {
+                    Ok(webhooks) => {
+                        let webhooks = Arc::new(webhooks);
+                        notify::webhook::spawn(webhooks.clone(), engine.subscribe());
+                        api_state.webhooks = Some(webhooks);
+                    }
+                    Err(e) => tracing::warn!("Webhooks disabled: {:#}", e),
+                }
+            }
+            alerts::spawn(handle.clone(), engine.clone());
+            api_state.alerts = Some(engine);
+        }
+        Err(e) => {
+            tracing::warn!("Failed to open alert history {:?}: {}. Alerts disabled.", alerts_path, e);
+        }
+    }
+
     // --- Storage (optional) ---
     if let Some(ref path) = storage_path {
         let storage_max_gb: u64 = std::env::var("KODAMA_STORAGE_MAX_GB")
@@ -115,80 +273,191 @@ async fn start_embedded_server(ui_path: Option<PathBuf>) -> anyhow::Result<()> {
             .ok()
             .and_then(|s| s.parse().ok())
             .unwrap_or(7);
+        let min_free_mb: u64 = std::env::var("KODAMA_STORAGE_MIN_FREE_MB")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(1024);
+
+        // Remote storage; the storage path then only spools segments
+        let s3_config = match (
+            std::env::var("KODAMA_S3_ENDPOINT"),
+            std::env::var("KODAMA_S3_BUCKET"),
+        ) {
+            (Ok(endpoint), Ok(bucket)) => Some(S3Config {
+                endpoint,
+                bucket,
+                region: std::env::var("KODAMA_S3_REGION").unwrap_or_else(|_| "us-east-1".into()),
+                access_key: std::env::var("KODAMA_S3_ACCESS_KEY").unwrap_or_default(),
+                secret_key: std::env::var("KODAMA_S3_SECRET_KEY").unwrap_or_default(),
+                prefix: std::env::var("KODAMA_S3_PREFIX").unwrap_or_default(),
+                part_size: storage::s3::DEFAULT_PART_SIZE,
+            }),
+            _ => None,
+        };
+
+        // Tiered storage; the storage path then holds recent footage only
+        let cold_path = std::env::var("KODAMA_STORAGE_COLD_PATH")
+            .map(PathBuf::from)
+            .ok();
+        let cold_after_hours: u64 = std::env::var("KODAMA_STORAGE_COLD_AFTER_HOURS")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(24);
+
+        // More roots, spilled over to or mirrored; spillover caps add up
+        let mut extra_roots = config.storage.roots.clone();
+        if !extra_roots.is_empty() && (s3_config.is_some() || cold_path.is_some()) {
+            tracing::warn!("Extra storage roots are ignored with S3 or tiered storage");
+            extra_roots.clear();
+        }
+        let spillover_gb: u64 = extra_roots
+            .iter()
+            .filter(|r| r.mode == RootMode::Spillover)
+            .map(|r| r.max_gb)
+            .sum();
 
         let local_config = LocalStorageConfig {
             root_path: path.clone(),
-            max_size_bytes: storage_max_gb * 1024 * 1024 * 1024,
-            segment_duration_us: 60 * 1_000_000,
         };
 
-        match LocalStorage::new(local_config) {
-            Ok(backend) => {
-                let backend: Arc<dyn StorageBackend> = Arc::new(backend);
+        let opened = LocalStorage::new(local_config).and_then(|local| {
+            let index = RecordingIndex::open(path.join("index.jsonl"))?;
+            // Fail rather than run retention without the protected ranges
+            let protected = ProtectedClips::open(path.join("protected.json"))?;
+            let mut uploader = None;
+            let mut tiered = None;
+            let mut roots = None;
+            let mut backend: Arc<dyn StorageBackend> = match (s3_config, cold_path) {
+                (Some(s3_config), _) => {
+                    tracing::info!(
+                        "Recordings stored in S3 bucket {} at {}",
+                        s3_config.bucket,
+                        s3_config.endpoint
+                    );
+                    let s3 = Arc::new(S3Storage::new(s3_config, local)?);
+                    uploader = Some(s3.clone());
+                    s3
+                }
+                (None, Some(cold_path)) => {
+                    tracing::info!(
+                        "Recordings older than {}h move to {:?}",
+                        cold_after_hours,
+                        cold_path
+                    );
+                    let cold = LocalStorage::new(LocalStorageConfig {
+                        root_path: cold_path,
+                    })?;
+                    let storage = Arc::new(TieredStorage::new(local, cold));
+                    tiered = Some(storage.clone());
+                    storage
+                }
+                (None, None) if !extra_roots.is_empty() => {
+                    drop(local);
+                    let mut all = vec![StorageRootConfig {
+                        path: path.clone(),
+                        mode: RootMode::Spillover,
+                        max_gb: storage_max_gb,
+                    }];
+                    all.extend(extra_roots);
+                    for root in &all[1..] {
+                        tracing::info!(
+                            "Recordings also stored in {:?} ({:?})",
+                            root.path,
+                            root.mode
+                        );
+                    }
+                    let storage = Arc::new(MultiRootStorage::open(all)?);
+                    roots = Some(storage.clone());
+                    storage
+                }
+                (None, None) => Arc::new(local),
+            };
+            // Encryption at rest; previous secrets keep older segments readable
+            if let Ok(secret) = std::env::var("KODAMA_STORAGE_SECRET") {
+                let previous: Vec<String> = std::env::var("KODAMA_STORAGE_OLD_SECRETS")
+                    .map(|s| s.split(',').filter(|s| !s.is_empty()).map(String::from).collect())
+                    .unwrap_or_default();
+                let keys = Keyring::open(&path.join("encryption.salt"), &secret, &previous)?;
+                backend = Arc::new(EncryptedStorage::new(backend, keys));
+                tracing::info!("Recordings encrypted at rest");
+            }
+            Ok((backend, index, protected, uploader, tiered, roots))
+        });
+        match opened {
+            Ok((backend, index, protected, uploader, tiered, roots)) => {
                 let storage_config = StorageConfig {
-                    max_size_bytes: storage_max_gb * 1024 * 1024 * 1024,
+                    max_size_bytes: (storage_max_gb + spillover_gb) * 1024 * 1024 * 1024,
                     retention_secs: retention_days * 24 * 60 * 60,
                     keyframes_only: false,
                     cleanup_interval_secs: 3600,
+                    min_free_bytes: min_free_mb * 1024 * 1024,
+                    segment_duration_us: 60 * 1_000_000,
                 };
-                let mut manager = StorageManager::new(storage_config, backend);
+                let mut manager = StorageManager::new(storage_config, backend, index);
+                manager.set_protected_clips(Arc::new(protected));
+                if let Some(roots) = roots {
+                    manager.set_roots(roots);
+                }
+                let thumbnail_secs: u64 = std::env::var("KODAMA_THUMBNAIL_INTERVAL_SECS")
+                    .ok()
+                    .and_then(|s| s.parse().ok())
+                    .unwrap_or(10);
+                if std::env::var("KODAMA_STORAGE_SECRET").is_ok() {
+                    // Thumbnails would be stored in the clear
+                    tracing::info!("Recording thumbnails disabled with encryption at rest");
+                } else if thumbnail_secs > 0 {
+                    let thumbnail_config = ThumbnailConfig {
+                        interval_ms: thumbnail_secs * 1000,
+                        width: storage::thumbnails::DEFAULT_WIDTH,
+                        ffmpeg: ffmpeg.clone(),
+                    };
+                    match Thumbnails::open(path.clone(), thumbnail_config) {
+                        Ok(thumbnails) => manager.set_thumbnails(Arc::new(thumbnails)),
+                        Err(e) => tracing::warn!("Failed to open thumbnails: {}", e),
+                    }
+                }
+                let recording = config.recording.clone();
+                match manager
+                    .import_legacy(path, |camera| {
+                        storage::segment_kind(recording.for_camera(camera))
+                    })
+                    .await
+                {
+                    Ok(0) => {}
+                    Ok(n) => tracing::info!("Imported {} segments recorded by kodama's storage", n),
+                    Err(e) => tracing::warn!("Importing earlier recordings failed: {}", e),
+                }
+                if let Err(e) = manager
+                    .scan(|camera| storage::segment_kind(recording.for_camera(camera)))
+                    .await
+                {
+                    tracing::warn!("Storage scan failed: {}", e);
+                }
+                if let Some(s3) = uploader {
+                    s3.start_uploader();
+                }
                 manager.start_cleanup_task();
+                manager.start_health_task(path.clone(), api_state.events.clone());
+                if let Some(tiered) = tiered {
+                    manager.start_migration_task(tiered, cold_after_hours * 60 * 60);
+                }
 
-                // Spawn storage with per-camera fan-out.
-                //
-                // The global broadcast has a single shared buffer. If the
-                // storage task blocks on disk I/O, its broadcast cursor falls
-                // behind and it lags for ALL cameras. Instead, we drain the
-                // broadcast as fast as possible into per-camera mpsc channels.
-                // Each camera gets its own bounded buffer so a slow write for
-                // one camera doesn't starve others.
-                let storage_handle = handle.clone();
-                let manager = Arc::new(tokio::sync::Mutex::new(manager));
-
-                // Fast drain: broadcast -> per-camera mpsc
-                tokio::spawn(async move {
-                    let mut rx = storage_handle.subscribe();
-                    let mut camera_txs: HashMap<
-                        kodama::SourceId,
-                        tokio::sync::mpsc::Sender<kodama::Frame>,
-                    > = HashMap::new();
-
-                    loop {
-                        match rx.recv().await {
-                            Ok(frame) => {
-                                let source = frame.source;
-                                let tx = camera_txs.entry(source).or_insert_with(|| {
-                                    let (tx, mut rx) =
-                                        tokio::sync::mpsc::channel::<kodama::Frame>(256);
-                                    let mgr = manager.clone();
-                                    tokio::spawn(async move {
-                                        while let Some(f) = rx.recv().await {
-                                            let m = mgr.lock().await;
-                                            let _ = m.store(&f).await;
-                                        }
-                                    });
-                                    tracing::info!(camera = ?source, "Storage channel created");
-                                    tx
-                                });
-
-                                // try_send: if this camera's buffer is full, drop
-                                // the frame rather than blocking the drain loop
-                                if let Err(tokio::sync::mpsc::error::TrySendError::Full(_)) =
-                                    tx.try_send(frame)
-                                {
-                                    tracing::debug!(
-                                        camera = ?source,
-                                        "Storage buffer full, dropping frame"
-                                    );
-                                }
-                            }
-                            Err(tokio::sync::broadcast::error::RecvError::Lagged(n)) => {
-                                tracing::warn!("Storage broadcast lagged, missed {} frames", n);
-                            }
-                            Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
-                        }
+                let manager = Arc::new(manager);
+                storage::spawn_pipeline(
+                    handle.clone(),
+                    manager.clone(),
+                    config.recording.clone(),
+                    api_state.modes.clone(),
+                );
+                match export::ExportManager::new(manager.clone(), path.join("exports")) {
+                    Ok(exporter) => {
+                        let exporter = Arc::new(exporter);
+                        let _ = exports.set(exporter.clone());
+                        api_state.exports = Some(exporter);
                     }
-                });
+                    Err(e) => tracing::warn!("Failed to initialize exports: {}", e),
+                }
+                api_state.storage = Some(manager);
 
                 tracing::info!("Recording enabled: {:?}", path);
             }
@@ -198,23 +467,62 @@ async fn start_embedded_server(ui_path: Option<PathBuf>) -> anyhow::Result<()> {
         }
     }
 
+    // --- MQTT and email (optional) ---
+    // After storage, so snapshots can fall back to its thumbnails
+    if let Some(engine) = &api_state.alerts {
+        let thumbnails = api_state
+            .storage
+            .as_ref()
+            .and_then(|storage| storage.thumbnails().cloned());
+        if let Some(mqtt) = config.notifications.mqtt.clone() {
+            tracing::info!("Publishing to MQTT broker {}:{}", mqtt.host, mqtt.port);
+            let (bridge, eventloop) = notify::MqttBridge::new(
+                mqtt,
+                engine.clone(),
+                snapshots.clone(),
+                thumbnails.clone(),
+            );
+            notify::mqtt::spawn(bridge, eventloop, handle.clone(), engine.subscribe());
+        }
+        if let Some(email) = config.notifications.email.clone() {
+            match notify::Mailer::new(email, snapshots.clone(), thumbnails) {
+                Ok(mailer) => notify::email::spawn(Arc::new(mailer), engine.subscribe()),
+                Err(e) => tracing::warn!("Alert emails disabled: {:#}", e),
+            }
+        }
+    }
+
     // --- Web server ---
+    // The core server listens privately; the app API in front of it on the
+    // web port forwards everything it doesn't serve itself
     let web_handle = handle.clone();
-    let bind = SocketAddr::from(([127, 0, 0, 1], web_port));
+    let core_bind = SocketAddr::from(([127, 0, 0, 1], proxy::free_port()?));
     let web_public_key = Some(public_key);
     tokio::spawn(async move {
-        if let Err(e) = kodama::web::start(web_handle, bind, ui_path, web_public_key, None, 0).await {
+        if let Err(e) =
+            kodama::web::start(web_handle, core_bind, ui_path, web_public_key, None, 0).await
+        {
             tracing::error!("Web server error: {}", e);
         }
     });
 
+    // --- App API ---
+    let bind = SocketAddr::from(([127, 0, 0, 1], web_port));
+    tokio::spawn(async move {
+        if let Err(e) = api::serve(api_state, bind, core_bind).await {
+            tracing::error!("App API error: {}", e);
+        }
+    });
+
     // --- Accept loop ---
     loop {
         match relay.accept().await {
             Some(conn) => {
                 let remote = conn.remote_public_key();
                 tracing::info!("New connection from: {}", remote);
+                metrics.connection_accepted();
                 let r = router.clone();
+                let metrics = metrics.clone();
                 tokio::spawn(async move {
                     let detect_timeout = Duration::from_secs(2);
                     tokio::select! {
@@ -222,6 +530,7 @@ async fn start_embedded_server(ui_path: Option<PathBuf>) -> anyhow::Result<()> {
                             match result {
                                 Ok(receiver) => {
                                     tracing::info!(peer = %remote, "Detected as camera");
+                                    let _peer = metrics.peer_connected(metrics::PeerKind::Camera);
                                     // Accept command stream for OTA updates etc.
                                     let cmd_conn = conn.clone_handle();
                                     let cmd_router = r.clone();
@@ -247,6 +556,7 @@ async fn start_embedded_server(ui_path: Option<PathBuf>) -> anyhow::Result<()> {
                         }
                         _ = tokio::time::sleep(detect_timeout) => {
                             tracing::info!(peer = %remote, "Detected as client");
+                            let _peer = metrics.peer_connected(metrics::PeerKind::Client);
                             let cmd_conn = conn.clone_handle();
                             let cmd_router = r.clone();
                             tokio::spawn(async move {
@@ -0,0 +1,67 @@
+//! Audio frame payloads.
+//!
+//! Cameras send audio as `sample_rate(4) + channels(1) + pcm`, with 16-bit
+//! signed little-endian interleaved samples: the same layout the web server
+//! forwards to the UI as WS message `0x06`.
+
+/// A borrowed view of one audio frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PcmFrame<'a> {
+    pub sample_rate: u32,
+    pub channels: u8,
+    /// Whole sample frames only; a trailing partial sample is dropped.
+    pub data: &'a [u8],
+}
+
+impl<'a> PcmFrame<'a> {
+    pub fn parse(payload: &'a [u8]) -> Option<Self> {
+        let header = payload.get(..5)?;
+        let sample_rate = u32::from_le_bytes(header[..4].try_into().ok()?);
+        let channels = header[4];
+        if sample_rate == 0 || channels == 0 {
+            return None;
+        }
+        let data = &payload[5..];
+        let frame_len = 2 * channels as usize;
+        Some(Self {
+            sample_rate,
+            channels,
+            data: &data[..data.len() / frame_len * frame_len],
+        })
+    }
+
+    /// Bytes per sample frame (all channels).
+    pub fn frame_len(&self) -> u32 {
+        2 * self.channels as u32
+    }
+
+    /// Number of sample frames.
+    pub fn samples(&self) -> u32 {
+        self.data.len() as u32 / self.frame_len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_header_and_trims_partial_sample() {
+        let mut payload = 48_000u32.to_le_bytes().to_vec();
+        payload.push(2);
+        payload.extend_from_slice(&[1, 0, 2, 0, 3, 0, 4, 0, 5]);
+
+        let frame = PcmFrame::parse(&payload).unwrap();
+        assert_eq!(frame.sample_rate, 48_000);
+        assert_eq!(frame.channels, 2);
+        assert_eq!(frame.samples(), 2);
+        assert_eq!(frame.data.len(), 8);
+    }
+
+    #[test]
+    fn rejects_empty_format() {
+        assert!(PcmFrame::parse(&[0, 0, 0, 0, 1]).is_none());
+        assert!(PcmFrame::parse(&[0x80, 0xbb, 0, 0, 0]).is_none());
+        assert!(PcmFrame::parse(&[1, 2]).is_none());
+    }
+}
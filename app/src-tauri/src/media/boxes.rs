@@ -0,0 +1,200 @@
+//! ISO BMFF box building blocks shared by the fMP4 and MP4 writers.
+
+use super::h264::ParameterSets;
+
+/// Unity transformation matrix for `mvhd` / `tkhd`.
+const MATRIX: [u32; 9] = [0x0001_0000, 0, 0, 0, 0x0001_0000, 0, 0, 0, 0x4000_0000];
+
+/// A box under construction. Methods append big-endian fields to the body.
+pub struct Mp4Box {
+    kind: [u8; 4],
+    body: Vec<u8>,
+}
+
+impl Mp4Box {
+    pub fn new(kind: &[u8; 4]) -> Self {
+        Self {
+            kind: *kind,
+            body: Vec::new(),
+        }
+    }
+
+    /// A "full box": version and 24-bit flags precede the body.
+    pub fn full(kind: &[u8; 4], version: u8, flags: u32) -> Self {
+        Self::new(kind).u32((version as u32) << 24 | (flags & 0x00ff_ffff))
+    }
+
+    pub fn u8(mut self, v: u8) -> Self {
+        self.body.push(v);
+        self
+    }
+
+    pub fn u16(mut self, v: u16) -> Self {
+        self.body.extend_from_slice(&v.to_be_bytes());
+        self
+    }
+
+    pub fn u32(mut self, v: u32) -> Self {
+        self.body.extend_from_slice(&v.to_be_bytes());
+        self
+    }
+
+    pub fn u64(mut self, v: u64) -> Self {
+        self.body.extend_from_slice(&v.to_be_bytes());
+        self
+    }
+
+    pub fn bytes(mut self, v: &[u8]) -> Self {
+        self.body.extend_from_slice(v);
+        self
+    }
+
+    pub fn zeros(mut self, n: usize) -> Self {
+        self.body.resize(self.body.len() + n, 0);
+        self
+    }
+
+    /// Append an encoded child box.
+    pub fn child(self, child: Vec<u8>) -> Self {
+        self.bytes(&child)
+    }
+
+    /// Encode as size + type + body.
+    pub fn build(self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(8 + self.body.len());
+        out.extend_from_slice(&(8 + self.body.len() as u32).to_be_bytes());
+        out.extend_from_slice(&self.kind);
+        out.extend_from_slice(&self.body);
+        out
+    }
+}
+
+fn matrix(b: Mp4Box) -> Mp4Box {
+    MATRIX.iter().fold(b, |b, &v| b.u32(v))
+}
+
+pub fn ftyp(major: &[u8; 4], compatible: &[&[u8; 4]]) -> Vec<u8> {
+    let b = Mp4Box::new(b"ftyp").bytes(major).u32(0x200);
+    compatible.iter().fold(b, |b, c| b.bytes(*c)).build()
+}
+
+/// Seconds from the MP4 epoch (1904-01-01) to the Unix epoch.
+pub const MP4_EPOCH_OFFSET_SECS: u64 = 2_082_844_800;
+
+/// Movie header. `created` is in seconds since 1904, or 0 if unknown.
+pub fn mvhd(timescale: u32, duration: u64, next_track_id: u32, created: u64) -> Vec<u8> {
+    let b = Mp4Box::full(b"mvhd", 1, 0)
+        .u64(created) // creation_time
+        .u64(created) // modification_time
+        .u32(timescale)
+        .u64(duration)
+        .u32(0x0001_0000) // rate 1.0
+        .u16(0x0100) // volume 1.0
+        .zeros(10);
+    matrix(b).zeros(24).u32(next_track_id).build()
+}
+
+/// Track header. `width`/`height` are zero for audio tracks.
+pub fn tkhd(track_id: u32, duration: u64, width: u16, height: u16, audio: bool) -> Vec<u8> {
+    let b = Mp4Box::full(b"tkhd", 1, 0x3) // enabled | in_movie
+        .u64(0)
+        .u64(0)
+        .u32(track_id)
+        .u32(0)
+        .u64(duration)
+        .zeros(8)
+        .u16(0) // layer
+        .u16(0) // alternate_group
+        .u16(if audio { 0x0100 } else { 0 })
+        .u16(0);
+    matrix(b)
+        .u32((width as u32) << 16)
+        .u32((height as u32) << 16)
+        .build()
+}
+
+pub fn mdhd(timescale: u32, duration: u64) -> Vec<u8> {
+    Mp4Box::full(b"mdhd", 1, 0)
+        .u64(0)
+        .u64(0)
+        .u32(timescale)
+        .u64(duration)
+        .u16(0x55c4) // language "und"
+        .u16(0)
+        .build()
+}
+
+pub fn hdlr(handler: &[u8; 4], name: &str) -> Vec<u8> {
+    Mp4Box::full(b"hdlr", 0, 0)
+        .u32(0)
+        .bytes(handler)
+        .zeros(12)
+        .bytes(name.as_bytes())
+        .u8(0)
+        .build()
+}
+
+pub fn vmhd() -> Vec<u8> {
+    Mp4Box::full(b"vmhd", 0, 1).zeros(8).build()
+}
+
+pub fn smhd() -> Vec<u8> {
+    Mp4Box::full(b"smhd", 0, 0).zeros(4).build()
+}
+
+/// Data information: samples live in this file.
+pub fn dinf() -> Vec<u8> {
+    let url = Mp4Box::full(b"url ", 0, 1).build();
+    let dref = Mp4Box::full(b"dref", 0, 0).u32(1).child(url).build();
+    Mp4Box::new(b"dinf").child(dref).build()
+}
+
+/// `avc1` sample entry with its `avcC` configuration.
+pub fn avc1(params: &ParameterSets, width: u16, height: u16) -> Vec<u8> {
+    let avcc = Mp4Box::new(b"avcC").bytes(&params.avcc_record()).build();
+    Mp4Box::new(b"avc1")
+        .zeros(6)
+        .u16(1) // data_reference_index
+        .zeros(16)
+        .u16(width)
+        .u16(height)
+        .u32(0x0048_0000) // 72 dpi
+        .u32(0x0048_0000)
+        .u32(0)
+        .u16(1) // frame_count
+        .zeros(32) // compressorname
+        .u16(0x0018) // depth
+        .u16(0xffff) // pre_defined = -1
+        .child(avcc)
+        .build()
+}
+
+/// Sample description box holding one entry.
+pub fn stsd(entry: Vec<u8>) -> Vec<u8> {
+    Mp4Box::full(b"stsd", 0, 0).u32(1).child(entry).build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn box_size_includes_header() {
+        let b = Mp4Box::new(b"free").bytes(b"abc").build();
+        assert_eq!(b, [0, 0, 0, 11, b'f', b'r', b'e', b'e', b'a', b'b', b'c']);
+    }
+
+    #[test]
+    fn full_box_prefixes_version_and_flags() {
+        let b = Mp4Box::full(b"tfhd", 1, 0x020000).build();
+        assert_eq!(&b[8..], &[1, 0x02, 0, 0]);
+    }
+
+    #[test]
+    fn fixed_size_boxes() {
+        assert_eq!(mvhd(1000, 0, 2, 0).len(), 120);
+        assert_eq!(tkhd(1, 0, 320, 240, false).len(), 104);
+        assert_eq!(mdhd(90_000, 0).len(), 44);
+        assert_eq!(vmhd().len(), 20);
+    }
+}
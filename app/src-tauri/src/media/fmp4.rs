@@ -0,0 +1,169 @@
+//! Fragmented MP4 (H.264 video) for playback, matching the live WS stream:
+//! one init segment (`ftyp` + `moov`) followed by `moof` + `mdat` fragments.
+
+use super::boxes::{self, Mp4Box};
+use super::h264::ParameterSets;
+
+/// Media timescale for video tracks (90 kHz, as in MPEG-TS and RTP).
+pub const VIDEO_TIMESCALE: u32 = 90_000;
+
+const TRACK_ID: u32 = 1;
+
+const SAMPLE_FLAGS_SYNC: u32 = 0x0200_0000;
+const SAMPLE_FLAGS_NON_SYNC: u32 = 0x0101_0000;
+
+/// One encoded video frame in AVCC form.
+#[derive(Debug, Clone)]
+pub struct Sample {
+    /// Duration in [`VIDEO_TIMESCALE`] units.
+    pub duration: u32,
+    pub keyframe: bool,
+    pub data: Vec<u8>,
+}
+
+/// `ftyp` + `moov` describing a single H.264 track.
+pub fn init_segment(params: &ParameterSets) -> anyhow::Result<Vec<u8>> {
+    let (width, height) = params
+        .dimensions()
+        .ok_or_else(|| anyhow::anyhow!("unparseable SPS"))?;
+
+    let stbl = Mp4Box::new(b"stbl")
+        .child(boxes::stsd(boxes::avc1(params, width, height)))
+        .child(Mp4Box::full(b"stts", 0, 0).u32(0).build())
+        .child(Mp4Box::full(b"stsc", 0, 0).u32(0).build())
+        .child(Mp4Box::full(b"stsz", 0, 0).u32(0).u32(0).build())
+        .child(Mp4Box::full(b"stco", 0, 0).u32(0).build())
+        .build();
+    let minf = Mp4Box::new(b"minf")
+        .child(boxes::vmhd())
+        .child(boxes::dinf())
+        .child(stbl)
+        .build();
+    let mdia = Mp4Box::new(b"mdia")
+        .child(boxes::mdhd(VIDEO_TIMESCALE, 0))
+        .child(boxes::hdlr(b"vide", "VideoHandler"))
+        .child(minf)
+        .build();
+    let trak = Mp4Box::new(b"trak")
+        .child(boxes::tkhd(TRACK_ID, 0, width, height, false))
+        .child(mdia)
+        .build();
+    let trex = Mp4Box::full(b"trex", 0, 0)
+        .u32(TRACK_ID)
+        .u32(1) // default_sample_description_index
+        .u32(0)
+        .u32(0)
+        .u32(0)
+        .build();
+    let moov = Mp4Box::new(b"moov")
+        .child(boxes::mvhd(1000, 0, TRACK_ID + 1, 0))
+        .child(trak)
+        .child(Mp4Box::new(b"mvex").child(trex).build())
+        .build();
+
+    let mut out = boxes::ftyp(b"isom", &[b"isom", b"iso6", b"avc1", b"mp41"]);
+    out.extend(moov);
+    Ok(out)
+}
+
+/// `moof` + `mdat` for consecutive samples starting at `base_decode_time`.
+pub fn media_segment(sequence: u32, base_decode_time: u64, samples: &[Sample]) -> Vec<u8> {
+    // Every field is fixed-size, so the moof length is known up front and
+    // the trun data offset (moof + mdat header) can be written directly.
+    let trun_len = 8 + 4 + 4 + 4 + samples.len() * 12;
+    let traf_len = 8 + 16 + 20 + trun_len;
+    let moof_len = 8 + 16 + traf_len;
+    let data_offset = (moof_len + 8) as u32;
+
+    let trun = samples.iter().fold(
+        Mp4Box::full(b"trun", 0, 0x000701) // data-offset | duration | size | flags
+            .u32(samples.len() as u32)
+            .u32(data_offset),
+        |b, s| {
+            b.u32(s.duration)
+                .u32(s.data.len() as u32)
+                .u32(if s.keyframe {
+                    SAMPLE_FLAGS_SYNC
+                } else {
+                    SAMPLE_FLAGS_NON_SYNC
+                })
+        },
+    );
+    let traf = Mp4Box::new(b"traf")
+        .child(Mp4Box::full(b"tfhd", 0, 0x020000).u32(TRACK_ID).build()) // default-base-is-moof
+        .child(Mp4Box::full(b"tfdt", 1, 0).u64(base_decode_time).build())
+        .child(trun.build())
+        .build();
+    let moof = Mp4Box::new(b"moof")
+        .child(Mp4Box::full(b"mfhd", 0, 0).u32(sequence).build())
+        .child(traf)
+        .build();
+    debug_assert_eq!(moof.len(), moof_len);
+
+    let mdat = samples
+        .iter()
+        .fold(Mp4Box::new(b"mdat"), |b, s| b.bytes(&s.data))
+        .build();
+
+    let mut out = moof;
+    out.extend(mdat);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::media::h264::tests::keyframe_au;
+
+    /// Top-level box types and sizes.
+    fn top_level(data: &[u8]) -> Vec<(String, usize)> {
+        let mut out = Vec::new();
+        let mut pos = 0;
+        while pos + 8 <= data.len() {
+            let size = u32::from_be_bytes(data[pos..pos + 4].try_into().unwrap()) as usize;
+            out.push((
+                String::from_utf8_lossy(&data[pos + 4..pos + 8]).into_owned(),
+                size,
+            ));
+            pos += size;
+        }
+        assert_eq!(pos, data.len(), "boxes must tile the buffer");
+        out
+    }
+
+    #[test]
+    fn init_segment_structure() {
+        let params = ParameterSets::from_access_unit(&keyframe_au()).unwrap();
+        let init = init_segment(&params).unwrap();
+        let kinds: Vec<_> = top_level(&init).into_iter().map(|(k, _)| k).collect();
+        assert_eq!(kinds, vec!["ftyp", "moov"]);
+        assert!(init.windows(4).any(|w| w == b"avcC"));
+        assert!(init.windows(4).any(|w| w == b"trex"));
+    }
+
+    #[test]
+    fn media_segment_offsets_point_into_mdat() {
+        let samples = vec![
+            Sample {
+                duration: 3000,
+                keyframe: true,
+                data: vec![0xaa; 10],
+            },
+            Sample {
+                duration: 3000,
+                keyframe: false,
+                data: vec![0xbb; 5],
+            },
+        ];
+        let seg = media_segment(7, 90_000, &samples);
+        let boxes = top_level(&seg);
+        assert_eq!(boxes[0].0, "moof");
+        assert_eq!(boxes[1], ("mdat".to_string(), 8 + 15));
+
+        // trun data_offset is relative to the start of moof
+        let trun = seg.windows(4).position(|w| w == b"trun").unwrap();
+        let offset = u32::from_be_bytes(seg[trun + 12..trun + 16].try_into().unwrap()) as usize;
+        assert_eq!(seg[offset], 0xaa);
+        assert_eq!(seg[offset + 10], 0xbb);
+    }
+}
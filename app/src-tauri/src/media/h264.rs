@@ -0,0 +1,319 @@
+//! H.264 Annex B parsing: NAL splitting, parameter sets and SPS dimensions.
+
+pub const NAL_SPS: u8 = 7;
+pub const NAL_PPS: u8 = 8;
+pub const NAL_AUD: u8 = 9;
+
+/// NAL unit type of a NAL (without start code).
+pub fn nal_type(nal: &[u8]) -> u8 {
+    nal.first().map(|b| b & 0x1f).unwrap_or(0)
+}
+
+/// Split an Annex B byte stream into NAL units (start codes removed).
+pub fn split_nals(data: &[u8]) -> Vec<&[u8]> {
+    let mut nals = Vec::new();
+    let mut start = None;
+    let mut i = 0;
+    while i + 3 <= data.len() {
+        if data[i] == 0 && data[i + 1] == 0 && data[i + 2] == 1 {
+            if let Some(s) = start {
+                nals.push(trim_trailing_zeros(&data[s..i]));
+            }
+            i += 3;
+            start = Some(i);
+        } else {
+            i += 1;
+        }
+    }
+    if let Some(s) = start {
+        if s < data.len() {
+            nals.push(&data[s..]);
+        }
+    }
+    nals.retain(|n| !n.is_empty());
+    nals
+}
+
+/// Drop the leading zero of a 4-byte start code that belongs to the next NAL.
+fn trim_trailing_zeros(nal: &[u8]) -> &[u8] {
+    let mut end = nal.len();
+    while end > 0 && nal[end - 1] == 0 {
+        end -= 1;
+    }
+    &nal[..end]
+}
+
+/// Convert an Annex B access unit to length-prefixed (AVCC) samples,
+/// dropping parameter sets and delimiters that belong in `avcC` instead.
+pub fn annexb_to_avcc(data: &[u8]) -> Vec<u8> {
+    length_prefixed(data, |nal| !matches!(nal_type(nal), NAL_SPS | NAL_PPS | NAL_AUD))
+}
+
+/// Convert an Annex B access unit to length-prefixed NALs, keeping
+/// in-band parameter sets and delimiters.
+pub fn annexb_to_length_prefixed(data: &[u8]) -> Vec<u8> {
+    length_prefixed(data, |_| true)
+}
+
+fn length_prefixed(data: &[u8], keep: impl Fn(&[u8]) -> bool) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + 16);
+    for nal in split_nals(data).into_iter().filter(|n| keep(n)) {
+        out.extend_from_slice(&(nal.len() as u32).to_be_bytes());
+        out.extend_from_slice(nal);
+    }
+    out
+}
+
+/// Convert 4-byte length-prefixed NALs back to Annex B with 4-byte start
+/// codes, or `None` if a length runs past the end.
+pub fn length_prefixed_to_annexb(mut data: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(data.len());
+    while !data.is_empty() {
+        let len = u32::from_be_bytes(data.get(..4)?.try_into().ok()?) as usize;
+        let nal = data.get(4..4 + len)?;
+        out.extend_from_slice(&[0, 0, 0, 1]);
+        out.extend_from_slice(nal);
+        data = &data[4 + len..];
+    }
+    Some(out)
+}
+
+/// SPS and PPS from a keyframe access unit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParameterSets {
+    pub sps: Vec<u8>,
+    pub pps: Vec<u8>,
+}
+
+impl ParameterSets {
+    pub fn from_access_unit(data: &[u8]) -> Option<Self> {
+        let nals = split_nals(data);
+        let sps = nals.iter().find(|n| nal_type(n) == NAL_SPS)?;
+        let pps = nals.iter().find(|n| nal_type(n) == NAL_PPS)?;
+        Some(Self {
+            sps: sps.to_vec(),
+            pps: pps.to_vec(),
+        })
+    }
+
+    /// `AVCDecoderConfigurationRecord` body for the `avcC` box.
+    pub fn avcc_record(&self) -> Vec<u8> {
+        let mut out = vec![
+            1,
+            self.sps.get(1).copied().unwrap_or(0x42),
+            self.sps.get(2).copied().unwrap_or(0),
+            self.sps.get(3).copied().unwrap_or(0x1e),
+            0xff, // 4-byte NAL lengths
+            0xe1, // one SPS
+        ];
+        out.extend_from_slice(&(self.sps.len() as u16).to_be_bytes());
+        out.extend_from_slice(&self.sps);
+        out.push(1);
+        out.extend_from_slice(&(self.pps.len() as u16).to_be_bytes());
+        out.extend_from_slice(&self.pps);
+        out
+    }
+
+    /// Coded picture size after cropping.
+    pub fn dimensions(&self) -> Option<(u16, u16)> {
+        parse_sps_dimensions(&self.sps)
+    }
+}
+
+/// Exp-Golomb bit reader over an RBSP (emulation prevention removed).
+struct BitReader {
+    data: Vec<u8>,
+    pos: usize,
+}
+
+impl BitReader {
+    fn new(nal: &[u8]) -> Self {
+        // Strip emulation prevention bytes (00 00 03 -> 00 00)
+        let mut data = Vec::with_capacity(nal.len());
+        let mut zeros = 0;
+        for &b in nal {
+            if zeros >= 2 && b == 3 {
+                zeros = 0;
+                continue;
+            }
+            zeros = if b == 0 { zeros + 1 } else { 0 };
+            data.push(b);
+        }
+        Self { data, pos: 0 }
+    }
+
+    fn bit(&mut self) -> Option<u32> {
+        let byte = *self.data.get(self.pos / 8)?;
+        let bit = (byte >> (7 - self.pos % 8)) & 1;
+        self.pos += 1;
+        Some(bit as u32)
+    }
+
+    fn bits(&mut self, n: u32) -> Option<u32> {
+        (0..n).try_fold(0, |acc, _| Some((acc << 1) | self.bit()?))
+    }
+
+    fn ue(&mut self) -> Option<u32> {
+        let mut zeros = 0;
+        while self.bit()? == 0 {
+            zeros += 1;
+            if zeros > 31 {
+                return None;
+            }
+        }
+        Some((1u32 << zeros) - 1 + self.bits(zeros)?)
+    }
+
+    fn se(&mut self) -> Option<i32> {
+        let v = self.ue()?;
+        Some(if v % 2 == 1 {
+            v.div_ceil(2) as i32
+        } else {
+            -((v / 2) as i32)
+        })
+    }
+}
+
+fn parse_sps_dimensions(sps: &[u8]) -> Option<(u16, u16)> {
+    let mut r = BitReader::new(sps);
+    r.bits(8)?; // NAL header
+    let profile_idc = r.bits(8)?;
+    r.bits(16)?; // constraint flags + level_idc
+    r.ue()?; // seq_parameter_set_id
+
+    let mut chroma_format_idc = 1;
+    if matches!(
+        profile_idc,
+        100 | 110 | 122 | 244 | 44 | 83 | 86 | 118 | 128 | 138 | 139 | 134 | 135
+    ) {
+        chroma_format_idc = r.ue()?;
+        if chroma_format_idc == 3 {
+            r.bit()?; // separate_colour_plane_flag
+        }
+        r.ue()?; // bit_depth_luma_minus8
+        r.ue()?; // bit_depth_chroma_minus8
+        r.bit()?; // qpprime_y_zero_transform_bypass_flag
+        if r.bit()? == 1 {
+            let lists = if chroma_format_idc == 3 { 12 } else { 8 };
+            for i in 0..lists {
+                if r.bit()? == 1 {
+                    skip_scaling_list(&mut r, if i < 6 { 16 } else { 64 })?;
+                }
+            }
+        }
+    }
+
+    r.ue()?; // log2_max_frame_num_minus4
+    match r.ue()? {
+        0 => {
+            r.ue()?; // log2_max_pic_order_cnt_lsb_minus4
+        }
+        1 => {
+            r.bit()?;
+            r.se()?;
+            r.se()?;
+            for _ in 0..r.ue()? {
+                r.se()?;
+            }
+        }
+        _ => {}
+    }
+    r.ue()?; // max_num_ref_frames
+    r.bit()?; // gaps_in_frame_num_value_allowed_flag
+
+    let width_mbs = r.ue()? + 1;
+    let height_map_units = r.ue()? + 1;
+    let frame_mbs_only = r.bit()?;
+    if frame_mbs_only == 0 {
+        r.bit()?; // mb_adaptive_frame_field_flag
+    }
+    r.bit()?; // direct_8x8_inference_flag
+
+    let mut width = width_mbs * 16;
+    let mut height = height_map_units * 16 * (2 - frame_mbs_only);
+    if r.bit()? == 1 {
+        let (left, right, top, bottom) = (r.ue()?, r.ue()?, r.ue()?, r.ue()?);
+        let (crop_x, crop_y) = match chroma_format_idc {
+            0 => (1, 2 - frame_mbs_only),
+            1 => (2, 2 * (2 - frame_mbs_only)),
+            2 => (2, 2 - frame_mbs_only),
+            _ => (1, 2 - frame_mbs_only),
+        };
+        width = width.checked_sub((left + right) * crop_x)?;
+        height = height.checked_sub((top + bottom) * crop_y)?;
+    }
+    Some((u16::try_from(width).ok()?, u16::try_from(height).ok()?))
+}
+
+fn skip_scaling_list(r: &mut BitReader, size: usize) -> Option<()> {
+    let mut last = 8i32;
+    let mut next = 8i32;
+    for _ in 0..size {
+        if next != 0 {
+            next = (last + r.se()? + 256) % 256;
+        }
+        if next != 0 {
+            last = next;
+        }
+    }
+    Some(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Constrained baseline, level 1.3, 320x240, POC type 2, no VUI.
+    pub(crate) const SPS_320X240: &[u8] = &[0x67, 0x42, 0xc0, 0x0d, 0xda, 0x05, 0x07, 0xe4];
+    pub(crate) const PPS: &[u8] = &[0x68, 0xcb, 0x83, 0xcb, 0x20];
+
+    /// A keyframe access unit: SPS + PPS + IDR slice, 4-byte start codes.
+    pub(crate) fn keyframe_au() -> Vec<u8> {
+        let mut au = Vec::new();
+        for nal in [SPS_320X240, PPS, &[0x65, 0x88, 0x84, 0x00][..]] {
+            au.extend_from_slice(&[0, 0, 0, 1]);
+            au.extend_from_slice(nal);
+        }
+        au
+    }
+
+    #[test]
+    fn splits_mixed_start_codes() {
+        let data = [
+            0, 0, 0, 1, 0x67, 0xaa, 0, 0, 1, 0x68, 0xbb, 0, 0, 0, 1, 0x65, 0xcc,
+        ];
+        let nals = split_nals(&data);
+        assert_eq!(
+            nals,
+            vec![&[0x67, 0xaa][..], &[0x68, 0xbb][..], &[0x65, 0xcc][..]]
+        );
+    }
+
+    #[test]
+    fn avcc_drops_parameter_sets() {
+        let avcc = annexb_to_avcc(&keyframe_au());
+        assert_eq!(avcc, vec![0, 0, 0, 4, 0x65, 0x88, 0x84, 0x00]);
+    }
+
+    #[test]
+    fn length_prefixed_roundtrip_keeps_parameter_sets() {
+        let au = keyframe_au();
+        let prefixed = annexb_to_length_prefixed(&au);
+        assert_eq!(&prefixed[..5], &[0, 0, 0, 8, 0x67]);
+        assert_eq!(length_prefixed_to_annexb(&prefixed).unwrap(), au);
+        assert!(length_prefixed_to_annexb(&prefixed[..prefixed.len() - 1]).is_none());
+    }
+
+    #[test]
+    fn parameter_sets_and_dimensions() {
+        let ps = ParameterSets::from_access_unit(&keyframe_au()).unwrap();
+        assert_eq!(ps.sps, SPS_320X240);
+        assert_eq!(ps.pps, PPS);
+        assert_eq!(ps.dimensions(), Some((320, 240)));
+    }
+
+    #[test]
+    fn delta_frame_has_no_parameter_sets() {
+        assert!(ParameterSets::from_access_unit(&[0, 0, 1, 0x41, 0x9a]).is_none());
+    }
+}
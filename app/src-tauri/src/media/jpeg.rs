@@ -0,0 +1,108 @@
+//! H.264 keyframe to JPEG decoding, through an external `ffmpeg`.
+//!
+//! Release builds of the desktop app bundle `ffmpeg` as a sidecar next to
+//! the app's executable (see `scripts/fetch-ffmpeg.sh`); otherwise it is
+//! looked up on the `PATH`.
+
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+
+use bytes::Bytes;
+use tokio::io::AsyncWriteExt;
+
+/// The `ffmpeg` to run: `KODAMA_FFMPEG` if set, else the bundled sidecar,
+/// else `ffmpeg` from the `PATH`.
+pub fn ffmpeg_path() -> PathBuf {
+    let exe_dir = std::env::current_exe()
+        .ok()
+        .and_then(|exe| exe.parent().map(Path::to_path_buf));
+    resolve(std::env::var_os("KODAMA_FFMPEG"), exe_dir.as_deref())
+}
+
+fn resolve(configured: Option<OsString>, exe_dir: Option<&Path>) -> PathBuf {
+    if let Some(path) = configured {
+        return path.into();
+    }
+    let name = format!("ffmpeg{}", std::env::consts::EXE_SUFFIX);
+    exe_dir
+        .map(|dir| dir.join(&name))
+        .filter(|sidecar| sidecar.is_file())
+        .unwrap_or_else(|| name.into())
+}
+
+/// Decode one H.264 keyframe (Annex B, with parameter sets) to a JPEG,
+/// scaled to `width` if given. `qscale` is ffmpeg's `-q:v`, from 2 (best)
+/// to 31.
+pub async fn decode(
+    ffmpeg: &Path,
+    keyframe: Bytes,
+    width: Option<u32>,
+    qscale: u8,
+) -> anyhow::Result<Vec<u8>> {
+    let mut command = tokio::process::Command::new(ffmpeg);
+    command
+        .args([
+            "-hide_banner",
+            "-loglevel",
+            "error",
+            "-f",
+            "h264",
+            "-i",
+            "pipe:0",
+        ])
+        .args(["-frames:v", "1"]);
+    if let Some(width) = width {
+        command.args(["-vf", &format!("scale={}:-2", width)]);
+    }
+    let mut child = command
+        .args(["-q:v", &qscale.to_string()])
+        .args(["-f", "image2", "-c:v", "mjpeg", "pipe:1"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+
+    // Feed input while collecting output, so neither pipe can fill up
+    let mut stdin = child.stdin.take().expect("piped");
+    let feed = tokio::spawn(async move {
+        let _ = stdin.write_all(&keyframe).await;
+    });
+    let output = child.wait_with_output().await?;
+    let _ = feed.await;
+    if !output.status.success() || output.stdout.is_empty() {
+        anyhow::bail!(
+            "ffmpeg failed ({}): {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(output.stdout)
+}
+
+/// Whether `e` means the `ffmpeg` executable wasn't found.
+pub fn is_missing_decoder(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<std::io::Error>()
+        .is_some_and(|e| e.kind() == std::io::ErrorKind::NotFound)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefers_configured_then_sidecar_then_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let name = format!("ffmpeg{}", std::env::consts::EXE_SUFFIX);
+        assert_eq!(resolve(None, Some(dir.path())), PathBuf::from(&name));
+
+        std::fs::write(dir.path().join(&name), b"").unwrap();
+        assert_eq!(resolve(None, Some(dir.path())), dir.path().join(&name));
+        assert_eq!(
+            resolve(Some("/opt/ffmpeg".into()), Some(dir.path())),
+            PathBuf::from("/opt/ffmpeg")
+        );
+        assert_eq!(resolve(None, None), PathBuf::from(&name));
+    }
+}
@@ -0,0 +1,8 @@
+//! Media parsing and container muxing for recorded video.
+
+pub mod audio;
+pub mod boxes;
+pub mod fmp4;
+pub mod h264;
+pub mod jpeg;
+pub mod mp4;
@@ -0,0 +1,518 @@
+//! Standalone (progressive) MP4 for clip export: H.264 video plus optional
+//! 16-bit PCM audio (`sowt`), playable in VLC and QuickTime.
+//!
+//! Written in a single pass: `ftyp`, then a 64-bit `mdat` whose samples are
+//! appended as frames arrive, then `moov` once every sample is known. The
+//! caller owns the I/O: each `push_*` returns bytes to append, and
+//! [`Mp4Writer::finish`] returns the `moov` plus the `mdat` size to patch.
+
+use super::audio::PcmFrame;
+use super::boxes::{self, Mp4Box, MP4_EPOCH_OFFSET_SECS};
+use super::fmp4::VIDEO_TIMESCALE;
+use super::h264::{annexb_to_avcc, ParameterSets};
+
+const MOVIE_TIMESCALE: u32 = 1000;
+const VIDEO_TRACK: u32 = 1;
+const AUDIO_TRACK: u32 = 2;
+
+/// Duration given to the final frame, which has no successor to measure against.
+const LAST_FRAME_US: u64 = 33_333;
+
+/// Audio arriving this much later than its predecessors' end is preceded by
+/// silence, keeping it in sync with video across recording gaps.
+const AUDIO_GAP_US: u64 = 100_000;
+
+struct VideoTrack {
+    params: ParameterSets,
+    width: u16,
+    height: u16,
+    times_us: Vec<u64>,
+    sizes: Vec<u32>,
+    offsets: Vec<u64>,
+    /// 1-based numbers of keyframe samples.
+    sync: Vec<u32>,
+}
+
+struct AudioTrack {
+    sample_rate: u32,
+    channels: u8,
+    start_us: u64,
+    samples: u64,
+    /// File offset and sample count of each chunk.
+    chunks: Vec<(u64, u32)>,
+}
+
+/// The tail of a finished file.
+#[derive(Debug)]
+pub struct Finished {
+    /// Where to write [`Finished::mdat_size`] (the `mdat` largesize field).
+    pub mdat_size_offset: u64,
+    pub mdat_size: [u8; 8],
+    /// Append after the last sample.
+    pub moov: Vec<u8>,
+    /// Wall-clock span of the clip (epoch µs).
+    pub start_us: u64,
+    pub end_us: u64,
+}
+
+/// Incremental MP4 muxer.
+pub struct Mp4Writer {
+    mdat_start: u64,
+    offset: u64,
+    video: Option<VideoTrack>,
+    audio: Option<AudioTrack>,
+}
+
+impl Mp4Writer {
+    /// Begin a file. Returns the writer and the bytes that open the file.
+    pub fn start() -> (Self, Vec<u8>) {
+        let mut head = boxes::ftyp(b"isom", &[b"isom", b"iso2", b"avc1", b"mp41"]);
+        let mdat_start = head.len() as u64;
+        head.extend_from_slice(&1u32.to_be_bytes()); // size in largesize
+        head.extend_from_slice(b"mdat");
+        head.extend_from_slice(&0u64.to_be_bytes());
+        let writer = Self {
+            mdat_start,
+            offset: head.len() as u64,
+            video: None,
+            audio: None,
+        };
+        (writer, head)
+    }
+
+    /// Add an Annex B video frame. Frames before the first keyframe are
+    /// skipped (empty result). Returns `None` once the encoder's parameter
+    /// sets change, as one file holds a single sample description; the
+    /// clip should end there.
+    pub fn push_video(&mut self, wall_us: u64, keyframe: bool, annexb: &[u8]) -> Option<Vec<u8>> {
+        let params = keyframe
+            .then(|| ParameterSets::from_access_unit(annexb))
+            .flatten();
+        match (&self.video, params) {
+            (None, None) => return Some(Vec::new()),
+            (None, Some(params)) => {
+                let Some((width, height)) = params.dimensions() else {
+                    return Some(Vec::new());
+                };
+                self.video = Some(VideoTrack {
+                    params,
+                    width,
+                    height,
+                    times_us: Vec::new(),
+                    sizes: Vec::new(),
+                    offsets: Vec::new(),
+                    sync: Vec::new(),
+                });
+            }
+            (Some(video), Some(params)) if params != video.params => return None,
+            (Some(_), _) => {}
+        }
+
+        let video = self.video.as_mut().expect("set above");
+        let data = annexb_to_avcc(annexb);
+        let wall_us = wall_us.max(video.times_us.last().copied().unwrap_or(0));
+        video.times_us.push(wall_us);
+        video.sizes.push(data.len() as u32);
+        video.offsets.push(self.offset);
+        if keyframe {
+            video.sync.push(video.sizes.len() as u32);
+        }
+        self.offset += data.len() as u64;
+        Some(data)
+    }
+
+    /// Add an audio frame. Audio before the first video frame, or in a
+    /// format other than the first one seen, is dropped.
+    pub fn push_audio(&mut self, wall_us: u64, pcm: &PcmFrame) -> Vec<u8> {
+        let Some(video_start) = self.video.as_ref().and_then(|v| v.times_us.first()) else {
+            return Vec::new();
+        };
+        if wall_us < *video_start || pcm.samples() == 0 {
+            return Vec::new();
+        }
+        let audio = self.audio.get_or_insert_with(|| AudioTrack {
+            sample_rate: pcm.sample_rate,
+            channels: pcm.channels,
+            start_us: wall_us,
+            samples: 0,
+            chunks: Vec::new(),
+        });
+        if audio.sample_rate != pcm.sample_rate || audio.channels != pcm.channels {
+            return Vec::new();
+        }
+
+        let rate = audio.sample_rate as u64;
+        let mut out = Vec::new();
+        let expected_us = audio.start_us + audio.samples * 1_000_000 / rate;
+        if wall_us > expected_us + AUDIO_GAP_US {
+            let silence = ((wall_us - expected_us) * rate / 1_000_000) as u32;
+            out.resize((silence * pcm.frame_len()) as usize, 0);
+            audio.chunks.push((self.offset, silence));
+            audio.samples += silence as u64;
+        }
+        audio
+            .chunks
+            .push((self.offset + out.len() as u64, pcm.samples()));
+        audio.samples += pcm.samples() as u64;
+        out.extend_from_slice(pcm.data);
+        self.offset += out.len() as u64;
+        out
+    }
+
+    /// Close the file, or `None` if no video was written.
+    pub fn finish(self) -> Option<Finished> {
+        let video = self.video?;
+        let start_us = video.times_us[0];
+        let ticks = |us: u64| us * VIDEO_TIMESCALE as u64 / 1_000_000;
+
+        let mut decode_time = 0u64;
+        let durations: Vec<u32> = video
+            .times_us
+            .iter()
+            .enumerate()
+            .map(|(i, t)| {
+                let next = video
+                    .times_us
+                    .get(i + 1)
+                    .copied()
+                    .unwrap_or(t + LAST_FRAME_US);
+                let d = ticks(next - start_us).saturating_sub(decode_time).max(1);
+                decode_time += d;
+                d as u32
+            })
+            .collect();
+        let video_ms = decode_time * MOVIE_TIMESCALE as u64 / VIDEO_TIMESCALE as u64;
+        let end_us = start_us + decode_time * 1_000_000 / VIDEO_TIMESCALE as u64;
+
+        let stbl = Mp4Box::new(b"stbl")
+            .child(boxes::stsd(boxes::avc1(
+                &video.params,
+                video.width,
+                video.height,
+            )))
+            .child(stts(&durations))
+            .child(stss(&video.sync))
+            .child(stsc(&[1])) // one sample per chunk
+            .child(stsz(0, &video.sizes))
+            .child(co64(&video.offsets))
+            .build();
+        let video_trak = trak(
+            boxes::tkhd(VIDEO_TRACK, video_ms, video.width, video.height, false),
+            None,
+            boxes::mdhd(VIDEO_TIMESCALE, decode_time),
+            boxes::hdlr(b"vide", "VideoHandler"),
+            boxes::vmhd(),
+            stbl,
+        );
+
+        let audio_end_ms = self.audio.as_ref().map_or(0, |a| {
+            (a.start_us - start_us) / 1000 + a.samples * 1000 / a.sample_rate as u64
+        });
+        let movie_ms = video_ms.max(audio_end_ms);
+
+        let audio_trak = self.audio.map(|audio| {
+            let rate = audio.sample_rate as u64;
+            let audio_ms = audio.samples * MOVIE_TIMESCALE as u64 / rate;
+            let delay_ms = (audio.start_us - start_us) / 1000;
+            let frame_len = 2 * audio.channels as u32;
+            let counts: Vec<u32> = audio.chunks.iter().map(|(_, n)| *n).collect();
+            let offsets: Vec<u64> = audio.chunks.iter().map(|(o, _)| *o).collect();
+
+            let stbl = Mp4Box::new(b"stbl")
+                .child(boxes::stsd(sowt(audio.sample_rate, audio.channels)))
+                .child(
+                    Mp4Box::full(b"stts", 0, 0)
+                        .u32(1)
+                        .u32(audio.samples as u32)
+                        .u32(1)
+                        .build(),
+                )
+                .child(stsc(&counts))
+                .child(
+                    Mp4Box::full(b"stsz", 0, 0)
+                        .u32(frame_len)
+                        .u32(audio.samples as u32)
+                        .build(),
+                )
+                .child(co64(&offsets))
+                .build();
+            // Start audio where it was captured relative to the first video frame
+            let edts = (delay_ms > 0).then(|| {
+                let elst = Mp4Box::full(b"elst", 0, 0)
+                    .u32(2)
+                    .u32(delay_ms as u32)
+                    .u32(u32::MAX) // media_time -1: empty edit
+                    .u32(0x0001_0000)
+                    .u32(audio_ms as u32)
+                    .u32(0)
+                    .u32(0x0001_0000)
+                    .build();
+                Mp4Box::new(b"edts").child(elst).build()
+            });
+            trak(
+                boxes::tkhd(AUDIO_TRACK, delay_ms + audio_ms, 0, 0, true),
+                edts,
+                boxes::mdhd(audio.sample_rate, audio.samples),
+                boxes::hdlr(b"soun", "SoundHandler"),
+                boxes::smhd(),
+                stbl,
+            )
+        });
+
+        let created = start_us / 1_000_000 + MP4_EPOCH_OFFSET_SECS;
+        let mut moov = Mp4Box::new(b"moov")
+            .child(boxes::mvhd(
+                MOVIE_TIMESCALE,
+                movie_ms,
+                AUDIO_TRACK + 1,
+                created,
+            ))
+            .child(video_trak);
+        if let Some(audio_trak) = audio_trak {
+            moov = moov.child(audio_trak);
+        }
+        let moov = moov.child(udta_day(&iso8601(start_us / 1000))).build();
+
+        Some(Finished {
+            mdat_size_offset: self.mdat_start + 8,
+            mdat_size: (self.offset - self.mdat_start).to_be_bytes(),
+            moov,
+            start_us,
+            end_us,
+        })
+    }
+}
+
+fn trak(
+    tkhd: Vec<u8>,
+    edts: Option<Vec<u8>>,
+    mdhd: Vec<u8>,
+    hdlr: Vec<u8>,
+    media_header: Vec<u8>,
+    stbl: Vec<u8>,
+) -> Vec<u8> {
+    let minf = Mp4Box::new(b"minf")
+        .child(media_header)
+        .child(boxes::dinf())
+        .child(stbl)
+        .build();
+    let mdia = Mp4Box::new(b"mdia")
+        .child(mdhd)
+        .child(hdlr)
+        .child(minf)
+        .build();
+    let b = Mp4Box::new(b"trak").child(tkhd);
+    match edts {
+        Some(edts) => b.child(edts),
+        None => b,
+    }
+    .child(mdia)
+    .build()
+}
+
+/// Run-length encoded sample durations.
+fn stts(durations: &[u32]) -> Vec<u8> {
+    let mut runs: Vec<(u32, u32)> = Vec::new();
+    for &d in durations {
+        match runs.last_mut() {
+            Some((count, delta)) if *delta == d => *count += 1,
+            _ => runs.push((1, d)),
+        }
+    }
+    runs.iter()
+        .fold(
+            Mp4Box::full(b"stts", 0, 0).u32(runs.len() as u32),
+            |b, (count, delta)| b.u32(*count).u32(*delta),
+        )
+        .build()
+}
+
+fn stss(sync: &[u32]) -> Vec<u8> {
+    sync.iter()
+        .fold(
+            Mp4Box::full(b"stss", 0, 0).u32(sync.len() as u32),
+            |b, n| b.u32(*n),
+        )
+        .build()
+}
+
+/// Sample-to-chunk table from per-chunk sample counts.
+fn stsc(chunk_samples: &[u32]) -> Vec<u8> {
+    let mut runs: Vec<(u32, u32)> = Vec::new();
+    for (i, &n) in chunk_samples.iter().enumerate() {
+        if runs.last().is_none_or(|(_, prev)| *prev != n) {
+            runs.push((i as u32 + 1, n));
+        }
+    }
+    runs.iter()
+        .fold(
+            Mp4Box::full(b"stsc", 0, 0).u32(runs.len() as u32),
+            |b, (first, n)| b.u32(*first).u32(*n).u32(1),
+        )
+        .build()
+}
+
+fn stsz(sample_size: u32, sizes: &[u32]) -> Vec<u8> {
+    sizes
+        .iter()
+        .fold(
+            Mp4Box::full(b"stsz", 0, 0)
+                .u32(sample_size)
+                .u32(sizes.len() as u32),
+            |b, s| b.u32(*s),
+        )
+        .build()
+}
+
+fn co64(offsets: &[u64]) -> Vec<u8> {
+    offsets
+        .iter()
+        .fold(
+            Mp4Box::full(b"co64", 0, 0).u32(offsets.len() as u32),
+            |b, o| b.u64(*o),
+        )
+        .build()
+}
+
+/// QuickTime 16-bit little-endian PCM sample entry.
+fn sowt(sample_rate: u32, channels: u8) -> Vec<u8> {
+    Mp4Box::new(b"sowt")
+        .zeros(6)
+        .u16(1) // data_reference_index
+        .u16(0) // version
+        .u16(0) // revision
+        .u32(0) // vendor
+        .u16(channels as u16)
+        .u16(16) // sample size
+        .u16(0) // compression id
+        .u16(0) // packet size
+        .u32(sample_rate.min(0xffff) << 16)
+        .build()
+}
+
+/// QuickTime `©day` user data: the recording date, shown by VLC and
+/// QuickTime as the clip's creation date.
+fn udta_day(date: &str) -> Vec<u8> {
+    let day = Mp4Box::new(b"\xa9day")
+        .u16(date.len() as u16)
+        .u16(0x55c4) // language "und"
+        .bytes(date.as_bytes())
+        .build();
+    Mp4Box::new(b"udta").child(day).build()
+}
+
+/// Format epoch ms as UTC ISO 8601, e.g. `2024-05-01T12:00:00.000Z`.
+pub fn iso8601(epoch_ms: u64) -> String {
+    let secs = epoch_ms / 1000;
+    let (days, rem) = ((secs / 86_400) as i64, secs % 86_400);
+
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem / 60 % 60,
+        rem % 60,
+        epoch_ms % 1000
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::media::h264::tests::keyframe_au;
+
+    const DELTA: &[u8] = &[0, 0, 0, 1, 0x41, 0x9a];
+
+    /// Run a writer to completion and assemble the file as a caller would.
+    fn assemble(writer: Mp4Writer, mut file: Vec<u8>) -> (Vec<u8>, Finished) {
+        let finished = writer.finish().unwrap();
+        file.extend_from_slice(&finished.moov);
+        let at = finished.mdat_size_offset as usize;
+        file[at..at + 8].copy_from_slice(&finished.mdat_size);
+        (file, finished)
+    }
+
+    fn top_level(data: &[u8]) -> Vec<&[u8]> {
+        let mut kinds = Vec::new();
+        let mut pos = 0;
+        while pos + 16 <= data.len() {
+            let mut size = u32::from_be_bytes(data[pos..pos + 4].try_into().unwrap()) as u64;
+            if size == 1 {
+                size = u64::from_be_bytes(data[pos + 8..pos + 16].try_into().unwrap());
+            }
+            kinds.push(&data[pos + 4..pos + 8]);
+            pos += size as usize;
+        }
+        assert_eq!(pos, data.len(), "boxes must tile the file");
+        kinds
+    }
+
+    #[test]
+    fn writes_video_and_audio() {
+        let (mut writer, mut file) = Mp4Writer::start();
+        let mut pcm = 8_000u32.to_le_bytes().to_vec();
+        pcm.push(1);
+        pcm.extend_from_slice(&[0; 160]); // 10ms mono
+
+        // Audio before the first keyframe is dropped
+        assert!(writer
+            .push_audio(999_000, &PcmFrame::parse(&pcm).unwrap())
+            .is_empty());
+        assert!(writer.push_video(999_000, false, DELTA).unwrap().is_empty());
+
+        let first = writer.push_video(1_000_000, true, &keyframe_au()).unwrap();
+        let first_offset = file.len();
+        file.extend(first);
+        file.extend(writer.push_video(1_033_333, false, DELTA).unwrap());
+        file.extend(writer.push_audio(1_010_000, &PcmFrame::parse(&pcm).unwrap()));
+
+        let (file, finished) = assemble(writer, file);
+        assert_eq!(top_level(&file), vec![&b"ftyp"[..], b"mdat", b"moov"]);
+        assert_eq!(finished.start_us, 1_000_000);
+        assert_eq!(&file[first_offset + 4..first_offset + 5], &[0x65]);
+
+        let moov = &finished.moov;
+        for kind in [b"stss", b"co64", b"sowt", b"elst", b"\xa9day"] {
+            assert!(moov.windows(4).any(|w| w == kind), "missing {:?}", kind);
+        }
+        let date = iso8601(1_000);
+        assert!(moov.windows(date.len()).any(|w| w == date.as_bytes()));
+    }
+
+    #[test]
+    fn ends_on_parameter_change() {
+        let (mut writer, _) = Mp4Writer::start();
+        writer.push_video(0, true, &keyframe_au()).unwrap();
+
+        let mut other = keyframe_au();
+        // Bump level_idc in the SPS
+        other[7] = 0x1f;
+        assert!(writer.push_video(1_000_000, true, &other).is_none());
+    }
+
+    #[test]
+    fn no_video_no_file() {
+        let (writer, _) = Mp4Writer::start();
+        assert!(writer.finish().is_none());
+    }
+
+    #[test]
+    fn iso8601_dates() {
+        assert_eq!(iso8601(0), "1970-01-01T00:00:00.000Z");
+        assert_eq!(iso8601(1_700_000_000_123), "2023-11-14T22:13:20.123Z");
+        assert_eq!(iso8601(951_782_400_000), "2000-02-29T00:00:00.000Z");
+    }
+}
@@ -0,0 +1,648 @@
+//! Prometheus metrics.
+//!
+//! `GET /metrics` on the app API serves the Prometheus text format:
+//! per-camera frame counts from the Router broadcast, storage usage, write
+//! latency and drops from [`StorageHealth`](crate::storage::health::StorageHealth),
+//! broadcast lag, connected peers and event clients, and process stats
+//! where the OS exposes them.
+
+use std::collections::BTreeMap;
+use std::fmt::{Display, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use kodama::server::RouterHandle;
+use kodama::Frame;
+use tokio::sync::broadcast;
+
+use crate::events::EventBus;
+use crate::storage::now_ms;
+use crate::storage::segment::Track;
+use crate::storage::StorageManager;
+
+/// `Content-Type` of the text format.
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Bucket bounds for latencies, in seconds.
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+];
+
+/// A latency histogram with fixed buckets.
+pub struct Histogram {
+    /// Per bucket, not cumulative; the last is `+Inf`.
+    counts: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    sum_us: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            counts: std::array::from_fn(|_| AtomicU64::new(0)),
+            sum_us: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Histogram {
+    pub fn observe(&self, d: Duration) {
+        let secs = d.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|&le| secs <= le)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_us
+            .fetch_add(d.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn write(&self, out: &mut Exposition, name: &str, help: &str) {
+        out.family(name, "histogram", help);
+        let mut cumulative = 0;
+        for (i, count) in self.counts.iter().enumerate() {
+            cumulative += count.load(Ordering::Relaxed);
+            let le = LATENCY_BUCKETS
+                .get(i)
+                .map_or("+Inf".to_string(), |le| le.to_string());
+            out.sample(&format!("{}_bucket", name), &[("le", &le)], cumulative);
+        }
+        let sum = self.sum_us.load(Ordering::Relaxed) as f64 / 1e6;
+        out.sample(&format!("{}_sum", name), &[], sum);
+        out.sample(&format!("{}_count", name), &[], cumulative);
+    }
+}
+
+/// Kinds of Iroh peer.
+#[derive(Debug, Clone, Copy)]
+pub enum PeerKind {
+    Camera,
+    Client,
+}
+
+/// Counts a connected peer until dropped.
+pub struct PeerGuard {
+    metrics: Arc<Metrics>,
+    kind: PeerKind,
+}
+
+impl Drop for PeerGuard {
+    fn drop(&mut self) {
+        self.metrics
+            .peers(self.kind)
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Default)]
+struct CameraCounters {
+    /// Frames and bytes per track
+    frames: BTreeMap<Track, (u64, u64)>,
+    keyframes: u64,
+}
+
+/// Counters kept for the metrics endpoint.
+pub struct Metrics {
+    started: Instant,
+    start_ms: u64,
+    cameras: Mutex<BTreeMap<String, CameraCounters>>,
+    cameras_connected: AtomicU64,
+    clients_connected: AtomicU64,
+    connections: AtomicU64,
+    lag_events: AtomicU64,
+    frames_lagged: AtomicU64,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            start_ms: now_ms(),
+            cameras: Mutex::new(BTreeMap::new()),
+            cameras_connected: AtomicU64::new(0),
+            clients_connected: AtomicU64::new(0),
+            connections: AtomicU64::new(0),
+            lag_events: AtomicU64::new(0),
+            frames_lagged: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Metrics {
+    /// Count a frame from the Router broadcast.
+    pub fn observe(&self, frame: &Frame) {
+        let Some(track) = Track::from_channel(&frame.channel) else {
+            return;
+        };
+        let mut cameras = self.cameras.lock().unwrap();
+        let camera = cameras.entry(frame.source.to_string()).or_default();
+        let (frames, bytes) = camera.frames.entry(track).or_default();
+        *frames += 1;
+        *bytes += frame.payload.len() as u64;
+        if track == Track::Video && frame.flags.is_keyframe() {
+            camera.keyframes += 1;
+        }
+    }
+
+    fn peers(&self, kind: PeerKind) -> &AtomicU64 {
+        match kind {
+            PeerKind::Camera => &self.cameras_connected,
+            PeerKind::Client => &self.clients_connected,
+        }
+    }
+
+    /// Count a peer as connected until the guard is dropped.
+    pub fn peer_connected(self: &Arc<Self>, kind: PeerKind) -> PeerGuard {
+        self.peers(kind).fetch_add(1, Ordering::Relaxed);
+        PeerGuard {
+            metrics: self.clone(),
+            kind,
+        }
+    }
+
+    /// Count an incoming connection, before it's known what it is.
+    pub fn connection_accepted(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Everything, in the text format.
+    pub fn render(&self, storage: Option<&StorageManager>, events: &EventBus) -> String {
+        let mut out = Exposition::default();
+
+        out.family(
+            "kodama_uptime_seconds",
+            "gauge",
+            "Seconds since the app started.",
+        );
+        out.sample(
+            "kodama_uptime_seconds",
+            &[],
+            self.started.elapsed().as_secs(),
+        );
+
+        self.write_cameras(&mut out);
+
+        out.family("kodama_peers", "gauge", "Connected Iroh peers, by kind.");
+        for (kind, n) in [
+            ("camera", &self.cameras_connected),
+            ("client", &self.clients_connected),
+        ] {
+            out.sample("kodama_peers", &[("kind", kind)], n.load(Ordering::Relaxed));
+        }
+        out.family(
+            "kodama_connections_total",
+            "counter",
+            "Incoming Iroh connections.",
+        );
+        out.sample(
+            "kodama_connections_total",
+            &[],
+            self.connections.load(Ordering::Relaxed),
+        );
+        out.family(
+            "kodama_event_clients",
+            "gauge",
+            "Clients connected to the live WebSocket, receiving app events.",
+        );
+        out.sample("kodama_event_clients", &[], events.subscribers());
+
+        let status = storage.map(|storage| storage.status());
+        let mut lag = vec![(
+            "metrics",
+            self.lag_events.load(Ordering::Relaxed),
+            self.frames_lagged.load(Ordering::Relaxed),
+        )];
+        if let Some(status) = &status {
+            lag.push(("storage", status.lag_events, status.frames_lagged));
+        }
+        out.family(
+            "kodama_broadcast_lag_events_total",
+            "counter",
+            "Times a Router broadcast subscriber fell behind.",
+        );
+        for (subscriber, events, _) in &lag {
+            out.sample(
+                "kodama_broadcast_lag_events_total",
+                &[("subscriber", subscriber)],
+                events,
+            );
+        }
+        out.family(
+            "kodama_broadcast_lagged_frames_total",
+            "counter",
+            "Frames a Router broadcast subscriber missed by falling behind.",
+        );
+        for (subscriber, _, frames) in &lag {
+            out.sample(
+                "kodama_broadcast_lagged_frames_total",
+                &[("subscriber", subscriber)],
+                frames,
+            );
+        }
+
+        if let (Some(storage), Some(status)) = (storage, &status) {
+            write_storage(&mut out, storage, status);
+        }
+        write_process(&mut out, self.start_ms);
+        out.0
+    }
+
+    fn write_cameras(&self, out: &mut Exposition) {
+        let cameras = self.cameras.lock().unwrap();
+        out.family(
+            "kodama_camera_frames_total",
+            "counter",
+            "Frames received from a camera, by track.",
+        );
+        for (camera, counters) in cameras.iter() {
+            for (track, (frames, _)) in &counters.frames {
+                out.sample(
+                    "kodama_camera_frames_total",
+                    &[("camera", camera), ("track", track_name(*track))],
+                    frames,
+                );
+            }
+        }
+        out.family(
+            "kodama_camera_bytes_total",
+            "counter",
+            "Payload bytes received from a camera, by track.",
+        );
+        for (camera, counters) in cameras.iter() {
+            for (track, (_, bytes)) in &counters.frames {
+                out.sample(
+                    "kodama_camera_bytes_total",
+                    &[("camera", camera), ("track", track_name(*track))],
+                    bytes,
+                );
+            }
+        }
+        out.family(
+            "kodama_camera_keyframes_total",
+            "counter",
+            "Video keyframes received from a camera.",
+        );
+        for (camera, counters) in cameras.iter() {
+            out.sample(
+                "kodama_camera_keyframes_total",
+                &[("camera", camera)],
+                counters.keyframes,
+            );
+        }
+    }
+}
+
+fn track_name(track: Track) -> &'static str {
+    match track {
+        Track::Video => "video",
+        Track::Audio => "audio",
+        Track::Telemetry => "telemetry",
+    }
+}
+
+fn write_storage(
+    out: &mut Exposition,
+    storage: &StorageManager,
+    status: &crate::storage::health::StorageStatus,
+) {
+    let gauges: [(&str, &str, u64); 3] = [
+        (
+            "kodama_storage_used_bytes",
+            "Bytes of finished recording segments.",
+            status.used_bytes,
+        ),
+        (
+            "kodama_storage_max_bytes",
+            "Recording size cap.",
+            status.max_bytes,
+        ),
+        (
+            "kodama_storage_segments",
+            "Finished recording segments.",
+            status.segments as u64,
+        ),
+    ];
+    for (name, help, value) in gauges {
+        out.family(name, "gauge", help);
+        out.sample(name, &[], value);
+    }
+    if let Some(disk) = &status.disk {
+        out.family(
+            "kodama_storage_disk_free_bytes",
+            "gauge",
+            "Free space on the recordings filesystem.",
+        );
+        out.sample("kodama_storage_disk_free_bytes", &[], disk.free_bytes);
+        out.family(
+            "kodama_storage_disk_total_bytes",
+            "gauge",
+            "Size of the recordings filesystem.",
+        );
+        out.sample("kodama_storage_disk_total_bytes", &[], disk.total_bytes);
+    }
+    out.family(
+        "kodama_storage_emergency_removed_total",
+        "counter",
+        "Segments deleted early to recover free space.",
+    );
+    out.sample(
+        "kodama_storage_emergency_removed_total",
+        &[],
+        status.emergency_removed,
+    );
+    out.family(
+        "kodama_storage_dropped_frames_total",
+        "counter",
+        "Frames dropped because a camera's storage buffer was full.",
+    );
+    for (camera, health) in &status.cameras {
+        out.sample(
+            "kodama_storage_dropped_frames_total",
+            &[("camera", camera)],
+            health.frames_dropped,
+        );
+    }
+    out.family(
+        "kodama_storage_store_errors_total",
+        "counter",
+        "Frames that failed to store.",
+    );
+    for (camera, health) in &status.cameras {
+        out.sample(
+            "kodama_storage_store_errors_total",
+            &[("camera", camera)],
+            health.store_errors,
+        );
+    }
+    storage.health().write_latency().write(
+        out,
+        "kodama_storage_write_seconds",
+        "Time to store a frame.",
+    );
+}
+
+/// Standard process metrics, from `/proc` on Linux.
+#[cfg(target_os = "linux")]
+fn write_process(out: &mut Exposition, start_ms: u64) {
+    /// `USER_HZ`, fixed for `/proc` on Linux.
+    const CLOCK_TICKS: f64 = 100.0;
+
+    out.family(
+        "process_start_time_seconds",
+        "gauge",
+        "Start time of the process since the Unix epoch.",
+    );
+    out.sample("process_start_time_seconds", &[], start_ms / 1000);
+
+    // Fields after the parenthesized command name, from the state (3rd) on
+    if let Some(stat) = std::fs::read_to_string("/proc/self/stat")
+        .ok()
+        .and_then(|s| Some(s.get(s.rfind(')')? + 2..)?.to_string()))
+    {
+        let fields: Vec<&str> = stat.split_whitespace().collect();
+        let field = |n: usize| fields.get(n - 3).and_then(|f| f.parse::<f64>().ok());
+        if let (Some(utime), Some(stime)) = (field(14), field(15)) {
+            out.family(
+                "process_cpu_seconds_total",
+                "counter",
+                "Total user and system CPU time spent in seconds.",
+            );
+            out.sample(
+                "process_cpu_seconds_total",
+                &[],
+                (utime + stime) / CLOCK_TICKS,
+            );
+        }
+        if let Some(threads) = field(20) {
+            out.family("process_threads", "gauge", "Number of OS threads.");
+            out.sample("process_threads", &[], threads);
+        }
+    }
+
+    if let Ok(status) = std::fs::read_to_string("/proc/self/status") {
+        let kb = |key: &str| {
+            status
+                .lines()
+                .find_map(|line| line.strip_prefix(key))
+                .and_then(|v| v.trim().trim_end_matches("kB").trim().parse::<u64>().ok())
+                .map(|kb| kb * 1024)
+        };
+        if let Some(rss) = kb("VmRSS:") {
+            out.family(
+                "process_resident_memory_bytes",
+                "gauge",
+                "Resident memory size in bytes.",
+            );
+            out.sample("process_resident_memory_bytes", &[], rss);
+        }
+        if let Some(vsize) = kb("VmSize:") {
+            out.family(
+                "process_virtual_memory_bytes",
+                "gauge",
+                "Virtual memory size in bytes.",
+            );
+            out.sample("process_virtual_memory_bytes", &[], vsize);
+        }
+    }
+
+    if let Ok(fds) = std::fs::read_dir("/proc/self/fd") {
+        out.family(
+            "process_open_fds",
+            "gauge",
+            "Number of open file descriptors.",
+        );
+        out.sample("process_open_fds", &[], fds.count());
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn write_process(out: &mut Exposition, start_ms: u64) {
+    out.family(
+        "process_start_time_seconds",
+        "gauge",
+        "Start time of the process since the Unix epoch.",
+    );
+    out.sample("process_start_time_seconds", &[], start_ms / 1000);
+}
+
+/// Text format writer.
+#[derive(Default)]
+struct Exposition(String);
+
+impl Exposition {
+    fn family(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.0, "# HELP {} {}", name, help);
+        let _ = writeln!(self.0, "# TYPE {} {}", name, kind);
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl Display) {
+        self.0.push_str(name);
+        if !labels.is_empty() {
+            self.0.push('{');
+            for (i, (key, value)) in labels.iter().enumerate() {
+                if i > 0 {
+                    self.0.push(',');
+                }
+                let value = value
+                    .replace('\\', "\\\\")
+                    .replace('"', "\\\"")
+                    .replace('\n', "\\n");
+                let _ = write!(self.0, "{}=\"{}\"", key, value);
+            }
+            self.0.push('}');
+        }
+        let _ = writeln!(self.0, " {}", value);
+    }
+}
+
+/// Count frames from the Router broadcast until it closes.
+pub fn spawn(handle: RouterHandle, metrics: Arc<Metrics>) {
+    tokio::spawn(async move {
+        let mut rx = handle.subscribe();
+        loop {
+            match rx.recv().await {
+                Ok(frame) => metrics.observe(&frame),
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    metrics.lag_events.fetch_add(1, Ordering::Relaxed);
+                    metrics.frames_lagged.fetch_add(n, Ordering::Relaxed);
+                    tracing::debug!("Metrics lagged, missed {} frames", n);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bytes::Bytes;
+    use kodama::{Channel, FrameFlags, SourceId};
+
+    use crate::storage::manager::{test_config, test_manager};
+    use crate::storage::SegmentKind;
+
+    fn frame(channel: Channel, keyframe: bool, len: usize) -> Frame {
+        Frame {
+            source: SourceId::from_node_id_bytes(&[1; 32]),
+            channel,
+            flags: if keyframe {
+                FrameFlags::keyframe()
+            } else {
+                FrameFlags::default()
+            },
+            timestamp_us: 0,
+            payload: Bytes::from(vec![0; len]),
+        }
+    }
+
+    fn lines(text: &str, prefix: &str) -> Vec<String> {
+        text.lines()
+            .filter(|l| l.starts_with(prefix))
+            .map(String::from)
+            .collect()
+    }
+
+    #[test]
+    fn counts_frames_and_peers() {
+        let metrics = Arc::new(Metrics::default());
+        metrics.observe(&frame(Channel::Video, true, 100));
+        metrics.observe(&frame(Channel::Video, false, 20));
+        metrics.observe(&frame(Channel::Telemetry, false, 5));
+        metrics.connection_accepted();
+        let camera = metrics.peer_connected(PeerKind::Camera);
+        let client = metrics.peer_connected(PeerKind::Client);
+        drop(client);
+
+        let events = EventBus::default();
+        let _rx = events.subscribe();
+        let text = metrics.render(None, &events);
+        let camera_id = SourceId::from_node_id_bytes(&[1; 32]).to_string();
+        assert_eq!(
+            lines(&text, "kodama_camera_frames_total{"),
+            vec![
+                format!(
+                    "kodama_camera_frames_total{{camera=\"{}\",track=\"video\"}} 2",
+                    camera_id
+                ),
+                format!(
+                    "kodama_camera_frames_total{{camera=\"{}\",track=\"telemetry\"}} 1",
+                    camera_id
+                ),
+            ]
+        );
+        assert!(text.contains(&format!(
+            "kodama_camera_bytes_total{{camera=\"{}\",track=\"video\"}} 120",
+            camera_id
+        )));
+        assert!(text.contains(&format!(
+            "kodama_camera_keyframes_total{{camera=\"{}\"}} 1",
+            camera_id
+        )));
+        assert!(text.contains("kodama_peers{kind=\"camera\"} 1\n"));
+        assert!(text.contains("kodama_peers{kind=\"client\"} 0\n"));
+        assert!(text.contains("kodama_connections_total 1\n"));
+        assert!(text.contains("kodama_event_clients 1\n"));
+        assert!(text.contains("kodama_broadcast_lag_events_total{subscriber=\"metrics\"} 0\n"));
+        // No storage, no storage metrics
+        assert!(!text.contains("kodama_storage_"));
+        drop(camera);
+        assert!(metrics
+            .render(None, &events)
+            .contains("kodama_peers{kind=\"camera\"} 0\n"));
+    }
+
+    #[tokio::test]
+    async fn storage_metrics() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = test_manager(dir.path(), test_config());
+        let mut rec = manager.recorder("cam".into());
+        rec.store(&frame(Channel::Video, true, 10), 0, SegmentKind::Continuous)
+            .await
+            .unwrap();
+        rec.finish().await.unwrap();
+        let health = manager.health();
+        health.frame_dropped("cam");
+        health.frames_lagged(7);
+        health.store_latency(Duration::from_millis(3));
+        health.store_latency(Duration::from_secs(5));
+
+        let text = Metrics::default().render(Some(&manager), &EventBus::default());
+        assert!(text.contains("kodama_storage_segments 1\n"));
+        assert!(text.contains("kodama_storage_dropped_frames_total{camera=\"cam\"} 1\n"));
+        assert!(text.contains("kodama_broadcast_lag_events_total{subscriber=\"storage\"} 1\n"));
+        assert!(text.contains("kodama_broadcast_lagged_frames_total{subscriber=\"storage\"} 7\n"));
+        assert!(text.contains("# TYPE kodama_storage_write_seconds histogram\n"));
+        assert!(text.contains("kodama_storage_write_seconds_bucket{le=\"0.0025\"} 0\n"));
+        assert!(text.contains("kodama_storage_write_seconds_bucket{le=\"0.005\"} 1\n"));
+        assert!(text.contains("kodama_storage_write_seconds_bucket{le=\"+Inf\"} 2\n"));
+        assert!(text.contains("kodama_storage_write_seconds_sum 5.003\n"));
+        assert!(text.contains("kodama_storage_write_seconds_count 2\n"));
+    }
+
+    #[test]
+    fn escapes_label_values() {
+        let mut out = Exposition::default();
+        out.sample("m", &[("a", "x\"y\\z\n")], 1);
+        assert_eq!(out.0, "m{a=\"x\\\"y\\\\z\\n\"} 1\n");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn process_stats() {
+        let text = Metrics::default().render(None, &EventBus::default());
+        for name in [
+            "process_cpu_seconds_total ",
+            "process_resident_memory_bytes ",
+            "process_open_fds ",
+            "process_start_time_seconds ",
+        ] {
+            assert!(
+                text.lines().any(|l| l.starts_with(name)),
+                "missing {}",
+                name
+            );
+        }
+    }
+}
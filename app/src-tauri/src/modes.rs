@@ -0,0 +1,369 @@
+//! Arming modes.
+//!
+//! The server is always in one of Home, Away, Night or Disarmed. Each
+//! camera's [`ModeEffect`] for the current mode decides whether it raises
+//! motion and geofence alerts and how it records. The mode is set through
+//! the API or MQTT, or switched by the weekly schedule, in local time; a
+//! manual change holds until the next scheduled switch.
+//!
+//! The mode is kept in `mode.json` so it survives restarts, and pushed to
+//! the UI as `mode` app events. Switches the schedule made while the app
+//! was stopped are applied when it starts.
+
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use jiff::civil::Date;
+use jiff::tz::TimeZone;
+use jiff::{Timestamp, ToSpan};
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+use crate::config::{ArmingMode, ModeEffect, ModesConfig, Weekday};
+use crate::events::{AppEvent, EventBus};
+use crate::storage::now_ms;
+
+/// How often the schedule is checked.
+const TICK: Duration = Duration::from_secs(15);
+
+/// Mode when nothing has set one: everything armed, as before modes.
+const INITIAL: ArmingMode = ArmingMode::Away;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ModeSource {
+    /// Nothing has set the mode yet.
+    Initial,
+    Manual,
+    Schedule,
+}
+
+/// The current mode, as stored in `mode.json`.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct Current {
+    mode: ArmingMode,
+    source: ModeSource,
+    #[serde(rename = "since")]
+    since_ms: u64,
+}
+
+/// A switch the schedule will make.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct ScheduledSwitch {
+    pub mode: ArmingMode,
+    #[serde(rename = "time")]
+    pub time_ms: u64,
+}
+
+/// The mode, as served by `/api/mode` and pushed to the UI.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ModeStatus {
+    pub mode: ArmingMode,
+    pub source: ModeSource,
+    /// When the mode was set (epoch ms)
+    #[serde(rename = "since")]
+    pub since_ms: u64,
+    pub next: Option<ScheduledSwitch>,
+}
+
+/// The current arming mode and its schedule.
+pub struct Modes {
+    config: ModesConfig,
+    path: PathBuf,
+    tz: TimeZone,
+    current: Mutex<Current>,
+    events: EventBus,
+    tx: watch::Sender<ArmingMode>,
+}
+
+impl Modes {
+    /// Open the mode saved at `path`, catching up with the schedule.
+    pub fn open(config: ModesConfig, path: PathBuf, events: EventBus) -> anyhow::Result<Self> {
+        Self::open_in(config, path, events, TimeZone::system(), now_ms())
+    }
+
+    fn open_in(
+        config: ModesConfig,
+        path: PathBuf,
+        events: EventBus,
+        tz: TimeZone,
+        now_ms: u64,
+    ) -> anyhow::Result<Self> {
+        let current = if path.exists() {
+            serde_json::from_str(&std::fs::read_to_string(&path)?)?
+        } else {
+            Current {
+                mode: INITIAL,
+                source: ModeSource::Initial,
+                since_ms: 0,
+            }
+        };
+        let modes = Self {
+            config,
+            path,
+            tz,
+            tx: watch::channel(current.mode).0,
+            current: Mutex::new(current),
+            events,
+        };
+        modes.tick(now_ms);
+        Ok(modes)
+    }
+
+    pub fn mode(&self) -> ArmingMode {
+        self.current.lock().unwrap().mode
+    }
+
+    /// What the current mode does to a camera.
+    pub fn effect(&self, camera_id: &str) -> &ModeEffect {
+        self.config.for_camera(camera_id).get(self.mode())
+    }
+
+    pub fn status(&self, now_ms: u64) -> ModeStatus {
+        let current = *self.current.lock().unwrap();
+        ModeStatus {
+            mode: current.mode,
+            source: current.source,
+            since_ms: current.since_ms,
+            next: self.next_switch(now_ms),
+        }
+    }
+
+    /// The mode, as it changes.
+    pub fn subscribe(&self) -> watch::Receiver<ArmingMode> {
+        self.tx.subscribe()
+    }
+
+    /// Switch to `mode` until the next scheduled switch.
+    pub fn set(&self, mode: ArmingMode, now_ms: u64) -> anyhow::Result<ModeStatus> {
+        self.switch(mode, ModeSource::Manual, now_ms)?;
+        Ok(self.status(now_ms))
+    }
+
+    /// Apply the latest scheduled switch, if it's newer than the mode.
+    pub fn tick(&self, now_ms: u64) {
+        let Some(switch) = self.last_switch(now_ms) else {
+            return;
+        };
+        if switch.time_ms > self.current.lock().unwrap().since_ms {
+            if let Err(e) = self.switch(switch.mode, ModeSource::Schedule, switch.time_ms) {
+                tracing::warn!("Failed to save scheduled mode: {}", e);
+            }
+        }
+    }
+
+    fn switch(&self, mode: ArmingMode, source: ModeSource, since_ms: u64) -> anyhow::Result<()> {
+        let current = Current {
+            mode,
+            source,
+            since_ms,
+        };
+        let previous = std::mem::replace(&mut *self.current.lock().unwrap(), current);
+        // Keep the new mode even if it can't be saved
+        let saved = self.save(&current);
+
+        if previous.mode != mode {
+            tracing::info!(
+                ?source,
+                "Mode changed from {:?} to {:?}",
+                previous.mode,
+                mode
+            );
+        }
+        self.tx.send_replace(mode);
+        self.events.publish(AppEvent::Mode(self.status(now_ms())));
+        saved
+    }
+
+    fn save(&self, current: &Current) -> anyhow::Result<()> {
+        let tmp = self.path.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_vec_pretty(current)?)?;
+        std::fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+
+    /// Scheduled switches on local `day`.
+    fn switches_on(&self, day: Date) -> impl Iterator<Item = ScheduledSwitch> + '_ {
+        let weekday = weekday(day);
+        self.config
+            .schedule
+            .iter()
+            .filter(move |entry| entry.days.is_empty() || entry.days.contains(&weekday))
+            .filter_map(move |entry| {
+                let at = day.at(entry.at.hour as i8, entry.at.minute as i8, 0, 0);
+                // Times skipped by a DST change resolve to just after it
+                let time = at.to_zoned(self.tz.clone()).ok()?.timestamp();
+                Some(ScheduledSwitch {
+                    mode: entry.mode,
+                    time_ms: time.as_millisecond().max(0) as u64,
+                })
+            })
+    }
+
+    fn days_around(&self, now_ms: u64, offsets: std::ops::RangeInclusive<i64>) -> Vec<Date> {
+        let Ok(now) = Timestamp::from_millisecond(now_ms as i64) else {
+            return Vec::new();
+        };
+        let today = now.to_zoned(self.tz.clone()).date();
+        offsets
+            .filter_map(|n| today.checked_add(n.days()).ok())
+            .collect()
+    }
+
+    /// The most recent scheduled switch at or before `now_ms`.
+    fn last_switch(&self, now_ms: u64) -> Option<ScheduledSwitch> {
+        self.days_around(now_ms, -7..=0)
+            .into_iter()
+            .flat_map(|day| self.switches_on(day).collect::<Vec<_>>())
+            .filter(|s| s.time_ms <= now_ms)
+            .max_by_key(|s| s.time_ms)
+    }
+
+    /// The first scheduled switch after `now_ms`.
+    fn next_switch(&self, now_ms: u64) -> Option<ScheduledSwitch> {
+        self.days_around(now_ms, 0..=7)
+            .into_iter()
+            .flat_map(|day| self.switches_on(day).collect::<Vec<_>>())
+            .filter(|s| s.time_ms > now_ms)
+            .min_by_key(|s| s.time_ms)
+    }
+}
+
+fn weekday(day: Date) -> Weekday {
+    match day.weekday() {
+        jiff::civil::Weekday::Monday => Weekday::Mon,
+        jiff::civil::Weekday::Tuesday => Weekday::Tue,
+        jiff::civil::Weekday::Wednesday => Weekday::Wed,
+        jiff::civil::Weekday::Thursday => Weekday::Thu,
+        jiff::civil::Weekday::Friday => Weekday::Fri,
+        jiff::civil::Weekday::Saturday => Weekday::Sat,
+        jiff::civil::Weekday::Sunday => Weekday::Sun,
+    }
+}
+
+/// Follow the schedule until the app exits.
+pub fn spawn(modes: Arc<Modes>) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(TICK);
+        loop {
+            ticker.tick().await;
+            modes.tick(now_ms());
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::config::{ModeRecording, ScheduleEntry};
+
+    /// 2024-01-01 00:00 UTC, a Monday.
+    const MONDAY: u64 = 1_704_067_200_000;
+    const HOUR: u64 = 60 * 60 * 1000;
+    const DAY: u64 = 24 * HOUR;
+
+    fn config() -> ModesConfig {
+        serde_json::from_value(serde_json::json!({
+            "cameras": { "cam": { "away": { "recording": "event" } } },
+            "schedule": [
+                { "at": "22:00", "mode": "night" },
+                { "days": ["mon", "tue", "wed", "thu", "fri"], "at": "08:00", "mode": "away" },
+                { "days": ["sat", "sun"], "at": "09:00", "mode": "home" }
+            ]
+        }))
+        .unwrap()
+    }
+
+    fn open(dir: &tempfile::TempDir, config: ModesConfig, now_ms: u64) -> Modes {
+        Modes::open_in(
+            config,
+            dir.path().join("mode.json"),
+            EventBus::default(),
+            TimeZone::UTC,
+            now_ms,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn follows_schedule() {
+        let dir = tempfile::tempdir().unwrap();
+        // Monday 12:00: the 08:00 switch to away applies on open
+        let modes = open(&dir, config(), MONDAY + 12 * HOUR);
+        let status = modes.status(MONDAY + 12 * HOUR);
+        assert_eq!(status.mode, ArmingMode::Away);
+        assert_eq!(status.source, ModeSource::Schedule);
+        assert_eq!(status.since_ms, MONDAY + 8 * HOUR);
+        assert_eq!(
+            status.next,
+            Some(ScheduledSwitch {
+                mode: ArmingMode::Night,
+                time_ms: MONDAY + 22 * HOUR
+            })
+        );
+        assert_eq!(modes.effect("cam").recording, ModeRecording::Event);
+        assert!(modes.effect("other").alerts);
+
+        modes.tick(MONDAY + 22 * HOUR);
+        assert_eq!(modes.mode(), ArmingMode::Night);
+
+        // Saturday morning switches to home at 09:00, not away at 08:00
+        modes.tick(MONDAY + 5 * DAY + 8 * HOUR + 30 * 60 * 1000);
+        assert_eq!(modes.mode(), ArmingMode::Night);
+        modes.tick(MONDAY + 5 * DAY + 9 * HOUR);
+        assert_eq!(modes.mode(), ArmingMode::Home);
+        assert!(!modes.effect("cam").alerts);
+    }
+
+    #[test]
+    fn manual_mode_holds_until_next_switch_and_persists() {
+        let dir = tempfile::tempdir().unwrap();
+        let modes = open(&dir, config(), MONDAY + 12 * HOUR);
+        let mut rx = modes.subscribe();
+        let mut events = modes.events.subscribe();
+
+        let status = modes.set(ArmingMode::Disarmed, MONDAY + 13 * HOUR).unwrap();
+        assert_eq!(status.source, ModeSource::Manual);
+        assert_eq!(*rx.borrow_and_update(), ArmingMode::Disarmed);
+        let event: serde_json::Value = serde_json::from_str(&events.try_recv().unwrap()).unwrap();
+        assert_eq!(event["type"], "mode");
+        assert_eq!(event["payload"]["mode"], "disarmed");
+        assert_eq!(event["payload"]["source"], "manual");
+
+        modes.tick(MONDAY + 14 * HOUR);
+        assert_eq!(modes.mode(), ArmingMode::Disarmed);
+
+        // Reopened before the next switch: still disarmed
+        drop(modes);
+        let modes = open(&dir, config(), MONDAY + 15 * HOUR);
+        assert_eq!(modes.mode(), ArmingMode::Disarmed);
+        // Reopened after it: the switch made while stopped applies
+        drop(modes);
+        let modes = open(&dir, config(), MONDAY + 23 * HOUR);
+        assert_eq!(modes.mode(), ArmingMode::Night);
+    }
+
+    #[test]
+    fn no_schedule_starts_away() {
+        let dir = tempfile::tempdir().unwrap();
+        let modes = open(&dir, ModesConfig::default(), MONDAY);
+        let status = modes.status(MONDAY);
+        assert_eq!(status.mode, ArmingMode::Away);
+        assert_eq!(status.source, ModeSource::Initial);
+        assert_eq!(status.next, None);
+
+        let config = ModesConfig {
+            schedule: vec![ScheduleEntry {
+                days: vec![Weekday::Sun],
+                at: "23:59".to_string().try_into().unwrap(),
+                mode: ArmingMode::Home,
+            }],
+            ..Default::default()
+        };
+        let dir = tempfile::tempdir().unwrap();
+        // Monday: last Sunday's switch still applies
+        assert_eq!(open(&dir, config, MONDAY).mode(), ArmingMode::Home);
+    }
+}
@@ -0,0 +1,420 @@
+//! Alert emails.
+//!
+//! Each alert is mailed to every recipient whose filters match, one message
+//! per recipient, with a live snapshot of the camera (or its nearest
+//! thumbnail) attached as `snapshot.jpg` when there is one. Recipients get at most
+//! [`EmailConfig::max_per_hour`] emails in any hour; alerts beyond that are
+//! counted and the count is mentioned in the next email, so a motion storm
+//! doesn't flood anyone's inbox.
+//!
+//! Sending isn't retried: alerts stay listed by `/api/alerts` either way.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, UNIX_EPOCH};
+
+use anyhow::Context;
+use lettre::message::header::ContentType;
+use lettre::message::{Attachment, Mailbox, MultiPart, SinglePart};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use tokio::sync::broadcast;
+
+use crate::alerts::{Alert, AlertEvent};
+use crate::config::{EmailConfig, SmtpTls};
+use crate::snapshot::Snapshots;
+use crate::storage::Thumbnails;
+
+use super::short_id;
+
+/// The window [`EmailConfig::max_per_hour`] counts over.
+const WINDOW_MS: u64 = 60 * 60 * 1000;
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Emails sent to a recipient within the window, and alerts held back.
+#[derive(Debug, Default)]
+struct Quota {
+    sent_ms: VecDeque<u64>,
+    suppressed: u32,
+}
+
+/// Mails alerts to the configured recipients.
+pub struct Mailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+    /// Parsed addresses, parallel to `config.recipients`.
+    to: Vec<Mailbox>,
+    config: EmailConfig,
+    snapshots: Arc<Snapshots>,
+    thumbnails: Option<Arc<Thumbnails>>,
+    /// Parallel to `config.recipients`.
+    quotas: Mutex<Vec<Quota>>,
+}
+
+impl Mailer {
+    pub fn new(
+        config: EmailConfig,
+        snapshots: Arc<Snapshots>,
+        thumbnails: Option<Arc<Thumbnails>>,
+    ) -> anyhow::Result<Self> {
+        let from: Mailbox = config
+            .from
+            .parse()
+            .with_context(|| format!("Invalid sender address {}", config.from))?;
+        let to = config
+            .recipients
+            .iter()
+            .map(|r| {
+                r.address
+                    .parse()
+                    .with_context(|| format!("Invalid recipient address {}", r.address))
+            })
+            .collect::<anyhow::Result<Vec<Mailbox>>>()?;
+
+        let mut builder = match config.tls {
+            SmtpTls::Starttls => {
+                AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.host)?
+            }
+            SmtpTls::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&config.host)?,
+            SmtpTls::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&config.host),
+        }
+        .port(config.port.unwrap_or(config.tls.default_port()))
+        .timeout(Some(TIMEOUT));
+        if let Some(username) = &config.username {
+            let password = config.password.clone().unwrap_or_default();
+            builder = builder.credentials(Credentials::new(username.clone(), password));
+        }
+
+        Ok(Self {
+            transport: builder.build(),
+            from,
+            to,
+            quotas: Mutex::new(config.recipients.iter().map(|_| Quota::default()).collect()),
+            config,
+            snapshots,
+            thumbnails,
+        })
+    }
+
+    /// Mail an alert to every recipient whose filters match and who is
+    /// within their quota. Camera connects and disconnects aren't mailed;
+    /// disconnect alerts are.
+    pub fn dispatch(self: &Arc<Self>, event: &AlertEvent) {
+        let AlertEvent::Alert { alert } = event else {
+            return;
+        };
+        let alert_type = match serde_json::to_value(alert.kind) {
+            Ok(serde_json::Value::String(name)) => name,
+            _ => return,
+        };
+
+        for (index, recipient) in self.config.recipients.iter().enumerate() {
+            let wanted = recipient.alerts.is_empty() || recipient.alerts.contains(&alert_type);
+            let camera =
+                recipient.cameras.is_empty() || recipient.cameras.contains(&alert.camera_id);
+            if !wanted || !camera {
+                continue;
+            }
+            let Some(suppressed) = self.admit(index, alert.time_ms) else {
+                tracing::debug!(to = %recipient.address, "Alert email rate limited");
+                continue;
+            };
+            tokio::spawn(self.clone().send(index, alert.clone(), suppressed));
+        }
+    }
+
+    /// Count an email to recipient `index` at `now_ms` against their quota.
+    /// Returns how many alerts were held back since their last email, or
+    /// `None` if this one must be too.
+    fn admit(&self, index: usize, now_ms: u64) -> Option<u32> {
+        let mut quotas = self.quotas.lock().unwrap();
+        let quota = &mut quotas[index];
+        while quota
+            .sent_ms
+            .front()
+            .is_some_and(|&t| t + WINDOW_MS <= now_ms)
+        {
+            quota.sent_ms.pop_front();
+        }
+        if quota.sent_ms.len() >= self.config.max_per_hour as usize {
+            quota.suppressed += 1;
+            return None;
+        }
+        quota.sent_ms.push_back(now_ms);
+        Some(std::mem::take(&mut quota.suppressed))
+    }
+
+    async fn send(self: Arc<Self>, index: usize, alert: Alert, suppressed: u32) {
+        let to = &self.config.recipients[index].address;
+        let result = match self.message(index, &alert, suppressed).await {
+            Ok(message) => self.transport.send(message).await.map_err(Into::into),
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(_) => tracing::debug!(to = %to, alert = alert.id, "Alert emailed"),
+            Err(e) => tracing::warn!(to = %to, alert = alert.id, "Failed to email alert: {:#}", e),
+        }
+    }
+
+    async fn message(
+        &self,
+        index: usize,
+        alert: &Alert,
+        suppressed: u32,
+    ) -> anyhow::Result<Message> {
+        let mut text = format!(
+            "{}\n\nCamera: {}\nAlert ID: {}\n",
+            alert.message, alert.camera_id, alert.id
+        );
+        if suppressed > 0 {
+            text.push_str(&format!(
+                "\n{} more alert{} since the last email {} not emailed; see the app for the full list.\n",
+                suppressed,
+                if suppressed == 1 { "" } else { "s" },
+                if suppressed == 1 { "was" } else { "were" },
+            ));
+        }
+
+        let builder = Message::builder()
+            .from(self.from.clone())
+            .to(self.to[index].clone())
+            .subject(format!(
+                "Kodama {}: {}",
+                short_id(&alert.camera_id),
+                alert.message
+            ))
+            .date(UNIX_EPOCH + Duration::from_millis(alert.time_ms));
+        let body = SinglePart::plain(text);
+
+        let message = match self.snapshot(alert).await {
+            Some(jpeg) => builder.multipart(
+                MultiPart::mixed().singlepart(body).singlepart(
+                    Attachment::new("snapshot.jpg".into())
+                        .body(jpeg, ContentType::parse("image/jpeg")?),
+                ),
+            )?,
+            None => builder.singlepart(body)?,
+        };
+        Ok(message)
+    }
+
+    /// A snapshot of the alert's camera, if snapshots are on.
+    async fn snapshot(&self, alert: &Alert) -> Option<Vec<u8>> {
+        if !self.config.snapshot {
+            return None;
+        }
+        let thumbnails = self.thumbnails.as_deref();
+        super::snapshot(&self.snapshots, thumbnails, &alert.camera_id, alert.time_ms).await
+    }
+}
+
+/// Mail the engine's alerts until it stops.
+pub fn spawn(mailer: Arc<Mailer>, mut rx: broadcast::Receiver<AlertEvent>) {
+    tokio::spawn(async move {
+        loop {
+            match rx.recv().await {
+                Ok(event) => mailer.dispatch(&event),
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    tracing::warn!("Alert emails lagged, missed {} events", n);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::sync::mpsc;
+
+    use crate::alerts::AlertKind;
+    use crate::config::EmailRecipient;
+    use crate::storage::ThumbnailConfig;
+
+    const CAMERA: &str = "0102030405060708";
+
+    /// A message accepted by the sink.
+    #[derive(Debug)]
+    struct Mail {
+        to: Vec<String>,
+        data: String,
+    }
+
+    /// Local SMTP sink: accepts every message and hands it over.
+    async fn start_sink() -> (u16, mpsc::UnboundedReceiver<Mail>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                tokio::spawn(session(socket, tx.clone()));
+            }
+        });
+        (port, rx)
+    }
+
+    async fn session(socket: tokio::net::TcpStream, tx: mpsc::UnboundedSender<Mail>) {
+        let (read, mut write) = socket.into_split();
+        let mut lines = BufReader::new(read).lines();
+        let mut to = Vec::new();
+        write.write_all(b"220 sink ESMTP\r\n").await.unwrap();
+        while let Ok(Some(line)) = lines.next_line().await {
+            let command = line.to_ascii_uppercase();
+            let reply: &[u8] = if command.starts_with("EHLO") || command.starts_with("HELO") {
+                b"250 sink\r\n"
+            } else if command.starts_with("RCPT TO:") {
+                to.push(line[8..].trim_matches(|c| c == '<' || c == '>').to_string());
+                b"250 OK\r\n"
+            } else if command == "DATA" {
+                write.write_all(b"354 Go ahead\r\n").await.unwrap();
+                let mut data = String::new();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    if line == "." {
+                        break;
+                    }
+                    data.push_str(&line);
+                    data.push('\n');
+                }
+                let _ = tx.send(Mail {
+                    to: std::mem::take(&mut to),
+                    data,
+                });
+                b"250 Queued\r\n"
+            } else if command == "QUIT" {
+                let _ = write.write_all(b"221 Bye\r\n").await;
+                return;
+            } else {
+                b"250 OK\r\n"
+            };
+            write.write_all(reply).await.unwrap();
+        }
+    }
+
+    async fn next(rx: &mut mpsc::UnboundedReceiver<Mail>) -> Mail {
+        tokio::time::timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .expect("no email")
+            .unwrap()
+    }
+
+    fn config(port: u16, recipients: Vec<EmailRecipient>) -> EmailConfig {
+        EmailConfig {
+            host: "127.0.0.1".into(),
+            port: Some(port),
+            tls: SmtpTls::None,
+            username: None,
+            password: None,
+            from: "Kodama <kodama@example.com>".into(),
+            recipients,
+            max_per_hour: 2,
+            snapshot: true,
+        }
+    }
+
+    fn recipient(address: &str, alerts: &[&str], cameras: &[&str]) -> EmailRecipient {
+        EmailRecipient {
+            address: address.into(),
+            alerts: alerts.iter().map(|a| a.to_string()).collect(),
+            cameras: cameras.iter().map(|c| c.to_string()).collect(),
+        }
+    }
+
+    fn alert(id: u64, kind: AlertKind, camera_id: &str, time_ms: u64) -> AlertEvent {
+        AlertEvent::Alert {
+            alert: Alert {
+                id,
+                kind,
+                camera_id: camera_id.into(),
+                message: "Motion detected (90%)".into(),
+                time_ms,
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn filters_and_rate_limits() {
+        let (port, mut sink) = start_sink().await;
+        let mailer = Arc::new(
+            Mailer::new(
+                config(
+                    port,
+                    vec![
+                        recipient("motion@example.com", &["motion"], &[]),
+                        recipient("other@example.com", &[], &["ffffffffffffffff"]),
+                    ],
+                ),
+                Arc::new(Snapshots::new("ffmpeg".into())),
+                None,
+            )
+            .unwrap(),
+        );
+
+        mailer.dispatch(&alert(1, AlertKind::Disconnect, CAMERA, 0));
+        for (id, time_ms) in [(2, 1_000), (3, 2_000), (4, 3_000), (5, 4_000)] {
+            mailer.dispatch(&alert(id, AlertKind::Motion, CAMERA, time_ms));
+        }
+        let mut ids = Vec::new();
+        for _ in 0..2 {
+            let mail = next(&mut sink).await;
+            assert_eq!(mail.to, vec!["motion@example.com"]);
+            assert!(mail
+                .data
+                .contains("Subject: Kodama 01020304: Motion detected (90%)"));
+            assert!(!mail.data.contains("not emailed"));
+            ids.push(mail.data.contains("Alert ID: 2"));
+        }
+        // Both allowed emails went out, for alerts 2 and 3
+        assert_eq!(ids.iter().filter(|&&first| first).count(), 1);
+
+        // An hour after the first email the quota has room again
+        mailer.dispatch(&alert(6, AlertKind::Motion, CAMERA, 3_601_000));
+        let mail = next(&mut sink).await;
+        assert!(mail.data.contains("Alert ID: 6"));
+        assert!(mail
+            .data
+            .contains("2 more alerts since the last email were not emailed"));
+        assert!(sink.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn attaches_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join(CAMERA)).unwrap();
+        std::fs::write(dir.path().join(CAMERA).join("10000.jpg"), b"jpeg").unwrap();
+        let thumbnails = Thumbnails::open(
+            dir.path(),
+            ThumbnailConfig {
+                interval_ms: 10_000,
+                width: 320,
+                ffmpeg: "ffmpeg".into(),
+            },
+        )
+        .unwrap();
+
+        let (port, mut sink) = start_sink().await;
+        let mailer = Arc::new(
+            Mailer::new(
+                config(port, vec![recipient("me@example.com", &[], &[])]),
+                Arc::new(Snapshots::new("ffmpeg".into())),
+                Some(Arc::new(thumbnails)),
+            )
+            .unwrap(),
+        );
+
+        mailer.dispatch(&alert(1, AlertKind::Motion, CAMERA, 12_000));
+        let mail = next(&mut sink).await;
+        assert!(mail.data.contains("Content-Type: multipart/mixed"));
+        assert!(mail.data.contains("Content-Type: image/jpeg"));
+        assert!(mail.data.contains("filename=\"snapshot.jpg\""));
+
+        // Nothing stored near this one: no attachment
+        mailer.dispatch(&alert(2, AlertKind::Motion, CAMERA, 60_000));
+        let mail = next(&mut sink).await;
+        assert!(!mail.data.contains("image/jpeg"));
+    }
+}
@@ -0,0 +1,54 @@
+//! Notifiers: deliver [`AlertEvent`](crate::alerts::AlertEvent)s to systems
+//! outside the app.
+
+pub mod email;
+pub mod mqtt;
+pub mod webhook;
+
+pub use email::Mailer;
+pub use mqtt::MqttBridge;
+pub use webhook::Webhooks;
+
+use crate::snapshot::{SnapshotError, Snapshots};
+use crate::storage::Thumbnails;
+
+/// JPEG quality of snapshots sent out, as the snapshot endpoint's default.
+const SNAPSHOT_QUALITY: u8 = 80;
+
+/// Enough of a camera ID to tell cameras apart in subjects and device names.
+fn short_id(camera_id: &str) -> &str {
+    camera_id.get(..8).unwrap_or(camera_id)
+}
+
+/// A JPEG of `camera_id`: a live snapshot from its latest keyframe, or
+/// else the recording thumbnail nearest `at_ms`, which needs recording and
+/// no encryption.
+async fn snapshot(
+    snapshots: &Snapshots,
+    thumbnails: Option<&Thumbnails>,
+    camera_id: &str,
+    at_ms: u64,
+) -> Option<Vec<u8>> {
+    let live = match snapshots.get(camera_id, None, SNAPSHOT_QUALITY).await {
+        Err(SnapshotError::RateLimited { retry_after }) => {
+            tokio::time::sleep(retry_after).await;
+            snapshots.get(camera_id, None, SNAPSHOT_QUALITY).await
+        }
+        result => result,
+    };
+    match live {
+        Ok(snapshot) => return Some(snapshot.jpeg.to_vec()),
+        Err(SnapshotError::Decode(e)) => {
+            tracing::debug!(camera = %camera_id, "Live snapshot failed: {:#}", e);
+        }
+        Err(_) => {}
+    }
+    let (_, path) = thumbnails?.nearest(camera_id, at_ms)?;
+    match tokio::fs::read(&path).await {
+        Ok(jpeg) => Some(jpeg),
+        Err(e) => {
+            tracing::debug!("Failed to read snapshot {:?}: {}", path, e);
+            None
+        }
+    }
+}
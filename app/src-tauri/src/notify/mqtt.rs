@@ -0,0 +1,947 @@
+//! MQTT bridge, for Home Assistant and other home automation.
+//!
+//! Topics, under the configured prefix `<p>`:
+//!
+//! | Topic | Payload |
+//! |-------|---------|
+//! | `<p>/status` | `online`/`offline` (retained, offline as last will) |
+//! | `<p>/mode` | arming mode, e.g. `away` (retained) |
+//! | `<p>/<camera>/availability` | `online`/`offline` (retained) |
+//! | `<p>/<camera>/telemetry` | JSON health readings, at most every [`TELEMETRY_INTERVAL`] |
+//! | `<p>/<camera>/gps` | JSON position, with the telemetry |
+//! | `<p>/<camera>/motion` | `ON`/`OFF` against the camera's motion threshold (retained) |
+//! | `<p>/<camera>/alert` | JSON [`Alert`] |
+//! | `<p>/<camera>/armed` | `ON`/`OFF` (retained) |
+//! | `<p>/<camera>/snapshot` | JPEG of the latest keyframe, or thumbnail (retained) |
+//!
+//! Publishing `ON`/`OFF` to `<p>/<camera>/armed/set` arms or disarms the
+//! camera's alerts, and anything to `<p>/<camera>/snapshot/set` publishes a
+//! fresh snapshot. Publishing a mode name to `<p>/mode/set` switches the
+//! arming mode.
+//!
+//! Each camera is announced through Home Assistant MQTT discovery when it
+//! is first seen, and again whenever the broker connection is
+//! re-established, so the entities survive a Home Assistant restart.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use kodama::capture::TelemetryData;
+use kodama::server::RouterHandle;
+use kodama::Frame;
+use rumqttc::{AsyncClient, Event, EventLoop, LastWill, MqttOptions, Packet, QoS};
+use serde::Serialize;
+use serde_json::json;
+use tokio::sync::{broadcast, watch};
+
+use crate::alerts::{self, Alert, AlertEngine, AlertEvent};
+use crate::config::{ArmingMode, MqttConfig};
+use crate::snapshot::Snapshots;
+use crate::storage::now_ms;
+use crate::storage::Thumbnails;
+
+use super::short_id;
+
+/// Minimum gap between a camera's telemetry publishes.
+pub const TELEMETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+
+/// Wait before reconnecting after the connection drops.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Publishes queued while the connection is down, before new ones are
+/// dropped.
+const QUEUE: usize = 256;
+
+/// Large enough for a snapshot.
+const MAX_PACKET: usize = 4 * 1024 * 1024;
+
+const ONLINE: &str = "online";
+const OFFLINE: &str = "offline";
+
+#[derive(Debug, Default)]
+struct CameraState {
+    online: bool,
+    motion: Option<bool>,
+    last_telemetry_ms: Option<u64>,
+}
+
+/// Telemetry as published, flattened for Home Assistant templates.
+#[derive(Debug, Serialize)]
+struct TelemetryState {
+    cpu_usage: f32,
+    cpu_temp: Option<f32>,
+    memory_usage: f32,
+    disk_usage: f32,
+    /// One-minute load average
+    load_average: f32,
+    motion_level: Option<f32>,
+    uptime_secs: u64,
+}
+
+/// Publishes camera state and alerts to a broker and handles its commands.
+pub struct MqttBridge {
+    config: MqttConfig,
+    client: AsyncClient,
+    engine: Arc<AlertEngine>,
+    snapshots: Arc<Snapshots>,
+    thumbnails: Option<Arc<Thumbnails>>,
+    cameras: Mutex<HashMap<String, CameraState>>,
+}
+
+impl MqttBridge {
+    /// Create the bridge and the event loop that drives its connection.
+    /// Nothing is sent until the event loop is polled, as [`spawn`] does.
+    pub fn new(
+        config: MqttConfig,
+        engine: Arc<AlertEngine>,
+        snapshots: Arc<Snapshots>,
+        thumbnails: Option<Arc<Thumbnails>>,
+    ) -> (Arc<Self>, EventLoop) {
+        let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+        options
+            .set_keep_alive(KEEP_ALIVE)
+            .set_max_packet_size(MAX_PACKET, MAX_PACKET)
+            .set_last_will(LastWill::new(
+                format!("{}/status", config.topic_prefix),
+                OFFLINE,
+                QoS::AtLeastOnce,
+                true,
+            ));
+        if let Some(username) = &config.username {
+            options.set_credentials(username, config.password.as_deref().unwrap_or_default());
+        }
+        let (client, eventloop) = AsyncClient::new(options, QUEUE);
+        let bridge = Arc::new(Self {
+            config,
+            client,
+            engine,
+            snapshots,
+            thumbnails,
+            cameras: Mutex::new(HashMap::new()),
+        });
+        (bridge, eventloop)
+    }
+
+    fn topic(&self, camera_id: &str, name: &str) -> String {
+        format!("{}/{}/{}", self.config.topic_prefix, camera_id, name)
+    }
+
+    fn publish(&self, topic: String, retain: bool, payload: impl Into<Vec<u8>>) {
+        if let Err(e) = self
+            .client
+            .try_publish(&topic, QoS::AtLeastOnce, retain, payload)
+        {
+            tracing::debug!("Dropped MQTT publish to {}: {}", topic, e);
+        }
+    }
+
+    /// Announce the app and every known camera, and subscribe to commands.
+    /// Called on each (re)connect.
+    pub fn on_connected(&self) {
+        let prefix = &self.config.topic_prefix;
+        self.publish(format!("{}/status", prefix), true, ONLINE);
+        let mut filters: Vec<String> = ["armed/set", "snapshot/set"]
+            .iter()
+            .map(|command| format!("{}/+/{}", prefix, command))
+            .collect();
+        if let Some(modes) = self.engine.modes() {
+            filters.push(format!("{}/mode/set", prefix));
+            self.discover_mode();
+            self.publish(format!("{}/mode", prefix), true, modes.mode().as_str());
+        }
+        for filter in filters {
+            if let Err(e) = self.client.try_subscribe(&filter, QoS::AtLeastOnce) {
+                tracing::warn!("Failed to subscribe to {}: {}", filter, e);
+            }
+        }
+
+        let cameras: Vec<(String, bool)> = self
+            .cameras
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, state)| (id.clone(), state.online))
+            .collect();
+        for (camera_id, online) in cameras {
+            self.announce(&camera_id, online);
+        }
+    }
+
+    /// Track `camera_id`, announcing it if it's new or coming back online.
+    fn camera_online(&self, camera_id: &str) {
+        let announce = {
+            let mut cameras = self.cameras.lock().unwrap();
+            let state = cameras.entry(camera_id.to_string()).or_default();
+            !std::mem::replace(&mut state.online, true)
+        };
+        if announce {
+            self.announce(camera_id, true);
+        }
+    }
+
+    /// Publish a camera's discovery configs, availability and armed state.
+    fn announce(&self, camera_id: &str, online: bool) {
+        self.discover(camera_id);
+        self.publish(
+            self.topic(camera_id, "availability"),
+            true,
+            if online { ONLINE } else { OFFLINE },
+        );
+        self.publish_armed(camera_id);
+    }
+
+    fn publish_armed(&self, camera_id: &str) {
+        let armed = self.engine.is_armed(camera_id);
+        self.publish(self.topic(camera_id, "armed"), true, on_off(armed));
+    }
+
+    /// Publish a new arming mode, and the armed state it gives each camera.
+    pub fn on_mode(&self, mode: ArmingMode) {
+        let prefix = &self.config.topic_prefix;
+        self.publish(format!("{}/mode", prefix), true, mode.as_str());
+        let cameras: Vec<String> = self.cameras.lock().unwrap().keys().cloned().collect();
+        for camera_id in cameras {
+            self.publish_armed(&camera_id);
+        }
+    }
+
+    /// Home Assistant discovery config for the arming mode, on a device of
+    /// its own.
+    fn discover_mode(&self) {
+        if self.config.discovery_prefix.is_empty() {
+            return;
+        }
+        let prefix = &self.config.topic_prefix;
+        let config = json!({
+            "name": "Arming mode",
+            "unique_id": "kodama_mode",
+            "object_id": "kodama_mode",
+            "state_topic": format!("{}/mode", prefix),
+            "command_topic": format!("{}/mode/set", prefix),
+            "options": ArmingMode::ALL.map(ArmingMode::as_str),
+            "availability_topic": format!("{}/status", prefix),
+            "device": {
+                "identifiers": ["kodama"],
+                "name": "Kodama",
+                "manufacturer": "Kodama",
+            },
+        });
+        let topic = format!("{}/select/kodama/mode/config", self.config.discovery_prefix);
+        self.publish(topic, true, config.to_string());
+    }
+
+    /// Home Assistant discovery configs for a camera's entities.
+    fn discover(&self, camera_id: &str) {
+        if self.config.discovery_prefix.is_empty() {
+            return;
+        }
+        for (component, object, mut config) in self.entities(camera_id) {
+            let object_id = format!("kodama_{}_{}", camera_id, object);
+            config["unique_id"] = json!(object_id);
+            config["object_id"] = json!(object_id);
+            config["device"] = json!({
+                "identifiers": [format!("kodama_{}", camera_id)],
+                "name": format!("Kodama {}", short_id(camera_id)),
+                "manufacturer": "Kodama",
+            });
+            config["availability"] = json!([
+                { "topic": format!("{}/status", self.config.topic_prefix) },
+                { "topic": self.topic(camera_id, "availability") },
+            ]);
+            config["availability_mode"] = json!("all");
+            let topic = format!(
+                "{}/{}/kodama_{}/{}/config",
+                self.config.discovery_prefix, component, camera_id, object
+            );
+            self.publish(topic, true, config.to_string());
+        }
+    }
+
+    fn entities(&self, camera_id: &str) -> Vec<(&'static str, &'static str, serde_json::Value)> {
+        let telemetry = self.topic(camera_id, "telemetry");
+        let sensor = |name: &str, field: &str, unit: Option<&str>, class: Option<&str>| {
+            let mut config = json!({
+                "name": name,
+                "state_topic": telemetry,
+                "value_template": format!("{{{{ value_json.{} }}}}", field),
+                "state_class": "measurement",
+            });
+            if let Some(unit) = unit {
+                config["unit_of_measurement"] = json!(unit);
+            }
+            if let Some(class) = class {
+                config["device_class"] = json!(class);
+            }
+            config
+        };
+
+        vec![
+            (
+                "binary_sensor",
+                "motion",
+                json!({
+                    "name": "Motion",
+                    "device_class": "motion",
+                    "state_topic": self.topic(camera_id, "motion"),
+                }),
+            ),
+            (
+                "sensor",
+                "cpu_temp",
+                sensor(
+                    "CPU temperature",
+                    "cpu_temp",
+                    Some("°C"),
+                    Some("temperature"),
+                ),
+            ),
+            (
+                "sensor",
+                "cpu_usage",
+                sensor("CPU usage", "cpu_usage", Some("%"), None),
+            ),
+            (
+                "sensor",
+                "memory_usage",
+                sensor("Memory usage", "memory_usage", Some("%"), None),
+            ),
+            (
+                "sensor",
+                "disk_usage",
+                sensor("Disk usage", "disk_usage", Some("%"), None),
+            ),
+            (
+                "sensor",
+                "load_average",
+                sensor("Load average", "load_average", None, None),
+            ),
+            (
+                "sensor",
+                "last_alert",
+                json!({
+                    "name": "Last alert",
+                    "state_topic": self.topic(camera_id, "alert"),
+                    "value_template": "{{ value_json.message }}",
+                    "json_attributes_topic": self.topic(camera_id, "alert"),
+                }),
+            ),
+            (
+                "device_tracker",
+                "gps",
+                json!({
+                    "name": "Location",
+                    "source_type": "gps",
+                    "json_attributes_topic": self.topic(camera_id, "gps"),
+                }),
+            ),
+            (
+                "switch",
+                "armed",
+                json!({
+                    "name": "Armed",
+                    "state_topic": self.topic(camera_id, "armed"),
+                    "command_topic": self.topic(camera_id, "armed/set"),
+                }),
+            ),
+            (
+                "button",
+                "snapshot",
+                json!({
+                    "name": "Take snapshot",
+                    "command_topic": self.topic(camera_id, "snapshot/set"),
+                }),
+            ),
+            (
+                "camera",
+                "snapshot",
+                json!({
+                    "name": "Snapshot",
+                    "topic": self.topic(camera_id, "snapshot"),
+                }),
+            ),
+        ]
+    }
+
+    /// Publish what an alert engine event changes.
+    pub fn on_event(&self, event: &AlertEvent) {
+        match event {
+            AlertEvent::Alert { alert } => self.publish_alert(alert),
+            AlertEvent::CameraConnected { camera_id, .. } => self.camera_online(camera_id),
+            AlertEvent::CameraDisconnected { camera_id, .. } => {
+                if let Some(state) = self.cameras.lock().unwrap().get_mut(camera_id) {
+                    state.online = false;
+                }
+                self.publish(self.topic(camera_id, "availability"), true, OFFLINE);
+            }
+        }
+    }
+
+    fn publish_alert(&self, alert: &Alert) {
+        match serde_json::to_string(alert) {
+            Ok(json) => self.publish(self.topic(&alert.camera_id, "alert"), false, json),
+            Err(e) => tracing::warn!("Failed to serialize alert: {}", e),
+        }
+    }
+
+    /// Publish a camera's telemetry reading, received at `now_ms`.
+    pub fn on_telemetry(&self, camera_id: &str, telemetry: &TelemetryData, now_ms: u64) {
+        self.camera_online(camera_id);
+
+        let (motion, due) = {
+            let mut cameras = self.cameras.lock().unwrap();
+            let state = cameras.entry(camera_id.to_string()).or_default();
+
+            let motion = telemetry.motion_level.and_then(|level| {
+                let on = level >= self.engine.rules(camera_id).motion.threshold;
+                (state.motion.replace(on) != Some(on)).then_some(on)
+            });
+            let interval = TELEMETRY_INTERVAL.as_millis() as u64;
+            let due = state
+                .last_telemetry_ms
+                .is_none_or(|last| now_ms >= last + interval);
+            if due {
+                state.last_telemetry_ms = Some(now_ms);
+            }
+            (motion, due)
+        };
+
+        if let Some(on) = motion {
+            self.publish(self.topic(camera_id, "motion"), true, on_off(on));
+        }
+        if !due {
+            return;
+        }
+        let state = TelemetryState {
+            cpu_usage: telemetry.cpu_usage,
+            cpu_temp: telemetry.cpu_temp,
+            memory_usage: telemetry.memory_usage,
+            disk_usage: telemetry.disk_usage,
+            load_average: telemetry.load_average[0],
+            motion_level: telemetry.motion_level,
+            uptime_secs: telemetry.uptime_secs,
+        };
+        if let Ok(json) = serde_json::to_string(&state) {
+            self.publish(self.topic(camera_id, "telemetry"), false, json);
+        }
+        if let Some(gps) = &telemetry.gps {
+            let json = json!({
+                "latitude": gps.latitude,
+                "longitude": gps.longitude,
+                "altitude": gps.altitude,
+                "speed": gps.speed,
+                "heading": gps.heading,
+            });
+            self.publish(self.topic(camera_id, "gps"), false, json.to_string());
+        }
+    }
+
+    /// Handle a message on one of the command topics.
+    pub async fn on_command(&self, topic: &str, payload: &[u8]) {
+        let Some(rest) = topic
+            .strip_prefix(self.config.topic_prefix.as_str())
+            .and_then(|rest| rest.strip_prefix('/'))
+        else {
+            return;
+        };
+        if rest == "mode/set" {
+            self.set_mode(payload);
+            return;
+        }
+        let Some((camera_id, command)) = rest.split_once('/') else {
+            return;
+        };
+
+        match command {
+            "armed/set" => {
+                let armed = match payload.trim_ascii() {
+                    b"ON" => true,
+                    b"OFF" => false,
+                    other => {
+                        tracing::warn!(
+                            camera = %camera_id,
+                            "Ignoring arm command {:?}",
+                            String::from_utf8_lossy(other)
+                        );
+                        return;
+                    }
+                };
+                tracing::info!(camera = %camera_id, "Camera {} over MQTT", if armed { "armed" } else { "disarmed" });
+                self.engine.set_armed(camera_id, armed);
+                self.publish_armed(camera_id);
+            }
+            "snapshot/set" => self.publish_snapshot(camera_id).await,
+            _ => {}
+        }
+    }
+
+    /// Switch the arming mode; the change is published by [`run`], as for
+    /// changes made any other way.
+    fn set_mode(&self, payload: &[u8]) {
+        let Some(modes) = self.engine.modes() else {
+            return;
+        };
+        let name = String::from_utf8_lossy(payload.trim_ascii());
+        let Some(mode) = ArmingMode::ALL.into_iter().find(|m| m.as_str() == name) else {
+            tracing::warn!("Ignoring mode command {:?}", name);
+            return;
+        };
+        tracing::info!("Mode set to {} over MQTT", name);
+        if let Err(e) = modes.set(mode, now_ms()) {
+            tracing::warn!("Failed to save mode: {}", e);
+        }
+    }
+
+    async fn publish_snapshot(&self, camera_id: &str) {
+        let thumbnails = self.thumbnails.as_deref();
+        match super::snapshot(&self.snapshots, thumbnails, camera_id, now_ms()).await {
+            Some(jpeg) => self.publish(self.topic(camera_id, "snapshot"), true, jpeg),
+            None => tracing::info!(camera = %camera_id, "No snapshot for MQTT"),
+        }
+    }
+}
+
+fn on_off(on: bool) -> &'static str {
+    if on {
+        "ON"
+    } else {
+        "OFF"
+    }
+}
+
+/// Run the bridge on the Router broadcast and the engine's events.
+pub fn spawn(
+    bridge: Arc<MqttBridge>,
+    eventloop: EventLoop,
+    handle: RouterHandle,
+    events: broadcast::Receiver<AlertEvent>,
+) {
+    tokio::spawn(run(bridge, eventloop, handle.subscribe(), events));
+}
+
+/// Resolves with the next mode change; never without modes.
+async fn mode_changed(modes: &mut Option<watch::Receiver<ArmingMode>>) -> Option<ArmingMode> {
+    match modes {
+        Some(rx) => {
+            rx.changed().await.ok()?;
+            Some(*rx.borrow_and_update())
+        }
+        None => std::future::pending().await,
+    }
+}
+
+async fn run(
+    bridge: Arc<MqttBridge>,
+    mut eventloop: EventLoop,
+    mut frames: broadcast::Receiver<Frame>,
+    mut events: broadcast::Receiver<AlertEvent>,
+) {
+    let mut modes = bridge.engine.modes().map(|modes| modes.subscribe());
+    let mut connected = false;
+    loop {
+        tokio::select! {
+            event = eventloop.poll() => match event {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    tracing::info!(
+                        "Connected to MQTT broker {}:{}",
+                        bridge.config.host,
+                        bridge.config.port
+                    );
+                    connected = true;
+                    bridge.on_connected();
+                }
+                Ok(Event::Incoming(Packet::Publish(publish))) => {
+                    bridge.on_command(&publish.topic, &publish.payload).await;
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    // Polling again reconnects
+                    if std::mem::take(&mut connected) {
+                        tracing::warn!("MQTT connection lost: {}", e);
+                    } else {
+                        tracing::debug!("MQTT connection failed: {}", e);
+                    }
+                    tokio::time::sleep(RECONNECT_DELAY).await;
+                }
+            },
+            frame = frames.recv() => match frame {
+                Ok(frame) => {
+                    if let Some(telemetry) = alerts::telemetry(&frame) {
+                        bridge.on_telemetry(&frame.source.to_string(), &telemetry, now_ms());
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    tracing::warn!("MQTT bridge lagged, missed {} frames", n);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            event = events.recv() => match event {
+                Ok(event) => bridge.on_event(&event),
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    tracing::warn!("MQTT bridge lagged, missed {} events", n);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            Some(mode) = mode_changed(&mut modes) => bridge.on_mode(mode),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bytes::BytesMut;
+    use kodama::capture::GpsData;
+    use rumqttc::SubscribeReasonCode;
+    use rumqttc::{ConnAck, ConnectReturnCode, PingResp, PubAck, Publish, SubAck};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::sync::mpsc;
+
+    use crate::alerts::{test_telemetry, AlertHistory, AlertKind};
+    use crate::config::AlertsConfig;
+    use crate::events::EventBus;
+    use crate::modes::Modes;
+    use crate::storage::ThumbnailConfig;
+
+    const CAMERA: &str = "0102030405060708";
+
+    /// Local stand-in for a broker with a single client. Records the
+    /// client's publishes and subscriptions, and delivers publishes sent on
+    /// `inject` to it.
+    struct Broker {
+        port: u16,
+        published: mpsc::UnboundedReceiver<Publish>,
+        subscribed: Arc<Mutex<Vec<String>>>,
+        inject: mpsc::UnboundedSender<Publish>,
+    }
+
+    async fn start_broker() -> Broker {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (published_tx, published) = mpsc::unbounded_channel();
+        let (inject, mut injected) = mpsc::unbounded_channel::<Publish>();
+        let subscribed = Arc::new(Mutex::new(Vec::new()));
+        let subscriptions = subscribed.clone();
+
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = BytesMut::new();
+            loop {
+                let mut out = BytesMut::new();
+                while let Ok(packet) = rumqttc::mqttbytes::v4::read(&mut buf, MAX_PACKET) {
+                    match packet {
+                        Packet::Connect(_) => {
+                            ConnAck::new(ConnectReturnCode::Success, false).write(&mut out)
+                        }
+                        Packet::Subscribe(subscribe) => {
+                            let codes = subscribe
+                                .filters
+                                .iter()
+                                .map(|f| SubscribeReasonCode::Success(f.qos))
+                                .collect();
+                            subscriptions
+                                .lock()
+                                .unwrap()
+                                .extend(subscribe.filters.into_iter().map(|f| f.path));
+                            SubAck::new(subscribe.pkid, codes).write(&mut out)
+                        }
+                        Packet::Publish(publish) => {
+                            let ack = PubAck::new(publish.pkid).write(&mut out);
+                            let _ = published_tx.send(publish);
+                            ack
+                        }
+                        Packet::PingReq => PingResp.write(&mut out),
+                        _ => Ok(0),
+                    }
+                    .unwrap();
+                }
+                socket.write_all(&out).await.unwrap();
+
+                tokio::select! {
+                    read = socket.read_buf(&mut buf) => {
+                        if read.unwrap_or(0) == 0 {
+                            return;
+                        }
+                    }
+                    Some(publish) = injected.recv() => {
+                        let mut out = BytesMut::new();
+                        publish.write(&mut out).unwrap();
+                        socket.write_all(&out).await.unwrap();
+                    }
+                }
+            }
+        });
+
+        Broker {
+            port,
+            published,
+            subscribed,
+            inject,
+        }
+    }
+
+    impl Broker {
+        /// Publishes up to and including the first on `topic`.
+        async fn until(&mut self, topic: &str) -> Vec<Publish> {
+            let mut seen = Vec::new();
+            loop {
+                let publish = tokio::time::timeout(Duration::from_secs(5), self.published.recv())
+                    .await
+                    .unwrap_or_else(|_| panic!("nothing published to {}", topic))
+                    .unwrap();
+                let done = publish.topic == topic;
+                seen.push(publish);
+                if done {
+                    return seen;
+                }
+            }
+        }
+    }
+
+    struct Harness {
+        bridge: Arc<MqttBridge>,
+        engine: Arc<AlertEngine>,
+        broker: Broker,
+        _frames: broadcast::Sender<Frame>,
+        _dir: tempfile::TempDir,
+    }
+
+    async fn start(thumbnails: Option<Arc<Thumbnails>>) -> Harness {
+        start_with(Arc::new(Snapshots::new("ffmpeg".into())), thumbnails).await
+    }
+
+    async fn start_with(snapshots: Arc<Snapshots>, thumbnails: Option<Arc<Thumbnails>>) -> Harness {
+        let broker = start_broker().await;
+        let dir = tempfile::tempdir().unwrap();
+        let history = AlertHistory::open(dir.path().join("alerts.jsonl")).unwrap();
+        let mut engine = AlertEngine::new(AlertsConfig::default(), history, EventBus::default());
+        let modes = Modes::open(
+            Default::default(),
+            dir.path().join("mode.json"),
+            EventBus::default(),
+        )
+        .unwrap();
+        engine.set_modes(Arc::new(modes));
+        let engine = Arc::new(engine);
+        let config = MqttConfig {
+            host: "127.0.0.1".into(),
+            port: broker.port,
+            ..MqttConfig::default()
+        };
+        let (bridge, eventloop) = MqttBridge::new(config, engine.clone(), snapshots, thumbnails);
+        // Frames come in through `on_telemetry` directly
+        let (frames_tx, frames) = broadcast::channel(1);
+        tokio::spawn(run(bridge.clone(), eventloop, frames, engine.subscribe()));
+        Harness {
+            bridge,
+            engine,
+            broker,
+            _frames: frames_tx,
+            _dir: dir,
+        }
+    }
+
+    fn payload(publishes: &[Publish], topic: &str) -> String {
+        let publish = publishes
+            .iter()
+            .rev()
+            .find(|p| p.topic == topic)
+            .unwrap_or_else(|| panic!("nothing published to {}", topic));
+        String::from_utf8(publish.payload.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn publishes_state_and_discovery() {
+        let mut h = start(None).await;
+        let status = h.broker.until("kodama/status").await;
+        assert_eq!(payload(&status, "kodama/status"), "online");
+        assert!(status[0].retain);
+        let seen = h.broker.until("kodama/mode").await;
+        assert_eq!(payload(&seen, "kodama/mode"), "away");
+        let select: serde_json::Value = serde_json::from_str(&payload(
+            &seen,
+            "homeassistant/select/kodama/mode/config",
+        ))
+        .unwrap();
+        assert_eq!(select["command_topic"], "kodama/mode/set");
+        assert_eq!(select["options"][3], "disarmed");
+
+        let telemetry = TelemetryData {
+            gps: Some(GpsData {
+                latitude: 35.0,
+                longitude: 139.0,
+                altitude: None,
+                speed: None,
+                heading: None,
+                fix_mode: 3,
+            }),
+            ..test_telemetry(Some(0.9))
+        };
+        h.bridge.on_telemetry(CAMERA, &telemetry, 0);
+        let seen = h.broker.until(&format!("kodama/{}/gps", CAMERA)).await;
+
+        let motion = format!(
+            "homeassistant/binary_sensor/kodama_{}/motion/config",
+            CAMERA
+        );
+        let discovery: serde_json::Value = serde_json::from_str(&payload(&seen, &motion)).unwrap();
+        assert_eq!(
+            discovery["state_topic"],
+            format!("kodama/{}/motion", CAMERA)
+        );
+        assert_eq!(discovery["availability_mode"], "all");
+        assert_eq!(
+            discovery["device"]["identifiers"][0],
+            format!("kodama_{}", CAMERA)
+        );
+        assert_eq!(
+            payload(&seen, &format!("kodama/{}/availability", CAMERA)),
+            "online"
+        );
+        assert_eq!(payload(&seen, &format!("kodama/{}/armed", CAMERA)), "ON");
+        assert_eq!(payload(&seen, &format!("kodama/{}/motion", CAMERA)), "ON");
+        let state: serde_json::Value =
+            serde_json::from_str(&payload(&seen, &format!("kodama/{}/telemetry", CAMERA))).unwrap();
+        assert_eq!(state["cpu_temp"], 50.0);
+        assert_eq!(state["disk_usage"], 40.0);
+
+        // Motion is published on change, telemetry at most every interval
+        h.bridge
+            .on_telemetry(CAMERA, &test_telemetry(Some(0.95)), 1_000);
+        h.bridge
+            .on_telemetry(CAMERA, &test_telemetry(Some(0.1)), 2_000);
+        h.bridge.on_telemetry(CAMERA, &test_telemetry(None), 10_000);
+        let seen = h
+            .broker
+            .until(&format!("kodama/{}/telemetry", CAMERA))
+            .await;
+        let topics: Vec<&str> = seen.iter().map(|p| p.topic.as_str()).collect();
+        assert_eq!(
+            topics,
+            vec![
+                format!("kodama/{}/motion", CAMERA),
+                format!("kodama/{}/telemetry", CAMERA)
+            ]
+        );
+        assert_eq!(payload(&seen, &format!("kodama/{}/motion", CAMERA)), "OFF");
+
+        h.bridge.on_event(&AlertEvent::Alert {
+            alert: Alert {
+                id: 3,
+                kind: AlertKind::Motion,
+                camera_id: CAMERA.into(),
+                message: "Motion detected (90%)".into(),
+                time_ms: 1_000,
+            },
+        });
+        h.bridge.on_event(&AlertEvent::CameraDisconnected {
+            camera_id: CAMERA.into(),
+            time_ms: 20_000,
+        });
+        let seen = h
+            .broker
+            .until(&format!("kodama/{}/availability", CAMERA))
+            .await;
+        let alert: serde_json::Value =
+            serde_json::from_str(&payload(&seen, &format!("kodama/{}/alert", CAMERA))).unwrap();
+        assert_eq!(alert["type"], "motion");
+        assert_eq!(alert["message"], "Motion detected (90%)");
+        assert_eq!(
+            payload(&seen, &format!("kodama/{}/availability", CAMERA)),
+            "offline"
+        );
+    }
+
+    #[tokio::test]
+    async fn commands() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join(CAMERA)).unwrap();
+        let taken = now_ms();
+        std::fs::write(
+            dir.path().join(CAMERA).join(format!("{}.jpg", taken)),
+            b"jpeg",
+        )
+        .unwrap();
+        let thumbnails = Thumbnails::open(
+            dir.path(),
+            ThumbnailConfig {
+                interval_ms: 60_000,
+                width: 320,
+                ffmpeg: "ffmpeg".into(),
+            },
+        )
+        .unwrap();
+
+        let mut h = start(Some(Arc::new(thumbnails))).await;
+        h.broker.until("kodama/status").await;
+        let commands = [
+            "kodama/+/armed/set",
+            "kodama/+/snapshot/set",
+            "kodama/mode/set",
+        ];
+        for _ in 0..50 {
+            if h.broker.subscribed.lock().unwrap().len() == commands.len() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(*h.broker.subscribed.lock().unwrap(), commands);
+
+        let inject = h.broker.inject.clone();
+        let send = |topic: String, payload: &str| {
+            inject
+                .send(Publish::new(topic, QoS::AtMostOnce, payload))
+                .unwrap();
+        };
+        send(format!("kodama/{}/armed/set", CAMERA), "OFF");
+        let seen = h.broker.until(&format!("kodama/{}/armed", CAMERA)).await;
+        assert_eq!(payload(&seen, &format!("kodama/{}/armed", CAMERA)), "OFF");
+        assert!(!h.engine.is_armed(CAMERA));
+
+        send(format!("kodama/{}/snapshot/set", CAMERA), "PRESS");
+        let seen = h.broker.until(&format!("kodama/{}/snapshot", CAMERA)).await;
+        assert_eq!(
+            payload(&seen, &format!("kodama/{}/snapshot", CAMERA)),
+            "jpeg"
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn live_snapshot_without_recording() {
+        use crate::snapshot::tests::{annexb, fake_ffmpeg, keyframe, IDR, PPS, SPS};
+
+        let dir = tempfile::tempdir().unwrap();
+        let snapshots = Snapshots::new(fake_ffmpeg(dir.path()));
+        let frame = keyframe(annexb(&[SPS, PPS, IDR]));
+        snapshots.observe(&frame, now_ms());
+        let camera_id = frame.source.to_string();
+
+        let mut h = start_with(Arc::new(snapshots), None).await;
+        h.broker.until("kodama/status").await;
+        h.bridge.publish_snapshot(&camera_id).await;
+        let topic = format!("kodama/{}/snapshot", camera_id);
+        let seen = h.broker.until(&topic).await;
+        let publish = seen.iter().rev().find(|p| p.topic == topic).unwrap();
+        assert!(publish.retain);
+        assert!(publish.payload.starts_with(&annexb(&[SPS, PPS, IDR])));
+    }
+
+    #[tokio::test]
+    async fn mode_command_updates_armed_state() {
+        let mut h = start(None).await;
+        h.broker.until("kodama/mode").await;
+        h.bridge.on_telemetry(CAMERA, &test_telemetry(None), 0);
+        h.broker.until(&format!("kodama/{}/armed", CAMERA)).await;
+
+        h.bridge.on_command("kodama/mode/set", b"party").await;
+        h.bridge.on_command("kodama/mode/set", b"home").await;
+        let seen = h.broker.until(&format!("kodama/{}/armed", CAMERA)).await;
+        assert_eq!(payload(&seen, "kodama/mode"), "home");
+        assert_eq!(payload(&seen, &format!("kodama/{}/armed", CAMERA)), "OFF");
+        assert!(!h.engine.is_armed(CAMERA));
+    }
+}
@@ -0,0 +1,460 @@
+//! Outgoing webhooks.
+//!
+//! Each [`AlertEvent`] is POSTed as JSON to every webhook whose filters
+//! match, with its name in `X-Kodama-Event` and a delivery ID in
+//! `X-Kodama-Delivery`. Webhooks with a secret also get
+//! `X-Kodama-Signature: sha256=<hex>`, an HMAC-SHA256 of the body.
+//!
+//! Network errors, 429 and 5xx responses are retried with exponential
+//! backoff, up to [`MAX_ATTEMPTS`]. The newest [`MAX_DELIVERIES`] deliveries
+//! are kept in memory for `/api/webhooks/deliveries`.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Context;
+use bytes::Bytes;
+use hmac::{Hmac, Mac};
+use reqwest::header::CONTENT_TYPE;
+use reqwest::StatusCode;
+use serde::Serialize;
+use sha2::Sha256;
+use tokio::sync::broadcast;
+
+use crate::alerts::AlertEvent;
+use crate::config::WebhookConfig;
+use crate::storage::now_ms;
+
+/// Attempts per delivery, including the first.
+pub const MAX_ATTEMPTS: u32 = 5;
+
+/// Deliveries kept in the log.
+pub const MAX_DELIVERIES: usize = 200;
+
+/// Wait before the first retry, doubling after each.
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+const EVENT_HEADER: &str = "x-kodama-event";
+const DELIVERY_HEADER: &str = "x-kodama-delivery";
+const SIGNATURE_HEADER: &str = "x-kodama-signature";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryStatus {
+    /// Not yet accepted; retries may be pending.
+    Pending,
+    Delivered,
+    Failed,
+}
+
+/// One event sent to one webhook.
+#[derive(Debug, Clone, Serialize)]
+pub struct Delivery {
+    pub id: u64,
+    pub url: String,
+    pub event: String,
+    pub camera_id: String,
+    pub status: DeliveryStatus,
+    pub attempts: u32,
+    /// HTTP status of the last attempt, if it got a response.
+    pub response_status: Option<u16>,
+    /// Why the last attempt failed.
+    pub error: Option<String>,
+    /// When the event was dispatched (epoch ms)
+    #[serde(rename = "time")]
+    pub time_ms: u64,
+}
+
+/// The configured webhooks and their delivery log.
+pub struct Webhooks {
+    http: reqwest::Client,
+    hooks: Vec<WebhookConfig>,
+    log: Mutex<VecDeque<Delivery>>,
+    next_id: AtomicU64,
+    retry_delay: Duration,
+}
+
+impl Webhooks {
+    pub fn new(hooks: Vec<WebhookConfig>) -> anyhow::Result<Self> {
+        for hook in &hooks {
+            reqwest::Url::parse(&hook.url)
+                .with_context(|| format!("Invalid webhook URL {}", hook.url))?;
+        }
+        Ok(Self {
+            http: reqwest::Client::builder().timeout(TIMEOUT).build()?,
+            hooks,
+            log: Mutex::new(VecDeque::new()),
+            next_id: AtomicU64::new(1),
+            retry_delay: RETRY_DELAY,
+        })
+    }
+
+    /// Up to `limit` recent deliveries, newest first.
+    pub fn deliveries(&self, limit: usize) -> Vec<Delivery> {
+        self.log
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    /// Send `event` to every webhook whose filters match, each delivery in
+    /// its own task.
+    pub fn dispatch(self: &Arc<Self>, event: &AlertEvent) {
+        let json = match serde_json::to_value(event) {
+            Ok(json) => json,
+            Err(e) => {
+                tracing::warn!("Failed to serialize webhook event: {}", e);
+                return;
+            }
+        };
+        let name = json["event"].as_str().unwrap_or_default().to_string();
+        let alert_type = json["alert"]["type"].as_str();
+        let body = Bytes::from(json.to_string());
+
+        for (index, hook) in self.hooks.iter().enumerate() {
+            let wanted = hook.events.is_empty()
+                || hook
+                    .events
+                    .iter()
+                    .any(|e| *e == name || Some(e.as_str()) == alert_type);
+            let camera =
+                hook.cameras.is_empty() || hook.cameras.iter().any(|c| c == event.camera_id());
+            if !wanted || !camera {
+                continue;
+            }
+
+            let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+            {
+                let mut log = self.log.lock().unwrap();
+                log.push_back(Delivery {
+                    id,
+                    url: hook.url.clone(),
+                    event: name.clone(),
+                    camera_id: event.camera_id().to_string(),
+                    status: DeliveryStatus::Pending,
+                    attempts: 0,
+                    response_status: None,
+                    error: None,
+                    time_ms: now_ms(),
+                });
+                if log.len() > MAX_DELIVERIES {
+                    log.pop_front();
+                }
+            }
+            tokio::spawn(self.clone().deliver(index, id, name.clone(), body.clone()));
+        }
+    }
+
+    async fn deliver(self: Arc<Self>, index: usize, id: u64, event: String, body: Bytes) {
+        let hook = &self.hooks[index];
+        let mut delay = self.retry_delay;
+
+        for attempt in 1..=MAX_ATTEMPTS {
+            let (mut status, response, error) = match self.send(hook, id, &event, &body).await {
+                Ok(code) if code.is_success() => {
+                    (Some(DeliveryStatus::Delivered), Some(code), None)
+                }
+                Ok(code) if code == StatusCode::TOO_MANY_REQUESTS || code.is_server_error() => {
+                    (None, Some(code), Some(format!("HTTP {}", code)))
+                }
+                Ok(code) => (
+                    Some(DeliveryStatus::Failed),
+                    Some(code),
+                    Some(format!("HTTP {}", code)),
+                ),
+                Err(e) => (None, None, Some(format!("{:#}", e))),
+            };
+            if attempt == MAX_ATTEMPTS {
+                status.get_or_insert(DeliveryStatus::Failed);
+            }
+
+            self.update(id, |d| {
+                d.attempts = attempt;
+                d.response_status = response.map(|code| code.as_u16());
+                d.error = error.clone();
+                if let Some(status) = status {
+                    d.status = status;
+                }
+            });
+            match status {
+                Some(DeliveryStatus::Failed) => {
+                    tracing::warn!(
+                        url = %hook.url,
+                        delivery = id,
+                        "Webhook delivery failed after {} attempts: {}",
+                        attempt,
+                        error.unwrap_or_default()
+                    );
+                    return;
+                }
+                Some(_) => return,
+                None => {
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                }
+            }
+        }
+    }
+
+    async fn send(
+        &self,
+        hook: &WebhookConfig,
+        id: u64,
+        event: &str,
+        body: &Bytes,
+    ) -> anyhow::Result<StatusCode> {
+        let mut req = self
+            .http
+            .post(&hook.url)
+            .header(CONTENT_TYPE, "application/json")
+            .header(EVENT_HEADER, event)
+            .header(DELIVERY_HEADER, id.to_string())
+            .body(body.clone());
+        if let Some(secret) = &hook.secret {
+            req = req.header(SIGNATURE_HEADER, format!("sha256={}", sign(secret, body)));
+        }
+        Ok(req.send().await?.status())
+    }
+
+    fn update(&self, id: u64, f: impl FnOnce(&mut Delivery)) {
+        // Gone if the log has wrapped since
+        if let Some(delivery) = self.log.lock().unwrap().iter_mut().find(|d| d.id == id) {
+            f(delivery);
+        }
+    }
+}
+
+/// Hex HMAC-SHA256 of `body`.
+fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(body);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Dispatch the engine's events until it stops.
+pub fn spawn(webhooks: Arc<Webhooks>, mut rx: broadcast::Receiver<AlertEvent>) {
+    tokio::spawn(async move {
+        loop {
+            match rx.recv().await {
+                Ok(event) => webhooks.dispatch(&event),
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    tracing::warn!("Webhooks lagged, missed {} events", n);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::AtomicU32;
+
+    use axum::extract::State;
+    use axum::http::HeaderMap;
+
+    use crate::alerts::{Alert, AlertKind};
+
+    /// Local stand-in for a webhook receiver, failing the first
+    /// `fail_first` requests with `fail_with`.
+    struct Receiver {
+        received: Mutex<Vec<(HeaderMap, Bytes)>>,
+        fail_first: AtomicU32,
+        fail_with: StatusCode,
+    }
+
+    async fn start_receiver(fail_first: u32, fail_with: StatusCode) -> (Arc<Receiver>, String) {
+        let receiver = Arc::new(Receiver {
+            received: Mutex::new(Vec::new()),
+            fail_first: AtomicU32::new(fail_first),
+            fail_with,
+        });
+        let app = axum::Router::new()
+            .route("/hook", axum::routing::post(receive))
+            .with_state(receiver.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (receiver, url)
+    }
+
+    async fn receive(
+        State(receiver): State<Arc<Receiver>>,
+        headers: HeaderMap,
+        body: Bytes,
+    ) -> StatusCode {
+        receiver.received.lock().unwrap().push((headers, body));
+        let failing = receiver
+            .fail_first
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+            .is_ok();
+        if failing {
+            receiver.fail_with
+        } else {
+            StatusCode::NO_CONTENT
+        }
+    }
+
+    fn webhooks(hooks: Vec<WebhookConfig>) -> Arc<Webhooks> {
+        let mut webhooks = Webhooks::new(hooks).unwrap();
+        webhooks.retry_delay = Duration::from_millis(10);
+        Arc::new(webhooks)
+    }
+
+    fn hook(url: &str) -> WebhookConfig {
+        WebhookConfig {
+            url: url.into(),
+            secret: None,
+            events: Vec::new(),
+            cameras: Vec::new(),
+        }
+    }
+
+    fn alert(kind: AlertKind, camera_id: &str) -> AlertEvent {
+        AlertEvent::Alert {
+            alert: Alert {
+                id: 7,
+                kind,
+                camera_id: camera_id.into(),
+                message: "Motion detected (80%)".into(),
+                time_ms: 1_000,
+            },
+        }
+    }
+
+    /// Wait for every delivery to finish.
+    async fn settled(webhooks: &Webhooks) -> Vec<Delivery> {
+        for _ in 0..500 {
+            let deliveries = webhooks.deliveries(MAX_DELIVERIES);
+            if deliveries
+                .iter()
+                .all(|d| d.status != DeliveryStatus::Pending)
+            {
+                return deliveries;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("deliveries still pending");
+    }
+
+    #[tokio::test]
+    async fn signs_and_retries() {
+        let (receiver, url) = start_receiver(2, StatusCode::SERVICE_UNAVAILABLE).await;
+        let webhooks = webhooks(vec![WebhookConfig {
+            secret: Some("s3cret".into()),
+            ..hook(&url)
+        }]);
+
+        webhooks.dispatch(&alert(AlertKind::Motion, "cam"));
+        let deliveries = settled(&webhooks).await;
+        assert_eq!(deliveries.len(), 1);
+        assert_eq!(deliveries[0].status, DeliveryStatus::Delivered);
+        assert_eq!(deliveries[0].attempts, 3);
+        assert_eq!(deliveries[0].response_status, Some(204));
+        assert_eq!(deliveries[0].event, "alert");
+
+        let received = receiver.received.lock().unwrap();
+        assert_eq!(received.len(), 3);
+        let (headers, body) = &received[2];
+        assert_eq!(headers[EVENT_HEADER], "alert");
+        assert_eq!(
+            headers[DELIVERY_HEADER].to_str().unwrap(),
+            deliveries[0].id.to_string()
+        );
+        assert_eq!(
+            headers[SIGNATURE_HEADER].to_str().unwrap(),
+            format!("sha256={}", sign("s3cret", body))
+        );
+        let json: serde_json::Value = serde_json::from_slice(body).unwrap();
+        assert_eq!(json["event"], "alert");
+        assert_eq!(json["alert"]["type"], "motion");
+        assert_eq!(json["alert"]["camera_id"], "cam");
+    }
+
+    #[tokio::test]
+    async fn gives_up() {
+        let (receiver, url) = start_receiver(u32::MAX, StatusCode::BAD_REQUEST).await;
+        let (_, busy_url) = start_receiver(u32::MAX, StatusCode::INTERNAL_SERVER_ERROR).await;
+        let webhooks = webhooks(vec![hook(&url), hook(&busy_url)]);
+
+        webhooks.dispatch(&AlertEvent::CameraConnected {
+            camera_id: "cam".into(),
+            time_ms: 1_000,
+        });
+        let deliveries = settled(&webhooks).await;
+        // Client errors aren't retried
+        let rejected = deliveries.iter().find(|d| d.url == url).unwrap();
+        assert_eq!(rejected.status, DeliveryStatus::Failed);
+        assert_eq!(rejected.attempts, 1);
+        assert_eq!(rejected.response_status, Some(400));
+        assert!(receiver.received.lock().unwrap()[0]
+            .0
+            .get(SIGNATURE_HEADER)
+            .is_none());
+
+        let busy = deliveries.iter().find(|d| d.url == busy_url).unwrap();
+        assert_eq!(busy.status, DeliveryStatus::Failed);
+        assert_eq!(busy.attempts, MAX_ATTEMPTS);
+        assert_eq!(
+            busy.error.as_deref(),
+            Some("HTTP 500 Internal Server Error")
+        );
+    }
+
+    #[tokio::test]
+    async fn filters_events_and_cameras() {
+        let (receiver, url) = start_receiver(0, StatusCode::OK).await;
+        let webhooks = webhooks(vec![
+            WebhookConfig {
+                events: vec!["motion".into(), "camera_disconnected".into()],
+                cameras: vec!["a".into()],
+                ..hook(&url)
+            },
+            WebhookConfig {
+                events: vec!["alert".into()],
+                ..hook(&url)
+            },
+        ]);
+
+        webhooks.dispatch(&alert(AlertKind::Motion, "a"));
+        webhooks.dispatch(&alert(AlertKind::Motion, "b"));
+        webhooks.dispatch(&alert(AlertKind::GeofenceExit, "a"));
+        webhooks.dispatch(&AlertEvent::CameraConnected {
+            camera_id: "a".into(),
+            time_ms: 0,
+        });
+        webhooks.dispatch(&AlertEvent::CameraDisconnected {
+            camera_id: "a".into(),
+            time_ms: 0,
+        });
+
+        let deliveries = settled(&webhooks).await;
+        let sent: Vec<_> = deliveries
+            .iter()
+            .rev()
+            .map(|d| (d.event.as_str(), d.camera_id.as_str()))
+            .collect();
+        assert_eq!(
+            sent,
+            vec![
+                ("alert", "a"),
+                ("alert", "a"),
+                ("alert", "b"),
+                ("alert", "a"),
+                ("camera_disconnected", "a"),
+            ]
+        );
+        assert_eq!(receiver.received.lock().unwrap().len(), 5);
+    }
+}
@@ -0,0 +1,300 @@
+//! Reverse proxy to the core Kodama web server.
+//!
+//! The app API and the core server share the web port: the core server
+//! listens on a private loopback port, and every request the app doesn't
+//! route itself (the UI, the core `/api/*` endpoints) is forwarded there
+//! as-is, WebSocket upgrades included.
+//!
+//! The live `/ws` stream is relayed message by message instead, so app
+//! events can be sent to the UI on it as text messages.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use axum::body::Body;
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::Request;
+use axum::http::header::{AUTHORIZATION, COOKIE, ORIGIN, UPGRADE};
+use axum::http::{HeaderMap, StatusCode, Uri};
+use axum::response::{IntoResponse, Response};
+use futures_util::{SinkExt, StreamExt};
+use hyper_util::rt::TokioIo;
+use tokio::net::TcpStream;
+use tokio::sync::broadcast;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::Message as CoreMessage;
+use tokio_tungstenite::WebSocketStream;
+
+use crate::events::EventBus;
+
+/// A free loopback port for the core server to listen on.
+pub fn free_port() -> anyhow::Result<u16> {
+    let listener = std::net::TcpListener::bind(("127.0.0.1", 0))?;
+    Ok(listener.local_addr()?.port())
+}
+
+/// Forward `req` to the core server at `upstream`.
+pub async fn forward(upstream: SocketAddr, req: Request) -> Response {
+    match send(upstream, req).await {
+        Ok(res) => res,
+        Err(e) => {
+            tracing::debug!("Forwarding to the core web server failed: {}", e);
+            (StatusCode::BAD_GATEWAY, e.to_string()).into_response()
+        }
+    }
+}
+
+async fn send(upstream: SocketAddr, mut req: Request) -> anyhow::Result<Response> {
+    let client = req
+        .headers()
+        .contains_key(UPGRADE)
+        .then(|| hyper::upgrade::on(&mut req));
+
+    let stream = TcpStream::connect(upstream).await?;
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+    tokio::spawn(async move {
+        if let Err(e) = conn.with_upgrades().await {
+            tracing::debug!("Core web server connection ended: {}", e);
+        }
+    });
+    let mut res = sender.send_request(req).await?;
+
+    if let Some(client) = client.filter(|_| res.status() == StatusCode::SWITCHING_PROTOCOLS) {
+        let server = hyper::upgrade::on(&mut res);
+        tokio::spawn(async move {
+            match tokio::try_join!(client, server) {
+                Ok((client, server)) => {
+                    let _ = tokio::io::copy_bidirectional(
+                        &mut TokioIo::new(client),
+                        &mut TokioIo::new(server),
+                    )
+                    .await;
+                }
+                Err(e) => tracing::debug!("Forwarded upgrade failed: {}", e),
+            }
+        });
+    }
+    Ok(res.map(Body::new))
+}
+
+/// `GET /ws` (WebSocket)
+///
+/// The core server's live stream, with app events from `events` sent
+/// alongside as text messages.
+pub async fn live(
+    core: SocketAddr,
+    events: EventBus,
+    ws: WebSocketUpgrade,
+    uri: Uri,
+    headers: HeaderMap,
+) -> Response {
+    let upstream = match connect(core, &uri, &headers).await {
+        Ok(upstream) => upstream,
+        Err(e) => {
+            tracing::debug!("Connecting to the core live stream failed: {}", e);
+            return (StatusCode::BAD_GATEWAY, e.to_string()).into_response();
+        }
+    };
+    let rx = events.subscribe();
+    ws.on_upgrade(|socket| relay(socket, upstream, rx))
+}
+
+async fn connect(
+    core: SocketAddr,
+    uri: &Uri,
+    headers: &HeaderMap,
+) -> anyhow::Result<WebSocketStream<TcpStream>> {
+    let path = uri.path_and_query().map_or("/ws", |p| p.as_str());
+    let mut request = format!("ws://{}{}", core, path).into_client_request()?;
+    for name in [ORIGIN, COOKIE, AUTHORIZATION] {
+        if let Some(value) = headers.get(&name) {
+            request.headers_mut().insert(name, value.clone());
+        }
+    }
+    let stream = TcpStream::connect(core).await?;
+    let (upstream, _) = tokio_tungstenite::client_async(request, stream).await?;
+    Ok(upstream)
+}
+
+async fn relay(
+    mut socket: WebSocket,
+    mut upstream: WebSocketStream<TcpStream>,
+    mut events: broadcast::Receiver<Arc<str>>,
+) {
+    use tokio::sync::broadcast::error::RecvError;
+
+    // Pings are answered on each side of the relay
+    loop {
+        tokio::select! {
+            msg = upstream.next() => {
+                let msg = match msg {
+                    Some(Ok(CoreMessage::Binary(data))) => Message::Binary(data),
+                    Some(Ok(CoreMessage::Text(text))) => Message::Text(text.as_str().into()),
+                    Some(Ok(CoreMessage::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => continue,
+                };
+                if socket.send(msg).await.is_err() {
+                    break;
+                }
+            }
+            msg = socket.recv() => {
+                let msg = match msg {
+                    Some(Ok(Message::Binary(data))) => CoreMessage::Binary(data),
+                    Some(Ok(Message::Text(text))) => CoreMessage::text(text.as_str()),
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => continue,
+                };
+                if upstream.send(msg).await.is_err() {
+                    break;
+                }
+            }
+            event = events.recv() => match event {
+                Ok(json) => {
+                    if socket.send(Message::Text(json.as_ref().into())).await.is_err() {
+                        break;
+                    }
+                }
+                Err(RecvError::Lagged(n)) => {
+                    tracing::debug!("Live client lagged, missed {} events", n);
+                }
+                Err(RecvError::Closed) => break,
+            },
+        }
+    }
+    let _ = upstream.close(None).await;
+    let _ = socket.close().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use axum::http::header::CONNECTION;
+    use axum::routing::get;
+    use axum::Router;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Serve `app` on a loopback port.
+    async fn serve(app: Router) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        addr
+    }
+
+    /// Upgrades to a raw echo of whatever the client sends.
+    async fn echo(mut req: Request) -> Response {
+        let upgrade = hyper::upgrade::on(&mut req);
+        tokio::spawn(async move {
+            let mut io = TokioIo::new(upgrade.await.unwrap());
+            let mut buf = [0; 64];
+            while let Ok(n @ 1..) = io.read(&mut buf).await {
+                io.write_all(&buf[..n]).await.unwrap();
+            }
+        });
+        Response::builder()
+            .status(StatusCode::SWITCHING_PROTOCOLS)
+            .header(CONNECTION, "upgrade")
+            .header(UPGRADE, "echo")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn forwards_requests_and_upgrades() {
+        let core = serve(
+            Router::new()
+                .route("/api/status", get(|| async { "core" }))
+                .route("/echo", get(echo)),
+        )
+        .await;
+        let front = serve(
+            Router::new()
+                .route("/api/app", get(|| async { "app" }))
+                .fallback(move |req: Request| forward(core, req)),
+        )
+        .await;
+
+        let get = |path: &str| reqwest::get(format!("http://{}{}", front, path));
+        assert_eq!(get("/api/app").await.unwrap().text().await.unwrap(), "app");
+        assert_eq!(
+            get("/api/status").await.unwrap().text().await.unwrap(),
+            "core"
+        );
+        assert_eq!(get("/nope").await.unwrap().status(), StatusCode::NOT_FOUND);
+
+        let mut stream = TcpStream::connect(front).await.unwrap();
+        let request = "GET /echo HTTP/1.1\r\n\
+                       Host: kodama\r\n\
+                       Connection: upgrade\r\n\
+                       Upgrade: echo\r\n\r\n";
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            head.push(stream.read_u8().await.unwrap());
+        }
+        assert!(head.starts_with(b"HTTP/1.1 101"));
+        stream.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    #[tokio::test]
+    async fn live_stream_carries_app_events() {
+        use crate::config::ArmingMode;
+        use crate::events::AppEvent;
+        use crate::modes::{ModeSource, ModeStatus};
+
+        let core = serve(Router::new().route(
+            "/ws",
+            get(|ws: WebSocketUpgrade| async move {
+                ws.on_upgrade(|mut socket| async move {
+                    while let Some(Ok(msg)) = socket.recv().await {
+                        if socket.send(msg).await.is_err() {
+                            break;
+                        }
+                    }
+                })
+            }),
+        ))
+        .await;
+        let events = EventBus::default();
+        let bus = events.clone();
+        let front = serve(Router::new().route(
+            "/ws",
+            get(move |ws, uri, headers| live(core, bus.clone(), ws, uri, headers)),
+        ))
+        .await;
+
+        let stream = TcpStream::connect(front).await.unwrap();
+        let (mut client, _) = tokio_tungstenite::client_async(format!("ws://{}/ws", front), stream)
+            .await
+            .unwrap();
+        client
+            .send(CoreMessage::binary(vec![1, 2, 3]))
+            .await
+            .unwrap();
+        let msg = client.next().await.unwrap().unwrap();
+        assert_eq!(msg, CoreMessage::binary(vec![1, 2, 3]));
+
+        events.publish(AppEvent::Mode(ModeStatus {
+            mode: ArmingMode::Away,
+            source: ModeSource::Manual,
+            since_ms: 1,
+            next: None,
+        }));
+        let msg = client.next().await.unwrap().unwrap();
+        let event: serde_json::Value = serde_json::from_str(msg.to_text().unwrap()).unwrap();
+        assert_eq!(event["type"], "mode");
+        assert_eq!(event["payload"]["mode"], "away");
+    }
+
+    #[tokio::test]
+    async fn core_server_down_is_a_bad_gateway() {
+        let core = SocketAddr::from(([127, 0, 0, 1], free_port().unwrap()));
+        let res = forward(core, Request::new(Body::empty())).await;
+        assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+    }
+}
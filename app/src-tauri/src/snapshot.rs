@@ -0,0 +1,310 @@
+//! Live camera snapshots.
+//!
+//! The latest video keyframe of each camera is kept from the Router
+//! broadcast and decoded to JPEG on request by `ffmpeg` (see
+//! [`crate::media::jpeg::ffmpeg_path`]).
+//! Decoded snapshots are cached per keyframe, width and quality. Each camera
+//! is decoded at most once per [`MIN_INTERVAL`]; requests in between get the
+//! cached snapshot if there is one, even if a newer keyframe has arrived,
+//! and are told when to retry otherwise. At most [`MAX_DECODES`] decodes
+//! run at once.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use kodama::server::RouterHandle;
+use kodama::{Channel, Frame};
+use tokio::sync::{broadcast, Semaphore};
+
+use crate::media::h264::ParameterSets;
+use crate::media::jpeg;
+use crate::storage::now_ms;
+
+/// Minimum gap between decodes for one camera.
+pub const MIN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Decodes running at once, across cameras.
+pub const MAX_DECODES: usize = 2;
+
+/// Largest width a snapshot can be scaled to.
+pub const MAX_WIDTH: u32 = 3840;
+
+/// Cached sizes and qualities per camera before the cache is reset.
+const MAX_VARIANTS: usize = 8;
+
+/// A decoded snapshot.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub jpeg: Bytes,
+    /// When the keyframe was received (epoch ms)
+    pub time_ms: u64,
+}
+
+/// Why there's no snapshot.
+#[derive(Debug)]
+pub enum SnapshotError {
+    /// No keyframe has been received from the camera.
+    NoKeyframe,
+    /// The camera was decoded too recently and nothing is cached.
+    RateLimited {
+        retry_after: Duration,
+    },
+    Decode(anyhow::Error),
+}
+
+#[derive(Debug, Clone)]
+struct Keyframe {
+    /// Annex B, with parameter sets
+    data: Bytes,
+    time_ms: u64,
+}
+
+#[derive(Debug, Default)]
+struct CameraCache {
+    last_decode: Option<Instant>,
+    /// Time of the keyframe `variants` were decoded from.
+    keyframe_ms: u64,
+    /// JPEGs by width and ffmpeg quality scale.
+    variants: HashMap<(Option<u32>, u8), Bytes>,
+}
+
+/// Latest keyframes and their decoded snapshots.
+pub struct Snapshots {
+    ffmpeg: PathBuf,
+    keyframes: Mutex<HashMap<String, Keyframe>>,
+    /// Last parameter sets seen per camera, for keyframes sent without them.
+    parameter_sets: Mutex<HashMap<String, ParameterSets>>,
+    cache: Mutex<HashMap<String, CameraCache>>,
+    decodes: Semaphore,
+    min_interval: Duration,
+}
+
+impl Snapshots {
+    pub fn new(ffmpeg: PathBuf) -> Self {
+        Self {
+            ffmpeg,
+            keyframes: Mutex::new(HashMap::new()),
+            parameter_sets: Mutex::new(HashMap::new()),
+            cache: Mutex::new(HashMap::new()),
+            decodes: Semaphore::new(MAX_DECODES),
+            min_interval: MIN_INTERVAL,
+        }
+    }
+
+    /// Keep `frame` if it's a video keyframe, received at `now_ms`.
+    pub fn observe(&self, frame: &Frame, now_ms: u64) {
+        if !matches!(frame.channel, Channel::Video) || !frame.flags.is_keyframe() {
+            return;
+        }
+        let camera_id = frame.source.to_string();
+        let data = match ParameterSets::from_access_unit(&frame.payload) {
+            Some(params) => {
+                self.parameter_sets
+                    .lock()
+                    .unwrap()
+                    .insert(camera_id.clone(), params);
+                frame.payload.clone()
+            }
+            None => match self.parameter_sets.lock().unwrap().get(&camera_id) {
+                Some(params) => with_parameter_sets(params, &frame.payload),
+                // Undecodable until the camera sends its parameter sets
+                None => return,
+            },
+        };
+        self.keyframes.lock().unwrap().insert(
+            camera_id,
+            Keyframe {
+                data,
+                time_ms: now_ms,
+            },
+        );
+    }
+
+    /// A snapshot of `camera_id`, scaled to `width` if given, at JPEG
+    /// `quality` from 1 to 100.
+    pub async fn get(
+        &self,
+        camera_id: &str,
+        width: Option<u32>,
+        quality: u8,
+    ) -> Result<Snapshot, SnapshotError> {
+        let keyframe = self
+            .keyframes
+            .lock()
+            .unwrap()
+            .get(camera_id)
+            .cloned()
+            .ok_or(SnapshotError::NoKeyframe)?;
+        let width = width.map(|w| w.clamp(16, MAX_WIDTH));
+        let variant = (width, qscale(quality));
+
+        {
+            let mut cache = self.cache.lock().unwrap();
+            let camera = cache.entry(camera_id.to_string()).or_default();
+            let cached = camera.variants.get(&variant).map(|jpeg| Snapshot {
+                jpeg: jpeg.clone(),
+                time_ms: camera.keyframe_ms,
+            });
+            if camera.keyframe_ms == keyframe.time_ms {
+                if let Some(snapshot) = cached {
+                    return Ok(snapshot);
+                }
+            }
+            if let Some(last) = camera.last_decode {
+                let since = last.elapsed();
+                if since < self.min_interval {
+                    return cached.ok_or(SnapshotError::RateLimited {
+                        retry_after: self.min_interval - since,
+                    });
+                }
+            }
+            camera.last_decode = Some(Instant::now());
+        }
+
+        let _permit = self.decodes.acquire().await.expect("never closed");
+        let jpeg = jpeg::decode(&self.ffmpeg, keyframe.data, variant.0, variant.1)
+            .await
+            .map_err(SnapshotError::Decode)?;
+        let jpeg = Bytes::from(jpeg);
+
+        let mut cache = self.cache.lock().unwrap();
+        let camera = cache.entry(camera_id.to_string()).or_default();
+        if camera.keyframe_ms != keyframe.time_ms || camera.variants.len() >= MAX_VARIANTS {
+            camera.variants.clear();
+            camera.keyframe_ms = keyframe.time_ms;
+        }
+        camera.variants.insert(variant, jpeg.clone());
+        Ok(Snapshot {
+            jpeg,
+            time_ms: keyframe.time_ms,
+        })
+    }
+}
+
+/// ffmpeg's `-q:v` scale (2 best, 31 worst) for a JPEG quality of 1 to 100.
+fn qscale(quality: u8) -> u8 {
+    let quality = quality.clamp(1, 100);
+    2 + ((100 - quality) as u32 * 29 / 99) as u8
+}
+
+/// `keyframe` with `params` prepended, as Annex B.
+fn with_parameter_sets(params: &ParameterSets, keyframe: &[u8]) -> Bytes {
+    let mut data = Vec::with_capacity(params.sps.len() + params.pps.len() + keyframe.len() + 8);
+    for nal in [&params.sps, &params.pps] {
+        data.extend_from_slice(&[0, 0, 0, 1]);
+        data.extend_from_slice(nal);
+    }
+    data.extend_from_slice(keyframe);
+    data.into()
+}
+
+/// Keep keyframes from the Router broadcast until it closes.
+pub fn spawn(handle: RouterHandle, snapshots: Arc<Snapshots>) {
+    tokio::spawn(async move {
+        let mut rx = handle.subscribe();
+        loop {
+            match rx.recv().await {
+                Ok(frame) => snapshots.observe(&frame, now_ms()),
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    tracing::debug!("Snapshots lagged, missed {} frames", n);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+#[cfg(all(test, unix))]
+pub(crate) mod tests {
+    use super::*;
+
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
+
+    use kodama::{FrameFlags, SourceId};
+
+    pub(crate) const SPS: &[u8] = &[0x67, 0x42, 0x00, 0x1e];
+    pub(crate) const PPS: &[u8] = &[0x68, 0xce];
+    pub(crate) const IDR: &[u8] = &[0x65, 0x88];
+
+    /// Stand-in for ffmpeg that echoes its input and arguments as the
+    /// "JPEG".
+    pub(crate) fn fake_ffmpeg(dir: &Path) -> PathBuf {
+        let path = dir.join("ffmpeg");
+        std::fs::write(&path, "#!/bin/sh\ncat\nprintf ' %s' \"$@\"\n").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    pub(crate) fn annexb(nals: &[&[u8]]) -> Bytes {
+        nals.iter()
+            .flat_map(|nal| [&[0, 0, 0, 1][..], nal].concat())
+            .collect()
+    }
+
+    pub(crate) fn keyframe(payload: Bytes) -> Frame {
+        Frame {
+            source: SourceId::from_node_id_bytes(&[1; 32]),
+            channel: Channel::Video,
+            flags: FrameFlags::keyframe(),
+            timestamp_us: 0,
+            payload,
+        }
+    }
+
+    #[test]
+    fn quality_maps_to_qscale() {
+        assert_eq!(qscale(100), 2);
+        assert_eq!(qscale(1), 31);
+        assert_eq!(qscale(0), 31);
+        assert_eq!(qscale(80), 7);
+    }
+
+    #[tokio::test]
+    async fn decodes_caches_and_rate_limits() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut snapshots = Snapshots::new(fake_ffmpeg(dir.path()));
+        snapshots.min_interval = Duration::from_millis(200);
+        let camera_id = SourceId::from_node_id_bytes(&[1; 32]).to_string();
+
+        assert!(matches!(
+            snapshots.get(&camera_id, None, 80).await,
+            Err(SnapshotError::NoKeyframe)
+        ));
+        // No parameter sets yet: not decodable
+        snapshots.observe(&keyframe(annexb(&[IDR])), 500);
+        assert!(matches!(
+            snapshots.get(&camera_id, None, 80).await,
+            Err(SnapshotError::NoKeyframe)
+        ));
+
+        snapshots.observe(&keyframe(annexb(&[SPS, PPS, IDR])), 1_000);
+        let first = snapshots.get(&camera_id, Some(640), 80).await.unwrap();
+        assert_eq!(first.time_ms, 1_000);
+        assert!(first.jpeg.starts_with(&annexb(&[SPS, PPS, IDR])));
+        let output = String::from_utf8_lossy(&first.jpeg);
+        assert!(output.contains("-vf scale=640:-2"));
+        assert!(output.contains("-q:v 7"));
+
+        // Same keyframe and variant: cached
+        let again = snapshots.get(&camera_id, Some(640), 80).await.unwrap();
+        assert_eq!(again.jpeg, first.jpeg);
+        // A different variant this soon is rate limited
+        assert!(matches!(
+            snapshots.get(&camera_id, None, 80).await,
+            Err(SnapshotError::RateLimited { .. })
+        ));
+
+        // Parameter sets are added to keyframes sent without them
+        snapshots.observe(&keyframe(annexb(&[IDR])), 2_000);
+        let stale = snapshots.get(&camera_id, Some(640), 80).await.unwrap();
+        assert_eq!(stale.time_ms, 1_000);
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        let fresh = snapshots.get(&camera_id, Some(640), 80).await.unwrap();
+        assert_eq!(fresh.time_ms, 2_000);
+        assert!(fresh.jpeg.starts_with(&annexb(&[SPS, PPS, IDR])));
+    }
+}
@@ -0,0 +1,108 @@
+//! Storage backend abstraction.
+
+use async_trait::async_trait;
+use bytes::Bytes;
+
+use super::segment::SegmentKey;
+
+/// Where segment bytes live.
+///
+/// Segments are written append-only while open and immutable once
+/// finished. Backends are shared as `Arc<dyn StorageBackend>` and may be
+/// layered (e.g. encryption over a local or remote backend).
+#[async_trait]
+pub trait StorageBackend: Send + Sync {
+    /// Append bytes to a segment, creating it on first write.
+    async fn append(&self, key: &SegmentKey, data: &[u8]) -> anyhow::Result<()>;
+
+    /// Read a whole segment, finished or not.
+    async fn read(&self, key: &SegmentKey) -> anyhow::Result<Bytes>;
+
+    /// Mark a segment complete. No further appends follow.
+    async fn finish(&self, key: &SegmentKey) -> anyhow::Result<()>;
+
+    /// Remove a segment.
+    async fn delete(&self, key: &SegmentKey) -> anyhow::Result<()>;
+
+    /// Every stored segment, finished or not.
+    async fn list(&self) -> anyhow::Result<Vec<SegmentKey>>;
+
+    /// Cut a segment back to its first `len` bytes, as returned by
+    /// [`read`](Self::read). Used to drop a partial trailing write.
+    async fn truncate(&self, key: &SegmentKey, len: u64) -> anyhow::Result<()>;
+
+    /// Move an unreadable segment aside, out of [`list`](Self::list), for
+    /// inspection.
+    async fn quarantine(&self, key: &SegmentKey) -> anyhow::Result<()>;
+
+    /// Whether the segment takes up space on the storage path's disk,
+    /// which deleting it would free. Remote backends return `false` once
+    /// uploaded, tiered storage once moved to the cold root.
+    async fn is_local(&self, _key: &SegmentKey) -> bool {
+        true
+    }
+}
+
+/// Local storage whose appends can be made to fail, after writing part of
+/// the data like a filling disk, for tests.
+#[cfg(test)]
+pub(crate) struct FlakyStorage {
+    inner: super::LocalStorage,
+    failing: std::sync::atomic::AtomicBool,
+}
+
+#[cfg(test)]
+impl FlakyStorage {
+    pub fn new(dir: &std::path::Path) -> Self {
+        Self {
+            inner: super::LocalStorage::new(super::LocalStorageConfig {
+                root_path: dir.to_path_buf(),
+            })
+            .unwrap(),
+            failing: Default::default(),
+        }
+    }
+
+    pub fn set_failing(&self, failing: bool) {
+        self.failing
+            .store(failing, std::sync::atomic::Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+#[async_trait]
+impl StorageBackend for FlakyStorage {
+    async fn append(&self, key: &SegmentKey, data: &[u8]) -> anyhow::Result<()> {
+        if !self.failing.load(std::sync::atomic::Ordering::Relaxed) {
+            return self.inner.append(key, data).await;
+        }
+        if data.len() > 1 {
+            self.inner.append(key, &data[..data.len() / 2]).await?;
+        }
+        anyhow::bail!("No space left on device")
+    }
+
+    async fn read(&self, key: &SegmentKey) -> anyhow::Result<Bytes> {
+        self.inner.read(key).await
+    }
+
+    async fn finish(&self, key: &SegmentKey) -> anyhow::Result<()> {
+        self.inner.finish(key).await
+    }
+
+    async fn delete(&self, key: &SegmentKey) -> anyhow::Result<()> {
+        self.inner.delete(key).await
+    }
+
+    async fn list(&self) -> anyhow::Result<Vec<SegmentKey>> {
+        self.inner.list().await
+    }
+
+    async fn truncate(&self, key: &SegmentKey, len: u64) -> anyhow::Result<()> {
+        self.inner.truncate(key, len).await
+    }
+
+    async fn quarantine(&self, key: &SegmentKey) -> anyhow::Result<()> {
+        self.inner.quarantine(key).await
+    }
+}
@@ -0,0 +1,414 @@
+//! Encryption at rest: a [`StorageBackend`] wrapper that seals segments.
+//!
+//! Every append becomes one ChaCha20-Poly1305 record, so segments stay
+//! append-only and readable while open. A segment starts with a header
+//! naming the key it was sealed with:
+//!
+//! ```text
+//! header: "KENC" | version(1) | key_id(4) | nonce_prefix(8)
+//! record: len(4 LE) | ciphertext + tag(16)
+//! ```
+//!
+//! Record `n` uses nonce `nonce_prefix | n (BE)`, and the segment's path is
+//! authenticated alongside, so records can't be reordered or moved between
+//! segments. A torn trailing record (crash mid-write) is dropped on read,
+//! like a truncated KSEG record.
+//!
+//! Keys are derived from user secrets with Argon2id and a per-store salt.
+//! New segments are sealed with the current key; previous keys stay in the
+//! [`Keyring`] to read older segments until retention removes them, so a
+//! rotation never rewrites existing recordings. Unsealed segments (recorded
+//! before encryption was enabled) are passed through as-is.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use argon2::Argon2;
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, KeyInit, Nonce};
+use tokio::sync::Mutex;
+
+use super::backend::StorageBackend;
+use super::segment::SegmentKey;
+
+const MAGIC: &[u8; 4] = b"KENC";
+const VERSION: u8 = 1;
+const HEADER_LEN: usize = 17;
+const TAG_LEN: usize = 16;
+const SALT_LEN: usize = 16;
+
+/// A derived key and its public identifier.
+struct SealingKey {
+    id: u32,
+    cipher: ChaCha20Poly1305,
+}
+
+/// The current key plus any previous ones still needed for reading.
+pub struct Keyring {
+    current: SealingKey,
+    previous: Vec<SealingKey>,
+}
+
+impl Keyring {
+    /// Derive keys from `current` and `previous` secrets.
+    ///
+    /// The salt is read from `salt_path`, or created there on first use.
+    /// Losing it makes every sealed segment unreadable.
+    pub fn open(salt_path: &Path, current: &str, previous: &[String]) -> anyhow::Result<Self> {
+        let salt = match std::fs::read(salt_path) {
+            Ok(salt) if salt.len() == SALT_LEN => salt,
+            Ok(_) => anyhow::bail!("corrupt encryption salt: {:?}", salt_path),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let mut salt = vec![0u8; SALT_LEN];
+                OsRng.fill_bytes(&mut salt);
+                std::fs::write(salt_path, &salt)?;
+                salt
+            }
+            Err(e) => return Err(e.into()),
+        };
+        Self::derive(&salt, current, previous)
+    }
+
+    fn derive(salt: &[u8], current: &str, previous: &[String]) -> anyhow::Result<Self> {
+        let derive = |secret: &str| -> anyhow::Result<SealingKey> {
+            if secret.is_empty() {
+                anyhow::bail!("empty encryption secret");
+            }
+            // 32 key bytes, then 4 more as the key's id
+            let mut out = [0u8; 36];
+            Argon2::default()
+                .hash_password_into(secret.as_bytes(), salt, &mut out)
+                .map_err(|e| anyhow::anyhow!("key derivation failed: {}", e))?;
+            Ok(SealingKey {
+                id: u32::from_le_bytes(out[32..].try_into().expect("4 bytes")),
+                cipher: ChaCha20Poly1305::new(Key::from_slice(&out[..32])),
+            })
+        };
+        Ok(Self {
+            current: derive(current)?,
+            previous: previous
+                .iter()
+                .map(|s| derive(s))
+                .collect::<anyhow::Result<_>>()?,
+        })
+    }
+
+    fn get(&self, id: u32) -> Option<&SealingKey> {
+        std::iter::once(&self.current)
+            .chain(&self.previous)
+            .find(|k| k.id == id)
+    }
+}
+
+/// A segment being written: its header and the next record number.
+struct OpenSegment {
+    header: [u8; HEADER_LEN],
+    counter: u32,
+    /// A write failed, so later records would follow a torn one or have
+    /// no header; appends fail until the segment is finished.
+    failed: bool,
+}
+
+/// Seals segments before handing them to the inner backend.
+pub struct EncryptedStorage {
+    inner: Arc<dyn StorageBackend>,
+    keys: Keyring,
+    open: Mutex<HashMap<SegmentKey, OpenSegment>>,
+}
+
+impl EncryptedStorage {
+    pub fn new(inner: Arc<dyn StorageBackend>, keys: Keyring) -> Self {
+        Self {
+            inner,
+            keys,
+            open: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+fn nonce(header: &[u8; HEADER_LEN], counter: u32) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[..8].copy_from_slice(&header[9..17]);
+    nonce[8..].copy_from_slice(&counter.to_be_bytes());
+    nonce
+}
+
+/// Associated data: the header plus the segment's path.
+fn aad(header: &[u8; HEADER_LEN], key: &SegmentKey) -> Vec<u8> {
+    let mut aad = header.to_vec();
+    aad.extend_from_slice(key.relative_path().to_string_lossy().as_bytes());
+    aad
+}
+
+#[async_trait]
+impl StorageBackend for EncryptedStorage {
+    async fn append(&self, key: &SegmentKey, data: &[u8]) -> anyhow::Result<()> {
+        let mut open = self.open.lock().await;
+        let mut out = Vec::with_capacity(HEADER_LEN + 4 + data.len() + TAG_LEN);
+        let segment = open.entry(key.clone()).or_insert_with(|| {
+            let mut header = [0u8; HEADER_LEN];
+            header[..4].copy_from_slice(MAGIC);
+            header[4] = VERSION;
+            header[5..9].copy_from_slice(&self.keys.current.id.to_le_bytes());
+            OsRng.fill_bytes(&mut header[9..]);
+            out.extend_from_slice(&header);
+            OpenSegment {
+                header,
+                counter: 0,
+                failed: false,
+            }
+        });
+        if segment.failed {
+            anyhow::bail!("Segment {:?} failed an earlier write", key);
+        }
+
+        // Spend the nonce before writing: a failed write may still have
+        // reached the disk
+        let counter = segment.counter;
+        segment.counter += 1;
+        let sealed = self.keys.current.cipher.encrypt(
+            Nonce::from_slice(&nonce(&segment.header, counter)),
+            Payload {
+                msg: data,
+                aad: &aad(&segment.header, key),
+            },
+        );
+        let result = match sealed {
+            Ok(sealed) => {
+                out.extend_from_slice(&(sealed.len() as u32).to_le_bytes());
+                out.extend_from_slice(&sealed);
+                self.inner.append(key, &out).await
+            }
+            Err(_) => Err(anyhow::anyhow!("encryption failed")),
+        };
+        if result.is_err() {
+            segment.failed = true;
+        }
+        result
+    }
+
+    async fn read(&self, key: &SegmentKey) -> anyhow::Result<Bytes> {
+        let data = self.inner.read(key).await?;
+        if !data.starts_with(MAGIC) {
+            return Ok(data);
+        }
+        let header: [u8; HEADER_LEN] = data
+            .get(..HEADER_LEN)
+            .and_then(|h| h.try_into().ok())
+            .ok_or_else(|| anyhow::anyhow!("truncated encryption header"))?;
+        if header[4] != VERSION {
+            anyhow::bail!("unsupported encryption version {}", header[4]);
+        }
+        let key_id = u32::from_le_bytes(header[5..9].try_into().expect("4 bytes"));
+        let sealing = self
+            .keys
+            .get(key_id)
+            .ok_or_else(|| anyhow::anyhow!("segment sealed with unknown key {:08x}", key_id))?;
+        let aad = aad(&header, key);
+
+        let mut plain = BytesMut::with_capacity(data.len());
+        let mut pos = HEADER_LEN;
+        let mut counter = 0u32;
+        while let Some(len) = data.get(pos..pos + 4) {
+            let len = u32::from_le_bytes(len.try_into().expect("4 bytes")) as usize;
+            let Some(sealed) = data.get(pos + 4..pos + 4 + len) else {
+                break;
+            };
+            let record = sealing
+                .cipher
+                .decrypt(
+                    Nonce::from_slice(&nonce(&header, counter)),
+                    Payload {
+                        msg: sealed,
+                        aad: &aad,
+                    },
+                )
+                .map_err(|_| anyhow::anyhow!("segment failed authentication"))?;
+            plain.extend_from_slice(&record);
+            pos += 4 + len;
+            counter += 1;
+        }
+        Ok(plain.freeze())
+    }
+
+    async fn finish(&self, key: &SegmentKey) -> anyhow::Result<()> {
+        self.open.lock().await.remove(key);
+        self.inner.finish(key).await
+    }
+
+    async fn delete(&self, key: &SegmentKey) -> anyhow::Result<()> {
+        self.open.lock().await.remove(key);
+        self.inner.delete(key).await
+    }
+
+    async fn list(&self) -> anyhow::Result<Vec<SegmentKey>> {
+        self.inner.list().await
+    }
+
+    /// `len` counts plaintext bytes; it must fall on a record boundary.
+    async fn truncate(&self, key: &SegmentKey, len: u64) -> anyhow::Result<()> {
+        let data = self.inner.read(key).await?;
+        if !data.starts_with(MAGIC) {
+            return self.inner.truncate(key, len).await;
+        }
+        let (mut pos, mut plain) = (HEADER_LEN, 0u64);
+        while plain < len {
+            let record = data
+                .get(pos..pos + 4)
+                .map(|l| u32::from_le_bytes(l.try_into().expect("4 bytes")) as usize)
+                .filter(|l| *l >= TAG_LEN && pos + 4 + l <= data.len())
+                .ok_or_else(|| anyhow::anyhow!("truncation past the last sealed record"))?;
+            pos += 4 + record;
+            plain += (record - TAG_LEN) as u64;
+        }
+        if plain != len {
+            anyhow::bail!("truncation inside a sealed record");
+        }
+        self.inner.truncate(key, pos as u64).await
+    }
+
+    async fn quarantine(&self, key: &SegmentKey) -> anyhow::Result<()> {
+        self.open.lock().await.remove(key);
+        self.inner.quarantine(key).await
+    }
+
+    async fn is_local(&self, key: &SegmentKey) -> bool {
+        self.inner.is_local(key).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::storage::backend::FlakyStorage;
+    use crate::storage::{LocalStorage, LocalStorageConfig};
+
+    const SALT: &[u8] = b"0123456789abcdef";
+
+    fn keyring(current: &str, previous: &[&str]) -> Keyring {
+        let previous: Vec<String> = previous.iter().map(|s| s.to_string()).collect();
+        Keyring::derive(SALT, current, &previous).unwrap()
+    }
+
+    fn local(dir: &Path) -> Arc<dyn StorageBackend> {
+        Arc::new(
+            LocalStorage::new(LocalStorageConfig {
+                root_path: dir.to_path_buf(),
+            })
+            .unwrap(),
+        )
+    }
+
+    fn key(start_ms: u64) -> SegmentKey {
+        SegmentKey {
+            camera_id: "cam".into(),
+            start_ms,
+        }
+    }
+
+    #[tokio::test]
+    async fn seals_and_reads_back_while_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = EncryptedStorage::new(local(dir.path()), keyring("hunter2", &[]));
+
+        storage.append(&key(1), b"hello ").await.unwrap();
+        storage.append(&key(1), b"world").await.unwrap();
+        assert_eq!(&storage.read(&key(1)).await.unwrap()[..], b"hello world");
+        storage.finish(&key(1)).await.unwrap();
+
+        let path = dir.path().join("cam/1.kseg");
+        let raw = std::fs::read(&path).unwrap();
+        assert!(raw.starts_with(MAGIC));
+        assert!(!raw.windows(5).any(|w| w == b"hello"));
+
+        // A torn trailing record is dropped, and can be cut off
+        std::fs::write(&path, &raw[..raw.len() - 3]).unwrap();
+        assert_eq!(&storage.read(&key(1)).await.unwrap()[..], b"hello ");
+        storage.truncate(&key(1), 6).await.unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), HEADER_LEN as u64 + 4 + 6 + 16);
+        assert!(storage.truncate(&key(1), 3).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rotated_keys_still_read_old_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let old = EncryptedStorage::new(local(dir.path()), keyring("old", &[]));
+        old.append(&key(1), b"before").await.unwrap();
+        old.finish(&key(1)).await.unwrap();
+
+        let rotated = EncryptedStorage::new(local(dir.path()), keyring("new", &["old"]));
+        rotated.append(&key(2), b"after").await.unwrap();
+        assert_eq!(&rotated.read(&key(1)).await.unwrap()[..], b"before");
+        assert_eq!(&rotated.read(&key(2)).await.unwrap()[..], b"after");
+
+        let forgotten = EncryptedStorage::new(local(dir.path()), keyring("new", &[]));
+        assert!(forgotten.read(&key(1)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_tampered_and_moved_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = EncryptedStorage::new(local(dir.path()), keyring("k", &[]));
+        storage.append(&key(1), b"payload").await.unwrap();
+        storage.finish(&key(1)).await.unwrap();
+
+        let path = dir.path().join("cam/1.kseg");
+        let raw = std::fs::read(&path).unwrap();
+        std::fs::write(dir.path().join("cam/2.kseg"), &raw).unwrap();
+        assert!(storage.read(&key(2)).await.is_err());
+
+        let mut tampered = raw.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        std::fs::write(&path, &tampered).unwrap();
+        assert!(storage.read(&key(1)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn passes_through_unsealed_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let inner = local(dir.path());
+        inner.append(&key(1), b"KSEG plain").await.unwrap();
+
+        let storage = EncryptedStorage::new(inner, keyring("k", &[]));
+        assert_eq!(&storage.read(&key(1)).await.unwrap()[..], b"KSEG plain");
+    }
+
+    #[test]
+    fn salt_is_created_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("encryption.salt");
+        let first = Keyring::open(&path, "k", &[]).unwrap();
+        let second = Keyring::open(&path, "k", &[]).unwrap();
+        assert_eq!(first.current.id, second.current.id);
+        assert!(Keyring::open(&path, "", &[]).is_err());
+    }
+
+    #[tokio::test]
+    async fn failed_write_ends_the_segment() {
+        let dir = tempfile::tempdir().unwrap();
+        let flaky = Arc::new(FlakyStorage::new(dir.path()));
+        let storage = EncryptedStorage::new(flaky.clone(), keyring("k", &[]));
+
+        // The header never fully reached the disk
+        flaky.set_failing(true);
+        assert!(storage.append(&key(1), b"head").await.is_err());
+        flaky.set_failing(false);
+        assert!(storage.append(&key(1), b"record").await.is_err());
+        storage.delete(&key(1)).await.unwrap();
+
+        // A torn record: nothing more is sealed after it, under any nonce
+        storage.append(&key(2), b"head").await.unwrap();
+        flaky.set_failing(true);
+        assert!(storage.append(&key(2), b"record one").await.is_err());
+        flaky.set_failing(false);
+        assert!(storage.append(&key(2), b"record two").await.is_err());
+        storage.truncate(&key(2), 4).await.unwrap();
+        storage.finish(&key(2)).await.unwrap();
+        assert_eq!(&storage.read(&key(2)).await.unwrap()[..], b"head");
+    }
+}
@@ -0,0 +1,253 @@
+//! Storage health: write failures, dropped frames and free disk space.
+//!
+//! The pipeline reports into a shared [`StorageHealth`]; the manager's
+//! health task samples disk space, runs emergency cleanup and publishes a
+//! [`StorageStatus`] snapshot for the API and UI.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde::Serialize;
+use tokio::sync::Notify;
+
+use crate::metrics::Histogram;
+
+use super::manager::now_ms;
+use super::repair::ScanReport;
+use super::roots::RootStatus;
+
+/// A camera counts as failing for this long after its last store error.
+const ERROR_WINDOW_MS: u64 = 60_000;
+
+/// Per-camera write outcomes since startup.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CameraHealth {
+    /// Frames dropped because the camera's storage buffer was full.
+    pub frames_dropped: u64,
+    /// Frames that failed to store (disk full, storage unmounted, ...).
+    pub store_errors: u64,
+    pub last_error: Option<String>,
+    /// Epoch ms
+    pub last_error_at: Option<u64>,
+}
+
+/// Space on the filesystem holding the recordings.
+#[derive(Debug, Clone, Serialize)]
+pub struct DiskSpace {
+    pub path: PathBuf,
+    pub free_bytes: u64,
+    pub total_bytes: u64,
+}
+
+impl DiskSpace {
+    pub fn measure(path: &Path) -> std::io::Result<Self> {
+        Ok(Self {
+            path: path.to_path_buf(),
+            free_bytes: fs4::available_space(path)?,
+            total_bytes: fs4::total_space(path)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthState {
+    Ok,
+    /// Free space is below the threshold; emergency cleanup is running.
+    LowSpace,
+    /// Writes are failing for at least one camera.
+    Failing,
+    /// A storage root is failing; recording continues on the others.
+    Degraded,
+}
+
+/// Snapshot served by `GET /api/storage` and the `storage-status` event.
+#[derive(Debug, Clone, Serialize)]
+pub struct StorageStatus {
+    pub state: HealthState,
+    /// `None` until first measured, or if the storage path is unreachable.
+    pub disk: Option<DiskSpace>,
+    pub min_free_bytes: u64,
+    /// Bytes of finished segments in the index.
+    pub used_bytes: u64,
+    pub max_bytes: u64,
+    pub segments: usize,
+    /// Times the pipeline fell behind the broadcast.
+    pub lag_events: u64,
+    /// Frames missed by the whole pipeline when the broadcast lagged.
+    pub frames_lagged: u64,
+    /// Segments deleted early to recover free space.
+    pub emergency_removed: u64,
+    /// Result of the startup integrity scan.
+    pub scan: Option<ScanReport>,
+    pub cameras: BTreeMap<String, CameraHealth>,
+    /// Each storage root, when there are several.
+    pub roots: Vec<RootStatus>,
+}
+
+/// Shared health counters.
+#[derive(Default)]
+pub struct StorageHealth {
+    cameras: Mutex<BTreeMap<String, CameraHealth>>,
+    lag_events: AtomicU64,
+    frames_lagged: AtomicU64,
+    write_latency: Histogram,
+    emergency_removed: AtomicU64,
+    disk: Mutex<Option<DiskSpace>>,
+    scan: Mutex<Option<ScanReport>>,
+    /// Wakes the health task early after a store error.
+    pub(super) error_notify: Notify,
+}
+
+impl StorageHealth {
+    pub fn frame_dropped(&self, camera_id: &str) {
+        self.camera(camera_id, |c| c.frames_dropped += 1);
+    }
+
+    pub fn frames_lagged(&self, n: u64) {
+        self.lag_events.fetch_add(1, Ordering::Relaxed);
+        self.frames_lagged.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Time taken to store one frame, successful or not.
+    pub fn store_latency(&self, d: Duration) {
+        self.write_latency.observe(d);
+    }
+
+    pub fn write_latency(&self) -> &Histogram {
+        &self.write_latency
+    }
+
+    pub fn store_failed(&self, camera_id: &str, error: &anyhow::Error) {
+        let first = self.camera(camera_id, |c| {
+            let first = c
+                .last_error_at
+                .is_none_or(|at| now_ms().saturating_sub(at) > ERROR_WINDOW_MS);
+            c.store_errors += 1;
+            c.last_error = Some(error.to_string());
+            c.last_error_at = Some(now_ms());
+            first
+        });
+        if first {
+            tracing::error!(camera = %camera_id, error = %error, "Recording write failed");
+            self.error_notify.notify_one();
+        }
+    }
+
+    pub(super) fn set_disk(&self, disk: Option<DiskSpace>) {
+        *self.disk.lock().unwrap() = disk;
+    }
+
+    pub(super) fn disk(&self) -> Option<DiskSpace> {
+        self.disk.lock().unwrap().clone()
+    }
+
+    pub(super) fn set_scan(&self, report: ScanReport) {
+        *self.scan.lock().unwrap() = Some(report);
+    }
+
+    pub(super) fn emergency_removed(&self, n: usize) {
+        self.emergency_removed
+            .fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    /// Snapshot of the counters, with index totals and roots filled in by
+    /// the caller.
+    pub(super) fn status(
+        &self,
+        min_free_bytes: u64,
+        used_bytes: u64,
+        max_bytes: u64,
+        segments: usize,
+        roots: Vec<RootStatus>,
+    ) -> StorageStatus {
+        let cameras = self.cameras.lock().unwrap().clone();
+        let disk = self.disk();
+        let now = now_ms();
+        let failing = cameras.values().any(|c| {
+            c.last_error_at
+                .is_some_and(|at| now.saturating_sub(at) <= ERROR_WINDOW_MS)
+        });
+        let state = if failing {
+            HealthState::Failing
+        } else if roots.iter().any(|r| r.failing) {
+            HealthState::Degraded
+        } else if disk.as_ref().is_some_and(|d| d.free_bytes < min_free_bytes) {
+            HealthState::LowSpace
+        } else {
+            HealthState::Ok
+        };
+        StorageStatus {
+            state,
+            disk,
+            min_free_bytes,
+            used_bytes,
+            max_bytes,
+            segments,
+            lag_events: self.lag_events.load(Ordering::Relaxed),
+            frames_lagged: self.frames_lagged.load(Ordering::Relaxed),
+            emergency_removed: self.emergency_removed.load(Ordering::Relaxed),
+            scan: self.scan.lock().unwrap().clone(),
+            cameras,
+            roots,
+        }
+    }
+
+    fn camera<T>(&self, camera_id: &str, f: impl FnOnce(&mut CameraHealth) -> T) -> T {
+        let mut cameras = self.cameras.lock().unwrap();
+        f(cameras.entry(camera_id.to_string()).or_default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn state_reflects_recent_errors_and_low_space() {
+        let health = StorageHealth::default();
+        assert_eq!(
+            health.status(100, 0, 0, 0, Vec::new()).state,
+            HealthState::Ok
+        );
+
+        health.set_disk(Some(DiskSpace {
+            path: "/rec".into(),
+            free_bytes: 50,
+            total_bytes: 1000,
+        }));
+        assert_eq!(
+            health.status(100, 0, 0, 0, Vec::new()).state,
+            HealthState::LowSpace
+        );
+
+        let root = RootStatus {
+            path: "/mirror".into(),
+            mode: crate::config::RootMode::Mirror,
+            used_bytes: 0,
+            max_bytes: None,
+            disk: None,
+            failing: true,
+            last_error: Some("Input/output error".into()),
+            last_error_at: Some(now_ms()),
+        };
+        assert_eq!(
+            health.status(100, 0, 0, 0, vec![root]).state,
+            HealthState::Degraded
+        );
+
+        health.frame_dropped("cam");
+        health.store_failed("cam", &anyhow::anyhow!("No space left on device"));
+        let status = health.status(100, 0, 0, 0, Vec::new());
+        assert_eq!(status.state, HealthState::Failing);
+        assert_eq!(status.cameras["cam"].frames_dropped, 1);
+        assert_eq!(status.cameras["cam"].store_errors, 1);
+        assert_eq!(
+            status.cameras["cam"].last_error.as_deref(),
+            Some("No space left on device")
+        );
+    }
+}
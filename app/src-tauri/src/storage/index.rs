@@ -0,0 +1,254 @@
+//! Recordings index.
+//!
+//! Tracks every finished segment so the timeline can be listed without
+//! touching segment files. The index is persisted as an append-only JSON
+//! lines log of add/remove operations next to the recordings, and compacted
+//! each time it is opened.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use super::segment::{SegmentKey, SegmentKind, Track};
+use super::tiered::Tier;
+
+/// A finished segment, as listed by `/api/recordings`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SegmentEntry {
+    pub camera_id: String,
+    /// Wall-clock time of the first frame, epoch ms.
+    #[serde(rename = "start")]
+    pub start_ms: u64,
+    /// Wall-clock time of the last frame, epoch ms.
+    #[serde(rename = "end")]
+    pub end_ms: u64,
+    #[serde(rename = "type")]
+    pub kind: SegmentKind,
+    #[serde(rename = "size")]
+    pub size_bytes: u64,
+    pub channels: BTreeSet<Track>,
+    /// Always hot without tiered storage.
+    #[serde(default)]
+    pub tier: Tier,
+}
+
+impl SegmentEntry {
+    pub fn key(&self) -> SegmentKey {
+        SegmentKey {
+            camera_id: self.camera_id.clone(),
+            start_ms: self.start_ms,
+        }
+    }
+
+    /// Whether the segment overlaps `[from, to]` (either bound optional).
+    pub fn overlaps(&self, from: Option<u64>, to: Option<u64>) -> bool {
+        from.is_none_or(|from| self.end_ms >= from) && to.is_none_or(|to| self.start_ms <= to)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum LogOp {
+    Add(SegmentEntry),
+    Remove(SegmentKey),
+}
+
+/// In-memory segment index backed by a log file.
+pub struct RecordingIndex {
+    log: File,
+    entries: BTreeMap<SegmentKey, SegmentEntry>,
+}
+
+impl RecordingIndex {
+    /// Open (or create) the index at `path`, replaying and compacting its log.
+    pub fn open(path: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let path = path.into();
+        let mut entries = BTreeMap::new();
+
+        if path.exists() {
+            for line in BufReader::new(File::open(&path)?).lines() {
+                let line = line?;
+                // A torn final line from a crash is expected; skip it
+                let Ok(op) = serde_json::from_str::<LogOp>(&line) else {
+                    continue;
+                };
+                match op {
+                    LogOp::Add(entry) => {
+                        entries.insert(entry.key(), entry);
+                    }
+                    LogOp::Remove(key) => {
+                        entries.remove(&key);
+                    }
+                }
+            }
+        }
+
+        let log = write_compacted(&path, entries.values())?;
+        Ok(Self { log, entries })
+    }
+
+    /// Add or replace a segment.
+    pub fn insert(&mut self, entry: SegmentEntry) -> anyhow::Result<()> {
+        self.append(&LogOp::Add(entry.clone()))?;
+        self.entries.insert(entry.key(), entry);
+        Ok(())
+    }
+
+    /// Remove a segment, returning its entry if it was indexed.
+    pub fn remove(&mut self, key: &SegmentKey) -> anyhow::Result<Option<SegmentEntry>> {
+        let removed = self.entries.remove(key);
+        if removed.is_some() {
+            self.append(&LogOp::Remove(key.clone()))?;
+        }
+        Ok(removed)
+    }
+
+    pub fn get(&self, key: &SegmentKey) -> Option<&SegmentEntry> {
+        self.entries.get(key)
+    }
+
+    /// Segments overlapping `[from, to]`, optionally for one camera, oldest first.
+    pub fn query(
+        &self,
+        camera_id: Option<&str>,
+        from: Option<u64>,
+        to: Option<u64>,
+    ) -> Vec<SegmentEntry> {
+        let mut out: Vec<SegmentEntry> = self
+            .entries
+            .values()
+            .filter(|e| camera_id.is_none_or(|id| e.camera_id == id))
+            .filter(|e| e.overlaps(from, to))
+            .cloned()
+            .collect();
+        out.sort_by_key(|e| e.start_ms);
+        out
+    }
+
+    /// Every segment, oldest first.
+    pub fn oldest_first(&self) -> Vec<SegmentEntry> {
+        self.query(None, None, None)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn total_bytes(&self) -> u64 {
+        self.entries.values().map(|e| e.size_bytes).sum()
+    }
+
+    fn append(&mut self, op: &LogOp) -> anyhow::Result<()> {
+        let mut line = serde_json::to_vec(op)?;
+        line.push(b'\n');
+        self.log.write_all(&line)?;
+        Ok(())
+    }
+}
+
+/// Rewrite the log with one `add` per entry and return it open for appending.
+fn write_compacted<'a>(
+    path: &Path,
+    entries: impl Iterator<Item = &'a SegmentEntry>,
+) -> anyhow::Result<File> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let tmp = path.with_extension("jsonl.tmp");
+    {
+        let mut file = File::create(&tmp)?;
+        for entry in entries {
+            let mut line = serde_json::to_vec(&LogOp::Add(entry.clone()))?;
+            line.push(b'\n');
+            file.write_all(&line)?;
+        }
+        file.sync_all()?;
+    }
+    std::fs::rename(&tmp, path)?;
+    Ok(OpenOptions::new().append(true).open(path)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(camera_id: &str, start_ms: u64, end_ms: u64) -> SegmentEntry {
+        SegmentEntry {
+            camera_id: camera_id.into(),
+            start_ms,
+            end_ms,
+            kind: SegmentKind::Continuous,
+            size_bytes: 100,
+            channels: [Track::Video].into(),
+            tier: Tier::Hot,
+        }
+    }
+
+    #[test]
+    fn query_filters_by_camera_and_range() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut index = RecordingIndex::open(dir.path().join("index.jsonl")).unwrap();
+        index.insert(entry("a", 0, 60_000)).unwrap();
+        index.insert(entry("a", 60_000, 120_000)).unwrap();
+        index.insert(entry("b", 30_000, 90_000)).unwrap();
+
+        let starts = |v: Vec<SegmentEntry>| v.iter().map(|e| e.start_ms).collect::<Vec<_>>();
+        assert_eq!(starts(index.query(Some("a"), None, None)), vec![0, 60_000]);
+        assert_eq!(
+            starts(index.query(None, Some(70_000), None)),
+            vec![30_000, 60_000]
+        );
+        assert_eq!(
+            starts(index.query(Some("a"), Some(10_000), Some(20_000))),
+            vec![0]
+        );
+        assert_eq!(index.total_bytes(), 300);
+    }
+
+    #[test]
+    fn log_survives_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("index.jsonl");
+        {
+            let mut index = RecordingIndex::open(&path).unwrap();
+            index.insert(entry("a", 0, 1)).unwrap();
+            index.insert(entry("a", 2, 3)).unwrap();
+            index.remove(&entry("a", 0, 1).key()).unwrap();
+        }
+
+        let index = RecordingIndex::open(&path).unwrap();
+        assert_eq!(index.entries.len(), 1);
+        assert!(index.entries.contains_key(&entry("a", 2, 3).key()));
+        // Compacted: the add/remove pair is gone
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 1);
+    }
+
+    #[test]
+    fn torn_line_is_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("index.jsonl");
+        {
+            let mut index = RecordingIndex::open(&path).unwrap();
+            index.insert(entry("a", 0, 1)).unwrap();
+        }
+        let mut log = OpenOptions::new().append(true).open(&path).unwrap();
+        log.write_all(br#"{"op":"add","camera_id":"a","sta"#)
+            .unwrap();
+
+        let index = RecordingIndex::open(&path).unwrap();
+        assert_eq!(index.entries.len(), 1);
+    }
+
+    #[test]
+    fn serializes_for_timeline() {
+        let json = serde_json::to_value(entry("a", 1, 2)).unwrap();
+        assert_eq!(json["start"], 1);
+        assert_eq!(json["end"], 2);
+        assert_eq!(json["type"], "continuous");
+        assert_eq!(json["size"], 100);
+        assert_eq!(json["channels"], serde_json::json!(["video"]));
+    }
+}
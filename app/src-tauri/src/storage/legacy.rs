@@ -0,0 +1,216 @@
+//! Import of recordings made by kodama's own storage.
+//!
+//! Before the app kept its own segments, recordings were written by
+//! kodama's `LocalStorage` under the same `KODAMA_STORAGE_PATH`, one file
+//! per camera segment:
+//!
+//! ```text
+//! path:   <camera_id>/<name>.seg
+//! record: channel(1) + flags(1) + timestamp_us(8) + len(4) + payload
+//! ```
+//!
+//! with little-endian integers, kodama's channel order (video, audio,
+//! telemetry) and bit 0 of `flags` marking keyframes. The files carry no
+//! wall-clock time, so a segment is placed to end at the file's
+//! modification time.
+//!
+//! At startup each such file is rewritten as a native segment through the
+//! backend, indexed, and removed. Unreadable files are left in place.
+
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+use bytes::{Buf, Bytes};
+
+use super::backend::StorageBackend;
+use super::index::{RecordingIndex, SegmentEntry};
+use super::segment::{SegmentHeader, SegmentKey, SegmentKind, StoredFrame, Track};
+use super::tiered::Tier;
+
+const EXTENSION: &str = "seg";
+const RECORD_HEADER_LEN: usize = 14;
+const FLAG_KEYFRAME: u8 = 0x01;
+
+/// Read the frames of a legacy segment, up to its last complete record.
+fn parse(data: &Bytes) -> Vec<StoredFrame> {
+    let mut frames = Vec::new();
+    let mut pos = 0;
+    while data.len() - pos >= RECORD_HEADER_LEN {
+        let mut rec = &data[pos..pos + RECORD_HEADER_LEN];
+        let channel = rec.get_u8();
+        let flags = rec.get_u8();
+        let timestamp_us = rec.get_u64_le();
+        let len = rec.get_u32_le() as usize;
+
+        let start = pos + RECORD_HEADER_LEN;
+        if data.len() - start < len {
+            break;
+        }
+        let track = match channel {
+            0 => Some(Track::Video),
+            1 => Some(Track::Audio),
+            2 => Some(Track::Telemetry),
+            _ => None,
+        };
+        if let Some(track) = track {
+            frames.push(StoredFrame {
+                track,
+                keyframe: track == Track::Video && flags & FLAG_KEYFRAME != 0,
+                timestamp_us,
+                payload: data.slice(start..start + len),
+            });
+        }
+        pos = start + len;
+    }
+    frames
+}
+
+/// Import every legacy segment under `root` into `backend` and `index`.
+/// `kind` gives the segment kind per camera. Returns how many were
+/// imported.
+pub(super) async fn import(
+    root: &Path,
+    backend: &dyn StorageBackend,
+    index: &Mutex<RecordingIndex>,
+    kind: impl Fn(&str) -> SegmentKind,
+) -> anyhow::Result<usize> {
+    let mut imported = 0;
+    let mut dirs = tokio::fs::read_dir(root).await?;
+    while let Some(dir) = dirs.next_entry().await? {
+        if !dir.file_type().await?.is_dir() {
+            continue;
+        }
+        let Some(camera_id) = dir.file_name().to_str().map(String::from) else {
+            continue;
+        };
+        let mut files = tokio::fs::read_dir(dir.path()).await?;
+        while let Some(file) = files.next_entry().await? {
+            let path = file.path();
+            if path.extension().is_none_or(|ext| ext != EXTENSION) {
+                continue;
+            }
+            match import_file(&path, &camera_id, backend, index, &kind).await {
+                Ok(()) => imported += 1,
+                Err(e) => {
+                    tracing::warn!(file = ?path, error = %e, "Failed to import earlier recording")
+                }
+            }
+        }
+    }
+    Ok(imported)
+}
+
+async fn import_file(
+    path: &Path,
+    camera_id: &str,
+    backend: &dyn StorageBackend,
+    index: &Mutex<RecordingIndex>,
+    kind: &impl Fn(&str) -> SegmentKind,
+) -> anyhow::Result<()> {
+    let data = Bytes::from(tokio::fs::read(path).await?);
+    let frames = parse(&data);
+    let (Some(first), Some(last)) = (frames.first(), frames.last()) else {
+        anyhow::bail!("no frames");
+    };
+    let modified_ms = tokio::fs::metadata(path)
+        .await?
+        .modified()?
+        .duration_since(UNIX_EPOCH)?
+        .as_millis() as u64;
+    let span_ms = last.timestamp_us.saturating_sub(first.timestamp_us) / 1000;
+    let header = SegmentHeader {
+        start_ms: modified_ms.saturating_sub(span_ms),
+        base_ts_us: first.timestamp_us,
+    };
+    let key = SegmentKey {
+        camera_id: camera_id.to_string(),
+        start_ms: header.start_ms,
+    };
+    if index.lock().unwrap().get(&key).is_some() {
+        anyhow::bail!("a segment starting at {} ms already exists", key.start_ms);
+    }
+
+    let mut segment = header.encode().to_vec();
+    for frame in &frames {
+        segment.extend(frame.encode());
+    }
+    backend.append(&key, &segment).await?;
+    backend.finish(&key).await?;
+    index.lock().unwrap().insert(SegmentEntry {
+        camera_id: key.camera_id.clone(),
+        start_ms: key.start_ms,
+        end_ms: header.wall_ms(last.timestamp_us),
+        kind: kind(camera_id),
+        size_bytes: segment.len() as u64,
+        channels: frames.iter().map(|f| f.track).collect(),
+        tier: Tier::Hot,
+    })?;
+    tokio::fs::remove_file(path).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::storage::manager::{test_config, test_manager};
+
+    /// A record as kodama's storage wrote it.
+    fn record(channel: u8, flags: u8, timestamp_us: u64, payload: &[u8]) -> Vec<u8> {
+        let mut out = vec![channel, flags];
+        out.extend_from_slice(&timestamp_us.to_le_bytes());
+        out.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        out.extend_from_slice(payload);
+        out
+    }
+
+    #[tokio::test]
+    async fn imports_kodama_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let camera = dir.path().join("0011223344556677");
+        std::fs::create_dir(&camera).unwrap();
+        let mut data = record(0, 1, 5_000_000, b"key");
+        data.extend(record(2, 0, 5_500_000, b"{}"));
+        data.extend(record(0, 0, 7_000_000, b"delta"));
+        data.extend(&record(1, 0, 7_500_000, b"torn")[..9]);
+        std::fs::write(camera.join("segment_0001.seg"), &data).unwrap();
+        std::fs::write(camera.join("broken.seg"), b"short").unwrap();
+        std::fs::write(camera.join("1000.jpg"), b"thumbnail").unwrap();
+        let modified_ms = std::fs::metadata(camera.join("segment_0001.seg"))
+            .unwrap()
+            .modified()
+            .unwrap()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+
+        let manager = test_manager(dir.path(), test_config());
+        let imported = manager
+            .import_legacy(dir.path(), |_| SegmentKind::Continuous)
+            .await
+            .unwrap();
+        assert_eq!(imported, 1);
+
+        let entries = manager.list(Some("0011223344556677"), None, None);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].start_ms, modified_ms - 2000);
+        assert_eq!(entries[0].end_ms, modified_ms);
+        let segment = manager.read(&entries[0].key()).await.unwrap();
+        let tracks: Vec<_> = segment.frames.iter().map(|f| f.track).collect();
+        assert_eq!(tracks, [Track::Video, Track::Telemetry, Track::Video]);
+        assert!(segment.frames[0].keyframe);
+        assert_eq!(&segment.frames[2].payload[..], b"delta");
+
+        assert!(!camera.join("segment_0001.seg").exists());
+        assert!(camera.join("broken.seg").exists());
+        assert!(camera.join("1000.jpg").exists());
+
+        // Nothing left to import on the next start
+        let imported = manager
+            .import_legacy(dir.path(), |_| SegmentKind::Continuous)
+            .await
+            .unwrap();
+        assert_eq!(imported, 0);
+    }
+}
@@ -0,0 +1,191 @@
+//! Local filesystem storage backend.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+use super::backend::StorageBackend;
+use super::segment::SegmentKey;
+
+/// Configuration for [`LocalStorage`].
+#[derive(Debug, Clone)]
+pub struct LocalStorageConfig {
+    pub root_path: PathBuf,
+}
+
+/// Quarantined segments are moved under `root_path/quarantine/`.
+const QUARANTINE_DIR: &str = "quarantine";
+
+/// Stores segments as files under `root_path/<camera_id>/<start_ms>.kseg`.
+pub struct LocalStorage {
+    root: PathBuf,
+    /// Open handles for segments still being written.
+    open: Mutex<HashMap<SegmentKey, File>>,
+}
+
+impl LocalStorage {
+    pub fn new(config: LocalStorageConfig) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(&config.root_path)?;
+        Ok(Self {
+            root: config.root_path,
+            open: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn path_for(&self, key: &SegmentKey) -> PathBuf {
+        self.root.join(key.relative_path())
+    }
+}
+
+#[async_trait]
+impl StorageBackend for LocalStorage {
+    async fn append(&self, key: &SegmentKey, data: &[u8]) -> anyhow::Result<()> {
+        let mut open = self.open.lock().await;
+        if !open.contains_key(key) {
+            let path = self.path_for(key);
+            if let Some(parent) = path.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .await?;
+            open.insert(key.clone(), file);
+        }
+        let file = open.get_mut(key).expect("inserted above");
+        file.write_all(data).await?;
+        // tokio hands writes to a blocking thread; wait for it so readers of
+        // an open segment see every appended record
+        file.flush().await?;
+        Ok(())
+    }
+
+    async fn read(&self, key: &SegmentKey) -> anyhow::Result<Bytes> {
+        Ok(tokio::fs::read(self.path_for(key)).await?.into())
+    }
+
+    async fn finish(&self, key: &SegmentKey) -> anyhow::Result<()> {
+        if let Some(file) = self.open.lock().await.remove(key) {
+            file.sync_all().await?;
+        }
+        Ok(())
+    }
+
+    async fn delete(&self, key: &SegmentKey) -> anyhow::Result<()> {
+        self.open.lock().await.remove(key);
+        tokio::fs::remove_file(self.path_for(key)).await?;
+        Ok(())
+    }
+
+    async fn list(&self) -> anyhow::Result<Vec<SegmentKey>> {
+        let mut keys = Vec::new();
+        let mut dirs = tokio::fs::read_dir(&self.root).await?;
+        while let Some(dir) = dirs.next_entry().await? {
+            if !dir.file_type().await?.is_dir() || dir.file_name() == QUARANTINE_DIR {
+                continue;
+            }
+            let Some(camera_id) = dir.file_name().to_str().map(String::from) else {
+                continue;
+            };
+            let mut files = tokio::fs::read_dir(dir.path()).await?;
+            while let Some(file) = files.next_entry().await? {
+                let name = file.file_name();
+                let start_ms = name
+                    .to_str()
+                    .and_then(|n| n.strip_suffix(".kseg"))
+                    .and_then(|n| n.parse().ok());
+                if let Some(start_ms) = start_ms {
+                    keys.push(SegmentKey {
+                        camera_id: camera_id.clone(),
+                        start_ms,
+                    });
+                }
+            }
+        }
+        keys.sort();
+        Ok(keys)
+    }
+
+    async fn truncate(&self, key: &SegmentKey, len: u64) -> anyhow::Result<()> {
+        let file = OpenOptions::new()
+            .write(true)
+            .open(self.path_for(key))
+            .await?;
+        file.set_len(len).await?;
+        file.sync_all().await?;
+        Ok(())
+    }
+
+    async fn quarantine(&self, key: &SegmentKey) -> anyhow::Result<()> {
+        self.open.lock().await.remove(key);
+        let dest = self.root.join(QUARANTINE_DIR).join(key.relative_path());
+        if let Some(parent) = dest.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::rename(self.path_for(key), dest).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn append_read_delete() {
+        let root = tempfile::tempdir().unwrap();
+        let storage = LocalStorage::new(LocalStorageConfig {
+            root_path: root.path().to_path_buf(),
+        })
+        .unwrap();
+        let key = SegmentKey {
+            camera_id: "cam".into(),
+            start_ms: 1,
+        };
+
+        storage.append(&key, b"hello ").await.unwrap();
+        storage.append(&key, b"world").await.unwrap();
+        storage.finish(&key).await.unwrap();
+        assert_eq!(&storage.read(&key).await.unwrap()[..], b"hello world");
+
+        storage.delete(&key).await.unwrap();
+        assert!(!root.path().join("cam/1.kseg").exists());
+        assert!(storage.read(&key).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn list_truncate_quarantine() {
+        let root = tempfile::tempdir().unwrap();
+        let storage = LocalStorage::new(LocalStorageConfig {
+            root_path: root.path().to_path_buf(),
+        })
+        .unwrap();
+        let key = |camera_id: &str, start_ms| SegmentKey {
+            camera_id: camera_id.into(),
+            start_ms,
+        };
+        for k in [key("b", 5), key("a", 2), key("a", 1)] {
+            storage.append(&k, b"data").await.unwrap();
+            storage.finish(&k).await.unwrap();
+        }
+        std::fs::create_dir(root.path().join("exports")).unwrap();
+        std::fs::write(root.path().join("exports/1.mp4"), b"").unwrap();
+        std::fs::write(root.path().join("index.jsonl"), b"").unwrap();
+
+        let keys = storage.list().await.unwrap();
+        assert_eq!(keys, vec![key("a", 1), key("a", 2), key("b", 5)]);
+
+        storage.truncate(&key("a", 1), 2).await.unwrap();
+        assert_eq!(&storage.read(&key("a", 1)).await.unwrap()[..], b"da");
+
+        storage.quarantine(&key("b", 5)).await.unwrap();
+        assert!(root.path().join("quarantine/b/5.kseg").exists());
+        assert_eq!(storage.list().await.unwrap().len(), 2);
+    }
+}
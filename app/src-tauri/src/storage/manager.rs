@@ -0,0 +1,1025 @@
+//! Segment recording, indexing and retention.
+
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use kodama::Frame;
+
+use crate::events::{AppEvent, EventBus};
+
+use super::backend::StorageBackend;
+use super::health::{DiskSpace, StorageHealth, StorageStatus};
+use super::index::{RecordingIndex, SegmentEntry};
+use super::legacy;
+use super::protected::ProtectedClips;
+use super::repair::{self, ScanReport};
+use super::roots::MultiRootStorage;
+use super::segment::{
+    Container, ParsedSegment, SegmentHeader, SegmentKey, SegmentKind, SegmentWriter, StoredFrame,
+    Track,
+};
+use super::thumbnails::Thumbnails;
+use super::tiered::{self, Tier, TieredStorage};
+
+/// A gap in camera timestamps longer than this starts a new segment.
+const MAX_GAP_US: u64 = 5_000_000;
+
+/// How often the migration task looks for segments to move to cold storage.
+const MIGRATION_INTERVAL: Duration = Duration::from_secs(300);
+
+/// How often the health task samples free space and publishes status.
+const HEALTH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Recording policy.
+#[derive(Debug, Clone)]
+pub struct StorageConfig {
+    /// Oldest segments are evicted once the index exceeds this size.
+    pub max_size_bytes: u64,
+    /// Segments older than this are deleted.
+    pub retention_secs: u64,
+    /// Store only video keyframes (and telemetry).
+    pub keyframes_only: bool,
+    pub cleanup_interval_secs: u64,
+    /// Emergency cleanup deletes the oldest segments when free disk space
+    /// drops below this.
+    pub min_free_bytes: u64,
+    /// Segments roll at the first keyframe after this much footage.
+    pub segment_duration_us: u64,
+}
+
+/// Owns the backend and index; hands out a [`SegmentRecorder`] per camera.
+pub struct StorageManager {
+    config: StorageConfig,
+    backend: Arc<dyn StorageBackend>,
+    index: Arc<Mutex<RecordingIndex>>,
+    health: Arc<StorageHealth>,
+    thumbnails: Option<Arc<Thumbnails>>,
+    protected: Option<Arc<ProtectedClips>>,
+    roots: Option<Arc<MultiRootStorage>>,
+    cleanup_task: Option<tokio::task::JoinHandle<()>>,
+    health_task: Option<tokio::task::JoinHandle<()>>,
+    migration_task: Option<tokio::task::JoinHandle<()>>,
+}
+
+impl StorageManager {
+    pub fn new(
+        config: StorageConfig,
+        backend: Arc<dyn StorageBackend>,
+        index: RecordingIndex,
+    ) -> Self {
+        Self {
+            config,
+            backend,
+            index: Arc::new(Mutex::new(index)),
+            health: Arc::default(),
+            thumbnails: None,
+            protected: None,
+            roots: None,
+            cleanup_task: None,
+            health_task: None,
+            migration_task: None,
+        }
+    }
+
+    /// Periodically enforce retention and the size cap.
+    pub fn start_cleanup_task(&mut self) {
+        let config = self.config.clone();
+        let backend = self.backend.clone();
+        let index = self.index.clone();
+        let thumbnails = self.thumbnails.clone();
+        let protected = self.protected.clone();
+        self.cleanup_task = Some(tokio::spawn(async move {
+            let mut interval =
+                tokio::time::interval(Duration::from_secs(config.cleanup_interval_secs));
+            loop {
+                interval.tick().await;
+                let now = now_ms();
+                match cleanup(&config, backend.as_ref(), &index, protected.as_deref(), now).await {
+                    Ok(0) => {}
+                    Ok(n) => tracing::info!("Storage cleanup removed {} segments", n),
+                    Err(e) => tracing::warn!("Storage cleanup failed: {}", e),
+                }
+                if let Some(thumbnails) = &thumbnails {
+                    let cutoff_ms = now.saturating_sub(config.retention_secs * 1000);
+                    thumbnails.prune(&index, cutoff_ms);
+                }
+            }
+        }));
+    }
+
+    /// Take keyframe thumbnails while recording. Call before creating
+    /// recorders and starting cleanup, which prunes them.
+    pub fn set_thumbnails(&mut self, thumbnails: Arc<Thumbnails>) {
+        self.thumbnails = Some(thumbnails);
+    }
+
+    pub fn thumbnails(&self) -> Option<&Arc<Thumbnails>> {
+        self.thumbnails.as_ref()
+    }
+
+    /// Exempt the segments covered by `protected` from all cleanup. Call
+    /// before starting the cleanup and health tasks.
+    pub fn set_protected_clips(&mut self, protected: Arc<ProtectedClips>) {
+        self.protected = Some(protected);
+    }
+
+    pub fn protected_clips(&self) -> Option<&Arc<ProtectedClips>> {
+        self.protected.as_ref()
+    }
+
+    /// Report the state of each root of `roots`, which must be (or sit
+    /// under) this manager's backend. Call before starting the health task.
+    pub fn set_roots(&mut self, roots: Arc<MultiRootStorage>) {
+        self.roots = Some(roots);
+    }
+
+    /// Periodically move segments that ended more than `cold_after_secs`
+    /// ago from the hot to the cold tier of `tiered`, which must be (or sit
+    /// under) this manager's backend.
+    pub fn start_migration_task(&mut self, tiered: Arc<TieredStorage>, cold_after_secs: u64) {
+        let index = self.index.clone();
+        self.migration_task = Some(tokio::spawn(async move {
+            let mut interval = tokio::time::interval(MIGRATION_INTERVAL);
+            loop {
+                interval.tick().await;
+                let cutoff_ms = now_ms().saturating_sub(cold_after_secs * 1000);
+                let moved = tiered::migrate_older_than(&tiered, &index, cutoff_ms).await;
+                if moved > 0 {
+                    tracing::info!("Moved {} segments to cold storage", moved);
+                }
+            }
+        }));
+    }
+
+    /// Sample free space under `root` and publish status to `events`
+    /// periodically, and right after a write fails. Runs emergency cleanup
+    /// when free space is below [`StorageConfig::min_free_bytes`].
+    pub fn start_health_task(&mut self, root: PathBuf, events: EventBus) {
+        let config = self.config.clone();
+        let backend = self.backend.clone();
+        let index = self.index.clone();
+        let health = self.health.clone();
+        let protected = self.protected.clone();
+        let roots = self.roots.clone();
+        self.health_task = Some(tokio::spawn(async move {
+            let mut interval = tokio::time::interval(HEALTH_INTERVAL);
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = health.error_notify.notified() => {}
+                }
+                let disk = match DiskSpace::measure(&root) {
+                    Ok(disk) => Some(disk),
+                    Err(e) => {
+                        tracing::warn!(path = ?root, error = %e, "Failed to read free disk space");
+                        None
+                    }
+                };
+                let low = disk
+                    .as_ref()
+                    .is_some_and(|d| d.free_bytes < config.min_free_bytes);
+                health.set_disk(disk);
+
+                if low {
+                    // Recover a margin above the threshold so writes don't
+                    // immediately push it under again
+                    let target = config.min_free_bytes + config.min_free_bytes / 4;
+                    let free = || DiskSpace::measure(&root).map(|d| d.free_bytes);
+                    let cleanup = emergency_cleanup(
+                        backend.as_ref(),
+                        &index,
+                        protected.as_deref(),
+                        target,
+                        free,
+                    );
+                    match cleanup.await {
+                        Ok(n) => {
+                            tracing::warn!(
+                                "Low disk space, emergency cleanup removed {} segments",
+                                n
+                            );
+                            health.emergency_removed(n);
+                            health.set_disk(DiskSpace::measure(&root).ok());
+                        }
+                        Err(e) => tracing::warn!("Emergency cleanup failed: {}", e),
+                    }
+                }
+                let status = status(&config, &index, &health, roots.as_deref());
+                events.publish(AppEvent::StorageStatus(status));
+            }
+        }));
+    }
+
+    /// Import recordings made by kodama's own storage under `root`, before
+    /// the app kept its own. Run before [`scan`](Self::scan); `kind` gives
+    /// the kind of imported segments per camera.
+    pub async fn import_legacy(
+        &self,
+        root: &Path,
+        kind: impl Fn(&str) -> SegmentKind,
+    ) -> anyhow::Result<usize> {
+        legacy::import(root, self.backend.as_ref(), &self.index, kind).await
+    }
+
+    /// Reconcile the index with the segments in storage, repairing or
+    /// quarantining any left incomplete by a crash. Run before recording
+    /// starts; `kind` gives the kind of recovered segments per camera.
+    pub async fn scan(&self, kind: impl Fn(&str) -> SegmentKind) -> anyhow::Result<ScanReport> {
+        let report = repair::scan(self.backend.as_ref(), &self.index, kind).await?;
+        if report.is_clean() {
+            tracing::info!(
+                segments = report.scanned,
+                ms = report.duration_ms,
+                "Storage scan found no problems"
+            );
+        } else {
+            tracing::warn!(
+                segments = report.scanned,
+                recovered = report.recovered,
+                truncated = report.truncated,
+                truncated_bytes = report.truncated_bytes,
+                quarantined = report.quarantined,
+                empty = report.empty,
+                missing = report.missing,
+                "Storage scan repaired segments"
+            );
+        }
+        self.health.set_scan(report.clone());
+        Ok(report)
+    }
+
+    /// Health counters for the recording pipeline to report into.
+    pub fn health(&self) -> Arc<StorageHealth> {
+        self.health.clone()
+    }
+
+    /// Current health snapshot.
+    pub fn status(&self) -> StorageStatus {
+        status(
+            &self.config,
+            &self.index,
+            &self.health,
+            self.roots.as_deref(),
+        )
+    }
+
+    /// Create the writer for one camera's segments.
+    pub fn recorder(&self, camera_id: String) -> SegmentRecorder {
+        SegmentRecorder {
+            camera_id,
+            backend: self.backend.clone(),
+            index: self.index.clone(),
+            thumbnails: self.thumbnails.clone(),
+            keyframes_only: self.config.keyframes_only,
+            segment_duration_us: self.config.segment_duration_us,
+            container: Container::Native,
+            open: None,
+        }
+    }
+
+    /// Finished segments overlapping `[from, to]`, oldest first.
+    pub fn list(
+        &self,
+        camera_id: Option<&str>,
+        from: Option<u64>,
+        to: Option<u64>,
+    ) -> Vec<SegmentEntry> {
+        self.index.lock().unwrap().query(camera_id, from, to)
+    }
+
+    /// Read and decode one segment.
+    pub async fn read(&self, key: &SegmentKey) -> anyhow::Result<ParsedSegment> {
+        let data = self.backend.read(key).await?;
+        let segment = ParsedSegment::parse(&data)?;
+        if segment.is_truncated(data.len()) {
+            tracing::warn!(segment = ?key, "Segment ends in a partial record");
+        }
+        Ok(segment)
+    }
+}
+
+impl Drop for StorageManager {
+    fn drop(&mut self) {
+        for task in [
+            self.cleanup_task.take(),
+            self.health_task.take(),
+            self.migration_task.take(),
+        ]
+        .into_iter()
+        .flatten()
+        {
+            task.abort();
+        }
+    }
+}
+
+/// Delete expired segments, then the oldest until under the size cap.
+/// Protected segments are kept, and still count toward the cap.
+async fn cleanup(
+    config: &StorageConfig,
+    backend: &dyn StorageBackend,
+    index: &Mutex<RecordingIndex>,
+    protected: Option<&ProtectedClips>,
+    now_ms: u64,
+) -> anyhow::Result<usize> {
+    let cutoff_ms = now_ms.saturating_sub(config.retention_secs * 1000);
+    let (segments, mut total) = {
+        let index = index.lock().unwrap();
+        (index.oldest_first(), index.total_bytes())
+    };
+
+    let mut removed = 0;
+    for entry in segments {
+        if entry.end_ms >= cutoff_ms && total <= config.max_size_bytes {
+            break;
+        }
+        if protected.is_some_and(|p| p.covers(&entry)) {
+            continue;
+        }
+        remove_segment(backend, index, &entry).await?;
+        total = total.saturating_sub(entry.size_bytes);
+        removed += 1;
+    }
+    Ok(removed)
+}
+
+/// Delete the oldest unprotected segments until `free_bytes` reports at
+/// least `target`.
+async fn emergency_cleanup(
+    backend: &dyn StorageBackend,
+    index: &Mutex<RecordingIndex>,
+    protected: Option<&ProtectedClips>,
+    target: u64,
+    free_bytes: impl Fn() -> std::io::Result<u64>,
+) -> anyhow::Result<usize> {
+    let segments = index.lock().unwrap().oldest_first();
+    let mut removed = 0;
+    for entry in segments {
+        if free_bytes()? >= target {
+            break;
+        }
+        if protected.is_some_and(|p| p.covers(&entry)) {
+            continue;
+        }
+        // Deleting uploaded segments wouldn't free anything here
+        if !backend.is_local(&entry.key()).await {
+            continue;
+        }
+        remove_segment(backend, index, &entry).await?;
+        removed += 1;
+    }
+    Ok(removed)
+}
+
+async fn remove_segment(
+    backend: &dyn StorageBackend,
+    index: &Mutex<RecordingIndex>,
+    entry: &SegmentEntry,
+) -> anyhow::Result<()> {
+    let key = entry.key();
+    if let Err(e) = backend.delete(&key).await {
+        tracing::warn!(segment = ?key, error = %e, "Failed to delete segment");
+    }
+    index.lock().unwrap().remove(&key)?;
+    Ok(())
+}
+
+fn status(
+    config: &StorageConfig,
+    index: &Mutex<RecordingIndex>,
+    health: &StorageHealth,
+    roots: Option<&MultiRootStorage>,
+) -> StorageStatus {
+    let (used_bytes, segments) = {
+        let index = index.lock().unwrap();
+        (index.total_bytes(), index.len())
+    };
+    health.status(
+        config.min_free_bytes,
+        used_bytes,
+        config.max_size_bytes,
+        segments,
+        roots.map(|r| r.status()).unwrap_or_default(),
+    )
+}
+
+struct OpenSegment {
+    key: SegmentKey,
+    header: SegmentHeader,
+    writer: SegmentWriter,
+    kind: SegmentKind,
+    last_ts_us: u64,
+    end_ms: u64,
+    size_bytes: u64,
+    channels: BTreeSet<Track>,
+}
+
+/// Writes one camera's frames into rolling segments. Video, audio and
+/// telemetry are interleaved in the same segment, each with its capture
+/// timestamp.
+pub struct SegmentRecorder {
+    camera_id: String,
+    backend: Arc<dyn StorageBackend>,
+    index: Arc<Mutex<RecordingIndex>>,
+    thumbnails: Option<Arc<Thumbnails>>,
+    keyframes_only: bool,
+    segment_duration_us: u64,
+    container: Container,
+    open: Option<OpenSegment>,
+}
+
+impl SegmentRecorder {
+    /// Roll segments at the first keyframe after `us` of camera time
+    /// instead of the configured default.
+    pub fn with_segment_duration(mut self, us: u64) -> Self {
+        self.segment_duration_us = us;
+        self
+    }
+
+    /// Write new segments in `container`.
+    pub fn with_container(mut self, container: Container) -> Self {
+        self.container = container;
+        self
+    }
+
+    /// Store a frame received at wall-clock `wall_ms`, rolling segments as needed.
+    ///
+    /// Segments always start on a video keyframe; frames that arrive while
+    /// no segment is open are dropped until the next one. A failed write
+    /// closes the open segment at its last complete record.
+    pub async fn store(
+        &mut self,
+        frame: &Frame,
+        wall_ms: u64,
+        kind: SegmentKind,
+    ) -> anyhow::Result<()> {
+        let Some(stored) = StoredFrame::from_frame(frame) else {
+            return Ok(());
+        };
+        if self.keyframes_only
+            && (stored.track == Track::Audio || (stored.track == Track::Video && !stored.keyframe))
+        {
+            return Ok(());
+        }
+
+        let ts = stored.timestamp_us;
+        if let Some(open) = &self.open {
+            let discontinuity = ts < open.last_ts_us || ts - open.last_ts_us > MAX_GAP_US;
+            let due = stored.keyframe
+                && (ts.saturating_sub(open.header.base_ts_us) >= self.segment_duration_us
+                    || kind != open.kind);
+            if discontinuity || due {
+                self.finish().await?;
+            }
+        }
+
+        if self.open.is_none() {
+            if !stored.keyframe {
+                return Ok(());
+            }
+            let key = SegmentKey {
+                camera_id: self.camera_id.clone(),
+                start_ms: wall_ms,
+            };
+            let header = SegmentHeader {
+                start_ms: wall_ms,
+                base_ts_us: ts,
+            };
+            let (writer, head) = SegmentWriter::open(self.container, header, &stored);
+            if let Err(e) = self.backend.append(&key, &head).await {
+                // Part of the head may have been written; nothing to keep
+                if let Err(e) = self.backend.delete(&key).await {
+                    tracing::debug!(segment = ?key, error = %e, "Failed to remove torn segment");
+                }
+                return Err(e);
+            }
+            self.open = Some(OpenSegment {
+                key,
+                header,
+                writer,
+                kind,
+                last_ts_us: ts,
+                end_ms: wall_ms,
+                size_bytes: head.len() as u64,
+                channels: BTreeSet::new(),
+            });
+        }
+
+        let open = self.open.as_mut().expect("opened above");
+        let record = open.writer.record(&stored);
+        if let Err(e) = self.backend.append(&open.key, &record).await {
+            self.close_torn().await;
+            return Err(e);
+        }
+        open.last_ts_us = ts;
+        open.end_ms = open.header.wall_ms(ts);
+        open.size_bytes += record.len() as u64;
+        open.channels.insert(stored.track);
+        if let Some(thumbnails) = self.thumbnails.as_ref().filter(|_| stored.keyframe) {
+            thumbnails.offer(&self.camera_id, wall_ms, stored.payload);
+        }
+        Ok(())
+    }
+
+    /// Close the open segment, if any, and add it to the index.
+    pub async fn finish(&mut self) -> anyhow::Result<()> {
+        let Some(open) = self.open.take() else {
+            return Ok(());
+        };
+        self.close(open).await
+    }
+
+    /// Close the open segment after a failed append: cut it back to its
+    /// last complete record and index that, so the next keyframe starts a
+    /// clean segment. One that can't be cut back is quarantined.
+    async fn close_torn(&mut self) {
+        let Some(open) = self.open.take() else {
+            return;
+        };
+        let key = open.key.clone();
+        if open.channels.is_empty() {
+            // Only the head made it
+            if let Err(e) = self.backend.delete(&key).await {
+                tracing::debug!(segment = ?key, error = %e, "Failed to remove torn segment");
+            }
+            return;
+        }
+        if let Err(e) = self.backend.truncate(&key, open.size_bytes).await {
+            tracing::warn!(segment = ?key, error = %e, "Failed to truncate torn segment, quarantining it");
+            if let Err(e) = self.backend.quarantine(&key).await {
+                tracing::warn!(segment = ?key, error = %e, "Failed to quarantine torn segment");
+            }
+            return;
+        }
+        if let Err(e) = self.close(open).await {
+            tracing::warn!(segment = ?key, error = %e, "Failed to close torn segment");
+        }
+    }
+
+    /// Add a segment to the index, even if the backend failed to finish it:
+    /// everything up to `size_bytes` was written.
+    async fn close(&mut self, open: OpenSegment) -> anyhow::Result<()> {
+        let finished = self.backend.finish(&open.key).await;
+        let entry = SegmentEntry {
+            camera_id: open.key.camera_id,
+            start_ms: open.key.start_ms,
+            end_ms: open.end_ms,
+            kind: open.kind,
+            size_bytes: open.size_bytes,
+            channels: open.channels,
+            tier: Tier::Hot,
+        };
+        tracing::debug!(camera = %entry.camera_id, start = entry.start_ms, size = entry.size_bytes, "Segment finished");
+        self.index.lock().unwrap().insert(entry)?;
+        finished
+    }
+
+    pub fn camera_id(&self) -> &str {
+        &self.camera_id
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.open.is_some()
+    }
+}
+
+/// Current wall-clock time in epoch milliseconds.
+pub fn now_ms() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Manager over a fresh local backend in `dir`, for tests.
+#[cfg(test)]
+pub(crate) fn test_manager(dir: &std::path::Path, config: StorageConfig) -> StorageManager {
+    use super::local::{LocalStorage, LocalStorageConfig};
+
+    let backend = LocalStorage::new(LocalStorageConfig {
+        root_path: dir.to_path_buf(),
+    })
+    .unwrap();
+    let index = RecordingIndex::open(dir.join("index.jsonl")).unwrap();
+    StorageManager::new(config, Arc::new(backend), index)
+}
+
+#[cfg(test)]
+pub(crate) fn test_config() -> StorageConfig {
+    StorageConfig {
+        max_size_bytes: u64::MAX,
+        retention_secs: 3600,
+        keyframes_only: false,
+        cleanup_interval_secs: 3600,
+        min_free_bytes: 0,
+        segment_duration_us: 60_000_000,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bytes::Bytes;
+    use kodama::{Channel, FrameFlags, SourceId};
+
+    use crate::media::h264::tests::keyframe_au;
+    use crate::storage::backend::FlakyStorage;
+
+    fn frame(channel: Channel, keyframe: bool, timestamp_ms: u64) -> Frame {
+        Frame {
+            source: SourceId::from_node_id_bytes(&[1; 32]),
+            channel,
+            flags: if keyframe {
+                FrameFlags::keyframe()
+            } else {
+                FrameFlags::default()
+            },
+            timestamp_us: timestamp_ms * 1000,
+            payload: Bytes::from_static(b"data"),
+        }
+    }
+
+    fn config(segment_secs: u64) -> StorageConfig {
+        StorageConfig {
+            segment_duration_us: segment_secs * 1_000_000,
+            ..test_config()
+        }
+    }
+
+    fn manager(dir: &tempfile::TempDir, config: StorageConfig) -> StorageManager {
+        test_manager(dir.path(), config)
+    }
+
+    #[tokio::test]
+    async fn rolls_on_keyframe_after_duration() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = manager(&dir, config(2));
+        let mut rec = manager.recorder("cam".into());
+
+        // Keyframe every second, 1s = 1000ms of camera time
+        for t in 0..5u64 {
+            let ms = t * 1000;
+            rec.store(
+                &frame(Channel::Video, true, ms),
+                10_000 + ms,
+                SegmentKind::Continuous,
+            )
+            .await
+            .unwrap();
+            rec.store(
+                &frame(Channel::Audio, false, ms + 500),
+                10_500 + ms,
+                SegmentKind::Continuous,
+            )
+            .await
+            .unwrap();
+        }
+        rec.finish().await.unwrap();
+
+        let segments = manager.list(Some("cam"), None, None);
+        let bounds: Vec<_> = segments.iter().map(|s| (s.start_ms, s.end_ms)).collect();
+        assert_eq!(
+            bounds,
+            vec![(10_000, 11_500), (12_000, 13_500), (14_000, 14_500)]
+        );
+        assert_eq!(segments[0].channels, [Track::Video, Track::Audio].into());
+
+        let path = dir.path().join(segments[0].key().relative_path());
+        assert_eq!(
+            std::fs::metadata(path).unwrap().len(),
+            segments[0].size_bytes
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_append_closes_the_segment() {
+        async fn store(
+            rec: &mut SegmentRecorder,
+            channel: Channel,
+            keyframe: bool,
+            ms: u64,
+        ) -> anyhow::Result<()> {
+            let frame = frame(channel, keyframe, ms);
+            rec.store(&frame, 10_000 + ms, SegmentKind::Continuous)
+                .await
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let backend = Arc::new(FlakyStorage::new(dir.path()));
+        let index = RecordingIndex::open(dir.path().join("index.jsonl")).unwrap();
+        let manager = StorageManager::new(config(60), backend.clone(), index);
+        let mut rec = manager.recorder("cam".into());
+
+        store(&mut rec, Channel::Video, true, 0).await.unwrap();
+        store(&mut rec, Channel::Audio, false, 500).await.unwrap();
+        // The disk fills partway through a record
+        backend.set_failing(true);
+        assert!(store(&mut rec, Channel::Video, false, 1000).await.is_err());
+        assert!(!rec.is_open());
+        backend.set_failing(false);
+
+        // What was written is kept, cut back to the last whole record
+        let segments = manager.list(Some("cam"), None, None);
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].end_ms, 10_500);
+        let path = dir.path().join(segments[0].key().relative_path());
+        assert_eq!(
+            std::fs::metadata(path).unwrap().len(),
+            segments[0].size_bytes
+        );
+        let segment = manager.read(&segments[0].key()).await.unwrap();
+        assert_eq!(segment.frames.len(), 2);
+
+        // Nothing is appended after the torn record; the next keyframe
+        // starts a new segment
+        store(&mut rec, Channel::Video, false, 1500).await.unwrap();
+        assert!(!rec.is_open());
+        store(&mut rec, Channel::Video, true, 2000).await.unwrap();
+        rec.finish().await.unwrap();
+        let segments = manager.list(Some("cam"), None, None);
+        assert_eq!(segments.len(), 2);
+        assert_eq!(segments[1].start_ms, 12_000);
+        let segment = manager.read(&segments[1].key()).await.unwrap();
+        assert_eq!(segment.frames.len(), 1);
+
+        // A segment whose head fails is dropped altogether
+        backend.set_failing(true);
+        assert!(store(&mut rec, Channel::Video, true, 3000).await.is_err());
+        assert!(!rec.is_open());
+        assert!(!dir.path().join("cam/13000.kseg").exists());
+        assert_eq!(manager.list(Some("cam"), None, None).len(), 2);
+    }
+
+    #[tokio::test]
+    async fn recorder_overrides_duration_and_container() {
+        for container in [Container::Native, Container::Fmp4, Container::MpegTs] {
+            let dir = tempfile::tempdir().unwrap();
+            let manager = manager(&dir, config(60));
+            let mut rec = manager
+                .recorder("cam".into())
+                .with_segment_duration(1_000_000)
+                .with_container(container);
+
+            for t in 0..3u64 {
+                let ms = t * 1000;
+                let video = Frame {
+                    payload: Bytes::from(keyframe_au()),
+                    ..frame(Channel::Video, true, ms)
+                };
+                rec.store(&video, 10_000 + ms, SegmentKind::Continuous)
+                    .await
+                    .unwrap();
+                rec.store(
+                    &frame(Channel::Telemetry, false, ms + 500),
+                    10_500 + ms,
+                    SegmentKind::Continuous,
+                )
+                .await
+                .unwrap();
+            }
+            rec.finish().await.unwrap();
+
+            let segments = manager.list(Some("cam"), None, None);
+            assert_eq!(segments.len(), 3, "{:?}", container);
+            let segment = manager.read(&segments[1].key()).await.unwrap();
+            assert_eq!(segment.header.start_ms, 11_000);
+            let frames: Vec<_> = segment
+                .frames
+                .iter()
+                .map(|f| (f.track, f.keyframe, f.timestamp_us))
+                .collect();
+            assert_eq!(
+                frames,
+                [
+                    (Track::Video, true, 1_000_000),
+                    (Track::Telemetry, false, 1_500_000)
+                ],
+                "{:?}",
+                container
+            );
+            assert_eq!(segment.frames[0].payload, keyframe_au());
+            assert_eq!(segment.valid_len as u64, segments[1].size_bytes);
+        }
+    }
+
+    #[tokio::test]
+    async fn waits_for_keyframe_to_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = manager(&dir, config(60));
+        let mut rec = manager.recorder("cam".into());
+
+        rec.store(&frame(Channel::Video, false, 0), 0, SegmentKind::Continuous)
+            .await
+            .unwrap();
+        assert!(!rec.is_open());
+        rec.store(
+            &frame(Channel::Video, true, 40),
+            40,
+            SegmentKind::Continuous,
+        )
+        .await
+        .unwrap();
+        assert!(rec.is_open());
+    }
+
+    #[tokio::test]
+    async fn timestamp_reset_starts_new_segment() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = manager(&dir, config(60));
+        let mut rec = manager.recorder("cam".into());
+
+        rec.store(
+            &frame(Channel::Video, true, 50_000),
+            1_000,
+            SegmentKind::Continuous,
+        )
+        .await
+        .unwrap();
+        // Camera restarted: its clock went backwards
+        rec.store(
+            &frame(Channel::Video, true, 0),
+            2_000,
+            SegmentKind::Continuous,
+        )
+        .await
+        .unwrap();
+        rec.finish().await.unwrap();
+
+        assert_eq!(manager.list(None, None, None).len(), 2);
+    }
+
+    #[tokio::test]
+    async fn keyframes_only_skips_deltas_and_audio() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = manager(
+            &dir,
+            StorageConfig {
+                keyframes_only: true,
+                ..config(60)
+            },
+        );
+        let mut rec = manager.recorder("cam".into());
+
+        rec.store(&frame(Channel::Video, true, 0), 0, SegmentKind::Continuous)
+            .await
+            .unwrap();
+        rec.store(
+            &frame(Channel::Video, false, 40),
+            40,
+            SegmentKind::Continuous,
+        )
+        .await
+        .unwrap();
+        rec.store(
+            &frame(Channel::Audio, false, 50),
+            50,
+            SegmentKind::Continuous,
+        )
+        .await
+        .unwrap();
+        rec.finish().await.unwrap();
+
+        let segment = &manager.list(None, None, None)[0];
+        assert_eq!(segment.channels, [Track::Video].into());
+    }
+
+    #[tokio::test]
+    async fn cleanup_enforces_retention_and_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = StorageConfig {
+            retention_secs: 10,
+            ..config(1)
+        };
+        let manager = manager(&dir, config.clone());
+        let mut rec = manager.recorder("cam".into());
+        for t in 0..4u64 {
+            rec.store(
+                &frame(Channel::Video, true, t * 1000),
+                t * 10_000,
+                SegmentKind::Continuous,
+            )
+            .await
+            .unwrap();
+            rec.finish().await.unwrap();
+        }
+
+        // Segments end at 0, 10s, 20s, 30s; retention keeps >= 15s
+        let (backend, index) = (manager.backend.as_ref(), &manager.index);
+        let removed = cleanup(&config, backend, index, None, 25_000)
+            .await
+            .unwrap();
+        assert_eq!(removed, 2);
+
+        let size_cap = StorageConfig {
+            max_size_bytes: manager.list(None, None, None)[0].size_bytes,
+            ..config
+        };
+        cleanup(&size_cap, backend, index, None, 25_000)
+            .await
+            .unwrap();
+        let left: Vec<_> = manager
+            .list(None, None, None)
+            .iter()
+            .map(|s| s.start_ms)
+            .collect();
+        assert_eq!(left, vec![30_000]);
+    }
+
+    #[tokio::test]
+    async fn cleanup_keeps_protected_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = StorageConfig {
+            retention_secs: 10,
+            ..config(1)
+        };
+        let manager = manager(&dir, config.clone());
+        let mut rec = manager.recorder("cam".into());
+        for t in 0..4u64 {
+            rec.store(
+                &frame(Channel::Video, true, t * 1000),
+                t * 10_000,
+                SegmentKind::Continuous,
+            )
+            .await
+            .unwrap();
+            rec.finish().await.unwrap();
+        }
+        let protected = ProtectedClips::open(dir.path().join("protected.json")).unwrap();
+        let clip = protected
+            .add(
+                "cam".into(),
+                5_000,
+                12_000,
+                "Incident".into(),
+                String::new(),
+            )
+            .unwrap();
+        let left = || -> Vec<u64> {
+            manager
+                .list(None, None, None)
+                .iter()
+                .map(|s| s.start_ms)
+                .collect()
+        };
+
+        // Retention keeps >= 15s; the expired one at 10s is protected
+        let (backend, index) = (manager.backend.as_ref(), &manager.index);
+        cleanup(&config, backend, index, Some(&protected), 25_000)
+            .await
+            .unwrap();
+        assert_eq!(left(), vec![10_000, 20_000, 30_000]);
+        emergency_cleanup(backend, index, Some(&protected), 1, || Ok(0))
+            .await
+            .unwrap();
+        assert_eq!(left(), vec![10_000]);
+
+        protected.remove(&clip.id).unwrap();
+        cleanup(&config, backend, index, Some(&protected), 25_000)
+            .await
+            .unwrap();
+        assert!(left().is_empty());
+    }
+
+    #[tokio::test]
+    async fn emergency_cleanup_frees_oldest_until_target() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = manager(&dir, config(1));
+        let mut rec = manager.recorder("cam".into());
+        for t in 0..4u64 {
+            rec.store(
+                &frame(Channel::Video, true, t * 1000),
+                t * 10_000,
+                SegmentKind::Continuous,
+            )
+            .await
+            .unwrap();
+            rec.finish().await.unwrap();
+        }
+
+        // A disk holding just our segments, with 1000 bytes to spare
+        let segment_bytes = manager.list(None, None, None)[0].size_bytes;
+        let capacity = 1000 + 4 * segment_bytes;
+        let index = &manager.index;
+        let free = || Ok(capacity - index.lock().unwrap().total_bytes());
+        let removed = emergency_cleanup(
+            manager.backend.as_ref(),
+            index,
+            None,
+            1000 + 2 * segment_bytes,
+            free,
+        )
+        .await
+        .unwrap();
+        assert_eq!(removed, 2);
+        let left: Vec<_> = manager
+            .list(None, None, None)
+            .iter()
+            .map(|s| s.start_ms)
+            .collect();
+        assert_eq!(left, vec![20_000, 30_000]);
+        assert_eq!(manager.status().segments, 2);
+    }
+}
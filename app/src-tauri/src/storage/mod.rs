@@ -0,0 +1,300 @@
+//! Recording: the pipeline from the Router broadcast to segment storage.
+
+pub mod backend;
+pub mod encrypted;
+pub mod health;
+pub mod index;
+pub mod legacy;
+pub mod local;
+pub mod manager;
+pub mod playback;
+pub mod preroll;
+pub mod protected;
+pub mod repair;
+pub mod replay;
+pub mod roots;
+pub mod s3;
+pub mod segment;
+pub mod thumbnails;
+pub mod tiered;
+
+pub use backend::StorageBackend;
+pub use encrypted::{EncryptedStorage, Keyring};
+pub use index::{RecordingIndex, SegmentEntry};
+pub use local::{LocalStorage, LocalStorageConfig};
+pub use manager::{now_ms, SegmentRecorder, StorageConfig, StorageManager};
+pub use protected::{ProtectedClip, ProtectedClips};
+pub use roots::MultiRootStorage;
+pub use s3::{S3Config, S3Storage};
+pub use segment::{Container, SegmentKind};
+pub use thumbnails::{ThumbnailConfig, Thumbnails};
+pub use tiered::TieredStorage;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use kodama::server::RouterHandle;
+use tokio::sync::{broadcast, mpsc};
+
+use crate::config::{CameraRecordingConfig, ModeRecording, RecordingConfig, RecordingMode};
+use crate::modes::Modes;
+use health::StorageHealth;
+use preroll::{motion_active, EventGate};
+
+/// Close a camera's open segment after this long without frames, so a
+/// disconnected camera's last segment is indexed promptly.
+const IDLE_FINISH: Duration = Duration::from_secs(5);
+
+/// Longest segment a camera can be configured for.
+const MAX_SEGMENT_SECS: u64 = 60 * 60;
+
+/// Spawn storage with per-camera fan-out.
+///
+/// The global broadcast has a single shared buffer. If the storage task
+/// blocks on disk I/O, its broadcast cursor falls behind and it lags for ALL
+/// cameras. Instead, we drain the broadcast as fast as possible into
+/// per-camera mpsc channels. Each camera gets its own bounded buffer so a
+/// slow write for one camera doesn't starve others.
+///
+/// With arming `modes`, each camera records as its effect for the current
+/// mode says, switching when the mode changes.
+pub fn spawn_pipeline(
+    handle: RouterHandle,
+    manager: Arc<StorageManager>,
+    config: RecordingConfig,
+    modes: Option<Arc<Modes>>,
+) {
+    let health = manager.health();
+
+    // Fast drain: broadcast -> per-camera mpsc
+    tokio::spawn(async move {
+        let mut rx = handle.subscribe();
+        let mut camera_txs: HashMap<kodama::SourceId, mpsc::Sender<kodama::Frame>> = HashMap::new();
+
+        loop {
+            match rx.recv().await {
+                Ok(frame) => {
+                    let source = frame.source;
+                    let tx = camera_txs.entry(source).or_insert_with(|| {
+                        let (tx, rx) = mpsc::channel::<kodama::Frame>(256);
+                        let camera_id = source.to_string();
+                        let camera_config = config.for_camera(&camera_id).clone();
+                        let recorder = manager
+                            .recorder(camera_id)
+                            .with_segment_duration(
+                                camera_config.segment_secs.clamp(1, MAX_SEGMENT_SECS) * 1_000_000,
+                            )
+                            .with_container(camera_config.container);
+                        tokio::spawn(run_camera(
+                            rx,
+                            recorder,
+                            camera_config,
+                            modes.clone(),
+                            health.clone(),
+                        ));
+                        tracing::info!(camera = ?source, "Storage channel created");
+                        tx
+                    });
+
+                    // try_send: if this camera's buffer is full, drop
+                    // the frame rather than blocking the drain loop
+                    if let Err(mpsc::error::TrySendError::Full(_)) = tx.try_send(frame) {
+                        tracing::debug!(
+                            camera = ?source,
+                            "Storage buffer full, dropping frame"
+                        );
+                        health.frame_dropped(&source.to_string());
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    tracing::warn!("Storage broadcast lagged, missed {} frames", n);
+                    health.frames_lagged(n);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+/// Per-camera writer: gates frames through pre/post-roll, then stores them.
+async fn run_camera(
+    mut rx: mpsc::Receiver<kodama::Frame>,
+    mut recorder: SegmentRecorder,
+    base: CameraRecordingConfig,
+    modes: Option<Arc<Modes>>,
+    health: Arc<StorageHealth>,
+) {
+    let camera_id = recorder.camera_id().to_string();
+    let mut mode_rx = modes.as_ref().map(|m| m.subscribe());
+    let recording = |modes: &Option<Arc<Modes>>| {
+        modes
+            .as_ref()
+            .map_or(ModeRecording::Default, |m| m.effect(&camera_id).recording)
+    };
+    let mut config = with_mode(&base, recording(&modes));
+    let mut kind = config.as_ref().map(segment_kind);
+    let mut gate = config.as_ref().map(EventGate::new);
+    let mut clock = CameraClock::default();
+
+    loop {
+        let frame = match tokio::time::timeout(IDLE_FINISH, rx.recv()).await {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break,
+            Err(_) => {
+                if let Err(e) = recorder.finish().await {
+                    health.store_failed(recorder.camera_id(), &e);
+                }
+                continue;
+            }
+        };
+        clock.observe(frame.timestamp_us, now_ms());
+
+        // Mode changed: close the current clip and start over with the
+        // new settings, from the next keyframe
+        let changed = mode_rx
+            .as_mut()
+            .filter(|rx| rx.has_changed().unwrap_or(false));
+        if let Some(rx) = changed {
+            rx.mark_unchanged();
+            let next = with_mode(&base, recording(&modes));
+            if next.as_ref().map(|c| c.mode) != config.as_ref().map(|c| c.mode) {
+                tracing::info!(camera = ?frame.source, "Recording changed for mode");
+                if let Err(e) = recorder.finish().await {
+                    health.store_failed(recorder.camera_id(), &e);
+                }
+                kind = next.as_ref().map(segment_kind);
+                gate = next.as_ref().map(EventGate::new);
+                config = next;
+            }
+        }
+        let (Some(config), Some(gate), Some(kind)) = (&config, &mut gate, kind) else {
+            continue;
+        };
+
+        if let Some(active) = motion_active(&frame, config.motion_threshold) {
+            if active && !gate.is_recording() {
+                tracing::info!(
+                    camera = ?frame.source,
+                    frames = gate.buffered(),
+                    "Motion event, flushing pre-roll"
+                );
+            }
+            gate.set_event(active, frame.timestamp_us);
+        }
+        for f in gate.push(frame) {
+            let started = Instant::now();
+            let result = recorder
+                .store(&f, clock.wall_ms(f.timestamp_us), kind)
+                .await;
+            health.store_latency(started.elapsed());
+            if let Err(e) = result {
+                health.store_failed(recorder.camera_id(), &e);
+            }
+        }
+        // Post-roll over: close the clip so it shows up in the index
+        if !gate.is_recording() && recorder.is_open() {
+            if let Err(e) = recorder.finish().await {
+                health.store_failed(recorder.camera_id(), &e);
+            }
+        }
+    }
+    if let Err(e) = recorder.finish().await {
+        health.store_failed(recorder.camera_id(), &e);
+    }
+}
+
+/// A camera's recording settings under an arming mode's `recording`, or
+/// `None` if it doesn't record.
+pub fn with_mode(
+    config: &CameraRecordingConfig,
+    recording: ModeRecording,
+) -> Option<CameraRecordingConfig> {
+    let mode = match recording {
+        ModeRecording::Default => config.mode,
+        ModeRecording::Continuous => RecordingMode::Continuous,
+        ModeRecording::Event => RecordingMode::Event,
+        ModeRecording::Off => return None,
+    };
+    Some(CameraRecordingConfig {
+        mode,
+        ..config.clone()
+    })
+}
+
+/// What a camera's segments are recorded as, given its mode.
+pub fn segment_kind(config: &CameraRecordingConfig) -> SegmentKind {
+    match config.mode {
+        RecordingMode::Continuous => SegmentKind::Continuous,
+        RecordingMode::Event => SegmentKind::Motion,
+    }
+}
+
+/// Maps a camera's `timestamp_us` clock onto wall-clock epoch ms.
+///
+/// Anchored on the first frame seen and re-anchored whenever the mapping
+/// drifts from arrival time by more than [`CameraClock::MAX_SKEW_MS`], e.g.
+/// after the camera restarts and its clock resets. Frames buffered for
+/// pre-roll keep their true capture time.
+#[derive(Debug, Default)]
+struct CameraClock {
+    anchor: Option<(u64, u64)>,
+}
+
+impl CameraClock {
+    const MAX_SKEW_MS: i64 = 2000;
+
+    fn observe(&mut self, timestamp_us: u64, arrival_ms: u64) {
+        let skewed = self.anchor.is_none()
+            || (self.wall_ms(timestamp_us) as i64 - arrival_ms as i64).abs() > Self::MAX_SKEW_MS;
+        if skewed {
+            self.anchor = Some((timestamp_us, arrival_ms));
+        }
+    }
+
+    fn wall_ms(&self, timestamp_us: u64) -> u64 {
+        match self.anchor {
+            Some((ts, wall)) => {
+                (wall as i64 + (timestamp_us as i64 - ts as i64) / 1000).max(0) as u64
+            }
+            None => now_ms(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clock_maps_camera_time_to_wall_time() {
+        let mut clock = CameraClock::default();
+        clock.observe(1_000_000, 50_000);
+        assert_eq!(clock.wall_ms(1_000_000), 50_000);
+        assert_eq!(clock.wall_ms(500_000), 49_500);
+
+        // Jitter within tolerance keeps the anchor
+        clock.observe(2_000_000, 51_300);
+        assert_eq!(clock.wall_ms(2_000_000), 51_000);
+    }
+
+    #[test]
+    fn mode_overrides_recording() {
+        let config = CameraRecordingConfig::default();
+        assert_eq!(config.mode, RecordingMode::Continuous);
+        let same = with_mode(&config, ModeRecording::Default).unwrap();
+        assert_eq!(same.mode, RecordingMode::Continuous);
+        let event = with_mode(&config, ModeRecording::Event).unwrap();
+        assert_eq!(event.mode, RecordingMode::Event);
+        assert_eq!(event.pre_roll_secs, config.pre_roll_secs);
+        assert!(with_mode(&config, ModeRecording::Off).is_none());
+    }
+
+    #[test]
+    fn clock_reanchors_after_reset() {
+        let mut clock = CameraClock::default();
+        clock.observe(60_000_000, 100_000);
+        clock.observe(0, 101_000);
+        assert_eq!(clock.wall_ms(0), 101_000);
+    }
+}
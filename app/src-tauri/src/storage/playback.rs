@@ -0,0 +1,520 @@
+//! Playback of recorded video as a single fragmented MP4.
+//!
+//! Contiguous segments of one camera are stitched into one stream, so a
+//! browser `<video>` plays across 60s segment boundaries without a seam.
+//! The stream starts at the last keyframe at or before the requested time
+//! and ends at `to`, at the first recording gap, or where the encoder's
+//! parameter sets change (a new init segment would be needed).
+//!
+//! A request first lays the stream out: one fragment per GOP (split again
+//! at segment boundaries), with its byte offset and sample durations, but
+//! without keeping any media. Byte ranges are then served by rendering
+//! only the fragments they cover, so memory stays bounded by one segment
+//! however long the window.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use super::manager::StorageManager;
+use super::segment::{ParsedSegment, SegmentKey, Track};
+use crate::media::fmp4::{self, Sample, VIDEO_TIMESCALE};
+use crate::media::h264::{annexb_to_avcc, ParameterSets};
+
+/// Longest span a single playback request may cover.
+pub const MAX_WINDOW_MS: u64 = 10 * 60 * 1000;
+
+/// Segments further apart than this are not joined.
+const MAX_JOIN_GAP_MS: u64 = 2000;
+
+/// Duration given to the final frame, which has no successor to measure against.
+const LAST_FRAME_US: u64 = 33_333;
+
+const CACHE_ENTRIES: usize = 4;
+const CACHE_TTL: Duration = Duration::from_secs(30);
+
+/// The layout of an fMP4 stream, rendered on demand.
+#[derive(Debug)]
+pub struct Playback {
+    /// Wall-clock time of the first frame (epoch ms).
+    pub start_ms: u64,
+    /// Wall-clock time the last frame ends (epoch ms).
+    pub end_ms: u64,
+    /// Length of the whole stream in bytes.
+    pub len: u64,
+    init: Bytes,
+    fragments: Vec<Fragment>,
+}
+
+/// One `moof`/`mdat` pair, from consecutive video frames of one segment.
+#[derive(Debug)]
+struct Fragment {
+    key: SegmentKey,
+    /// Index of the first frame among the segment's video frames.
+    first: usize,
+    sequence: u32,
+    base_decode_time: u64,
+    /// One per frame, in [`VIDEO_TIMESCALE`] units.
+    durations: Vec<u32>,
+    /// Position in the stream.
+    offset: u64,
+    len: u64,
+}
+
+impl Fragment {
+    fn render(&self, segment: &ParsedSegment) -> anyhow::Result<Vec<u8>> {
+        let samples: Vec<Sample> = segment
+            .frames
+            .iter()
+            .filter(|f| f.track == Track::Video)
+            .skip(self.first)
+            .zip(&self.durations)
+            .map(|(f, &duration)| Sample {
+                duration,
+                keyframe: f.keyframe,
+                data: annexb_to_avcc(&f.payload),
+            })
+            .collect();
+        let data = fmp4::media_segment(self.sequence, self.base_decode_time, &samples);
+        if samples.len() != self.durations.len() || data.len() as u64 != self.len {
+            anyhow::bail!("segment {:?} changed during playback", self.key);
+        }
+        Ok(data)
+    }
+}
+
+impl Playback {
+    /// Write bytes `start..=end` of the stream to `out`, reading only the
+    /// segments those bytes come from.
+    pub async fn write_range<W: AsyncWrite + Unpin>(
+        &self,
+        manager: &StorageManager,
+        start: u64,
+        end: u64,
+        out: &mut W,
+    ) -> anyhow::Result<()> {
+        let init_len = self.init.len() as u64;
+        if start < init_len {
+            let to = end.min(init_len - 1);
+            out.write_all(&self.init[start as usize..=to as usize])
+                .await?;
+        }
+        let mut segment: Option<(&SegmentKey, ParsedSegment)> = None;
+        for fragment in self
+            .fragments
+            .iter()
+            .filter(|f| f.offset <= end && f.offset + f.len > start)
+        {
+            // Consecutive fragments mostly come from the same segment
+            let parsed = match segment.take() {
+                Some((key, parsed)) if *key == fragment.key => parsed,
+                _ => manager.read(&fragment.key).await?,
+            };
+            let data = fragment.render(&parsed)?;
+            segment = Some((&fragment.key, parsed));
+            let from = start.saturating_sub(fragment.offset);
+            let to = (end - fragment.offset).min(fragment.len - 1);
+            out.write_all(&data[from as usize..=to as usize]).await?;
+        }
+        out.flush().await?;
+        Ok(())
+    }
+}
+
+/// A video frame placed on the wall clock.
+struct VideoFrame {
+    wall_us: u64,
+    keyframe: bool,
+    payload: Bytes,
+}
+
+/// The fragment being laid out.
+struct Pending {
+    key: SegmentKey,
+    first: usize,
+    base_decode_time: u64,
+    frames: Vec<VideoFrame>,
+    durations: Vec<u32>,
+}
+
+/// Lays out a stream frame by frame, holding only the current fragment.
+#[derive(Default)]
+struct Layout {
+    /// Wall clock of the first frame; `None` until a keyframe starts the stream.
+    first_us: Option<u64>,
+    /// Parameter sets of the first keyframe.
+    params: Option<ParameterSets>,
+    decode_time: u64,
+    fragments: Vec<Fragment>,
+    pending: Option<Pending>,
+    /// Bytes laid out so far, excluding the init segment.
+    len: u64,
+}
+
+impl Layout {
+    fn push(&mut self, key: &SegmentKey, index: usize, frame: VideoFrame) {
+        let Some(first_us) = self.first_us else {
+            return;
+        };
+        self.end_frame(frame.wall_us - first_us);
+        if frame.keyframe || self.pending.as_ref().is_some_and(|p| p.key != *key) {
+            self.close();
+        }
+        let decode_time = self.decode_time;
+        self.pending
+            .get_or_insert_with(|| Pending {
+                key: key.clone(),
+                first: index,
+                base_decode_time: decode_time,
+                frames: Vec::new(),
+                durations: Vec::new(),
+            })
+            .frames
+            .push(frame);
+    }
+
+    /// Give the pending fragment's last frame its duration, now that the
+    /// next one starts `next_us` into the stream.
+    fn end_frame(&mut self, next_us: u64) {
+        let Some(pending) = &mut self.pending else {
+            return;
+        };
+        let ticks = next_us * VIDEO_TIMESCALE as u64 / 1_000_000;
+        let duration = ticks.saturating_sub(self.decode_time).max(1);
+        self.decode_time += duration;
+        pending.durations.push(duration as u32);
+    }
+
+    fn close(&mut self) {
+        let Some(pending) = self.pending.take() else {
+            return;
+        };
+        let samples: Vec<Sample> = pending
+            .frames
+            .iter()
+            .zip(&pending.durations)
+            .map(|(f, &duration)| Sample {
+                duration,
+                keyframe: f.keyframe,
+                data: annexb_to_avcc(&f.payload),
+            })
+            .collect();
+        let sequence = self.fragments.len() as u32 + 1;
+        let len = fmp4::media_segment(sequence, pending.base_decode_time, &samples).len() as u64;
+        self.fragments.push(Fragment {
+            key: pending.key,
+            first: pending.first,
+            sequence,
+            base_decode_time: pending.base_decode_time,
+            durations: pending.durations,
+            offset: self.len,
+            len,
+        });
+        self.len += len;
+    }
+
+    fn finish(mut self) -> anyhow::Result<Option<Playback>> {
+        let Some(first_us) = self.first_us else {
+            return Ok(None);
+        };
+        if let Some(last) = self.pending.as_ref().and_then(|p| p.frames.last()) {
+            let next_us = last.wall_us + LAST_FRAME_US - first_us;
+            self.end_frame(next_us);
+        }
+        self.close();
+        let params = self
+            .params
+            .ok_or_else(|| anyhow::anyhow!("keyframe without SPS/PPS"))?;
+        let init = Bytes::from(fmp4::init_segment(&params)?);
+        let init_len = init.len() as u64;
+        for fragment in &mut self.fragments {
+            fragment.offset += init_len;
+        }
+        Ok(Some(Playback {
+            start_ms: first_us / 1000,
+            end_ms: (first_us + self.decode_time * 1_000_000 / VIDEO_TIMESCALE as u64) / 1000,
+            len: init_len + self.len,
+            init,
+            fragments: self.fragments,
+        }))
+    }
+}
+
+/// Lay out `[from_ms, to_ms]` of a camera's recordings, or `None` if there
+/// is no recorded video in range.
+pub async fn render(
+    manager: &StorageManager,
+    camera_id: &str,
+    from_ms: u64,
+    to_ms: u64,
+) -> anyhow::Result<Option<Playback>> {
+    let (from_us, to_us) = (from_ms * 1000, to_ms * 1000);
+    let mut layout = Layout::default();
+    let mut prev_end_ms: Option<u64> = None;
+    let mut prev_wall_us: Option<u64> = None;
+
+    'segments: for entry in manager.list(Some(camera_id), Some(from_ms), Some(to_ms)) {
+        if !entry.channels.contains(&Track::Video) {
+            continue;
+        }
+        if prev_end_ms.is_some_and(|end| entry.start_ms > end + MAX_JOIN_GAP_MS) {
+            break;
+        }
+        prev_end_ms = Some(entry.end_ms);
+
+        let key = entry.key();
+        let segment = match manager.read(&key).await {
+            Ok(segment) => segment,
+            Err(e) => {
+                tracing::warn!(segment = ?key, error = %e, "Unreadable segment, ending playback");
+                break;
+            }
+        };
+        let header = segment.header;
+        let video = segment
+            .frames
+            .into_iter()
+            .filter(|f| f.track == Track::Video);
+        for (index, f) in video.enumerate() {
+            let wall_us = header.wall_us(f.timestamp_us);
+            // Keep decode order monotonic across small clock steps between segments
+            let wall_us = prev_wall_us.map_or(wall_us, |p| wall_us.max(p));
+            prev_wall_us = Some(wall_us);
+            if wall_us > to_us {
+                break 'segments;
+            }
+            if f.keyframe {
+                let params = ParameterSets::from_access_unit(&f.payload);
+                // Seek to the keyframe at or before `from`, else the first one after it
+                if wall_us <= from_us || layout.first_us.is_none() {
+                    layout = Layout {
+                        first_us: Some(wall_us),
+                        params,
+                        ..Default::default()
+                    };
+                } else if params.is_some_and(|p| layout.params.as_ref() != Some(&p)) {
+                    break 'segments;
+                }
+            }
+            layout.push(
+                &key,
+                index,
+                VideoFrame {
+                    wall_us,
+                    keyframe: f.keyframe,
+                    payload: f.payload,
+                },
+            );
+        }
+    }
+
+    layout.finish()
+}
+
+struct CacheEntry {
+    camera_id: String,
+    from_ms: u64,
+    to_ms: u64,
+    created: Instant,
+    playback: Arc<Playback>,
+}
+
+/// Recently laid out streams, so a player's follow-up Range requests don't
+/// re-read every segment.
+#[derive(Default)]
+pub struct PlaybackCache {
+    entries: Mutex<VecDeque<CacheEntry>>,
+}
+
+impl PlaybackCache {
+    pub async fn get_or_render(
+        &self,
+        manager: &StorageManager,
+        camera_id: &str,
+        from_ms: u64,
+        to_ms: u64,
+    ) -> anyhow::Result<Option<Arc<Playback>>> {
+        {
+            let mut entries = self.entries.lock().unwrap();
+            entries.retain(|e| e.created.elapsed() < CACHE_TTL);
+            if let Some(e) = entries
+                .iter()
+                .find(|e| e.camera_id == camera_id && e.from_ms == from_ms && e.to_ms == to_ms)
+            {
+                return Ok(Some(e.playback.clone()));
+            }
+        }
+
+        let Some(playback) = render(manager, camera_id, from_ms, to_ms).await? else {
+            return Ok(None);
+        };
+        let playback = Arc::new(playback);
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= CACHE_ENTRIES {
+            entries.pop_front();
+        }
+        entries.push_back(CacheEntry {
+            camera_id: camera_id.to_string(),
+            from_ms,
+            to_ms,
+            created: Instant::now(),
+            playback: playback.clone(),
+        });
+        Ok(Some(playback))
+    }
+}
+
+/// Record a synthetic H.264 stream for tests: a keyframe every second and
+/// three delta frames in between, `base_wall_ms` + camera time.
+#[cfg(test)]
+pub(crate) async fn record_test_video(
+    manager: &StorageManager,
+    camera_id: &str,
+    base_wall_ms: u64,
+    secs: std::ops::Range<u64>,
+) {
+    use kodama::{Channel, Frame, FrameFlags, SourceId};
+
+    use super::segment::SegmentKind;
+    use crate::media::h264::tests::keyframe_au;
+
+    let mut rec = manager.recorder(camera_id.into());
+    for ms in (secs.start * 1000..secs.end * 1000).step_by(250) {
+        let keyframe = ms % 1000 == 0;
+        let frame = Frame {
+            source: SourceId::from_node_id_bytes(&[1; 32]),
+            channel: Channel::Video,
+            flags: if keyframe {
+                FrameFlags::keyframe()
+            } else {
+                FrameFlags::default()
+            },
+            timestamp_us: ms * 1000,
+            payload: if keyframe {
+                Bytes::from(keyframe_au())
+            } else {
+                Bytes::from_static(&[0, 0, 0, 1, 0x41, 0x9a])
+            },
+        };
+        rec.store(&frame, base_wall_ms + ms, SegmentKind::Continuous)
+            .await
+            .unwrap();
+    }
+    rec.finish().await.unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::storage::manager::{test_config, test_manager, StorageConfig};
+
+    fn count(data: &[u8], kind: &[u8; 4]) -> usize {
+        data.windows(4).filter(|w| w == kind).count()
+    }
+
+    async fn bytes(manager: &StorageManager, playback: &Playback, start: u64, end: u64) -> Vec<u8> {
+        let mut data = Vec::new();
+        playback
+            .write_range(manager, start, end, &mut data)
+            .await
+            .unwrap();
+        data
+    }
+
+    fn manager(dir: &tempfile::TempDir) -> StorageManager {
+        test_manager(
+            dir.path(),
+            StorageConfig {
+                segment_duration_us: 2_000_000,
+                ..test_config()
+            },
+        )
+    }
+
+    #[tokio::test]
+    async fn seeks_to_preceding_keyframe_and_joins_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = manager(&dir);
+        record_test_video(&manager, "cam", 10_000, 0..5).await;
+        assert_eq!(manager.list(None, None, None).len(), 3);
+
+        let playback = render(&manager, "cam", 12_500, 20_000)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(playback.start_ms, 12_000);
+        assert_eq!(playback.end_ms, 14_783);
+        // One fragment per GOP, across the segment boundary at 14s
+        let data = bytes(&manager, &playback, 0, playback.len - 1).await;
+        assert_eq!(data.len() as u64, playback.len);
+        assert_eq!(count(&data, b"moov"), 1);
+        assert_eq!(count(&data, b"moof"), 3);
+
+        // Ranges cut across fragments
+        let part = bytes(&manager, &playback, 100, playback.len - 100).await;
+        assert_eq!(part, data[100..data.len() - 99]);
+    }
+
+    #[tokio::test]
+    async fn range_renders_only_the_fragments_it_covers() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = manager(&dir);
+        record_test_video(&manager, "cam", 10_000, 0..10).await;
+        let playback = render(&manager, "cam", 10_000, 20_000)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(playback.end_ms, 19_783);
+        let first = &playback.fragments[0];
+        let end = first.offset + first.len - 1;
+        let start = bytes(&manager, &playback, 0, end).await;
+        assert_eq!(count(&start, b"moof"), 1);
+
+        // Only the first segment is read for the start of the stream
+        for entry in manager.list(Some("cam"), None, None).iter().skip(1) {
+            std::fs::remove_file(dir.path().join(entry.key().relative_path())).unwrap();
+        }
+        assert_eq!(bytes(&manager, &playback, 0, end).await, start);
+        let mut rest = Vec::new();
+        assert!(playback
+            .write_range(&manager, 0, playback.len - 1, &mut rest)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn stops_at_recording_gap() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = manager(&dir);
+        record_test_video(&manager, "cam", 10_000, 0..2).await;
+        record_test_video(&manager, "cam", 10_000, 30..32).await;
+
+        let playback = render(&manager, "cam", 10_000, 50_000)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(playback.start_ms, 10_000);
+        let data = bytes(&manager, &playback, 0, playback.len - 1).await;
+        assert_eq!(count(&data, b"moof"), 2);
+    }
+
+    #[tokio::test]
+    async fn nothing_outside_recordings() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = manager(&dir);
+        record_test_video(&manager, "cam", 10_000, 0..2).await;
+
+        assert!(render(&manager, "cam", 60_000, 70_000)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(render(&manager, "other", 10_000, 12_000)
+            .await
+            .unwrap()
+            .is_none());
+    }
+}
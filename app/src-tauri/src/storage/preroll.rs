@@ -0,0 +1,251 @@
+//! Pre-roll and post-roll buffering for event recordings.
+//!
+//! In event mode a camera's frames are held in a GOP-aligned ring buffer
+//! instead of being written. When an event fires the buffer is flushed, so
+//! the recording starts up to `pre_roll` before the trigger, and frames keep
+//! flowing to storage until `post_roll` after the event ends.
+//!
+//! All times are the camera's own `timestamp_us` clock, so buffering is
+//! unaffected by network jitter between the camera and the server.
+
+use std::collections::VecDeque;
+
+use kodama::{Channel, Frame};
+
+use crate::config::{CameraRecordingConfig, RecordingMode};
+
+/// Ring buffer of recent frames that always starts on a video keyframe.
+pub struct PrerollBuffer {
+    frames: VecDeque<Frame>,
+    window_us: u64,
+}
+
+impl PrerollBuffer {
+    pub fn new(window_us: u64) -> Self {
+        Self {
+            frames: VecDeque::new(),
+            window_us,
+        }
+    }
+
+    /// Append a frame, evicting whole GOPs that fell out of the window.
+    pub fn push(&mut self, frame: Frame) {
+        // Nothing is decodable before the first keyframe
+        if self.is_empty() && !is_keyframe(&frame) {
+            return;
+        }
+        let now = frame.timestamp_us;
+        self.frames.push_back(frame);
+
+        // Drop the oldest GOP while the next one still covers the window
+        while let Some(next_gop) = self.frames.iter().skip(1).position(is_keyframe) {
+            let next_gop_start = self.frames[next_gop + 1].timestamp_us;
+            if now.saturating_sub(next_gop_start) < self.window_us {
+                break;
+            }
+            self.frames.drain(..=next_gop);
+        }
+    }
+
+    /// Take every buffered frame, oldest first.
+    pub fn drain(&mut self) -> impl Iterator<Item = Frame> + '_ {
+        self.frames.drain(..)
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum GateState {
+    /// No event: frames are buffered, not stored.
+    Idle,
+    /// Event in progress: frames pass straight through.
+    Active,
+    /// Event ended: frames pass through until the deadline.
+    PostRoll { until_us: u64 },
+}
+
+/// Decides which of a camera's frames reach storage.
+pub struct EventGate {
+    mode: RecordingMode,
+    buffer: PrerollBuffer,
+    post_roll_us: u64,
+    state: GateState,
+}
+
+impl EventGate {
+    pub fn new(config: &CameraRecordingConfig) -> Self {
+        Self {
+            mode: config.mode,
+            buffer: PrerollBuffer::new(config.pre_roll_secs * 1_000_000),
+            post_roll_us: config.post_roll_secs * 1_000_000,
+            state: GateState::Idle,
+        }
+    }
+
+    /// Whether frames are currently being written (always true in continuous mode).
+    pub fn is_recording(&self) -> bool {
+        self.mode == RecordingMode::Continuous || self.state != GateState::Idle
+    }
+
+    /// Number of frames held in the pre-roll buffer.
+    pub fn buffered(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Mark an event as started (`true`) or ended (`false`) at camera time `at_us`.
+    pub fn set_event(&mut self, active: bool, at_us: u64) {
+        self.state = match (self.state, active) {
+            (_, true) => GateState::Active,
+            (GateState::Active, false) => GateState::PostRoll {
+                until_us: at_us + self.post_roll_us,
+            },
+            (state, false) => state,
+        };
+    }
+
+    /// Feed a frame; returns the frames that should be stored now, in order.
+    pub fn push(&mut self, frame: Frame) -> Vec<Frame> {
+        if self.mode == RecordingMode::Continuous {
+            return vec![frame];
+        }
+
+        if let GateState::PostRoll { until_us } = self.state {
+            if frame.timestamp_us >= until_us {
+                self.state = GateState::Idle;
+            }
+        }
+
+        match self.state {
+            GateState::Idle => {
+                self.buffer.push(frame);
+                Vec::new()
+            }
+            GateState::Active | GateState::PostRoll { .. } => {
+                let mut out: Vec<Frame> = self.buffer.drain().collect();
+                out.push(frame);
+                out
+            }
+        }
+    }
+}
+
+/// Motion state carried by a telemetry frame, if it reports one.
+pub fn motion_active(frame: &Frame, threshold: f32) -> Option<bool> {
+    if !matches!(frame.channel, Channel::Telemetry) {
+        return None;
+    }
+    let telemetry = kodama::capture::decode_telemetry(&frame.payload).ok()?;
+    telemetry.motion_level.map(|level| level >= threshold)
+}
+
+fn is_keyframe(frame: &Frame) -> bool {
+    matches!(frame.channel, Channel::Video) && frame.flags.is_keyframe()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bytes::Bytes;
+    use kodama::{FrameFlags, SourceId};
+
+    fn video(timestamp_secs: u64, keyframe: bool) -> Frame {
+        Frame {
+            source: SourceId::from_node_id_bytes(&[7; 32]),
+            channel: Channel::Video,
+            flags: if keyframe {
+                FrameFlags::keyframe()
+            } else {
+                FrameFlags::default()
+            },
+            timestamp_us: timestamp_secs * 1_000_000,
+            payload: Bytes::new(),
+        }
+    }
+
+    fn event_config(pre_roll_secs: u64, post_roll_secs: u64) -> CameraRecordingConfig {
+        CameraRecordingConfig {
+            mode: RecordingMode::Event,
+            pre_roll_secs,
+            post_roll_secs,
+            ..Default::default()
+        }
+    }
+
+    fn timestamps(frames: &[Frame]) -> Vec<u64> {
+        frames.iter().map(|f| f.timestamp_us / 1_000_000).collect()
+    }
+
+    #[test]
+    fn buffer_skips_frames_before_first_keyframe() {
+        let mut buf = PrerollBuffer::new(10_000_000);
+        buf.push(video(0, false));
+        buf.push(video(1, false));
+        assert!(buf.is_empty());
+        buf.push(video(2, true));
+        assert_eq!(buf.len(), 1);
+    }
+
+    #[test]
+    fn buffer_evicts_whole_gops() {
+        let mut buf = PrerollBuffer::new(5_000_000);
+        // GOPs start at 0, 4, 8
+        for t in 0..=10 {
+            buf.push(video(t, t % 4 == 0));
+        }
+        let frames: Vec<Frame> = buf.drain().collect();
+        // At t=10 the GOP at 4 is 6s old, still needed to cover 5s back
+        assert_eq!(timestamps(&frames), vec![4, 5, 6, 7, 8, 9, 10]);
+    }
+
+    #[test]
+    fn continuous_mode_passes_through() {
+        let mut gate = EventGate::new(&CameraRecordingConfig::default());
+        assert!(gate.is_recording());
+        assert_eq!(gate.push(video(0, false)).len(), 1);
+    }
+
+    #[test]
+    fn event_flushes_preroll_then_streams() {
+        let mut gate = EventGate::new(&event_config(3, 2));
+        for t in 0..6 {
+            assert!(gate.push(video(t, t % 2 == 0)).is_empty());
+        }
+        assert!(!gate.is_recording());
+
+        gate.set_event(true, 6_000_000);
+        let flushed = gate.push(video(6, true));
+        assert_eq!(timestamps(&flushed), vec![2, 3, 4, 5, 6]);
+        assert_eq!(gate.push(video(7, false)).len(), 1);
+    }
+
+    #[test]
+    fn post_roll_keeps_recording_then_stops() {
+        let mut gate = EventGate::new(&event_config(0, 2));
+        gate.set_event(true, 0);
+        assert_eq!(gate.push(video(0, true)).len(), 1);
+
+        gate.set_event(false, 1_000_000);
+        assert!(gate.is_recording());
+        assert_eq!(gate.push(video(2, false)).len(), 1);
+        assert!(gate.push(video(3, false)).is_empty());
+        assert!(!gate.is_recording());
+    }
+
+    #[test]
+    fn event_during_post_roll_resumes() {
+        let mut gate = EventGate::new(&event_config(0, 2));
+        gate.set_event(true, 0);
+        gate.set_event(false, 1_000_000);
+        gate.set_event(true, 2_000_000);
+        assert_eq!(gate.push(video(10, true)).len(), 1);
+        assert!(gate.is_recording());
+    }
+}
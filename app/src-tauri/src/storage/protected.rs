@@ -0,0 +1,202 @@
+//! Protected clips: time ranges exempt from retention.
+//!
+//! Segments overlapping a protected range on the same camera are skipped by
+//! retention, the size cap and emergency cleanup until the range is
+//! released. Ranges are kept in `protected.json` next to the index,
+//! rewritten whole on every change.
+
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use super::index::SegmentEntry;
+use super::manager::now_ms;
+
+/// A protected time range on one camera.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProtectedClip {
+    pub id: String,
+    pub camera_id: String,
+    /// Protected range (epoch ms)
+    #[serde(rename = "start")]
+    pub start_ms: u64,
+    #[serde(rename = "end")]
+    pub end_ms: u64,
+    pub label: String,
+    #[serde(default)]
+    pub note: String,
+    /// When the range was protected (epoch ms)
+    #[serde(rename = "created")]
+    pub created_ms: u64,
+}
+
+impl ProtectedClip {
+    /// Whether this range protects `entry`.
+    pub fn covers(&self, entry: &SegmentEntry) -> bool {
+        entry.camera_id == self.camera_id && entry.overlaps(Some(self.start_ms), Some(self.end_ms))
+    }
+}
+
+/// The set of protected clips, persisted to a JSON file.
+pub struct ProtectedClips {
+    path: PathBuf,
+    clips: Mutex<Vec<ProtectedClip>>,
+}
+
+impl ProtectedClips {
+    /// Load protected clips from `path`, or start empty if it doesn't exist.
+    pub fn open(path: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let path = path.into();
+        let clips = match std::fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            path,
+            clips: Mutex::new(clips),
+        })
+    }
+
+    /// Protected clips, optionally for one camera, oldest range first.
+    pub fn list(&self, camera_id: Option<&str>) -> Vec<ProtectedClip> {
+        let mut clips: Vec<_> = self
+            .clips
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|c| camera_id.is_none_or(|id| c.camera_id == id))
+            .cloned()
+            .collect();
+        clips.sort_by_key(|c| (c.start_ms, c.end_ms));
+        clips
+    }
+
+    /// Protect `[start_ms, end_ms]` on `camera_id`.
+    pub fn add(
+        &self,
+        camera_id: String,
+        start_ms: u64,
+        end_ms: u64,
+        label: String,
+        note: String,
+    ) -> anyhow::Result<ProtectedClip> {
+        if end_ms <= start_ms {
+            anyhow::bail!("end must be after start");
+        }
+        if label.trim().is_empty() {
+            anyhow::bail!("label is required");
+        }
+
+        let mut clips = self.clips.lock().unwrap();
+        let id = clips
+            .iter()
+            .filter_map(|c| c.id.parse::<u64>().ok())
+            .max()
+            .unwrap_or(0)
+            + 1;
+        let clip = ProtectedClip {
+            id: id.to_string(),
+            camera_id,
+            start_ms,
+            end_ms,
+            label,
+            note,
+            created_ms: now_ms(),
+        };
+        clips.push(clip.clone());
+        if let Err(e) = self.save(&clips) {
+            clips.pop();
+            return Err(e);
+        }
+        Ok(clip)
+    }
+
+    /// Release a protected clip. Returns it, or `None` if there is no such
+    /// clip.
+    pub fn remove(&self, id: &str) -> anyhow::Result<Option<ProtectedClip>> {
+        let mut clips = self.clips.lock().unwrap();
+        let Some(pos) = clips.iter().position(|c| c.id == id) else {
+            return Ok(None);
+        };
+        let clip = clips.remove(pos);
+        if let Err(e) = self.save(&clips) {
+            clips.insert(pos, clip);
+            return Err(e);
+        }
+        Ok(Some(clip))
+    }
+
+    /// Whether any protected clip covers `entry`.
+    pub fn covers(&self, entry: &SegmentEntry) -> bool {
+        self.clips.lock().unwrap().iter().any(|c| c.covers(entry))
+    }
+
+    fn save(&self, clips: &[ProtectedClip]) -> anyhow::Result<()> {
+        let tmp = self.path.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_vec_pretty(clips)?)?;
+        std::fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::storage::segment::{SegmentKind, Track};
+
+    fn entry(camera_id: &str, start_ms: u64, end_ms: u64) -> SegmentEntry {
+        SegmentEntry {
+            camera_id: camera_id.into(),
+            start_ms,
+            end_ms,
+            kind: SegmentKind::Continuous,
+            size_bytes: 1,
+            channels: [Track::Video].into(),
+            tier: Default::default(),
+        }
+    }
+
+    #[test]
+    fn protects_overlapping_segments_until_released() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("protected.json");
+        let clips = ProtectedClips::open(&path).unwrap();
+        let clip = clips
+            .add(
+                "cam".into(),
+                10_000,
+                20_000,
+                "Break-in".into(),
+                String::new(),
+            )
+            .unwrap();
+        clips
+            .add("other".into(), 0, 5_000, "Delivery".into(), "porch".into())
+            .unwrap();
+        assert!(clips
+            .add("cam".into(), 5, 5, "x".into(), String::new())
+            .is_err());
+        assert!(clips
+            .add("cam".into(), 0, 5, " ".into(), String::new())
+            .is_err());
+
+        assert!(clips.covers(&entry("cam", 5_000, 10_000)));
+        assert!(clips.covers(&entry("cam", 15_000, 25_000)));
+        assert!(!clips.covers(&entry("cam", 20_001, 30_000)));
+        assert!(!clips.covers(&entry("other", 10_000, 20_000)));
+
+        // Survives a restart
+        let clips = ProtectedClips::open(&path).unwrap();
+        assert_eq!(clips.list(Some("cam")), std::slice::from_ref(&clip));
+        assert_eq!(clips.list(None).len(), 2);
+
+        assert_eq!(clips.remove(&clip.id).unwrap(), Some(clip.clone()));
+        assert_eq!(clips.remove(&clip.id).unwrap(), None);
+        assert!(!clips.covers(&entry("cam", 15_000, 25_000)));
+        let clips = ProtectedClips::open(&path).unwrap();
+        assert!(clips.list(Some("cam")).is_empty());
+    }
+}
@@ -0,0 +1,204 @@
+//! Startup integrity scan.
+//!
+//! Segments are synced and indexed only when finished, so indexed segments
+//! are trusted and only checked for existence. Segments on disk but missing
+//! from the index were open when the app stopped: they are decoded, cut back
+//! to their last complete frame and indexed, or quarantined if unreadable.
+
+use std::collections::BTreeSet;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use serde::Serialize;
+
+use super::backend::StorageBackend;
+use super::index::{RecordingIndex, SegmentEntry};
+use super::segment::{ParsedSegment, SegmentKey, SegmentKind};
+use super::tiered::Tier;
+
+/// What a scan found and fixed.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ScanReport {
+    /// Segments found in storage.
+    pub scanned: usize,
+    /// Unindexed segments added to the index.
+    pub recovered: usize,
+    /// Recovered segments that ended in a partial frame.
+    pub truncated: usize,
+    pub truncated_bytes: u64,
+    /// Unreadable segments moved aside.
+    pub quarantined: usize,
+    /// Segments without a single frame, deleted.
+    pub empty: usize,
+    /// Index entries whose segment no longer exists, dropped.
+    pub missing: usize,
+    pub duration_ms: u64,
+}
+
+impl ScanReport {
+    pub fn is_clean(&self) -> bool {
+        self.recovered + self.quarantined + self.empty + self.missing == 0
+    }
+}
+
+/// Reconcile `index` with the segments in `backend`. `kind` gives the
+/// segment kind for a recovered segment, which isn't stored in the file.
+pub(super) async fn scan(
+    backend: &dyn StorageBackend,
+    index: &Mutex<RecordingIndex>,
+    kind: impl Fn(&str) -> SegmentKind,
+) -> anyhow::Result<ScanReport> {
+    let started = Instant::now();
+    let mut report = ScanReport::default();
+    let stored = backend.list().await?;
+    report.scanned = stored.len();
+
+    let indexed: BTreeSet<SegmentKey> = index
+        .lock()
+        .unwrap()
+        .oldest_first()
+        .iter()
+        .map(SegmentEntry::key)
+        .collect();
+    let on_disk: BTreeSet<&SegmentKey> = stored.iter().collect();
+    for key in indexed.iter().filter(|k| !on_disk.contains(k)) {
+        tracing::warn!(segment = ?key, "Indexed segment is missing, dropping it");
+        index.lock().unwrap().remove(key)?;
+        report.missing += 1;
+    }
+
+    for key in stored.iter().filter(|k| !indexed.contains(k)) {
+        let parsed = match backend.read(key).await {
+            Ok(data) => ParsedSegment::parse(&data).map(|s| (s, data.len())),
+            Err(e) => Err(e),
+        };
+        let (segment, len) = match parsed {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                tracing::warn!(segment = ?key, error = %e, "Unreadable segment, quarantining");
+                backend.quarantine(key).await?;
+                report.quarantined += 1;
+                continue;
+            }
+        };
+        let Some(last) = segment.frames.last() else {
+            backend.delete(key).await?;
+            report.empty += 1;
+            continue;
+        };
+
+        if segment.is_truncated(len) {
+            if let Err(e) = backend.truncate(key, segment.valid_len as u64).await {
+                tracing::warn!(segment = ?key, error = %e, "Failed to truncate segment, quarantining");
+                backend.quarantine(key).await?;
+                report.quarantined += 1;
+                continue;
+            }
+            report.truncated += 1;
+            report.truncated_bytes += (len - segment.valid_len) as u64;
+        }
+        index.lock().unwrap().insert(SegmentEntry {
+            camera_id: key.camera_id.clone(),
+            start_ms: key.start_ms,
+            end_ms: segment.header.wall_ms(last.timestamp_us),
+            kind: kind(&key.camera_id),
+            size_bytes: segment.valid_len as u64,
+            channels: segment.frames.iter().map(|f| f.track).collect(),
+            tier: Tier::Hot,
+        })?;
+        report.recovered += 1;
+    }
+
+    report.duration_ms = started.elapsed().as_millis() as u64;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bytes::Bytes;
+
+    use crate::storage::manager::{test_config, test_manager};
+    use crate::storage::playback::record_test_video;
+    use crate::storage::segment::{SegmentHeader, StoredFrame, Track};
+    use crate::storage::{LocalStorage, LocalStorageConfig};
+
+    #[tokio::test]
+    async fn repairs_interrupted_and_broken_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = test_manager(dir.path(), test_config());
+        record_test_video(&manager, "cam", 10_000, 0..2).await;
+        record_test_video(&manager, "gone", 10_000, 0..2).await;
+        std::fs::remove_file(dir.path().join("gone/10000.kseg")).unwrap();
+
+        // Written directly, bypassing the index, as if open during a crash
+        let backend = LocalStorage::new(LocalStorageConfig {
+            root_path: dir.path().to_path_buf(),
+        })
+        .unwrap();
+        let torn = SegmentKey {
+            camera_id: "cam".into(),
+            start_ms: 60_000,
+        };
+        let header = SegmentHeader {
+            start_ms: 60_000,
+            base_ts_us: 0,
+        };
+        let record = |ts_ms: u64| {
+            StoredFrame {
+                track: Track::Video,
+                keyframe: ts_ms == 0,
+                timestamp_us: ts_ms * 1000,
+                payload: Bytes::from_static(b"frame"),
+            }
+            .encode()
+        };
+        backend.append(&torn, &header.encode()).await.unwrap();
+        backend.append(&torn, &record(0)).await.unwrap();
+        backend.append(&torn, &record(500)).await.unwrap();
+        let valid_len = std::fs::metadata(dir.path().join("cam/60000.kseg"))
+            .unwrap()
+            .len();
+        backend.append(&torn, &record(1000)[..7]).await.unwrap();
+
+        let empty = SegmentKey {
+            camera_id: "cam".into(),
+            start_ms: 70_000,
+        };
+        backend.append(&empty, &header.encode()).await.unwrap();
+        std::fs::write(dir.path().join("cam/80000.kseg"), b"garbage").unwrap();
+
+        let index = Mutex::new(RecordingIndex::open(dir.path().join("index.jsonl")).unwrap());
+        let report = scan(&backend, &index, |_| SegmentKind::Motion)
+            .await
+            .unwrap();
+        assert_eq!(report.scanned, 4);
+        assert_eq!(report.missing, 1);
+        assert_eq!(report.recovered, 1);
+        assert_eq!(report.truncated, 1);
+        assert_eq!(report.truncated_bytes, 7);
+        assert_eq!(report.quarantined, 1);
+        assert_eq!(report.empty, 1);
+
+        let entries = index.lock().unwrap().oldest_first();
+        let recovered = entries.iter().find(|e| e.key() == torn).unwrap();
+        assert_eq!(recovered.end_ms, 60_500);
+        assert_eq!(recovered.kind, SegmentKind::Motion);
+        assert_eq!(recovered.size_bytes, valid_len);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(
+            std::fs::metadata(dir.path().join("cam/60000.kseg"))
+                .unwrap()
+                .len(),
+            valid_len
+        );
+        assert!(dir.path().join("quarantine/cam/80000.kseg").exists());
+
+        // A second pass has nothing left to fix
+        let report = scan(&backend, &index, |_| SegmentKind::Motion)
+            .await
+            .unwrap();
+        assert!(report.is_clean());
+    }
+}
@@ -0,0 +1,494 @@
+//! Multiple storage roots: spillover and mirroring.
+//!
+//! Each new segment goes to the first spillover root still under its cap
+//! (the storage path first), plus a copy on every mirror root. A root that
+//! fails a write is dropped from the segments it was writing and skipped
+//! for new ones for [`RETRY_AFTER_MS`], so recording carries on with the
+//! others; the failure shows in [`MultiRootStorage::status`]. A segment
+//! that loses every root keeps failing until it's finished or deleted, so
+//! the recorder starts a new one rather than appending to a torn copy.
+//! Reads, deletes and repairs find a segment on whichever roots hold it.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use serde::Serialize;
+
+use super::backend::StorageBackend;
+use super::health::DiskSpace;
+use super::local::{LocalStorage, LocalStorageConfig};
+use super::manager::now_ms;
+use super::segment::SegmentKey;
+use crate::config::{RootMode, StorageRootConfig};
+
+/// A failed root gets new segments again after this long.
+pub const RETRY_AFTER_MS: u64 = 60_000;
+
+/// State of one root, served in the storage status.
+#[derive(Debug, Clone, Serialize)]
+pub struct RootStatus {
+    pub path: PathBuf,
+    pub mode: RootMode,
+    /// Bytes of segments on this root.
+    pub used_bytes: u64,
+    /// Spillover cap; `None` for mirrors.
+    pub max_bytes: Option<u64>,
+    pub disk: Option<DiskSpace>,
+    /// Whether the root is skipped after a recent failure.
+    pub failing: bool,
+    pub last_error: Option<String>,
+    /// Epoch ms
+    pub last_error_at: Option<u64>,
+}
+
+struct Root {
+    path: PathBuf,
+    mode: RootMode,
+    max_bytes: u64,
+    /// `None` if the root couldn't be opened at startup.
+    storage: Option<LocalStorage>,
+    used_bytes: AtomicU64,
+    /// Last error and when it happened.
+    error: Mutex<Option<(String, u64)>>,
+}
+
+impl Root {
+    fn available(&self, now: u64) -> bool {
+        self.storage.is_some()
+            && self
+                .error
+                .lock()
+                .unwrap()
+                .as_ref()
+                .is_none_or(|(_, at)| now.saturating_sub(*at) > RETRY_AFTER_MS)
+    }
+
+    fn has(&self, key: &SegmentKey) -> Option<&LocalStorage> {
+        self.storage
+            .as_ref()
+            .filter(|storage| storage.path_for(key).exists())
+    }
+
+    fn failed(&self, error: &anyhow::Error) {
+        let mut last = self.error.lock().unwrap();
+        let now = now_ms();
+        if last
+            .as_ref()
+            .is_none_or(|(_, at)| now.saturating_sub(*at) > RETRY_AFTER_MS)
+        {
+            tracing::error!(root = ?self.path, error = %error, "Storage root failed, recording continues on the others");
+        }
+        *last = Some((error.to_string(), now));
+    }
+}
+
+/// A [`StorageBackend`] over several [`LocalStorage`] roots.
+pub struct MultiRootStorage {
+    roots: Vec<Root>,
+    /// Roots each open segment is written to, by position; empty once
+    /// every root has failed it.
+    open: Mutex<HashMap<SegmentKey, Vec<usize>>>,
+}
+
+impl MultiRootStorage {
+    /// Open `roots`, the first being the storage path. Other roots that
+    /// can't be opened are reported as failing rather than stopping
+    /// recording.
+    pub fn open(roots: Vec<StorageRootConfig>) -> anyhow::Result<Self> {
+        let mut opened = Vec::with_capacity(roots.len());
+        for (i, config) in roots.into_iter().enumerate() {
+            let storage = LocalStorage::new(LocalStorageConfig {
+                root_path: config.path.clone(),
+            });
+            let (storage, error) = match storage {
+                Ok(storage) => (Some(storage), None),
+                Err(e) if i == 0 => return Err(e),
+                Err(e) => {
+                    tracing::error!(root = ?config.path, error = %e, "Failed to open storage root");
+                    (None, Some((e.to_string(), now_ms())))
+                }
+            };
+            opened.push(Root {
+                used_bytes: AtomicU64::new(segment_bytes(&config.path)),
+                path: config.path,
+                mode: config.mode,
+                max_bytes: config.max_gb * 1024 * 1024 * 1024,
+                storage,
+                error: Mutex::new(error),
+            });
+        }
+        anyhow::ensure!(!opened.is_empty(), "No storage roots");
+        Ok(Self {
+            roots: opened,
+            open: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Per-root usage and failures.
+    pub fn status(&self) -> Vec<RootStatus> {
+        let now = now_ms();
+        self.roots
+            .iter()
+            .map(|root| {
+                let error = root.error.lock().unwrap().clone();
+                RootStatus {
+                    path: root.path.clone(),
+                    mode: root.mode,
+                    used_bytes: root.used_bytes.load(Ordering::Relaxed),
+                    max_bytes: (root.mode == RootMode::Spillover).then_some(root.max_bytes),
+                    disk: DiskSpace::measure(&root.path).ok(),
+                    failing: !root.available(now),
+                    last_error: error.as_ref().map(|(e, _)| e.clone()),
+                    last_error_at: error.map(|(_, at)| at),
+                }
+            })
+            .collect()
+    }
+
+    /// Roots for a new segment: the first spillover root under its cap (or
+    /// the first available one if all are full, for cleanup to catch up),
+    /// and every mirror.
+    fn select(&self) -> Vec<usize> {
+        let now = now_ms();
+        let available: Vec<usize> = (0..self.roots.len())
+            .filter(|&i| self.roots[i].available(now))
+            .collect();
+        let spillover: Vec<usize> = available
+            .iter()
+            .copied()
+            .filter(|&i| self.roots[i].mode == RootMode::Spillover)
+            .collect();
+        let primary = spillover
+            .iter()
+            .copied()
+            .find(|&i| {
+                let root = &self.roots[i];
+                root.used_bytes.load(Ordering::Relaxed) < root.max_bytes
+            })
+            .or(spillover.first().copied());
+        primary
+            .into_iter()
+            .chain(
+                available
+                    .into_iter()
+                    .filter(|&i| self.roots[i].mode == RootMode::Mirror),
+            )
+            .collect()
+    }
+}
+
+/// Bytes of segment files under `root`.
+fn segment_bytes(root: &Path) -> u64 {
+    let Ok(dirs) = std::fs::read_dir(root) else {
+        return 0;
+    };
+    dirs.flatten()
+        .filter(|dir| dir.file_name() != "quarantine")
+        .filter_map(|dir| std::fs::read_dir(dir.path()).ok())
+        .flat_map(|files| files.flatten())
+        .filter(|file| file.path().extension().is_some_and(|e| e == "kseg"))
+        .filter_map(|file| file.metadata().ok())
+        .map(|meta| meta.len())
+        .sum()
+}
+
+#[async_trait]
+impl StorageBackend for MultiRootStorage {
+    async fn append(&self, key: &SegmentKey, data: &[u8]) -> anyhow::Result<()> {
+        let targets = {
+            let mut open = self.open.lock().unwrap();
+            open.entry(key.clone())
+                .or_insert_with(|| self.select())
+                .clone()
+        };
+        if targets.is_empty() {
+            anyhow::bail!("No storage root available for {:?}", key);
+        }
+
+        let mut written = Vec::with_capacity(targets.len());
+        let mut last_error = None;
+        for i in targets {
+            let root = &self.roots[i];
+            let storage = root
+                .storage
+                .as_ref()
+                .expect("only available roots are selected");
+            match storage.append(key, data).await {
+                Ok(()) => {
+                    root.used_bytes
+                        .fetch_add(data.len() as u64, Ordering::Relaxed);
+                    written.push(i);
+                }
+                Err(e) => {
+                    root.failed(&e);
+                    // The copy there is incomplete; drop it if the root allows
+                    let _ = storage.delete(key).await;
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        let failed = written.is_empty();
+        self.open.lock().unwrap().insert(key.clone(), written);
+        match last_error {
+            Some(e) if failed => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    async fn read(&self, key: &SegmentKey) -> anyhow::Result<Bytes> {
+        let mut first_error = None;
+        for storage in self.roots.iter().filter_map(|r| r.storage.as_ref()) {
+            match storage.read(key).await {
+                Ok(data) => return Ok(data),
+                Err(e) => {
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+        Err(first_error.unwrap_or_else(|| anyhow::anyhow!("Segment {:?} not found", key)))
+    }
+
+    async fn finish(&self, key: &SegmentKey) -> anyhow::Result<()> {
+        let targets = self.open.lock().unwrap().remove(key).unwrap_or_default();
+        let (mut finished, mut error) = (false, None);
+        for i in targets {
+            let root = &self.roots[i];
+            let storage = root
+                .storage
+                .as_ref()
+                .expect("only available roots are selected");
+            match storage.finish(key).await {
+                Ok(()) => finished = true,
+                Err(e) => {
+                    root.failed(&e);
+                    error.get_or_insert(e);
+                }
+            }
+        }
+        match error {
+            Some(e) if !finished => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    async fn delete(&self, key: &SegmentKey) -> anyhow::Result<()> {
+        self.open.lock().unwrap().remove(key);
+        let mut result = Err(anyhow::anyhow!("Segment {:?} not found", key));
+        for root in &self.roots {
+            let Some(storage) = root.has(key) else {
+                continue;
+            };
+            let len = std::fs::metadata(storage.path_for(key)).map_or(0, |m| m.len());
+            match storage.delete(key).await {
+                Ok(()) => {
+                    root.used_bytes.fetch_sub(
+                        len.min(root.used_bytes.load(Ordering::Relaxed)),
+                        Ordering::Relaxed,
+                    );
+                    result = Ok(());
+                }
+                // Deleting any copy is enough
+                Err(e) if result.is_err() => result = Err(e),
+                Err(_) => {}
+            }
+        }
+        result
+    }
+
+    async fn list(&self) -> anyhow::Result<Vec<SegmentKey>> {
+        let mut keys = Vec::new();
+        for (i, root) in self.roots.iter().enumerate() {
+            let Some(storage) = &root.storage else {
+                continue;
+            };
+            match storage.list().await {
+                Ok(listed) => keys.extend(listed),
+                Err(e) if i == 0 => return Err(e),
+                Err(e) => root.failed(&e),
+            }
+        }
+        keys.sort();
+        keys.dedup();
+        Ok(keys)
+    }
+
+    /// Copies shorter than `len` are left as they are.
+    async fn truncate(&self, key: &SegmentKey, len: u64) -> anyhow::Result<()> {
+        let mut result = Err(anyhow::anyhow!("Segment {:?} not found", key));
+        for root in &self.roots {
+            let Some(storage) = root.has(key) else {
+                continue;
+            };
+            let before = std::fs::metadata(storage.path_for(key)).map_or(0, |m| m.len());
+            if before < len {
+                continue;
+            }
+            match storage.truncate(key, len).await {
+                Ok(()) => {
+                    root.used_bytes.fetch_sub(
+                        (before - len).min(root.used_bytes.load(Ordering::Relaxed)),
+                        Ordering::Relaxed,
+                    );
+                    result = Ok(());
+                }
+                Err(e) if result.is_err() => result = Err(e),
+                Err(_) => {}
+            }
+        }
+        result
+    }
+
+    async fn quarantine(&self, key: &SegmentKey) -> anyhow::Result<()> {
+        self.open.lock().unwrap().remove(key);
+        let mut result = Err(anyhow::anyhow!("Segment {:?} not found", key));
+        for root in &self.roots {
+            if let Some(storage) = root.has(key) {
+                match storage.quarantine(key).await {
+                    Ok(()) => result = Ok(()),
+                    Err(e) if result.is_err() => result = Err(e),
+                    Err(_) => {}
+                }
+            }
+        }
+        result
+    }
+
+    /// Only the first root is the storage path whose free space is watched.
+    async fn is_local(&self, key: &SegmentKey) -> bool {
+        self.roots[0].has(key).is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn root(path: PathBuf, mode: RootMode, max_gb: u64) -> StorageRootConfig {
+        StorageRootConfig { path, mode, max_gb }
+    }
+
+    fn key(start_ms: u64) -> SegmentKey {
+        SegmentKey {
+            camera_id: "cam".into(),
+            start_ms,
+        }
+    }
+
+    async fn write(storage: &MultiRootStorage, key: &SegmentKey, len: usize) -> anyhow::Result<()> {
+        storage.append(key, &vec![7; len]).await?;
+        storage.finish(key).await
+    }
+
+    #[tokio::test]
+    async fn spills_over_when_a_root_is_full() {
+        let dir = tempfile::tempdir().unwrap();
+        let (a, b) = (dir.path().join("a"), dir.path().join("b"));
+        let storage = MultiRootStorage::open(vec![
+            root(a.clone(), RootMode::Spillover, 1),
+            root(b.clone(), RootMode::Spillover, 1),
+        ])
+        .unwrap();
+        // Stand in for a full first root
+        storage.roots[0]
+            .used_bytes
+            .store(1 << 30, Ordering::Relaxed);
+
+        write(&storage, &key(1), 10).await.unwrap();
+        assert!(!a.join("cam/1.kseg").exists());
+        assert!(b.join("cam/1.kseg").exists());
+        assert_eq!(storage.read(&key(1)).await.unwrap().len(), 10);
+        assert!(!storage.is_local(&key(1)).await);
+
+        // Room again after cleanup
+        storage.roots[0].used_bytes.store(0, Ordering::Relaxed);
+        write(&storage, &key(2), 10).await.unwrap();
+        assert!(a.join("cam/2.kseg").exists());
+        assert_eq!(storage.list().await.unwrap(), [key(1), key(2)]);
+
+        storage.delete(&key(1)).await.unwrap();
+        assert_eq!(storage.status()[1].used_bytes, 0);
+        assert!(storage.delete(&key(1)).await.is_err());
+
+        // Usage is picked up from disk on reopen
+        let storage = MultiRootStorage::open(vec![
+            root(a, RootMode::Spillover, 1),
+            root(b, RootMode::Spillover, 1),
+        ])
+        .unwrap();
+        assert_eq!(storage.status()[0].used_bytes, 10);
+    }
+
+    #[tokio::test]
+    async fn mirrors_and_survives_a_failed_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let (a, b) = (dir.path().join("a"), dir.path().join("b"));
+        let storage = MultiRootStorage::open(vec![
+            root(a.clone(), RootMode::Spillover, 1),
+            root(b.clone(), RootMode::Mirror, 1),
+        ])
+        .unwrap();
+
+        write(&storage, &key(1), 10).await.unwrap();
+        assert!(a.join("cam/1.kseg").exists());
+        assert!(b.join("cam/1.kseg").exists());
+
+        // The mirror goes away: its copy is gone, but recording continues
+        std::fs::remove_dir_all(&b).unwrap();
+        std::fs::write(&b, b"not a directory").unwrap();
+        write(&storage, &key(2), 10).await.unwrap();
+        assert_eq!(storage.read(&key(1)).await.unwrap().len(), 10);
+        assert_eq!(storage.read(&key(2)).await.unwrap().len(), 10);
+
+        let status = storage.status();
+        assert!(!status[0].failing);
+        assert!(status[1].failing);
+        assert!(status[1].last_error.is_some());
+        // Skipped while failing
+        assert_eq!(storage.select(), [0]);
+
+        // Nothing left to write to
+        std::fs::remove_dir_all(&a).unwrap();
+        std::fs::write(&a, b"not a directory").unwrap();
+        assert!(write(&storage, &key(3), 10).await.is_err());
+        assert!(storage.status()[0].failing);
+        assert!(write(&storage, &key(4), 10).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn failed_segment_stays_failed_until_finished() {
+        let dir = tempfile::tempdir().unwrap();
+        let (a, b) = (dir.path().join("a"), dir.path().join("b"));
+        let storage = MultiRootStorage::open(vec![
+            root(a.clone(), RootMode::Spillover, 1),
+            root(b.clone(), RootMode::Spillover, 1),
+        ])
+        .unwrap();
+        for path in [&a, &b] {
+            std::fs::remove_dir_all(path).unwrap();
+            std::fs::write(path, b"not a directory").unwrap();
+        }
+        // Every root fails the segment's first write
+        assert!(storage.append(&key(1), b"head").await.is_err());
+
+        // The roots recover, but the segment lost its head: no bare
+        // records are written after it
+        for (path, root) in [&a, &b].into_iter().zip(&storage.roots) {
+            std::fs::remove_file(path).unwrap();
+            std::fs::create_dir(path).unwrap();
+            *root.error.lock().unwrap() = None;
+        }
+        assert!(storage.append(&key(1), b"record").await.is_err());
+        assert!(!a.join("cam/1.kseg").exists());
+        assert!(!b.join("cam/1.kseg").exists());
+        storage.finish(&key(1)).await.unwrap();
+
+        // A new segment starts cleanly
+        write(&storage, &key(2), 10).await.unwrap();
+        assert_eq!(storage.read(&key(2)).await.unwrap().len(), 10);
+    }
+}
@@ -0,0 +1,586 @@
+//! S3-compatible remote storage with a local spool.
+//!
+//! Segments are written to a [`LocalStorage`] spool as usual. Once finished,
+//! an uploader task copies each one to the bucket (multipart when larger
+//! than [`S3Config::part_size`]) and removes the local copy, so the spool
+//! only holds open segments and whatever has piled up while the store was
+//! unreachable. Reads prefer the spool and fall back to the bucket.
+//!
+//! Deletes (retention, the size cap) remove the remote object too. If the
+//! store is unreachable they are retried by the uploader until they succeed.
+
+mod client;
+
+pub use client::S3Config;
+
+use std::collections::{BTreeSet, HashSet};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use tokio::sync::Notify;
+
+use super::backend::StorageBackend;
+use super::local::LocalStorage;
+use super::segment::SegmentKey;
+use client::S3Client;
+
+/// Multipart part size for uploads.
+pub const DEFAULT_PART_SIZE: usize = 8 * 1024 * 1024;
+
+/// Remote key prefix for quarantined segments, under [`S3Config::prefix`].
+const QUARANTINE_PREFIX: &str = "quarantine/";
+
+/// Wait after a failed upload pass, doubling up to [`MAX_RETRY`].
+const MIN_RETRY: Duration = Duration::from_secs(5);
+const MAX_RETRY: Duration = Duration::from_secs(300);
+
+/// A [`StorageBackend`] that spools locally and keeps finished segments in
+/// an S3-compatible bucket.
+pub struct S3Storage {
+    client: S3Client,
+    spool: LocalStorage,
+    /// Spooled segments still being written, never uploaded.
+    open: Mutex<HashSet<SegmentKey>>,
+    /// Remote deletes that failed, retried by the uploader.
+    pending_deletes: Mutex<BTreeSet<SegmentKey>>,
+    wake: Arc<Notify>,
+}
+
+impl S3Storage {
+    pub fn new(config: S3Config, spool: LocalStorage) -> anyhow::Result<Self> {
+        Ok(Self {
+            client: S3Client::new(config)?,
+            spool,
+            open: Mutex::new(HashSet::new()),
+            pending_deletes: Mutex::new(BTreeSet::new()),
+            wake: Arc::new(Notify::new()),
+        })
+    }
+
+    /// Upload finished segments in the background, including any left in
+    /// the spool by a previous run. Start after the startup scan, so torn
+    /// segments are repaired before they are uploaded.
+    pub fn start_uploader(self: &Arc<Self>) {
+        let storage = Arc::downgrade(self);
+        let wake = self.wake.clone();
+        tokio::spawn(run_uploader(storage, wake));
+    }
+
+    /// Upload every finished spooled segment and retry failed deletes.
+    /// Stops at the first failure; returns the number uploaded.
+    async fn flush(&self) -> anyhow::Result<usize> {
+        let deletes: Vec<SegmentKey> = self
+            .pending_deletes
+            .lock()
+            .unwrap()
+            .iter()
+            .cloned()
+            .collect();
+        for key in deletes {
+            self.client.delete_object(&self.object_key(&key)).await?;
+            self.pending_deletes.lock().unwrap().remove(&key);
+        }
+
+        let mut uploaded = 0;
+        for key in self.spool.list().await? {
+            if self.open.lock().unwrap().contains(&key) {
+                continue;
+            }
+            self.upload(&key).await?;
+            uploaded += 1;
+        }
+        Ok(uploaded)
+    }
+
+    async fn upload(&self, key: &SegmentKey) -> anyhow::Result<()> {
+        let data = match self.spool.read(key).await {
+            Ok(data) => data,
+            // Deleted since listed
+            Err(e) if is_not_found(&e) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let object = self.object_key(key);
+        let part_size = self.client.config().part_size.max(1);
+        if data.len() <= part_size {
+            self.client.put_object(&object, data).await?;
+        } else {
+            let upload_id = self.client.create_multipart_upload(&object).await?;
+            if let Err(e) = self
+                .upload_parts(&object, &upload_id, data, part_size)
+                .await
+            {
+                if let Err(abort) = self
+                    .client
+                    .abort_multipart_upload(&object, &upload_id)
+                    .await
+                {
+                    tracing::debug!(error = %abort, "Failed to abort multipart upload");
+                }
+                return Err(e);
+            }
+        }
+
+        match self.spool.delete(key).await {
+            Ok(()) => tracing::debug!(segment = ?key, "Uploaded segment"),
+            // Deleted during the upload: don't leave the copy behind
+            Err(e) if is_not_found(&e) => self.delete_remote(key).await,
+            Err(e) => return Err(e),
+        }
+        Ok(())
+    }
+
+    async fn upload_parts(
+        &self,
+        object: &str,
+        upload_id: &str,
+        data: Bytes,
+        part_size: usize,
+    ) -> anyhow::Result<()> {
+        let mut etags = Vec::new();
+        for (i, start) in (0..data.len()).step_by(part_size).enumerate() {
+            let part = data.slice(start..(start + part_size).min(data.len()));
+            etags.push(
+                self.client
+                    .upload_part(object, upload_id, i as u32 + 1, part)
+                    .await?,
+            );
+        }
+        self.client
+            .complete_multipart_upload(object, upload_id, &etags)
+            .await
+    }
+
+    async fn delete_remote(&self, key: &SegmentKey) {
+        if let Err(e) = self.client.delete_object(&self.object_key(key)).await {
+            tracing::warn!(segment = ?key, error = %e, "Remote delete failed, will retry");
+            self.pending_deletes.lock().unwrap().insert(key.clone());
+            self.wake.notify_one();
+        }
+    }
+
+    fn object_key(&self, key: &SegmentKey) -> String {
+        format!(
+            "{}{}",
+            self.client.config().prefix,
+            key.relative_path().display()
+        )
+    }
+
+    /// Inverse of [`object_key`](Self::object_key); `None` for anything
+    /// that isn't a segment, including quarantined ones.
+    fn segment_key(&self, object: &str) -> Option<SegmentKey> {
+        let rest = object.strip_prefix(&self.client.config().prefix)?;
+        let (camera_id, file) = rest.split_once('/')?;
+        let start_ms = file.strip_suffix(".kseg")?.parse().ok()?;
+        Some(SegmentKey {
+            camera_id: camera_id.to_string(),
+            start_ms,
+        })
+    }
+}
+
+#[async_trait]
+impl StorageBackend for S3Storage {
+    async fn append(&self, key: &SegmentKey, data: &[u8]) -> anyhow::Result<()> {
+        self.open.lock().unwrap().insert(key.clone());
+        self.spool.append(key, data).await
+    }
+
+    async fn read(&self, key: &SegmentKey) -> anyhow::Result<Bytes> {
+        match self.spool.read(key).await {
+            Err(e) if is_not_found(&e) => {}
+            result => return result,
+        }
+        self.client
+            .get_object(&self.object_key(key))
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("Segment {:?} not found", key))
+    }
+
+    async fn finish(&self, key: &SegmentKey) -> anyhow::Result<()> {
+        self.spool.finish(key).await?;
+        self.open.lock().unwrap().remove(key);
+        self.wake.notify_one();
+        Ok(())
+    }
+
+    async fn delete(&self, key: &SegmentKey) -> anyhow::Result<()> {
+        self.open.lock().unwrap().remove(key);
+        match self.spool.delete(key).await {
+            Err(e) if !is_not_found(&e) => return Err(e),
+            _ => {}
+        }
+        self.delete_remote(key).await;
+        Ok(())
+    }
+
+    async fn list(&self) -> anyhow::Result<Vec<SegmentKey>> {
+        let mut keys: BTreeSet<SegmentKey> = self.spool.list().await?.into_iter().collect();
+        let objects = self
+            .client
+            .list_objects(&self.client.config().prefix)
+            .await?;
+        keys.extend(objects.iter().filter_map(|o| self.segment_key(o)));
+        let pending = self.pending_deletes.lock().unwrap();
+        Ok(keys.into_iter().filter(|k| !pending.contains(k)).collect())
+    }
+
+    async fn truncate(&self, key: &SegmentKey, len: u64) -> anyhow::Result<()> {
+        if !self.spool.path_for(key).exists() {
+            anyhow::bail!("Uploaded segment {:?} can't be truncated", key);
+        }
+        self.spool.truncate(key, len).await
+    }
+
+    async fn quarantine(&self, key: &SegmentKey) -> anyhow::Result<()> {
+        self.open.lock().unwrap().remove(key);
+        if self.spool.path_for(key).exists() {
+            return self.spool.quarantine(key).await;
+        }
+        let object = self.object_key(key);
+        let dest = format!(
+            "{}{}{}",
+            self.client.config().prefix,
+            QUARANTINE_PREFIX,
+            key.relative_path().display()
+        );
+        self.client.copy_object(&object, &dest).await?;
+        self.client.delete_object(&object).await
+    }
+
+    async fn is_local(&self, key: &SegmentKey) -> bool {
+        self.spool.path_for(key).exists()
+    }
+}
+
+async fn run_uploader(storage: Weak<S3Storage>, wake: Arc<Notify>) {
+    let mut retry = MIN_RETRY;
+    loop {
+        let Some(storage) = storage.upgrade() else {
+            return;
+        };
+        match storage.flush().await {
+            Ok(n) => {
+                if n > 0 {
+                    tracing::debug!("Uploaded {} segments", n);
+                }
+                retry = MIN_RETRY;
+                drop(storage);
+                wake.notified().await;
+            }
+            Err(e) => {
+                tracing::warn!(error = %e, "Segment upload failed, retrying in {:?}", retry);
+                drop(storage);
+                tokio::time::sleep(retry).await;
+                retry = (retry * 2).min(MAX_RETRY);
+            }
+        }
+    }
+}
+
+fn is_not_found(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<std::io::Error>()
+        .is_some_and(|e| e.kind() == std::io::ErrorKind::NotFound)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::{BTreeMap, HashMap};
+    use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+    use axum::body::Body;
+    use axum::extract::{Request, State};
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::response::{IntoResponse, Response};
+    use sha2::{Digest, Sha256};
+
+    use super::client::{authorization, xml_values};
+    use crate::storage::LocalStorageConfig;
+
+    /// In-memory stand-in for an S3-compatible store, checking signatures.
+    #[derive(Default)]
+    struct FakeS3 {
+        objects: Mutex<BTreeMap<String, Bytes>>,
+        uploads: Mutex<HashMap<String, BTreeMap<u32, Bytes>>>,
+        next_upload: AtomicU64,
+        parts_received: AtomicU64,
+        offline: AtomicBool,
+    }
+
+    fn test_config(endpoint: String) -> S3Config {
+        S3Config {
+            endpoint,
+            bucket: "rec".into(),
+            region: "us-east-1".into(),
+            access_key: "test".into(),
+            secret_key: "secret".into(),
+            prefix: "kodama".into(),
+            part_size: 16,
+        }
+    }
+
+    async fn fake_s3() -> (Arc<FakeS3>, String) {
+        let fake = Arc::new(FakeS3::default());
+        let app = axum::Router::new()
+            .fallback(handle)
+            .with_state(fake.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (fake, endpoint)
+    }
+
+    async fn handle(State(s3): State<Arc<FakeS3>>, req: Request) -> Response {
+        if s3.offline.load(Ordering::SeqCst) {
+            return StatusCode::SERVICE_UNAVAILABLE.into_response();
+        }
+        let (parts, body) = req.into_parts();
+        let body = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+        if !signature_valid(&parts.method, &parts.uri, &parts.headers, &body) {
+            return (
+                StatusCode::FORBIDDEN,
+                "<Error><Code>SignatureDoesNotMatch</Code></Error>",
+            )
+                .into_response();
+        }
+
+        let path = parts.uri.path().strip_prefix("/rec").unwrap();
+        let key = path.trim_start_matches('/').replace("%20", " ");
+        let query: HashMap<&str, &str> = parts
+            .uri
+            .query()
+            .unwrap_or("")
+            .split('&')
+            .filter(|p| !p.is_empty())
+            .map(|p| p.split_once('=').unwrap_or((p, "")))
+            .collect();
+        let mut objects = s3.objects.lock().unwrap();
+        let mut uploads = s3.uploads.lock().unwrap();
+
+        match (parts.method.as_str(), key.is_empty()) {
+            ("GET", true) => {
+                let prefix = query.get("prefix").unwrap_or(&"").replace("%2F", "/");
+                let mut xml = String::from("<ListBucketResult><IsTruncated>false</IsTruncated>");
+                for key in objects.keys().filter(|k| k.starts_with(&prefix)) {
+                    xml.push_str(&format!("<Contents><Key>{}</Key></Contents>", key));
+                }
+                xml.push_str("</ListBucketResult>");
+                xml.into_response()
+            }
+            ("GET", false) => match objects.get(&key) {
+                Some(data) => Body::from(data.clone()).into_response(),
+                None => (
+                    StatusCode::NOT_FOUND,
+                    "<Error><Code>NoSuchKey</Code></Error>",
+                )
+                    .into_response(),
+            },
+            ("PUT", false) => {
+                if let Some(id) = query.get("uploadId") {
+                    let n: u32 = query["partNumber"].parse().unwrap();
+                    uploads.get_mut(*id).unwrap().insert(n, body);
+                    s3.parts_received.fetch_add(1, Ordering::SeqCst);
+                    ([("etag", format!("\"etag-{}\"", n))], "").into_response()
+                } else if let Some(source) = parts.headers.get("x-amz-copy-source") {
+                    let source = source.to_str().unwrap().strip_prefix("/rec/").unwrap();
+                    let data = objects[source].clone();
+                    objects.insert(key, data);
+                    "<CopyObjectResult></CopyObjectResult>".into_response()
+                } else {
+                    objects.insert(key, body);
+                    StatusCode::OK.into_response()
+                }
+            }
+            ("POST", false) if query.contains_key("uploads") => {
+                let id = s3.next_upload.fetch_add(1, Ordering::SeqCst).to_string();
+                uploads.insert(id.clone(), BTreeMap::new());
+                format!("<InitiateMultipartUploadResult><UploadId>{}</UploadId></InitiateMultipartUploadResult>", id)
+                    .into_response()
+            }
+            ("POST", false) => {
+                let stored = uploads.remove(query["uploadId"]).unwrap();
+                let listed = xml_values(std::str::from_utf8(&body).unwrap(), "PartNumber");
+                assert_eq!(listed.len(), stored.len());
+                let data: Vec<u8> = stored.values().flat_map(|p| p.to_vec()).collect();
+                objects.insert(key, data.into());
+                "<CompleteMultipartUploadResult></CompleteMultipartUploadResult>".into_response()
+            }
+            ("DELETE", false) => {
+                if let Some(id) = query.get("uploadId") {
+                    uploads.remove(*id);
+                } else {
+                    objects.remove(&key);
+                }
+                StatusCode::NO_CONTENT.into_response()
+            }
+            _ => StatusCode::METHOD_NOT_ALLOWED.into_response(),
+        }
+    }
+
+    /// Recompute the signature from the request as received.
+    fn signature_valid(
+        method: &axum::http::Method,
+        uri: &axum::http::Uri,
+        headers: &HeaderMap,
+        body: &[u8],
+    ) -> bool {
+        let Some(auth) = headers.get("authorization").and_then(|v| v.to_str().ok()) else {
+            return false;
+        };
+        let Some(signed) = auth
+            .split("SignedHeaders=")
+            .nth(1)
+            .and_then(|s| s.split(',').next())
+        else {
+            return false;
+        };
+        let mut signed: Vec<(String, String)> = signed
+            .split(';')
+            .map(|name| {
+                let value = headers.get(name).map(|v| v.to_str().unwrap()).unwrap_or("");
+                (name.to_string(), value.to_string())
+            })
+            .collect();
+        let payload_hash = hex::encode(Sha256::digest(body));
+        if headers.get("x-amz-content-sha256").map(|v| v.as_bytes())
+            != Some(payload_hash.as_bytes())
+        {
+            return false;
+        }
+        let amz_date = headers["x-amz-date"].to_str().unwrap();
+        // The client sends its query already in canonical form, bar order
+        let mut query: Vec<&str> = uri.query().unwrap_or("").split('&').collect();
+        query.sort();
+        let query = query.join("&");
+        let expected = authorization(
+            &test_config(String::new()),
+            amz_date,
+            method.as_str(),
+            uri.path(),
+            &query,
+            &mut signed,
+            &payload_hash,
+        );
+        auth == expected
+    }
+
+    fn segment(start_ms: u64) -> SegmentKey {
+        SegmentKey {
+            camera_id: "cam".into(),
+            start_ms,
+        }
+    }
+
+    fn s3_storage(endpoint: String, spool: &std::path::Path) -> S3Storage {
+        let spool = LocalStorage::new(LocalStorageConfig {
+            root_path: spool.to_path_buf(),
+        })
+        .unwrap();
+        S3Storage::new(test_config(endpoint), spool).unwrap()
+    }
+
+    #[tokio::test]
+    async fn uploads_finished_segments_and_deletes_remotely() {
+        let (fake, endpoint) = fake_s3().await;
+        let dir = tempfile::tempdir().unwrap();
+        let storage = s3_storage(endpoint, dir.path());
+
+        let small = segment(1000);
+        storage.append(&small, b"tiny").await.unwrap();
+        let large = segment(2000);
+        let data: Vec<u8> = (0..50).collect();
+        storage.append(&large, &data[..30]).await.unwrap();
+        storage.append(&large, &data[30..]).await.unwrap();
+        storage.finish(&large).await.unwrap();
+
+        // Only the finished segment goes up, in four parts
+        assert_eq!(storage.flush().await.unwrap(), 1);
+        assert_eq!(fake.parts_received.load(Ordering::SeqCst), 4);
+        assert_eq!(fake.objects.lock().unwrap()["kodama/cam/2000.kseg"], data);
+        assert!(!dir.path().join("cam/2000.kseg").exists());
+        assert!(!storage.is_local(&large).await);
+        assert!(storage.is_local(&small).await);
+
+        // Open segments read from the spool, uploaded ones from the bucket
+        assert_eq!(storage.read(&small).await.unwrap(), &b"tiny"[..]);
+        assert_eq!(storage.read(&large).await.unwrap(), data);
+        assert_eq!(
+            storage.list().await.unwrap(),
+            [small.clone(), large.clone()]
+        );
+
+        storage.finish(&small).await.unwrap();
+        assert_eq!(storage.flush().await.unwrap(), 1);
+        storage.delete(&large).await.unwrap();
+        assert_eq!(storage.list().await.unwrap(), std::slice::from_ref(&small));
+        assert!(storage.read(&large).await.is_err());
+
+        storage.quarantine(&small).await.unwrap();
+        assert!(storage.list().await.unwrap().is_empty());
+        assert!(fake
+            .objects
+            .lock()
+            .unwrap()
+            .contains_key("kodama/quarantine/cam/1000.kseg"));
+    }
+
+    #[tokio::test]
+    async fn spools_and_retries_while_offline() {
+        let (fake, endpoint) = fake_s3().await;
+        let dir = tempfile::tempdir().unwrap();
+        let storage = s3_storage(endpoint, dir.path());
+
+        let uploaded = segment(1000);
+        storage.append(&uploaded, b"first").await.unwrap();
+        storage.finish(&uploaded).await.unwrap();
+        storage.flush().await.unwrap();
+
+        fake.offline.store(true, Ordering::SeqCst);
+        let spooled = segment(2000);
+        storage.append(&spooled, b"second").await.unwrap();
+        storage.finish(&spooled).await.unwrap();
+        assert!(storage.flush().await.is_err());
+        assert_eq!(storage.read(&spooled).await.unwrap(), &b"second"[..]);
+
+        // The delete is queued, and the segment hidden until it goes through
+        storage.delete(&uploaded).await.unwrap();
+        fake.offline.store(false, Ordering::SeqCst);
+        assert_eq!(
+            storage.list().await.unwrap(),
+            std::slice::from_ref(&spooled)
+        );
+
+        assert_eq!(storage.flush().await.unwrap(), 1);
+        let objects = fake.objects.lock().unwrap();
+        assert_eq!(objects.keys().collect::<Vec<_>>(), ["kodama/cam/2000.kseg"]);
+        assert!(!dir.path().join("cam/2000.kseg").exists());
+    }
+
+    #[tokio::test]
+    async fn uploader_task_runs_on_finish() {
+        let (fake, endpoint) = fake_s3().await;
+        let dir = tempfile::tempdir().unwrap();
+        let storage = Arc::new(s3_storage(endpoint, dir.path()));
+        storage.start_uploader();
+
+        let key = segment(1000);
+        storage.append(&key, b"data").await.unwrap();
+        storage.finish(&key).await.unwrap();
+        for _ in 0..100 {
+            if fake
+                .objects
+                .lock()
+                .unwrap()
+                .contains_key("kodama/cam/1000.kseg")
+            {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("segment was not uploaded");
+    }
+}
@@ -0,0 +1,479 @@
+//! Minimal S3 REST client: the handful of object and multipart calls the
+//! spooling backend needs, signed with AWS Signature Version 4.
+//!
+//! Requests use path-style URLs (`<endpoint>/<bucket>/<key>`), which AWS,
+//! MinIO, Garage and most other S3-compatible stores accept.
+
+use std::fmt::Write as _;
+
+use anyhow::Context;
+use bytes::Bytes;
+use hmac::{Hmac, Mac};
+use reqwest::{Method, StatusCode};
+use sha2::{Digest, Sha256};
+
+use crate::media::mp4::iso8601;
+use crate::storage::now_ms;
+
+/// Connection settings for an S3-compatible bucket.
+#[derive(Debug, Clone)]
+pub struct S3Config {
+    /// e.g. `https://s3.eu-west-1.amazonaws.com` or `http://nas:9000`
+    pub endpoint: String,
+    pub bucket: String,
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+    /// Prepended to every object key, e.g. `kodama/`.
+    pub prefix: String,
+    /// Segments larger than this are uploaded in parts of this size. S3
+    /// requires at least 5 MiB for every part but the last.
+    pub part_size: usize,
+}
+
+pub(super) struct S3Client {
+    http: reqwest::Client,
+    config: S3Config,
+    /// `host[:port]` as sent in the Host header.
+    host: String,
+}
+
+impl S3Client {
+    pub fn new(mut config: S3Config) -> anyhow::Result<Self> {
+        config.endpoint = config.endpoint.trim_end_matches('/').to_string();
+        if !config.prefix.is_empty() && !config.prefix.ends_with('/') {
+            config.prefix.push('/');
+        }
+        let url = reqwest::Url::parse(&config.endpoint).context("Invalid S3 endpoint")?;
+        let mut host = url
+            .host_str()
+            .context("S3 endpoint has no host")?
+            .to_string();
+        if let Some(port) = url.port() {
+            write!(host, ":{}", port)?;
+        }
+        Ok(Self {
+            http: reqwest::Client::builder()
+                .timeout(std::time::Duration::from_secs(120))
+                .build()?,
+            config,
+            host,
+        })
+    }
+
+    pub fn config(&self) -> &S3Config {
+        &self.config
+    }
+
+    pub async fn put_object(&self, key: &str, body: Bytes) -> anyhow::Result<()> {
+        self.send(Method::PUT, key, &[], &[], body).await?;
+        Ok(())
+    }
+
+    /// Returns `None` if the object doesn't exist.
+    pub async fn get_object(&self, key: &str) -> anyhow::Result<Option<Bytes>> {
+        match self.send(Method::GET, key, &[], &[], Bytes::new()).await {
+            Ok(res) => Ok(Some(res.bytes().await?)),
+            Err(e) if is_not_found(&e) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Deleting a missing object succeeds, as in S3.
+    pub async fn delete_object(&self, key: &str) -> anyhow::Result<()> {
+        self.send(Method::DELETE, key, &[], &[], Bytes::new())
+            .await?;
+        Ok(())
+    }
+
+    pub async fn copy_object(&self, from: &str, to: &str) -> anyhow::Result<()> {
+        let source = format!("/{}/{}", self.config.bucket, uri_encode(from, true));
+        let res = self
+            .send(
+                Method::PUT,
+                to,
+                &[],
+                &[("x-amz-copy-source", source)],
+                Bytes::new(),
+            )
+            .await?;
+        // Copy errors can arrive after a 200 status
+        check_body(&res.text().await?)
+    }
+
+    /// Keys under `prefix`, across as many pages as it takes.
+    pub async fn list_objects(&self, prefix: &str) -> anyhow::Result<Vec<String>> {
+        let mut keys = Vec::new();
+        let mut token: Option<String> = None;
+        loop {
+            let mut query = vec![
+                ("list-type", "2".to_string()),
+                ("prefix", prefix.to_string()),
+            ];
+            if let Some(token) = token.take() {
+                query.push(("continuation-token", token));
+            }
+            let body = self
+                .send(Method::GET, "", &query, &[], Bytes::new())
+                .await?
+                .text()
+                .await?;
+            keys.extend(xml_values(&body, "Key"));
+            if xml_values(&body, "IsTruncated").first().map(String::as_str) != Some("true") {
+                return Ok(keys);
+            }
+            token = Some(
+                xml_values(&body, "NextContinuationToken")
+                    .pop()
+                    .context("Truncated listing without a continuation token")?,
+            );
+        }
+    }
+
+    pub async fn create_multipart_upload(&self, key: &str) -> anyhow::Result<String> {
+        let body = self
+            .send(
+                Method::POST,
+                key,
+                &[("uploads", String::new())],
+                &[],
+                Bytes::new(),
+            )
+            .await?
+            .text()
+            .await?;
+        xml_values(&body, "UploadId")
+            .pop()
+            .context("No UploadId in CreateMultipartUpload response")
+    }
+
+    /// Upload one part; returns its ETag for completion.
+    pub async fn upload_part(
+        &self,
+        key: &str,
+        upload_id: &str,
+        part_number: u32,
+        body: Bytes,
+    ) -> anyhow::Result<String> {
+        let res = self
+            .send(
+                Method::PUT,
+                key,
+                &[
+                    ("partNumber", part_number.to_string()),
+                    ("uploadId", upload_id.to_string()),
+                ],
+                &[],
+                body,
+            )
+            .await?;
+        let etag = res
+            .headers()
+            .get(reqwest::header::ETAG)
+            .and_then(|v| v.to_str().ok())
+            .context("No ETag in UploadPart response")?;
+        Ok(etag.to_string())
+    }
+
+    pub async fn complete_multipart_upload(
+        &self,
+        key: &str,
+        upload_id: &str,
+        etags: &[String],
+    ) -> anyhow::Result<()> {
+        let mut xml = String::from("<CompleteMultipartUpload>");
+        for (i, etag) in etags.iter().enumerate() {
+            write!(
+                xml,
+                "<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>",
+                i + 1,
+                xml_escape(etag)
+            )?;
+        }
+        xml.push_str("</CompleteMultipartUpload>");
+        let res = self
+            .send(
+                Method::POST,
+                key,
+                &[("uploadId", upload_id.to_string())],
+                &[],
+                xml.into(),
+            )
+            .await?;
+        // Completion errors can arrive after a 200 status
+        check_body(&res.text().await?)
+    }
+
+    pub async fn abort_multipart_upload(&self, key: &str, upload_id: &str) -> anyhow::Result<()> {
+        self.send(
+            Method::DELETE,
+            key,
+            &[("uploadId", upload_id.to_string())],
+            &[],
+            Bytes::new(),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Sign and send a request for `key` (the bucket itself if empty),
+    /// failing on any non-success status.
+    async fn send(
+        &self,
+        method: Method,
+        key: &str,
+        query: &[(&str, String)],
+        headers: &[(&str, String)],
+        body: Bytes,
+    ) -> anyhow::Result<reqwest::Response> {
+        let path = if key.is_empty() {
+            format!("/{}", self.config.bucket)
+        } else {
+            format!("/{}/{}", self.config.bucket, uri_encode(key, true))
+        };
+        let query = canonical_query(query.iter().map(|(k, v)| (*k, v.as_str())));
+        let amz_date = amz_date(now_ms());
+        let payload_hash = hex::encode(Sha256::digest(&body));
+
+        let mut signed: Vec<(String, String)> = vec![
+            ("host".into(), self.host.clone()),
+            ("x-amz-content-sha256".into(), payload_hash.clone()),
+            ("x-amz-date".into(), amz_date.clone()),
+        ];
+        signed.extend(headers.iter().map(|(k, v)| (k.to_lowercase(), v.clone())));
+        let authorization = authorization(
+            &self.config,
+            &amz_date,
+            method.as_str(),
+            &path,
+            &query,
+            &mut signed,
+            &payload_hash,
+        );
+
+        let mut url = format!("{}{}", self.config.endpoint, path);
+        if !query.is_empty() {
+            url.push('?');
+            url.push_str(&query);
+        }
+        let mut req = self
+            .http
+            .request(method.clone(), url)
+            .header(reqwest::header::AUTHORIZATION, authorization)
+            .body(body);
+        for (name, value) in signed.iter().filter(|(name, _)| name != "host") {
+            req = req.header(name.as_str(), value.as_str());
+        }
+        let res = req.send().await?;
+        let status = res.status();
+        if status.is_success() {
+            return Ok(res);
+        }
+        let text = res.text().await.unwrap_or_default();
+        Err(S3Error {
+            status,
+            code: xml_values(&text, "Code").pop(),
+        })
+        .with_context(|| format!("S3 {} {}", method, path))
+    }
+}
+
+/// A non-success response from the store.
+#[derive(Debug)]
+pub(super) struct S3Error {
+    pub status: StatusCode,
+    pub code: Option<String>,
+}
+
+impl std::fmt::Display for S3Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.code {
+            Some(code) => write!(f, "{} ({})", self.status, code),
+            None => write!(f, "{}", self.status),
+        }
+    }
+}
+
+impl std::error::Error for S3Error {}
+
+fn is_not_found(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<S3Error>()
+        .is_some_and(|e| e.status == StatusCode::NOT_FOUND)
+}
+
+fn check_body(body: &str) -> anyhow::Result<()> {
+    if body.contains("<Error>") {
+        anyhow::bail!(
+            "S3 request failed: {}",
+            xml_values(body, "Message")
+                .pop()
+                .or_else(|| xml_values(body, "Code").pop())
+                .unwrap_or_default()
+        );
+    }
+    Ok(())
+}
+
+/// `YYYYMMDD'T'HHMMSS'Z'` in UTC.
+fn amz_date(epoch_ms: u64) -> String {
+    let iso = iso8601(epoch_ms);
+    let mut date: String = iso[..19]
+        .chars()
+        .filter(|c| *c != '-' && *c != ':')
+        .collect();
+    date.push('Z');
+    date
+}
+
+/// The `Authorization` header value. Sorts `headers`, which must have
+/// lowercase names, into canonical order.
+pub(super) fn authorization(
+    config: &S3Config,
+    amz_date: &str,
+    method: &str,
+    path: &str,
+    query: &str,
+    headers: &mut [(String, String)],
+    payload_hash: &str,
+) -> String {
+    headers.sort();
+    let mut canonical_headers = String::new();
+    for (name, value) in headers.iter() {
+        let _ = writeln!(canonical_headers, "{}:{}", name, value.trim());
+    }
+    let signed_headers = headers
+        .iter()
+        .map(|(name, _)| name.as_str())
+        .collect::<Vec<_>>()
+        .join(";");
+    let canonical_request = format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        method, path, query, canonical_headers, signed_headers, payload_hash
+    );
+
+    let date = &amz_date[..8];
+    let scope = format!("{}/{}/s3/aws4_request", date, config.region);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        amz_date,
+        scope,
+        hex::encode(Sha256::digest(canonical_request.as_bytes()))
+    );
+    let key = signing_key(&config.secret_key, date, &config.region, "s3");
+    let signature = hex::encode(hmac(&key, string_to_sign.as_bytes()));
+    format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        config.access_key, scope, signed_headers, signature
+    )
+}
+
+fn signing_key(secret: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+    let key = hmac(format!("AWS4{}", secret).as_bytes(), date.as_bytes());
+    let key = hmac(&key, region.as_bytes());
+    let key = hmac(&key, service.as_bytes());
+    hmac(&key, b"aws4_request")
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Percent-encode everything but RFC 3986 unreserved characters (and `/`
+/// in paths), as SigV4 requires.
+pub(super) fn uri_encode(s: &str, keep_slash: bool) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                out.push(b as char)
+            }
+            b'/' if keep_slash => out.push('/'),
+            _ => {
+                let _ = write!(out, "%{:02X}", b);
+            }
+        }
+    }
+    out
+}
+
+/// Encoded `k=v` pairs sorted by key, joined with `&`.
+pub(super) fn canonical_query<'a>(pairs: impl Iterator<Item = (&'a str, &'a str)>) -> String {
+    let mut pairs: Vec<(String, String)> = pairs
+        .map(|(k, v)| (uri_encode(k, false), uri_encode(v, false)))
+        .collect();
+    pairs.sort();
+    pairs
+        .iter()
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// Text of every `<tag>` element. S3 responses are flat enough that this
+/// beats pulling in an XML parser.
+pub(super) fn xml_values(xml: &str, tag: &str) -> Vec<String> {
+    let open = format!("<{}>", tag);
+    let close = format!("</{}>", tag);
+    let mut values = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find(&open) {
+        rest = &rest[start + open.len()..];
+        let Some(end) = rest.find(&close) else {
+            break;
+        };
+        values.push(xml_unescape(&rest[..end]));
+        rest = &rest[end + close.len()..];
+    }
+    values
+}
+
+fn xml_unescape(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signing_matches_aws_reference() {
+        // Example from the AWS Signature Version 4 documentation
+        let key = signing_key(
+            "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+            "20150830",
+            "us-east-1",
+            "iam",
+        );
+        assert_eq!(
+            hex::encode(key),
+            "c4afb1cc5771d871763a393e44b703571b55cc28424d1a5e86da6ed3c154a4b9"
+        );
+        assert_eq!(amz_date(1_440_938_160_000), "20150830T123600Z");
+    }
+
+    #[test]
+    fn encodes_keys_and_queries() {
+        assert_eq!(uri_encode("cam 1/10.kseg", true), "cam%201/10.kseg");
+        assert_eq!(
+            canonical_query([("uploadId", "a/b"), ("partNumber", "2")].into_iter()),
+            "partNumber=2&uploadId=a%2Fb"
+        );
+        assert_eq!(
+            xml_values("<r><Key>a&amp;b</Key><Key>c</Key></r>", "Key"),
+            ["a&b", "c"]
+        );
+    }
+}
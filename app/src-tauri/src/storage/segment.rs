@@ -0,0 +1,494 @@
+//! On-disk segment format.
+//!
+//! A segment holds every stored channel of one camera for up to
+//! `segment_duration_us`, starting on a video keyframe. It is written
+//! append-only, so a crash loses at most the record in flight:
+//!
+//! ```text
+//! header: "KSEG" + version(1) + start_ms(8) + base_ts_us(8)
+//! record: track(1) + flags(1) + timestamp_us(8) + len(4) + payload
+//! ```
+//!
+//! Integers are little-endian. `start_ms` is the wall-clock time of the
+//! first record and `base_ts_us` its camera timestamp, so any record maps to
+//! wall-clock time without storing a second clock per frame.
+//!
+//! Cameras can instead record to fragmented MP4 or MPEG-TS (see
+//! [`Container`]), which standard players open directly. Those carry the
+//! same header and records in their own framing, and keep the `.kseg` name;
+//! readers tell the containers apart by their first bytes.
+
+mod fmp4;
+mod mpegts;
+
+use std::path::PathBuf;
+
+use bytes::{Buf, Bytes};
+use kodama::{Channel, Frame};
+use serde::{Deserialize, Serialize};
+
+pub const MAGIC: &[u8; 4] = b"KSEG";
+pub const VERSION: u8 = 1;
+pub const HEADER_LEN: usize = 21;
+pub const RECORD_HEADER_LEN: usize = 14;
+
+const FLAG_KEYFRAME: u8 = 0x01;
+
+/// Identifies a segment within a backend.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct SegmentKey {
+    pub camera_id: String,
+    pub start_ms: u64,
+}
+
+impl SegmentKey {
+    pub fn file_name(&self) -> String {
+        format!("{}.kseg", self.start_ms)
+    }
+
+    /// Path relative to a storage root: `<camera_id>/<start_ms>.kseg`.
+    pub fn relative_path(&self) -> PathBuf {
+        PathBuf::from(&self.camera_id).join(self.file_name())
+    }
+}
+
+/// Why a segment was recorded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SegmentKind {
+    Continuous,
+    Motion,
+    Alert,
+}
+
+/// Stored channel of a frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Track {
+    Video,
+    Audio,
+    Telemetry,
+}
+
+impl Track {
+    pub fn from_channel(channel: &Channel) -> Option<Self> {
+        match channel {
+            Channel::Video => Some(Self::Video),
+            Channel::Audio => Some(Self::Audio),
+            Channel::Telemetry => Some(Self::Telemetry),
+            #[allow(unreachable_patterns)]
+            _ => None,
+        }
+    }
+
+    fn code(self) -> u8 {
+        match self {
+            Self::Video => 0,
+            Self::Audio => 1,
+            Self::Telemetry => 2,
+        }
+    }
+
+    fn from_code(code: u8) -> Option<Self> {
+        match code {
+            0 => Some(Self::Video),
+            1 => Some(Self::Audio),
+            2 => Some(Self::Telemetry),
+            _ => None,
+        }
+    }
+}
+
+/// File format new segments are written in. Every format stays readable
+/// whatever a camera is currently set to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Container {
+    /// The format described above.
+    #[default]
+    Native,
+    /// Fragmented MP4, one fragment per frame.
+    Fmp4,
+    /// MPEG transport stream.
+    MpegTs,
+}
+
+/// Encodes one segment in its container.
+pub struct SegmentWriter(Writer);
+
+enum Writer {
+    Native,
+    Fmp4(fmp4::Writer),
+    MpegTs(mpegts::Writer),
+}
+
+impl SegmentWriter {
+    /// Start a segment whose first frame is `first`. Returns the writer and
+    /// the bytes that open the file. Falls back to the native format if the
+    /// container can't be set up from `first`.
+    pub fn open(
+        container: Container,
+        header: SegmentHeader,
+        first: &StoredFrame,
+    ) -> (Self, Vec<u8>) {
+        let (writer, head) = match container {
+            Container::Native => (Writer::Native, header.encode().to_vec()),
+            Container::Fmp4 => match fmp4::Writer::open(header, first) {
+                Ok((writer, head)) => (Writer::Fmp4(writer), head),
+                Err(e) => {
+                    tracing::warn!(error = %e, "Can't start fMP4 segment, writing native format");
+                    (Writer::Native, header.encode().to_vec())
+                }
+            },
+            Container::MpegTs => {
+                let (writer, head) = mpegts::Writer::open(header);
+                (Writer::MpegTs(writer), head)
+            }
+        };
+        (Self(writer), head)
+    }
+
+    /// Encode one frame.
+    pub fn record(&mut self, frame: &StoredFrame) -> Vec<u8> {
+        match &mut self.0 {
+            Writer::Native => frame.encode(),
+            Writer::Fmp4(writer) => writer.record(frame),
+            Writer::MpegTs(writer) => writer.record(frame),
+        }
+    }
+}
+
+/// A frame as read back from a segment.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredFrame {
+    pub track: Track,
+    pub keyframe: bool,
+    pub timestamp_us: u64,
+    pub payload: Bytes,
+}
+
+impl StoredFrame {
+    /// Convert a live frame, or `None` for channels that aren't recorded.
+    pub fn from_frame(frame: &Frame) -> Option<Self> {
+        let track = Track::from_channel(&frame.channel)?;
+        Some(Self {
+            track,
+            keyframe: track == Track::Video && frame.flags.is_keyframe(),
+            timestamp_us: frame.timestamp_us,
+            payload: frame.payload.clone(),
+        })
+    }
+
+    /// Serialize as a segment record.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(RECORD_HEADER_LEN + self.payload.len());
+        out.push(self.track.code());
+        out.push(if self.keyframe { FLAG_KEYFRAME } else { 0 });
+        out.extend_from_slice(&self.timestamp_us.to_le_bytes());
+        out.extend_from_slice(&(self.payload.len() as u32).to_le_bytes());
+        out.extend_from_slice(&self.payload);
+        out
+    }
+}
+
+/// Fixed header at the start of every segment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SegmentHeader {
+    pub start_ms: u64,
+    pub base_ts_us: u64,
+}
+
+impl SegmentHeader {
+    pub fn encode(&self) -> [u8; HEADER_LEN] {
+        let mut out = [0u8; HEADER_LEN];
+        out[..4].copy_from_slice(MAGIC);
+        out[4] = VERSION;
+        out[5..13].copy_from_slice(&self.start_ms.to_le_bytes());
+        out[13..21].copy_from_slice(&self.base_ts_us.to_le_bytes());
+        out
+    }
+
+    pub fn decode(data: &[u8]) -> anyhow::Result<Self> {
+        if data.len() < HEADER_LEN || &data[..4] != MAGIC {
+            anyhow::bail!("not a segment");
+        }
+        if data[4] != VERSION {
+            anyhow::bail!("unsupported segment version {}", data[4]);
+        }
+        let mut buf = &data[5..HEADER_LEN];
+        Ok(Self {
+            start_ms: buf.get_u64_le(),
+            base_ts_us: buf.get_u64_le(),
+        })
+    }
+
+    /// Wall-clock time of a record in this segment.
+    pub fn wall_ms(&self, timestamp_us: u64) -> u64 {
+        self.wall_us(timestamp_us) / 1000
+    }
+
+    /// Wall-clock time of a record in this segment, in epoch µs.
+    pub fn wall_us(&self, timestamp_us: u64) -> u64 {
+        let offset_us = timestamp_us as i64 - self.base_ts_us as i64;
+        (self.start_ms as i64 * 1000 + offset_us).max(0) as u64
+    }
+}
+
+/// A decoded segment.
+#[derive(Debug)]
+pub struct ParsedSegment {
+    pub header: SegmentHeader,
+    pub frames: Vec<StoredFrame>,
+    /// Length of the header plus every complete record. Anything past this
+    /// is a partial record left by an interrupted write.
+    pub valid_len: usize,
+}
+
+impl ParsedSegment {
+    /// Parse a segment in any [`Container`].
+    pub fn parse(data: &Bytes) -> anyhow::Result<Self> {
+        if fmp4::detect(data) {
+            return fmp4::parse(data);
+        }
+        if mpegts::detect(data) {
+            return mpegts::parse(data);
+        }
+
+        let header = SegmentHeader::decode(data)?;
+        let mut frames = Vec::new();
+        let mut pos = HEADER_LEN;
+
+        while data.len() - pos >= RECORD_HEADER_LEN {
+            let mut rec = &data[pos..pos + RECORD_HEADER_LEN];
+            let code = rec.get_u8();
+            let flags = rec.get_u8();
+            let timestamp_us = rec.get_u64_le();
+            let len = rec.get_u32_le() as usize;
+
+            let start = pos + RECORD_HEADER_LEN;
+            if data.len() - start < len {
+                break;
+            }
+            // Unknown tracks are skipped, not fatal, so newer writers stay readable
+            if let Some(track) = Track::from_code(code) {
+                frames.push(StoredFrame {
+                    track,
+                    keyframe: flags & FLAG_KEYFRAME != 0,
+                    timestamp_us,
+                    payload: data.slice(start..start + len),
+                });
+            }
+            pos = start + len;
+        }
+
+        Ok(Self {
+            header,
+            frames,
+            valid_len: pos,
+        })
+    }
+
+    pub fn is_truncated(&self, total_len: usize) -> bool {
+        self.valid_len < total_len
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::media::h264::tests::keyframe_au;
+
+    fn frame(
+        track: Track,
+        keyframe: bool,
+        timestamp_us: u64,
+        payload: &'static [u8],
+    ) -> StoredFrame {
+        StoredFrame {
+            track,
+            keyframe,
+            timestamp_us,
+            payload: Bytes::from_static(payload),
+        }
+    }
+
+    fn encode(header: SegmentHeader, frames: &[StoredFrame]) -> Vec<u8> {
+        let mut data = header.encode().to_vec();
+        for f in frames {
+            data.extend(f.encode());
+        }
+        data
+    }
+
+    #[test]
+    fn roundtrip() {
+        let header = SegmentHeader {
+            start_ms: 1_700_000_000_000,
+            base_ts_us: 5_000_000,
+        };
+        let frames = vec![
+            frame(Track::Video, true, 5_000_000, b"idr"),
+            frame(Track::Audio, false, 5_010_000, b"pcm"),
+            frame(Track::Telemetry, false, 5_020_000, b"{}"),
+        ];
+        let data = Bytes::from(encode(header, &frames));
+
+        let parsed = ParsedSegment::parse(&data).unwrap();
+        assert_eq!(parsed.header, header);
+        assert_eq!(parsed.frames, frames);
+        assert!(!parsed.is_truncated(data.len()));
+    }
+
+    #[test]
+    fn truncated_record_is_dropped() {
+        let header = SegmentHeader {
+            start_ms: 0,
+            base_ts_us: 0,
+        };
+        let frames = vec![
+            frame(Track::Video, true, 0, b"complete"),
+            frame(Track::Video, false, 1, b"partial"),
+        ];
+        let mut data = encode(header, &frames);
+        data.truncate(data.len() - 3);
+        let data = Bytes::from(data);
+
+        let parsed = ParsedSegment::parse(&data).unwrap();
+        assert_eq!(parsed.frames.len(), 1);
+        assert_eq!(parsed.valid_len, HEADER_LEN + RECORD_HEADER_LEN + 8);
+        assert!(parsed.is_truncated(data.len()));
+    }
+
+    /// `frames` written through a [`SegmentWriter`], with the length after
+    /// each one.
+    fn write(container: Container, frames: &[StoredFrame]) -> (Vec<u8>, Vec<usize>) {
+        let header = SegmentHeader {
+            start_ms: 1_700_000_000_000,
+            base_ts_us: frames[0].timestamp_us,
+        };
+        let (mut writer, mut data) = SegmentWriter::open(container, header, &frames[0]);
+        let mut ends = Vec::new();
+        for f in frames {
+            data.extend(writer.record(f));
+            ends.push(data.len());
+        }
+        (data, ends)
+    }
+
+    fn mixed_frames() -> Vec<StoredFrame> {
+        let mut large = vec![0, 0, 0, 1, 0x41];
+        large.resize(1000, 0x5a);
+        vec![
+            StoredFrame {
+                track: Track::Video,
+                keyframe: true,
+                timestamp_us: 5_000_000,
+                payload: Bytes::from(keyframe_au()),
+            },
+            frame(Track::Audio, false, 5_010_000, b"pcm"),
+            frame(Track::Telemetry, false, 5_020_000, b"{}"),
+            StoredFrame {
+                track: Track::Video,
+                keyframe: false,
+                timestamp_us: 5_033_333,
+                payload: Bytes::from(large),
+            },
+            frame(Track::Video, false, 5_066_666, b"\0\0\0\x01\x41\x9a"),
+        ]
+    }
+
+    #[test]
+    fn container_roundtrip_and_truncation() {
+        let frames = mixed_frames();
+        for container in [Container::Native, Container::Fmp4, Container::MpegTs] {
+            let (data, ends) = write(container, &frames);
+            let parsed = ParsedSegment::parse(&Bytes::from(data.clone())).unwrap();
+            assert_eq!(parsed.header.start_ms, 1_700_000_000_000, "{:?}", container);
+            assert_eq!(parsed.header.base_ts_us, 5_000_000);
+            assert_eq!(parsed.frames, frames, "{:?}", container);
+            assert_eq!(parsed.valid_len, data.len());
+
+            // Cut into the last frame: everything before it survives
+            let cut = Bytes::copy_from_slice(&data[..data.len() - 3]);
+            let parsed = ParsedSegment::parse(&cut).unwrap();
+            assert_eq!(parsed.frames, frames[..frames.len() - 1], "{:?}", container);
+            assert_eq!(parsed.valid_len, ends[ends.len() - 2], "{:?}", container);
+        }
+    }
+
+    #[test]
+    fn container_layouts() {
+        let frames = mixed_frames();
+        let (fmp4, _) = write(Container::Fmp4, &frames);
+        assert_eq!(&fmp4[4..8], b"ftyp");
+        let (ts, _) = write(Container::MpegTs, &frames);
+        assert_eq!(ts.len() % 188, 0);
+        assert!(ts.chunks(188).all(|p| p[0] == 0x47));
+
+        // fMP4 needs parameter sets from the first keyframe
+        let bare = [frame(Track::Video, true, 0, b"\0\0\0\x01\x65\x88")];
+        let (native, _) = write(Container::Fmp4, &bare);
+        assert_eq!(&native[..4], MAGIC);
+        assert_eq!(
+            ParsedSegment::parse(&Bytes::from(native)).unwrap().frames,
+            bare
+        );
+    }
+
+    #[test]
+    fn rejects_foreign_files() {
+        assert!(ParsedSegment::parse(&Bytes::from_static(b"not a segment at all")).is_err());
+    }
+
+    #[test]
+    fn record_layout() {
+        let frame = StoredFrame {
+            track: Track::Audio,
+            keyframe: false,
+            timestamp_us: 0x0102,
+            payload: Bytes::from_static(b"pcm"),
+        };
+        let data = frame.encode();
+        assert_eq!(data.len(), RECORD_HEADER_LEN + 3);
+        assert_eq!(data[0], 1);
+        assert_eq!(&data[2..4], &[0x02, 0x01]);
+        assert_eq!(&data[10..14], &3u32.to_le_bytes());
+        assert_eq!(&data[14..], b"pcm");
+    }
+
+    #[test]
+    fn header_layout() {
+        let header = SegmentHeader {
+            start_ms: 7,
+            base_ts_us: 9,
+        };
+        let data = header.encode();
+        assert_eq!(&data[..5], b"KSEG\x01");
+        assert_eq!(&data[5..13], &7u64.to_le_bytes());
+        assert_eq!(&data[13..], &9u64.to_le_bytes());
+    }
+
+    #[test]
+    fn wall_clock_mapping() {
+        let header = SegmentHeader {
+            start_ms: 10_000,
+            base_ts_us: 2_000_000,
+        };
+        assert_eq!(header.wall_ms(2_000_000), 10_000);
+        assert_eq!(header.wall_ms(3_500_000), 11_500);
+        assert_eq!(header.wall_us(2_000_250), 10_000_250);
+    }
+
+    #[test]
+    fn key_path_layout() {
+        let key = SegmentKey {
+            camera_id: "0102030405060708".into(),
+            start_ms: 42,
+        };
+        assert_eq!(
+            key.relative_path(),
+            PathBuf::from("0102030405060708/42.kseg")
+        );
+    }
+}
@@ -0,0 +1,364 @@
+//! Fragmented MP4 segments.
+//!
+//! The file opens with `ftyp` + `moov`, like the live stream's init
+//! segment, and each frame is appended as its own `moof` + `mdat`
+//! fragment. Video is a regular `avc1` track, so the file plays in standard
+//! players; audio and telemetry are timed metadata tracks carrying the
+//! frame payloads as stored. Every track counts time in camera µs from
+//! `base_ts_us`, and the segment header sits in a `kodm` box under
+//! `moov/udta`.
+
+use bytes::Bytes;
+
+use super::{ParsedSegment, SegmentHeader, StoredFrame, Track};
+use crate::media::boxes::{self, Mp4Box};
+use crate::media::h264::{self, ParameterSets};
+
+/// Media timescale of every track: camera µs.
+const TIMESCALE: u32 = 1_000_000;
+
+/// Duration written for each sample. The true spacing is only known once
+/// the next frame arrives; players time fragments by `tfdt`.
+const SAMPLE_DURATION: u32 = 33_333;
+
+const SAMPLE_FLAGS_SYNC: u32 = 0x0200_0000;
+const SAMPLE_FLAGS_NON_SYNC: u32 = 0x0101_0000;
+
+const HEADER_VERSION: u8 = 1;
+
+fn track_id(track: Track) -> u32 {
+    match track {
+        Track::Video => 1,
+        Track::Audio => 2,
+        Track::Telemetry => 3,
+    }
+}
+
+fn track_for_id(id: u32) -> Option<Track> {
+    match id {
+        1 => Some(Track::Video),
+        2 => Some(Track::Audio),
+        3 => Some(Track::Telemetry),
+        _ => None,
+    }
+}
+
+/// Whether `data` starts like a file written here.
+pub(super) fn detect(data: &[u8]) -> bool {
+    data.get(4..8) == Some(b"ftyp")
+}
+
+pub(super) struct Writer {
+    header: SegmentHeader,
+    sequence: u32,
+}
+
+impl Writer {
+    /// Start a segment whose first frame is the keyframe `first`. Fails if
+    /// it has no usable parameter sets to describe the video track.
+    pub(super) fn open(
+        header: SegmentHeader,
+        first: &StoredFrame,
+    ) -> anyhow::Result<(Self, Vec<u8>)> {
+        let params = ParameterSets::from_access_unit(&first.payload)
+            .ok_or_else(|| anyhow::anyhow!("keyframe without SPS/PPS"))?;
+        let (width, height) = params
+            .dimensions()
+            .ok_or_else(|| anyhow::anyhow!("unparseable SPS"))?;
+
+        let video = trak(
+            Track::Video,
+            width,
+            height,
+            boxes::hdlr(b"vide", "VideoHandler"),
+            boxes::vmhd(),
+            boxes::avc1(&params, width, height),
+        );
+        let audio = trak(
+            Track::Audio,
+            0,
+            0,
+            boxes::hdlr(b"meta", "KodamaAudio"),
+            nmhd(),
+            mett("application/x-kodama-pcm"),
+        );
+        let telemetry = trak(
+            Track::Telemetry,
+            0,
+            0,
+            boxes::hdlr(b"meta", "KodamaTelemetry"),
+            nmhd(),
+            mett("application/x-kodama-telemetry"),
+        );
+        let mvex = [Track::Video, Track::Audio, Track::Telemetry]
+            .into_iter()
+            .fold(Mp4Box::new(b"mvex"), |b, track| {
+                b.child(
+                    Mp4Box::full(b"trex", 0, 0)
+                        .u32(track_id(track))
+                        .u32(1) // default_sample_description_index
+                        .u32(0)
+                        .u32(0)
+                        .u32(0)
+                        .build(),
+                )
+            })
+            .build();
+        let kodm = Mp4Box::new(b"kodm")
+            .u8(HEADER_VERSION)
+            .u64(header.start_ms)
+            .u64(header.base_ts_us)
+            .build();
+        let moov = Mp4Box::new(b"moov")
+            .child(boxes::mvhd(1000, 0, 4, 0))
+            .child(video)
+            .child(audio)
+            .child(telemetry)
+            .child(mvex)
+            .child(Mp4Box::new(b"udta").child(kodm).build())
+            .build();
+
+        let mut out = boxes::ftyp(b"isom", &[b"isom", b"iso6", b"avc1", b"mp41"]);
+        out.extend(moov);
+        Ok((
+            Self {
+                header,
+                sequence: 0,
+            },
+            out,
+        ))
+    }
+
+    /// One frame as a `moof` + `mdat` fragment.
+    pub(super) fn record(&mut self, frame: &StoredFrame) -> Vec<u8> {
+        self.sequence += 1;
+        let data = match frame.track {
+            Track::Video => h264::annexb_to_length_prefixed(&frame.payload),
+            Track::Audio | Track::Telemetry => frame.payload.to_vec(),
+        };
+        let flags = if frame.track != Track::Video || frame.keyframe {
+            SAMPLE_FLAGS_SYNC
+        } else {
+            SAMPLE_FLAGS_NON_SYNC
+        };
+
+        // Fixed-size fields: the moof length, and so the data offset, is
+        // known up front (see `media::fmp4::media_segment`)
+        let trun_len = 8 + 4 + 4 + 4 + 12;
+        let traf_len = 8 + 16 + 20 + trun_len;
+        let moof_len = 8 + 16 + traf_len;
+        let traf = Mp4Box::new(b"traf")
+            .child(
+                Mp4Box::full(b"tfhd", 0, 0x020000) // default-base-is-moof
+                    .u32(track_id(frame.track))
+                    .build(),
+            )
+            .child(
+                Mp4Box::full(b"tfdt", 1, 0)
+                    .u64(frame.timestamp_us.saturating_sub(self.header.base_ts_us))
+                    .build(),
+            )
+            .child(
+                Mp4Box::full(b"trun", 0, 0x000701) // data-offset | duration | size | flags
+                    .u32(1)
+                    .u32(moof_len as u32 + 8)
+                    .u32(SAMPLE_DURATION)
+                    .u32(data.len() as u32)
+                    .u32(flags)
+                    .build(),
+            )
+            .build();
+        let mut out = Mp4Box::new(b"moof")
+            .child(Mp4Box::full(b"mfhd", 0, 0).u32(self.sequence).build())
+            .child(traf)
+            .build();
+        debug_assert_eq!(out.len(), moof_len);
+        out.extend(Mp4Box::new(b"mdat").bytes(&data).build());
+        out
+    }
+}
+
+fn trak(
+    track: Track,
+    width: u16,
+    height: u16,
+    hdlr: Vec<u8>,
+    media_header: Vec<u8>,
+    entry: Vec<u8>,
+) -> Vec<u8> {
+    let stbl = Mp4Box::new(b"stbl")
+        .child(boxes::stsd(entry))
+        .child(Mp4Box::full(b"stts", 0, 0).u32(0).build())
+        .child(Mp4Box::full(b"stsc", 0, 0).u32(0).build())
+        .child(Mp4Box::full(b"stsz", 0, 0).u32(0).u32(0).build())
+        .child(Mp4Box::full(b"stco", 0, 0).u32(0).build())
+        .build();
+    let minf = Mp4Box::new(b"minf")
+        .child(media_header)
+        .child(boxes::dinf())
+        .child(stbl)
+        .build();
+    let mdia = Mp4Box::new(b"mdia")
+        .child(boxes::mdhd(TIMESCALE, 0))
+        .child(hdlr)
+        .child(minf)
+        .build();
+    Mp4Box::new(b"trak")
+        .child(boxes::tkhd(track_id(track), 0, width, height, false))
+        .child(mdia)
+        .build()
+}
+
+/// Null media header, for tracks that aren't audio or video.
+fn nmhd() -> Vec<u8> {
+    Mp4Box::full(b"nmhd", 0, 0).build()
+}
+
+/// Text timed-metadata sample entry for payloads of a MIME type.
+fn mett(mime: &str) -> Vec<u8> {
+    Mp4Box::new(b"mett")
+        .zeros(6)
+        .u16(1) // data_reference_index
+        .u8(0) // content_encoding: none
+        .bytes(mime.as_bytes())
+        .u8(0)
+        .build()
+}
+
+/// Top-level boxes of `data` as (type, body, end offset), up to the first
+/// incomplete one.
+fn boxes(data: &[u8]) -> impl Iterator<Item = ([u8; 4], &[u8], usize)> {
+    let mut pos = 0;
+    std::iter::from_fn(move || {
+        let size = u32::from_be_bytes(data.get(pos..pos + 4)?.try_into().ok()?) as usize;
+        let kind: [u8; 4] = data.get(pos + 4..pos + 8)?.try_into().ok()?;
+        if size < 8 {
+            return None;
+        }
+        let body = data.get(pos + 8..pos + size)?;
+        pos += size;
+        Some((kind, body, pos))
+    })
+}
+
+fn child<'a>(data: &'a [u8], kind: &[u8; 4]) -> Option<&'a [u8]> {
+    boxes(data)
+        .find(|(k, ..)| k == kind)
+        .map(|(_, body, _)| body)
+}
+
+fn be_u32(data: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn be_u64(data: &[u8], at: usize) -> Option<u64> {
+    Some(u64::from_be_bytes(data.get(at..at + 8)?.try_into().ok()?))
+}
+
+pub(super) fn parse(data: &Bytes) -> anyhow::Result<ParsedSegment> {
+    let mut top = boxes(data);
+    let (header, mut valid_len) = match (top.next(), top.next()) {
+        (Some((kind, ..)), Some((moov_kind, moov, end)))
+            if &kind == b"ftyp" && &moov_kind == b"moov" =>
+        {
+            let kodm = child(moov, b"udta")
+                .and_then(|udta| child(udta, b"kodm"))
+                .filter(|kodm| kodm.first() == Some(&HEADER_VERSION))
+                .ok_or_else(|| anyhow::anyhow!("not a segment"))?;
+            let header = SegmentHeader {
+                start_ms: be_u64(kodm, 1).ok_or_else(|| anyhow::anyhow!("not a segment"))?,
+                base_ts_us: be_u64(kodm, 9).ok_or_else(|| anyhow::anyhow!("not a segment"))?,
+            };
+            (header, end)
+        }
+        _ => anyhow::bail!("not a segment"),
+    };
+
+    // Fragments come in moof + mdat pairs; a frame counts once both are whole
+    let mut frames = Vec::new();
+    let mut moof: Option<(&[u8], usize)> = None;
+    for (kind, body, end) in top {
+        match &kind {
+            b"moof" => moof = Some((body, end - body.len() - 8)),
+            b"mdat" => {
+                let Some((moof_body, moof_start)) = moof.take() else {
+                    continue;
+                };
+                let mut fragment = Vec::new();
+                for (kind, traf, _) in boxes(moof_body) {
+                    if &kind != b"traf" {
+                        continue;
+                    }
+                    let Some(parsed) = parse_traf(traf, &header, data, moof_start, end) else {
+                        anyhow::bail!("malformed fragment at {}", moof_start);
+                    };
+                    fragment.extend(parsed);
+                }
+                frames.extend(fragment);
+                valid_len = end;
+            }
+            _ => {}
+        }
+    }
+
+    Ok(ParsedSegment {
+        header,
+        frames,
+        valid_len,
+    })
+}
+
+/// Frames of one track fragment whose data lies within `..mdat_end`.
+fn parse_traf(
+    traf: &[u8],
+    header: &SegmentHeader,
+    data: &Bytes,
+    moof_start: usize,
+    mdat_end: usize,
+) -> Option<Vec<StoredFrame>> {
+    let track_id = be_u32(child(traf, b"tfhd")?, 4)?;
+    let tfdt = child(traf, b"tfdt")?;
+    let decode_time = match tfdt.first()? {
+        1 => be_u64(tfdt, 4)?,
+        _ => be_u32(tfdt, 4)? as u64,
+    };
+    let trun = child(traf, b"trun")?;
+    // Only the layout written here: data-offset | duration | size | flags
+    if be_u32(trun, 0)? & 0x00ff_ffff != 0x000701 {
+        return None;
+    }
+    let count = be_u32(trun, 4)? as usize;
+    let mut offset = moof_start + be_u32(trun, 8)? as usize;
+
+    // Tracks this version doesn't know are skipped, like unknown records
+    let track = track_for_id(track_id);
+    let mut frames = Vec::with_capacity(count);
+    let mut time = decode_time;
+    for i in 0..count {
+        let at = 12 + i * 12;
+        let (duration, size, flags) = (
+            be_u32(trun, at)?,
+            be_u32(trun, at + 4)?,
+            be_u32(trun, at + 8)?,
+        );
+        let end = offset + size as usize;
+        if end > mdat_end {
+            return None;
+        }
+        if let Some(track) = track {
+            let payload = match track {
+                Track::Video => Bytes::from(h264::length_prefixed_to_annexb(&data[offset..end])?),
+                Track::Audio | Track::Telemetry => data.slice(offset..end),
+            };
+            frames.push(StoredFrame {
+                track,
+                keyframe: track == Track::Video && flags & SAMPLE_FLAGS_SYNC != 0,
+                timestamp_us: header.base_ts_us + time,
+                payload,
+            });
+        }
+        offset = end;
+        time += duration as u64;
+    }
+    Some(frames)
+}
@@ -0,0 +1,399 @@
+//! MPEG transport stream segments.
+//!
+//! One program with a stream per track: H.264 video, plus audio and
+//! telemetry as private data streams. Each frame is one PES packet whose
+//! header carries, as PES private data, the exact camera timestamp, payload
+//! length and keyframe flag; the PTS is only the 90 kHz approximation
+//! players need. PAT and PMT open the file and are repeated before each
+//! keyframe, and the segment header travels as a descriptor in the PMT.
+
+use std::collections::HashMap;
+
+use bytes::Bytes;
+
+use super::{ParsedSegment, SegmentHeader, StoredFrame, Track};
+
+const PACKET_LEN: usize = 188;
+const SYNC_BYTE: u8 = 0x47;
+
+const PID_PAT: u16 = 0;
+const PID_PMT: u16 = 0x1000;
+const PROGRAM_NUMBER: u16 = 1;
+
+const STREAM_TYPE_H264: u8 = 0x1b;
+const STREAM_TYPE_PRIVATE: u8 = 0x06;
+const STREAM_ID_VIDEO: u8 = 0xe0;
+const STREAM_ID_PRIVATE: u8 = 0xbd;
+
+/// PMT descriptor holding the segment header: "KODM" + version(1) +
+/// start_ms(8) + base_ts_us(8), big-endian.
+const HEADER_DESCRIPTOR: u8 = 0xf0;
+const HEADER_TAG: &[u8; 4] = b"KODM";
+const HEADER_VERSION: u8 = 1;
+
+/// PES private data: timestamp_us(8) + len(4) + flags(1) + reserved(3).
+const PRIVATE_LEN: usize = 16;
+/// PTS(5) + extension flags(1) + private data.
+const PES_HEADER_DATA_LEN: usize = 5 + 1 + PRIVATE_LEN;
+const PES_HEADER_LEN: usize = 9 + PES_HEADER_DATA_LEN;
+
+const FLAG_KEYFRAME: u8 = 0x01;
+
+/// PTS of the first frame, so the PCR (1 s behind) doesn't go negative.
+const PTS_OFFSET: u64 = 90_000;
+
+fn pid(track: Track) -> u16 {
+    match track {
+        Track::Video => 0x100,
+        Track::Audio => 0x101,
+        Track::Telemetry => 0x102,
+    }
+}
+
+fn track_for_pid(pid: u16) -> Option<Track> {
+    match pid {
+        0x100 => Some(Track::Video),
+        0x101 => Some(Track::Audio),
+        0x102 => Some(Track::Telemetry),
+        _ => None,
+    }
+}
+
+/// Whether `data` starts like a file written here.
+pub(super) fn detect(data: &[u8]) -> bool {
+    data.first() == Some(&SYNC_BYTE)
+}
+
+pub(super) struct Writer {
+    header: SegmentHeader,
+    /// Continuity counter per PID.
+    counters: HashMap<u16, u8>,
+}
+
+impl Writer {
+    pub(super) fn open(header: SegmentHeader) -> (Self, Vec<u8>) {
+        let mut writer = Self {
+            header,
+            counters: HashMap::new(),
+        };
+        let head = writer.tables();
+        (writer, head)
+    }
+
+    /// One frame as a PES packet, after PAT and PMT for video keyframes so
+    /// a reader can start there.
+    pub(super) fn record(&mut self, frame: &StoredFrame) -> Vec<u8> {
+        let keyframe = frame.track == Track::Video && frame.keyframe;
+        let mut out = if keyframe { self.tables() } else { Vec::new() };
+
+        let pts = frame.timestamp_us.saturating_sub(self.header.base_ts_us) * 9 / 100 + PTS_OFFSET;
+        let stream_id = match frame.track {
+            Track::Video => STREAM_ID_VIDEO,
+            Track::Audio | Track::Telemetry => STREAM_ID_PRIVATE,
+        };
+        // 0 is unbounded, meant for video; other tracks give their length
+        // unless it doesn't fit in 16 bits
+        let packet_len = match frame.track {
+            Track::Video => 0,
+            _ => u16::try_from(3 + PES_HEADER_DATA_LEN + frame.payload.len()).unwrap_or(0),
+        };
+
+        let mut pes = Vec::with_capacity(PES_HEADER_LEN + frame.payload.len());
+        pes.extend_from_slice(&[0, 0, 1, stream_id]);
+        pes.extend_from_slice(&packet_len.to_be_bytes());
+        pes.push(0x84); // '10', data_alignment_indicator
+        pes.push(0x81); // PTS only, PES_extension_flag
+        pes.push(PES_HEADER_DATA_LEN as u8);
+        pes.extend_from_slice(&encode_pts(pts));
+        pes.push(0x8e); // PES_private_data_flag, reserved bits
+        pes.extend_from_slice(&frame.timestamp_us.to_be_bytes());
+        pes.extend_from_slice(&(frame.payload.len() as u32).to_be_bytes());
+        pes.push(if keyframe { FLAG_KEYFRAME } else { 0 });
+        pes.extend_from_slice(&[0; 3]);
+        pes.extend_from_slice(&frame.payload);
+
+        let pid = pid(frame.track);
+        let mut rest = &pes[..];
+        let mut first = true;
+        while !rest.is_empty() {
+            // Random access indicator and PCR on the first packet of a keyframe
+            let fields = (first && keyframe).then(|| {
+                let mut af = vec![0x50];
+                af.extend_from_slice(&encode_pcr(pts - PTS_OFFSET));
+                af
+            });
+            let cc = self.next_cc(pid);
+            let taken = packet(&mut out, pid, first, cc, fields, rest);
+            rest = &rest[taken..];
+            first = false;
+        }
+        out
+    }
+
+    fn tables(&mut self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(2 * PACKET_LEN);
+        let cc = self.next_cc(PID_PAT);
+        psi_packet(&mut out, PID_PAT, cc, &pat());
+        let cc = self.next_cc(PID_PMT);
+        psi_packet(&mut out, PID_PMT, cc, &pmt(&self.header));
+        out
+    }
+
+    fn next_cc(&mut self, pid: u16) -> u8 {
+        let cc = self.counters.entry(pid).or_insert(0);
+        let current = *cc;
+        *cc = (*cc + 1) & 0x0f;
+        current
+    }
+}
+
+/// Write one transport packet carrying as much of `payload` as fits, with
+/// an adaptation field holding `fields` (flags onwards) and any stuffing.
+/// Returns the number of payload bytes taken.
+fn packet(
+    out: &mut Vec<u8>,
+    pid: u16,
+    unit_start: bool,
+    cc: u8,
+    fields: Option<Vec<u8>>,
+    payload: &[u8],
+) -> usize {
+    let room = PACKET_LEN - 4 - fields.as_ref().map_or(0, |f| 1 + f.len());
+    let taken = payload.len().min(room);
+    let stuffing = room - taken;
+    let adaptation = match (fields, stuffing) {
+        (fields, 0) => fields,
+        (Some(mut fields), n) => {
+            fields.resize(fields.len() + n, 0xff);
+            Some(fields)
+        }
+        // The length byte alone takes one byte of stuffing
+        (None, 1) => Some(Vec::new()),
+        (None, n) => {
+            let mut fields = vec![0x00];
+            fields.resize(n - 1, 0xff);
+            Some(fields)
+        }
+    };
+
+    out.push(SYNC_BYTE);
+    out.push(((unit_start as u8) << 6) | (pid >> 8) as u8);
+    out.push(pid as u8);
+    out.push((if adaptation.is_some() { 0x30 } else { 0x10 }) | cc);
+    if let Some(adaptation) = adaptation {
+        out.push(adaptation.len() as u8);
+        out.extend_from_slice(&adaptation);
+    }
+    out.extend_from_slice(&payload[..taken]);
+    taken
+}
+
+/// Write a single-packet PSI section, padded with 0xff.
+fn psi_packet(out: &mut Vec<u8>, pid: u16, cc: u8, section: &[u8]) {
+    let start = out.len();
+    out.push(SYNC_BYTE);
+    out.push(0x40 | (pid >> 8) as u8);
+    out.push(pid as u8);
+    out.push(0x10 | cc);
+    out.push(0); // pointer_field
+    out.extend_from_slice(section);
+    out.resize(start + PACKET_LEN, 0xff);
+}
+
+/// A long-form PSI section: `table_id`, id, version 0, single section.
+fn section(table_id: u8, id: u16, body: &[u8]) -> Vec<u8> {
+    let len = 5 + body.len() + 4;
+    let mut out = vec![table_id, 0xb0 | (len >> 8) as u8, len as u8];
+    out.extend_from_slice(&id.to_be_bytes());
+    out.extend_from_slice(&[0xc1, 0, 0]); // version 0, current; section 0 of 0
+    out.extend_from_slice(body);
+    let crc = crc32(&out);
+    out.extend_from_slice(&crc.to_be_bytes());
+    out
+}
+
+fn pat() -> Vec<u8> {
+    let mut body = PROGRAM_NUMBER.to_be_bytes().to_vec();
+    body.extend_from_slice(&(0xe000 | PID_PMT).to_be_bytes());
+    section(0x00, 1, &body)
+}
+
+fn pmt(header: &SegmentHeader) -> Vec<u8> {
+    let mut descriptor = vec![HEADER_DESCRIPTOR, 0];
+    descriptor.extend_from_slice(HEADER_TAG);
+    descriptor.push(HEADER_VERSION);
+    descriptor.extend_from_slice(&header.start_ms.to_be_bytes());
+    descriptor.extend_from_slice(&header.base_ts_us.to_be_bytes());
+    descriptor[1] = (descriptor.len() - 2) as u8;
+
+    let mut body = Vec::new();
+    body.extend_from_slice(&(0xe000 | pid(Track::Video)).to_be_bytes()); // PCR PID
+    body.extend_from_slice(&(0xf000 | descriptor.len() as u16).to_be_bytes());
+    body.extend_from_slice(&descriptor);
+    for (track, stream_type) in [
+        (Track::Video, STREAM_TYPE_H264),
+        (Track::Audio, STREAM_TYPE_PRIVATE),
+        (Track::Telemetry, STREAM_TYPE_PRIVATE),
+    ] {
+        body.push(stream_type);
+        body.extend_from_slice(&(0xe000 | pid(track)).to_be_bytes());
+        body.extend_from_slice(&0xf000u16.to_be_bytes()); // no ES descriptors
+    }
+    section(0x02, PROGRAM_NUMBER, &body)
+}
+
+fn encode_pts(pts: u64) -> [u8; 5] {
+    let pts = pts & 0x1_ffff_ffff;
+    [
+        0x21 | ((pts >> 29) & 0x0e) as u8,
+        (pts >> 22) as u8,
+        0x01 | ((pts >> 14) & 0xfe) as u8,
+        (pts >> 7) as u8,
+        0x01 | ((pts << 1) & 0xfe) as u8,
+    ]
+}
+
+fn encode_pcr(base: u64) -> [u8; 6] {
+    let base = base & 0x1_ffff_ffff;
+    [
+        (base >> 25) as u8,
+        (base >> 17) as u8,
+        (base >> 9) as u8,
+        (base >> 1) as u8,
+        ((base << 7) as u8) | 0x7e,
+        0,
+    ]
+}
+
+/// CRC-32/MPEG-2, as used by PSI sections.
+fn crc32(data: &[u8]) -> u32 {
+    data.iter().fold(0xffff_ffff, |crc, &b| {
+        (0..8).fold(crc ^ ((b as u32) << 24), |crc, _| {
+            if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04c1_1db7
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+/// Segment header from a PMT packet payload, if it carries one.
+fn parse_pmt(payload: &[u8]) -> Option<SegmentHeader> {
+    let section = payload.get(1 + *payload.first()? as usize..)?;
+    if *section.first()? != 0x02 {
+        return None;
+    }
+    let len = (u16::from_be_bytes(section.get(1..3)?.try_into().ok()?) & 0x0fff) as usize;
+    let section = section.get(..3 + len)?;
+    let (body, crc) = section.split_at(section.len().checked_sub(4)?);
+    if crc32(body).to_be_bytes() != crc {
+        return None;
+    }
+    let info_len = (u16::from_be_bytes(body.get(10..12)?.try_into().ok()?) & 0x0fff) as usize;
+    let mut descriptors = body.get(12..12 + info_len)?;
+    while descriptors.len() >= 2 {
+        let (tag, len) = (descriptors[0], descriptors[1] as usize);
+        let data = descriptors.get(2..2 + len)?;
+        if tag == HEADER_DESCRIPTOR
+            && data.len() == 21
+            && &data[..4] == HEADER_TAG
+            && data[4] == HEADER_VERSION
+        {
+            return Some(SegmentHeader {
+                start_ms: u64::from_be_bytes(data[5..13].try_into().ok()?),
+                base_ts_us: u64::from_be_bytes(data[13..21].try_into().ok()?),
+            });
+        }
+        descriptors = &descriptors[2 + len..];
+    }
+    None
+}
+
+/// A frame from a complete PES packet in the layout written here, or
+/// `None` if it isn't complete (or isn't ours).
+fn parse_pes(track: Track, pes: &[u8]) -> Option<StoredFrame> {
+    let header = pes.get(..PES_HEADER_LEN)?;
+    if header[..3] != [0, 0, 1]
+        || header[7] != 0x81
+        || header[8] as usize != PES_HEADER_DATA_LEN
+        || header[14] & 0x80 == 0
+    {
+        return None;
+    }
+    let private = &header[15..15 + PRIVATE_LEN];
+    let timestamp_us = u64::from_be_bytes(private[..8].try_into().ok()?);
+    let len = u32::from_be_bytes(private[8..12].try_into().ok()?) as usize;
+    let payload = pes.get(PES_HEADER_LEN..PES_HEADER_LEN + len)?;
+    Some(StoredFrame {
+        track,
+        keyframe: track == Track::Video && private[12] & FLAG_KEYFRAME != 0,
+        timestamp_us,
+        payload: Bytes::copy_from_slice(payload),
+    })
+}
+
+pub(super) fn parse(data: &Bytes) -> anyhow::Result<ParsedSegment> {
+    let mut header = None;
+    let mut frames = Vec::new();
+    let mut valid_len = 0;
+    // PES data collected so far per PID
+    let mut pending: HashMap<u16, Vec<u8>> = HashMap::new();
+
+    for (i, packet) in data.chunks_exact(PACKET_LEN).enumerate() {
+        if packet[0] != SYNC_BYTE {
+            break;
+        }
+        let end = (i + 1) * PACKET_LEN;
+        let unit_start = packet[1] & 0x40 != 0;
+        let pid = u16::from_be_bytes([packet[1] & 0x1f, packet[2]]);
+        let control = (packet[3] >> 4) & 0x03;
+        if control & 0x01 == 0 {
+            continue;
+        }
+        let start = if control & 0x02 != 0 {
+            5 + packet[4] as usize
+        } else {
+            4
+        };
+        let Some(payload) = packet.get(start..) else {
+            continue;
+        };
+
+        if pid == PID_PMT {
+            if header.is_none() && unit_start {
+                header = parse_pmt(payload);
+                if header.is_some() {
+                    valid_len = end;
+                }
+            }
+            continue;
+        }
+        // Tracks this version doesn't know are skipped, like unknown records
+        let Some(track) = track_for_pid(pid) else {
+            continue;
+        };
+        if header.is_none() {
+            break;
+        }
+        if unit_start {
+            pending.insert(pid, Vec::new());
+        }
+        let Some(pes) = pending.get_mut(&pid) else {
+            continue;
+        };
+        pes.extend_from_slice(payload);
+        if let Some(frame) = parse_pes(track, pes) {
+            pending.remove(&pid);
+            frames.push(frame);
+            valid_len = end;
+        }
+    }
+
+    let header = header.ok_or_else(|| anyhow::anyhow!("not a segment"))?;
+    Ok(ParsedSegment {
+        header,
+        frames,
+        valid_len,
+    })
+}
@@ -0,0 +1,281 @@
+//! Keyframe thumbnails for timeline previews.
+//!
+//! While recording, one stored keyframe per camera every
+//! [`ThumbnailConfig::interval_ms`] is decoded to a small JPEG by `ffmpeg`
+//! (bundled with the desktop app, see [`crate::media::jpeg::ffmpeg_path`])
+//! and saved next to the camera's segments as
+//! `<camera_id>/<wall_ms>.jpg`. Decoding runs off the recording path, one
+//! frame per camera at a time; keyframes arriving meanwhile are skipped.
+//! Without a working `ffmpeg`, thumbnails are disabled after one warning.
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+
+use super::index::RecordingIndex;
+use crate::media::jpeg;
+
+/// Default thumbnail width in pixels.
+pub const DEFAULT_WIDTH: u32 = 320;
+
+/// ffmpeg JPEG quality scale; thumbnails are small, so middling is plenty.
+const QSCALE: u8 = 5;
+
+#[derive(Debug, Clone)]
+pub struct ThumbnailConfig {
+    /// At most one thumbnail per camera per this interval.
+    pub interval_ms: u64,
+    /// Output width; the height keeps the aspect ratio.
+    pub width: u32,
+    /// `ffmpeg` executable used to decode keyframes.
+    pub ffmpeg: PathBuf,
+}
+
+/// Thumbnail writer and lookup.
+pub struct Thumbnails {
+    root: PathBuf,
+    config: ThumbnailConfig,
+    /// Timestamps on disk per camera, for lookups without listing.
+    stored: Mutex<HashMap<String, BTreeSet<u64>>>,
+    /// Time of the last keyframe taken per camera.
+    last: Mutex<HashMap<String, u64>>,
+    /// Cameras with a decode in progress.
+    busy: Mutex<HashSet<String>>,
+    disabled: AtomicBool,
+}
+
+impl Thumbnails {
+    /// Open thumbnails under `root`, indexing any already there.
+    pub fn open(root: impl Into<PathBuf>, config: ThumbnailConfig) -> anyhow::Result<Self> {
+        let root = root.into();
+        std::fs::create_dir_all(&root)?;
+        let mut stored: HashMap<String, BTreeSet<u64>> = HashMap::new();
+        for dir in std::fs::read_dir(&root)? {
+            let dir = dir?;
+            let Some(camera_id) = dir.file_name().to_str().map(String::from) else {
+                continue;
+            };
+            if !dir.file_type()?.is_dir() {
+                continue;
+            }
+            for file in std::fs::read_dir(dir.path())? {
+                let name = file?.file_name();
+                let ts = name
+                    .to_str()
+                    .and_then(|n| n.strip_suffix(".jpg"))
+                    .and_then(|n| n.parse().ok());
+                if let Some(ts) = ts {
+                    stored.entry(camera_id.clone()).or_default().insert(ts);
+                }
+            }
+        }
+        Ok(Self {
+            root,
+            config,
+            stored: Mutex::new(stored),
+            last: Mutex::new(HashMap::new()),
+            busy: Mutex::new(HashSet::new()),
+            disabled: AtomicBool::new(false),
+        })
+    }
+
+    /// Offer a stored video keyframe (Annex B, with parameter sets) taken
+    /// at `wall_ms`. Decodes it in the background if a thumbnail is due.
+    pub fn offer(self: &Arc<Self>, camera_id: &str, wall_ms: u64, keyframe: Bytes) {
+        if self.disabled.load(Ordering::Relaxed) {
+            return;
+        }
+        {
+            let mut last = self.last.lock().unwrap();
+            let due = last
+                .get(camera_id)
+                .is_none_or(|&t| wall_ms >= t + self.config.interval_ms || wall_ms < t);
+            if !due || !self.busy.lock().unwrap().insert(camera_id.to_string()) {
+                return;
+            }
+            last.insert(camera_id.to_string(), wall_ms);
+        }
+
+        let this = self.clone();
+        let camera_id = camera_id.to_string();
+        tokio::spawn(async move {
+            match this.write(&camera_id, wall_ms, keyframe).await {
+                Ok(()) => {
+                    this.stored
+                        .lock()
+                        .unwrap()
+                        .entry(camera_id.clone())
+                        .or_default()
+                        .insert(wall_ms);
+                }
+                Err(e) if jpeg::is_missing_decoder(&e) => {
+                    tracing::warn!(
+                        ffmpeg = ?this.config.ffmpeg,
+                        "ffmpeg not found, recording thumbnails disabled"
+                    );
+                    this.disabled.store(true, Ordering::Relaxed);
+                }
+                Err(e) => {
+                    tracing::debug!(camera = %camera_id, error = %e, "Thumbnail failed")
+                }
+            }
+            this.busy.lock().unwrap().remove(&camera_id);
+        });
+    }
+
+    /// The thumbnail closest to `at_ms`, if one is within the interval.
+    pub fn nearest(&self, camera_id: &str, at_ms: u64) -> Option<(u64, PathBuf)> {
+        let stored = self.stored.lock().unwrap();
+        let times = stored.get(camera_id)?;
+        let before = times.range(..=at_ms).next_back();
+        let after = times.range(at_ms..).next();
+        let ts = [before, after]
+            .into_iter()
+            .flatten()
+            .copied()
+            .min_by_key(|t| t.abs_diff(at_ms))?;
+        (ts.abs_diff(at_ms) <= self.config.interval_ms).then(|| (ts, self.path(camera_id, ts)))
+    }
+
+    /// Delete thumbnails older than each camera's oldest indexed segment,
+    /// or than `cutoff_ms` for cameras without any. Returns the number
+    /// removed.
+    pub fn prune(&self, index: &Mutex<RecordingIndex>, cutoff_ms: u64) -> usize {
+        let cameras: Vec<String> = self.stored.lock().unwrap().keys().cloned().collect();
+        let keep_from: HashMap<String, u64> = {
+            let index = index.lock().unwrap();
+            cameras
+                .into_iter()
+                .map(|camera| {
+                    let oldest = index
+                        .query(Some(&camera), None, None)
+                        .first()
+                        .map(|e| e.start_ms);
+                    (camera, oldest.unwrap_or(cutoff_ms))
+                })
+                .collect()
+        };
+
+        let mut removed = 0;
+        for (camera_id, keep_from) in keep_from {
+            let expired: Vec<u64> = {
+                let mut stored = self.stored.lock().unwrap();
+                let Some(times) = stored.get_mut(&camera_id) else {
+                    continue;
+                };
+                let keep = times.split_off(&keep_from);
+                std::mem::replace(times, keep).into_iter().collect()
+            };
+            for ts in expired {
+                match std::fs::remove_file(self.path(&camera_id, ts)) {
+                    Ok(()) => removed += 1,
+                    Err(e) => tracing::debug!(error = %e, "Failed to delete thumbnail"),
+                }
+            }
+        }
+        removed
+    }
+
+    fn path(&self, camera_id: &str, ts: u64) -> PathBuf {
+        self.root.join(camera_id).join(format!("{}.jpg", ts))
+    }
+
+    async fn write(&self, camera_id: &str, ts: u64, keyframe: Bytes) -> anyhow::Result<()> {
+        let jpeg = jpeg::decode(
+            &self.config.ffmpeg,
+            keyframe,
+            Some(self.config.width),
+            QSCALE,
+        )
+        .await?;
+        let path = self.path(camera_id, ts);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let tmp = path.with_extension("jpg.tmp");
+        tokio::fs::write(&tmp, &jpeg).await?;
+        tokio::fs::rename(&tmp, &path).await?;
+        Ok(())
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
+    use std::time::Duration;
+
+    use crate::storage::index::SegmentEntry;
+    use crate::storage::segment::{SegmentKind, Track};
+
+    /// Stand-in for ffmpeg that swallows its input and prints a "JPEG".
+    fn fake_ffmpeg(dir: &Path) -> PathBuf {
+        let path = dir.join("ffmpeg");
+        std::fs::write(&path, "#!/bin/sh\ncat > /dev/null\nprintf JPEG\n").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    async fn wait_for(thumbs: &Thumbnails, camera_id: &str, ts: u64) {
+        for _ in 0..100 {
+            if thumbs.nearest(camera_id, ts).is_some_and(|(t, _)| t == ts) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("thumbnail at {} was not written", ts);
+    }
+
+    #[tokio::test]
+    async fn samples_keyframes_and_prunes_with_retention() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = ThumbnailConfig {
+            interval_ms: 10_000,
+            width: DEFAULT_WIDTH,
+            ffmpeg: fake_ffmpeg(dir.path()),
+        };
+        let root = dir.path().join("rec");
+        let thumbs = Arc::new(Thumbnails::open(&root, config.clone()).unwrap());
+
+        thumbs.offer("cam", 100_000, Bytes::from_static(b"keyframe"));
+        wait_for(&thumbs, "cam", 100_000).await;
+        // Too soon after the last one
+        thumbs.offer("cam", 105_000, Bytes::from_static(b"keyframe"));
+        thumbs.offer("cam", 111_000, Bytes::from_static(b"keyframe"));
+        wait_for(&thumbs, "cam", 111_000).await;
+
+        assert_eq!(std::fs::read(root.join("cam/100000.jpg")).unwrap(), b"JPEG");
+        assert!(!root.join("cam/105000.jpg").exists());
+        assert_eq!(thumbs.nearest("cam", 104_000).unwrap().0, 100_000);
+        assert_eq!(thumbs.nearest("cam", 107_000).unwrap().0, 111_000);
+        assert!(thumbs.nearest("cam", 150_000).is_none());
+        assert!(thumbs.nearest("other", 100_000).is_none());
+
+        // Reopening picks up what's on disk
+        let thumbs = Thumbnails::open(&root, config).unwrap();
+        assert_eq!(thumbs.nearest("cam", 111_000).unwrap().0, 111_000);
+
+        let index = Mutex::new(RecordingIndex::open(root.join("index.jsonl")).unwrap());
+        index
+            .lock()
+            .unwrap()
+            .insert(SegmentEntry {
+                camera_id: "cam".into(),
+                start_ms: 110_000,
+                end_ms: 170_000,
+                kind: SegmentKind::Continuous,
+                size_bytes: 1,
+                channels: [Track::Video].into(),
+                tier: Default::default(),
+            })
+            .unwrap();
+        assert_eq!(thumbs.prune(&index, 0), 1);
+        assert!(!root.join("cam/100000.jpg").exists());
+        assert!(root.join("cam/111000.jpg").exists());
+    }
+}
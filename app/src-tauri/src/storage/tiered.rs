@@ -0,0 +1,258 @@
+//! Hot/cold tiered storage.
+//!
+//! New segments are written to the hot root (typically an SSD). Once older
+//! than a configured age, the manager's migration task moves finished
+//! segments to the cold root (a larger, slower disk) and records the move
+//! in the index. Reads, deletes and repairs find a segment in either tier,
+//! so playback, export and retention don't need to know where it lives.
+
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+
+use super::backend::StorageBackend;
+use super::index::{RecordingIndex, SegmentEntry};
+use super::local::LocalStorage;
+use super::segment::SegmentKey;
+
+/// Which tier holds a segment.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Tier {
+    #[default]
+    Hot,
+    Cold,
+}
+
+/// A [`StorageBackend`] over a hot and a cold [`LocalStorage`] root.
+pub struct TieredStorage {
+    hot: LocalStorage,
+    cold: LocalStorage,
+}
+
+impl TieredStorage {
+    pub fn new(hot: LocalStorage, cold: LocalStorage) -> Self {
+        Self { hot, cold }
+    }
+
+    /// The tier holding `key`, if any. A segment caught mid-migration is in
+    /// both; hot wins.
+    fn locate(&self, key: &SegmentKey) -> Option<(&LocalStorage, Tier)> {
+        if self.hot.path_for(key).exists() {
+            Some((&self.hot, Tier::Hot))
+        } else if self.cold.path_for(key).exists() {
+            Some((&self.cold, Tier::Cold))
+        } else {
+            None
+        }
+    }
+
+    /// Move a finished segment's file from the hot to the cold root. The
+    /// copy is synced before the hot file is removed, so a crash leaves at
+    /// worst a duplicate. Succeeds without copying if already cold.
+    pub async fn migrate(&self, key: &SegmentKey) -> anyhow::Result<()> {
+        let src = self.hot.path_for(key);
+        let dest = self.cold.path_for(key);
+        if !src.exists() {
+            anyhow::ensure!(dest.exists(), "Segment {:?} not found", key);
+            return Ok(());
+        }
+        if let Some(parent) = dest.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let tmp = dest.with_extension("kseg.tmp");
+        tokio::fs::copy(&src, &tmp).await?;
+        tokio::fs::File::open(&tmp).await?.sync_all().await?;
+        tokio::fs::rename(&tmp, &dest).await?;
+        match tokio::fs::remove_file(&src).await {
+            // Deleted by retention while being copied; the caller finds it
+            // gone from the index
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            result => Ok(result?),
+        }
+    }
+}
+
+#[async_trait]
+impl StorageBackend for TieredStorage {
+    async fn append(&self, key: &SegmentKey, data: &[u8]) -> anyhow::Result<()> {
+        self.hot.append(key, data).await
+    }
+
+    async fn read(&self, key: &SegmentKey) -> anyhow::Result<Bytes> {
+        match self.hot.read(key).await {
+            Ok(data) => Ok(data),
+            // Not found, or migrated since: try cold
+            Err(hot) => self.cold.read(key).await.map_err(|_| hot),
+        }
+    }
+
+    async fn finish(&self, key: &SegmentKey) -> anyhow::Result<()> {
+        self.hot.finish(key).await
+    }
+
+    async fn delete(&self, key: &SegmentKey) -> anyhow::Result<()> {
+        let hot = self.hot.delete(key).await;
+        let cold = self.cold.delete(key).await;
+        // Deleting from either tier is enough
+        hot.or(cold)
+    }
+
+    async fn list(&self) -> anyhow::Result<Vec<SegmentKey>> {
+        let mut keys = self.hot.list().await?;
+        keys.extend(self.cold.list().await?);
+        keys.sort();
+        keys.dedup();
+        Ok(keys)
+    }
+
+    async fn truncate(&self, key: &SegmentKey, len: u64) -> anyhow::Result<()> {
+        match self.locate(key) {
+            Some((tier, _)) => tier.truncate(key, len).await,
+            None => anyhow::bail!("Segment {:?} not found", key),
+        }
+    }
+
+    async fn quarantine(&self, key: &SegmentKey) -> anyhow::Result<()> {
+        match self.locate(key) {
+            Some((tier, _)) => tier.quarantine(key).await,
+            None => anyhow::bail!("Segment {:?} not found", key),
+        }
+    }
+
+    /// Only the hot root is the storage path whose free space is watched.
+    async fn is_local(&self, key: &SegmentKey) -> bool {
+        matches!(self.locate(key), Some((_, Tier::Hot)))
+    }
+}
+
+/// Move indexed hot segments that ended before `cutoff_ms` to the cold
+/// tier, updating the index. A segment that fails is skipped until the
+/// next pass. Returns the number moved.
+pub(super) async fn migrate_older_than(
+    tiered: &TieredStorage,
+    index: &Mutex<RecordingIndex>,
+    cutoff_ms: u64,
+) -> usize {
+    let due: Vec<_> = index
+        .lock()
+        .unwrap()
+        .oldest_first()
+        .into_iter()
+        .filter(|e| e.tier == Tier::Hot && e.end_ms < cutoff_ms)
+        .collect();
+
+    let mut moved = 0;
+    for entry in due {
+        let key = entry.key();
+        if tiered.locate(&key).is_none() {
+            // Gone already; the startup scan drops it from the index
+            tracing::debug!(segment = ?key, "Segment to migrate not found");
+            continue;
+        }
+        if let Err(e) = tiered.migrate(&key).await {
+            tracing::warn!(segment = ?key, error = %e, "Cold storage migration failed");
+            continue;
+        }
+        let indexed = {
+            let mut index = index.lock().unwrap();
+            index.get(&key).cloned().map(|entry| {
+                index.insert(SegmentEntry {
+                    tier: Tier::Cold,
+                    ..entry
+                })
+            })
+        };
+        match indexed {
+            Some(Ok(())) => moved += 1,
+            Some(Err(e)) => {
+                tracing::warn!(segment = ?key, error = %e, "Failed to index migrated segment");
+            }
+            None => {
+                // Deleted by retention while being copied
+                let _ = tiered.cold.delete(&key).await;
+            }
+        }
+    }
+    moved
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::storage::manager::{test_config, test_manager};
+    use crate::storage::playback::record_test_video;
+    use crate::storage::LocalStorageConfig;
+
+    fn local(path: &std::path::Path) -> LocalStorage {
+        LocalStorage::new(LocalStorageConfig {
+            root_path: path.to_path_buf(),
+        })
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn migrates_old_segments_and_reads_across_tiers() {
+        let hot = tempfile::tempdir().unwrap();
+        let cold = tempfile::tempdir().unwrap();
+        let manager = test_manager(hot.path(), test_config());
+        record_test_video(&manager, "cam", 10_000, 0..2).await;
+        record_test_video(&manager, "cam", 100_000, 0..2).await;
+
+        let tiered = TieredStorage::new(local(hot.path()), local(cold.path()));
+        let old = SegmentKey {
+            camera_id: "cam".into(),
+            start_ms: 10_000,
+        };
+        let recent = SegmentKey {
+            start_ms: 100_000,
+            ..old.clone()
+        };
+        let data = tiered.read(&old).await.unwrap();
+        drop(manager);
+        let index = &Mutex::new(RecordingIndex::open(hot.path().join("index.jsonl")).unwrap());
+
+        assert_eq!(migrate_older_than(&tiered, index, 50_000).await, 1);
+        assert!(!hot.path().join("cam/10000.kseg").exists());
+        assert!(cold.path().join("cam/10000.kseg").exists());
+        assert_eq!(index.lock().unwrap().get(&old).unwrap().tier, Tier::Cold);
+        assert_eq!(index.lock().unwrap().get(&recent).unwrap().tier, Tier::Hot);
+
+        // Callers see one store
+        assert_eq!(tiered.read(&old).await.unwrap(), data);
+        assert_eq!(tiered.list().await.unwrap(), [old.clone(), recent.clone()]);
+        assert!(!tiered.is_local(&old).await);
+        assert!(tiered.is_local(&recent).await);
+
+        // Already migrated: nothing more to do
+        assert_eq!(migrate_older_than(&tiered, index, 50_000).await, 0);
+
+        tiered.delete(&old).await.unwrap();
+        assert!(!cold.path().join("cam/10000.kseg").exists());
+        assert!(tiered.delete(&old).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn missing_segment_does_not_block_migration() {
+        let hot = tempfile::tempdir().unwrap();
+        let cold = tempfile::tempdir().unwrap();
+        let manager = test_manager(hot.path(), test_config());
+        record_test_video(&manager, "cam", 10_000, 0..2).await;
+        record_test_video(&manager, "cam", 20_000, 0..2).await;
+        drop(manager);
+        std::fs::remove_file(hot.path().join("cam/10000.kseg")).unwrap();
+
+        let tiered = TieredStorage::new(local(hot.path()), local(cold.path()));
+        let index = &Mutex::new(RecordingIndex::open(hot.path().join("index.jsonl")).unwrap());
+        assert_eq!(migrate_older_than(&tiered, index, 50_000).await, 1);
+        assert!(cold.path().join("cam/20000.kseg").exists());
+        let gone = SegmentKey {
+            camera_id: "cam".into(),
+            start_ms: 10_000,
+        };
+        assert_eq!(index.lock().unwrap().get(&gone).unwrap().tier, Tier::Hot);
+    }
+}
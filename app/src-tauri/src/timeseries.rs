@@ -0,0 +1,730 @@
+//! Telemetry history.
+//!
+//! Every camera's telemetry readings are kept in a small time-series store,
+//! at three resolutions:
+//!
+//! | Resolution | Kept for |
+//! |------------|----------|
+//! | raw, at most one reading per [`RAW_INTERVAL`] | 24 hours |
+//! | 1-minute min/avg/max | 30 days |
+//! | 1-hour min/avg/max | 1 year |
+//!
+//! Each resolution is a directory of append-only files per camera, one per
+//! fixed span of time and named after its start, holding fixed-size
+//! little-endian records. Rollups are built from the resolution below as
+//! each minute and hour closes, catching up on whatever raw readings or
+//! minutes were stored before a restart. Whole files are removed once
+//! they're past retention.
+
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use kodama::capture::TelemetryData;
+use kodama::server::RouterHandle;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+use crate::alerts;
+use crate::storage::now_ms;
+
+/// Minimum gap between stored raw readings of a camera.
+pub const RAW_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Most points a query may return.
+pub const MAX_POINTS: u64 = 10_000;
+
+/// Points `auto` resolution aims to stay under.
+const AUTO_POINTS: u64 = 1_500;
+
+/// How often files past retention are removed.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+const MINUTE_MS: u64 = 60 * 1000;
+const HOUR_MS: u64 = 60 * MINUTE_MS;
+const DAY_MS: u64 = 24 * HOUR_MS;
+
+/// Metrics stored per reading, in record order.
+const METRICS: usize = 6;
+
+const RAW_RECORD: usize = 8 + 4 * METRICS;
+const BUCKET_RECORD: usize = 8 + 4 + 3 * 4 * METRICS;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Resolution {
+    #[serde(rename = "raw")]
+    Raw,
+    #[serde(rename = "1m")]
+    Minute,
+    #[serde(rename = "1h")]
+    Hour,
+}
+
+impl Resolution {
+    const ALL: [Resolution; 3] = [Resolution::Raw, Resolution::Minute, Resolution::Hour];
+
+    fn dir(self) -> &'static str {
+        match self {
+            Resolution::Raw => "raw",
+            Resolution::Minute => "1m",
+            Resolution::Hour => "1h",
+        }
+    }
+
+    /// Time between points; for raw, the most there can be.
+    fn step_ms(self) -> u64 {
+        match self {
+            Resolution::Raw => RAW_INTERVAL.as_millis() as u64,
+            Resolution::Minute => MINUTE_MS,
+            Resolution::Hour => HOUR_MS,
+        }
+    }
+
+    pub fn retention_ms(self) -> u64 {
+        match self {
+            Resolution::Raw => DAY_MS,
+            Resolution::Minute => 30 * DAY_MS,
+            Resolution::Hour => 365 * DAY_MS,
+        }
+    }
+
+    /// Time covered by one file.
+    fn file_span_ms(self) -> u64 {
+        match self {
+            Resolution::Raw => HOUR_MS,
+            Resolution::Minute => DAY_MS,
+            Resolution::Hour => 30 * DAY_MS,
+        }
+    }
+
+    /// The finest resolution that still holds `from_ms` at `now_ms` and
+    /// covers the range in few enough points for a chart.
+    pub fn auto(from_ms: u64, to_ms: u64, now_ms: u64) -> Self {
+        let span = to_ms.saturating_sub(from_ms);
+        Self::ALL
+            .into_iter()
+            .find(|res| {
+                from_ms >= now_ms.saturating_sub(res.retention_ms())
+                    && span / res.step_ms() <= AUTO_POINTS
+            })
+            .unwrap_or(Resolution::Hour)
+    }
+
+    /// Whether `[from_ms, to_ms]` fits in [`MAX_POINTS`] at this resolution.
+    pub fn fits(self, from_ms: u64, to_ms: u64) -> bool {
+        to_ms.saturating_sub(from_ms) / self.step_ms() <= MAX_POINTS
+    }
+}
+
+/// Telemetry metrics, `None` where the camera didn't report one.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Metrics {
+    pub cpu_usage: Option<f32>,
+    pub cpu_temp: Option<f32>,
+    pub memory_usage: Option<f32>,
+    pub disk_usage: Option<f32>,
+    /// One-minute load average
+    pub load_average: Option<f32>,
+    pub motion_level: Option<f32>,
+}
+
+impl From<[f32; METRICS]> for Metrics {
+    fn from(v: [f32; METRICS]) -> Self {
+        let get = |i: usize| (!v[i].is_nan()).then_some(v[i]);
+        Self {
+            cpu_usage: get(0),
+            cpu_temp: get(1),
+            memory_usage: get(2),
+            disk_usage: get(3),
+            load_average: get(4),
+            motion_level: get(5),
+        }
+    }
+}
+
+fn values(t: &TelemetryData) -> [f32; METRICS] {
+    [
+        t.cpu_usage,
+        t.cpu_temp.unwrap_or(f32::NAN),
+        t.memory_usage,
+        t.disk_usage,
+        t.load_average[0],
+        t.motion_level.unwrap_or(f32::NAN),
+    ]
+}
+
+/// One point of history. Rollups also carry the range within the bucket
+/// and the number of readings it summarizes.
+#[derive(Debug, Clone, Serialize)]
+pub struct Point {
+    /// Reading time, or bucket start (epoch ms)
+    pub time: u64,
+    /// The reading, or the bucket average
+    #[serde(flatten)]
+    pub avg: Metrics,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min: Option<Metrics>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max: Option<Metrics>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub count: Option<u32>,
+}
+
+/// A stored record: a raw reading is a bucket of one.
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    time_ms: u64,
+    count: u32,
+    min: [f32; METRICS],
+    avg: [f32; METRICS],
+    max: [f32; METRICS],
+}
+
+impl Bucket {
+    fn reading(time_ms: u64, v: [f32; METRICS]) -> Self {
+        Self {
+            time_ms,
+            count: 1,
+            min: v,
+            avg: v,
+            max: v,
+        }
+    }
+
+    fn encode(&self, res: Resolution, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.time_ms.to_le_bytes());
+        if res == Resolution::Raw {
+            out.extend(self.avg.iter().flat_map(|v| v.to_le_bytes()));
+            return;
+        }
+        out.extend_from_slice(&self.count.to_le_bytes());
+        for v in [&self.min, &self.avg, &self.max] {
+            out.extend(v.iter().flat_map(|v| v.to_le_bytes()));
+        }
+    }
+
+    fn decode(res: Resolution, b: &[u8]) -> Self {
+        let floats = |at: usize| -> [f32; METRICS] {
+            std::array::from_fn(|i| {
+                let o = at + 4 * i;
+                f32::from_le_bytes(b[o..o + 4].try_into().unwrap())
+            })
+        };
+        let time_ms = u64::from_le_bytes(b[..8].try_into().unwrap());
+        if res == Resolution::Raw {
+            return Self::reading(time_ms, floats(8));
+        }
+        Self {
+            time_ms,
+            count: u32::from_le_bytes(b[8..12].try_into().unwrap()),
+            min: floats(12),
+            avg: floats(12 + 4 * METRICS),
+            max: floats(12 + 8 * METRICS),
+        }
+    }
+
+    fn point(&self, res: Resolution) -> Point {
+        let rollup = res != Resolution::Raw;
+        Point {
+            time: self.time_ms,
+            avg: self.avg.into(),
+            min: rollup.then(|| self.min.into()),
+            max: rollup.then(|| self.max.into()),
+            count: rollup.then_some(self.count),
+        }
+    }
+}
+
+fn record_len(res: Resolution) -> usize {
+    match res {
+        Resolution::Raw => RAW_RECORD,
+        _ => BUCKET_RECORD,
+    }
+}
+
+/// Folds buckets into one, skipping missing metrics.
+struct Rollup {
+    count: u32,
+    min: [f32; METRICS],
+    max: [f32; METRICS],
+    sum: [f64; METRICS],
+    weight: [u64; METRICS],
+}
+
+impl Rollup {
+    fn new() -> Self {
+        Self {
+            count: 0,
+            min: [f32::NAN; METRICS],
+            max: [f32::NAN; METRICS],
+            sum: [0.0; METRICS],
+            weight: [0; METRICS],
+        }
+    }
+
+    fn add(&mut self, b: &Bucket) {
+        self.count += b.count;
+        for i in 0..METRICS {
+            if b.avg[i].is_nan() {
+                continue;
+            }
+            // f32::min/max ignore NaN
+            self.min[i] = self.min[i].min(b.min[i]);
+            self.max[i] = self.max[i].max(b.max[i]);
+            self.sum[i] += b.avg[i] as f64 * b.count as f64;
+            self.weight[i] += b.count as u64;
+        }
+    }
+
+    fn finish(&self, time_ms: u64) -> Bucket {
+        Bucket {
+            time_ms,
+            count: self.count,
+            min: self.min,
+            avg: std::array::from_fn(|i| match self.weight[i] {
+                0 => f32::NAN,
+                w => (self.sum[i] / w as f64) as f32,
+            }),
+            max: self.max,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct CameraState {
+    last_raw_ms: Option<u64>,
+    /// Per rollup resolution, where the next bucket starts; read from disk
+    /// when first needed.
+    rolled_to: HashMap<Resolution, u64>,
+}
+
+/// Telemetry history of every camera.
+pub struct TelemetryStore {
+    root: PathBuf,
+    cameras: Mutex<HashMap<String, CameraState>>,
+}
+
+impl TelemetryStore {
+    /// Open (or create) the store under `root`.
+    pub fn open(root: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let root = root.into();
+        for res in Resolution::ALL {
+            std::fs::create_dir_all(root.join(res.dir()))?;
+        }
+        Ok(Self {
+            root,
+            cameras: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn camera_dir(&self, camera_id: &str, res: Resolution) -> PathBuf {
+        self.root.join(res.dir()).join(camera_id)
+    }
+
+    /// Store a camera's reading, received at `now_ms`, and roll up any
+    /// minutes and hours it closes.
+    pub fn record(
+        &self,
+        camera_id: &str,
+        telemetry: &TelemetryData,
+        now_ms: u64,
+    ) -> anyhow::Result<()> {
+        anyhow::ensure!(valid_camera_id(camera_id), "invalid camera id");
+        let mut cameras = self.cameras.lock().unwrap();
+        let state = cameras.entry(camera_id.to_string()).or_default();
+        let due = state
+            .last_raw_ms
+            .is_none_or(|last| now_ms >= last + Resolution::Raw.step_ms() || now_ms < last);
+        if !due {
+            return Ok(());
+        }
+        state.last_raw_ms = Some(now_ms);
+        self.append(
+            camera_id,
+            Resolution::Raw,
+            &[Bucket::reading(now_ms, values(telemetry))],
+        )?;
+
+        for res in [Resolution::Minute, Resolution::Hour] {
+            let closed = now_ms - now_ms % res.step_ms();
+            self.roll_up(camera_id, state, res, closed)?;
+        }
+        Ok(())
+    }
+
+    /// Write `res` buckets from the resolution below, for everything before
+    /// `until_ms` that isn't rolled up yet.
+    fn roll_up(
+        &self,
+        camera_id: &str,
+        state: &mut CameraState,
+        res: Resolution,
+        until_ms: u64,
+    ) -> anyhow::Result<()> {
+        let rolled_to = match state.rolled_to.get(&res) {
+            Some(&t) => t,
+            None => self
+                .last(camera_id, res)?
+                .map_or(0, |b| b.time_ms + res.step_ms()),
+        };
+        if until_ms <= rolled_to {
+            state.rolled_to.insert(res, rolled_to);
+            return Ok(());
+        }
+
+        let source = match res {
+            Resolution::Hour => Resolution::Minute,
+            _ => Resolution::Raw,
+        };
+        let mut buckets = Vec::new();
+        let mut current: Option<(u64, Rollup)> = None;
+        for b in self.read(camera_id, source, rolled_to, until_ms - 1)? {
+            let start = b.time_ms - b.time_ms % res.step_ms();
+            match &mut current {
+                Some((t, rollup)) if *t == start => rollup.add(&b),
+                _ => {
+                    if let Some((t, rollup)) = current.take() {
+                        buckets.push(rollup.finish(t));
+                    }
+                    let mut rollup = Rollup::new();
+                    rollup.add(&b);
+                    current = Some((start, rollup));
+                }
+            }
+        }
+        if let Some((t, rollup)) = current {
+            buckets.push(rollup.finish(t));
+        }
+        self.append(camera_id, res, &buckets)?;
+        state.rolled_to.insert(res, until_ms);
+        Ok(())
+    }
+
+    fn append(&self, camera_id: &str, res: Resolution, buckets: &[Bucket]) -> anyhow::Result<()> {
+        let dir = self.camera_dir(camera_id, res);
+        let mut files: Vec<(u64, Vec<u8>)> = Vec::new();
+        for b in buckets {
+            let start = b.time_ms - b.time_ms % res.file_span_ms();
+            if files.last().is_none_or(|(s, _)| *s != start) {
+                files.push((start, Vec::new()));
+            }
+            b.encode(res, &mut files.last_mut().unwrap().1);
+        }
+        if files.is_empty() {
+            return Ok(());
+        }
+        std::fs::create_dir_all(&dir)?;
+        for (start, data) in files {
+            let path = dir.join(format!("{}.bin", start));
+            let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+            // Drop a record torn by a crash, so the ones after it line up
+            let len = file.metadata()?.len();
+            let torn = len % record_len(res) as u64;
+            if torn != 0 {
+                file.set_len(len - torn)?;
+            }
+            file.write_all(&data)?;
+        }
+        Ok(())
+    }
+
+    /// Files of a camera at `res`, by start time.
+    fn files(&self, camera_id: &str, res: Resolution) -> anyhow::Result<Vec<(u64, PathBuf)>> {
+        let dir = self.camera_dir(camera_id, res);
+        if !dir.exists() {
+            return Ok(Vec::new());
+        }
+        let mut files: Vec<(u64, PathBuf)> = std::fs::read_dir(&dir)?
+            .filter_map(|entry| {
+                let path = entry.ok()?.path();
+                let start = path.file_stem()?.to_str()?.parse().ok()?;
+                (path.extension()? == "bin").then_some((start, path))
+            })
+            .collect();
+        files.sort();
+        Ok(files)
+    }
+
+    /// Stored buckets in `[from_ms, to_ms]`, oldest first.
+    fn read(
+        &self,
+        camera_id: &str,
+        res: Resolution,
+        from_ms: u64,
+        to_ms: u64,
+    ) -> anyhow::Result<Vec<Bucket>> {
+        let mut out = Vec::new();
+        for (start, path) in self.files(camera_id, res)? {
+            if start > to_ms || start + res.file_span_ms() <= from_ms {
+                continue;
+            }
+            let data = std::fs::read(&path)?;
+            out.extend(
+                data.chunks_exact(record_len(res))
+                    .map(|b| Bucket::decode(res, b))
+                    .filter(|b| b.time_ms >= from_ms && b.time_ms <= to_ms),
+            );
+        }
+        Ok(out)
+    }
+
+    /// The newest stored bucket at `res`.
+    fn last(&self, camera_id: &str, res: Resolution) -> anyhow::Result<Option<Bucket>> {
+        for (_, path) in self.files(camera_id, res)?.into_iter().rev() {
+            let data = std::fs::read(&path)?;
+            if let Some(b) = data.chunks_exact(record_len(res)).last() {
+                return Ok(Some(Bucket::decode(res, b)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// A camera's history in `[from_ms, to_ms]` at `res`, oldest first.
+    pub fn query(
+        &self,
+        camera_id: &str,
+        res: Resolution,
+        from_ms: u64,
+        to_ms: u64,
+    ) -> anyhow::Result<Vec<Point>> {
+        if !valid_camera_id(camera_id) {
+            return Ok(Vec::new());
+        }
+        // Don't race a rollup being appended
+        let _cameras = self.cameras.lock().unwrap();
+        Ok(self
+            .read(camera_id, res, from_ms, to_ms)?
+            .iter()
+            .map(|b| b.point(res))
+            .collect())
+    }
+
+    /// Remove files entirely past retention at `now_ms`.
+    pub fn prune(&self, now_ms: u64) -> anyhow::Result<usize> {
+        let _cameras = self.cameras.lock().unwrap();
+        let mut removed = 0;
+        for res in Resolution::ALL {
+            let cutoff = now_ms.saturating_sub(res.retention_ms());
+            for entry in std::fs::read_dir(self.root.join(res.dir()))? {
+                let camera_id = entry?.file_name().to_string_lossy().into_owned();
+                for (start, path) in self.files(&camera_id, res)? {
+                    if start + res.file_span_ms() <= cutoff {
+                        std::fs::remove_file(&path)?;
+                        removed += 1;
+                    }
+                }
+            }
+        }
+        Ok(removed)
+    }
+}
+
+/// Camera ids are used as directory names.
+fn valid_camera_id(camera_id: &str) -> bool {
+    !camera_id.is_empty()
+        && camera_id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+/// Store telemetry from the Router broadcast until it closes, pruning
+/// hourly.
+pub fn spawn(handle: RouterHandle, store: Arc<TelemetryStore>) {
+    tokio::spawn(async move {
+        let mut rx = handle.subscribe();
+        let mut prune = tokio::time::interval(PRUNE_INTERVAL);
+        loop {
+            tokio::select! {
+                frame = rx.recv() => match frame {
+                    Ok(frame) => {
+                        let Some(telemetry) = alerts::telemetry(&frame) else {
+                            continue;
+                        };
+                        let camera_id = frame.source.to_string();
+                        if let Err(e) = store.record(&camera_id, &telemetry, now_ms()) {
+                            tracing::warn!(camera = %camera_id, "Failed to store telemetry: {}", e);
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        tracing::debug!("Telemetry history lagged, missed {} frames", n);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+                _ = prune.tick() => match store.prune(now_ms()) {
+                    Ok(0) => {}
+                    Ok(n) => tracing::debug!("Removed {} expired telemetry files", n),
+                    Err(e) => tracing::warn!("Failed to prune telemetry history: {}", e),
+                },
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::alerts::test_telemetry;
+
+    const CAMERA: &str = "0102030405060708";
+    /// 2024-01-01 00:00 UTC
+    const T0: u64 = 1_704_067_200_000;
+
+    fn reading(cpu: f32, motion: Option<f32>) -> TelemetryData {
+        TelemetryData {
+            cpu_usage: cpu,
+            ..test_telemetry(motion)
+        }
+    }
+
+    #[test]
+    fn stores_raw_and_rolls_up_minutes_and_hours() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = TelemetryStore::open(dir.path()).unwrap();
+
+        store.record(CAMERA, &reading(10.0, None), T0).unwrap();
+        // Within the raw interval: dropped
+        store
+            .record(CAMERA, &reading(99.0, None), T0 + 500)
+            .unwrap();
+        store
+            .record(CAMERA, &reading(30.0, Some(0.5)), T0 + 30_000)
+            .unwrap();
+        store
+            .record(CAMERA, &reading(50.0, None), T0 + MINUTE_MS)
+            .unwrap();
+
+        let raw = store
+            .query(CAMERA, Resolution::Raw, T0, T0 + HOUR_MS)
+            .unwrap();
+        let cpu: Vec<f32> = raw.iter().map(|p| p.avg.cpu_usage.unwrap()).collect();
+        assert_eq!(cpu, vec![10.0, 30.0, 50.0]);
+        assert_eq!(raw[0].avg.motion_level, None);
+        assert!(raw[0].min.is_none());
+
+        // The first minute closed with the third reading
+        let minutes = store
+            .query(CAMERA, Resolution::Minute, T0, T0 + HOUR_MS)
+            .unwrap();
+        assert_eq!(minutes.len(), 1);
+        let minute = &minutes[0];
+        assert_eq!(minute.time, T0);
+        assert_eq!(minute.count, Some(2));
+        assert_eq!(minute.avg.cpu_usage, Some(20.0));
+        assert_eq!(minute.min.unwrap().cpu_usage, Some(10.0));
+        assert_eq!(minute.max.unwrap().cpu_usage, Some(30.0));
+        assert_eq!(minute.avg.motion_level, Some(0.5));
+
+        store
+            .record(CAMERA, &reading(70.0, None), T0 + HOUR_MS)
+            .unwrap();
+        let hours = store
+            .query(CAMERA, Resolution::Hour, T0, T0 + DAY_MS)
+            .unwrap();
+        assert_eq!(hours.len(), 1);
+        assert_eq!(hours[0].count, Some(3));
+        assert_eq!(hours[0].avg.cpu_usage, Some(30.0));
+        assert_eq!(hours[0].max.unwrap().cpu_usage, Some(50.0));
+        let minutes = store
+            .query(CAMERA, Resolution::Minute, T0, T0 + DAY_MS)
+            .unwrap();
+        assert_eq!(minutes.len(), 2);
+    }
+
+    #[test]
+    fn catches_up_after_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = TelemetryStore::open(dir.path()).unwrap();
+        store.record(CAMERA, &reading(10.0, None), T0).unwrap();
+        store
+            .record(CAMERA, &reading(20.0, None), T0 + 5 * MINUTE_MS)
+            .unwrap();
+        drop(store);
+
+        // Back two hours later: the minutes and hour before are rolled up
+        let store = TelemetryStore::open(dir.path()).unwrap();
+        store
+            .record(CAMERA, &reading(30.0, None), T0 + 2 * HOUR_MS)
+            .unwrap();
+        let minutes = store
+            .query(CAMERA, Resolution::Minute, T0, T0 + DAY_MS)
+            .unwrap();
+        assert_eq!(
+            minutes.iter().map(|p| p.time).collect::<Vec<_>>(),
+            vec![T0, T0 + 5 * MINUTE_MS]
+        );
+        let hours = store
+            .query(CAMERA, Resolution::Hour, T0, T0 + DAY_MS)
+            .unwrap();
+        assert_eq!(hours.len(), 1);
+        assert_eq!(hours[0].avg.cpu_usage, Some(15.0));
+
+        // Nothing is rolled up twice
+        drop(store);
+        let store = TelemetryStore::open(dir.path()).unwrap();
+        store
+            .record(CAMERA, &reading(30.0, None), T0 + 3 * HOUR_MS)
+            .unwrap();
+        let hours = store
+            .query(CAMERA, Resolution::Hour, T0, T0 + DAY_MS)
+            .unwrap();
+        assert_eq!(hours.len(), 2);
+    }
+
+    #[test]
+    fn prunes_past_retention() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = TelemetryStore::open(dir.path()).unwrap();
+        store.record(CAMERA, &reading(10.0, None), T0).unwrap();
+        store
+            .record(CAMERA, &reading(10.0, None), T0 + 2 * HOUR_MS)
+            .unwrap();
+
+        assert_eq!(store.prune(T0 + DAY_MS).unwrap(), 0);
+        // The first raw file is now past 24 hours; rollups are kept
+        assert_eq!(store.prune(T0 + DAY_MS + HOUR_MS).unwrap(), 1);
+        let raw = store
+            .query(CAMERA, Resolution::Raw, T0, T0 + DAY_MS)
+            .unwrap();
+        assert_eq!(raw.len(), 1);
+        let hours = store
+            .query(CAMERA, Resolution::Hour, T0, T0 + DAY_MS)
+            .unwrap();
+        assert_eq!(hours.len(), 1);
+    }
+
+    #[test]
+    fn auto_resolution() {
+        let now = T0 + 100 * DAY_MS;
+        assert_eq!(
+            Resolution::auto(now - 10 * MINUTE_MS, now, now),
+            Resolution::Raw
+        );
+        assert_eq!(Resolution::auto(now - DAY_MS, now, now), Resolution::Minute);
+        // Past raw retention
+        assert_eq!(
+            Resolution::auto(now - 2 * DAY_MS, now - 2 * DAY_MS + MINUTE_MS, now),
+            Resolution::Minute
+        );
+        assert_eq!(
+            Resolution::auto(now - 7 * DAY_MS, now, now),
+            Resolution::Hour
+        );
+        assert!(!Resolution::Raw.fits(now - DAY_MS, now));
+        assert!(Resolution::Hour.fits(now - 365 * DAY_MS, now));
+    }
+
+    #[test]
+    fn rejects_path_like_camera_ids() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = TelemetryStore::open(dir.path()).unwrap();
+        assert!(store.record("../x", &reading(1.0, None), T0).is_err());
+        assert!(store
+            .query("../x", Resolution::Raw, T0, T0 + 1)
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,59 @@
+#!/usr/bin/env bash
+set -euo pipefail
+
+# Fetch a static ffmpeg build to bundle with the desktop app as a sidecar.
+#
+# Usage: scripts/fetch-ffmpeg.sh [target-triple]
+#
+# The binary is saved as app/src-tauri/binaries/ffmpeg-<target-triple>,
+# where `tauri build --config src-tauri/tauri.ffmpeg.conf.json` picks it up.
+# Defaults to the host target. Set FFMPEG_URL to use another build
+# (a .zip or .tar.xz containing an ffmpeg executable).
+
+SCRIPT_DIR="$(cd "$(dirname "${BASH_SOURCE[0]}")" && pwd)"
+PROJECT_ROOT="$(dirname "$SCRIPT_DIR")"
+
+TARGET="${1:-$(rustc -vV | sed -n 's/^host: //p')}"
+BTBN="https://github.com/BtbN/FFmpeg-Builds/releases/download/latest"
+
+case "$TARGET" in
+    x86_64-unknown-linux-gnu) URL="$BTBN/ffmpeg-master-latest-linux64-lgpl.tar.xz" ;;
+    aarch64-unknown-linux-gnu) URL="$BTBN/ffmpeg-master-latest-linuxarm64-lgpl.tar.xz" ;;
+    x86_64-pc-windows-msvc) URL="$BTBN/ffmpeg-master-latest-win64-lgpl.zip" ;;
+    # x86_64 only; runs on Apple silicon through Rosetta
+    x86_64-apple-darwin | aarch64-apple-darwin) URL="https://evermeet.cx/ffmpeg/getrelease/zip" ;;
+    *) URL="" ;;
+esac
+URL="${FFMPEG_URL:-$URL}"
+if [ -z "$URL" ]; then
+    echo "ERROR: No ffmpeg build known for $TARGET. Set FFMPEG_URL."
+    exit 1
+fi
+
+EXE=""
+if [[ "$TARGET" == *windows* ]]; then
+    EXE=".exe"
+fi
+DEST="$PROJECT_ROOT/app/src-tauri/binaries/ffmpeg-$TARGET$EXE"
+
+TMP="$(mktemp -d)"
+trap 'rm -rf "$TMP"' EXIT
+
+echo "Downloading ffmpeg for $TARGET from $URL..."
+curl -fsSL "$URL" -o "$TMP/archive"
+mkdir "$TMP/out"
+case "$URL" in
+    *.tar.xz) tar -xJf "$TMP/archive" -C "$TMP/out" ;;
+    *) unzip -q "$TMP/archive" -d "$TMP/out" ;;
+esac
+
+BIN="$(find "$TMP/out" -type f -name "ffmpeg$EXE" | head -n 1)"
+if [ -z "$BIN" ]; then
+    echo "ERROR: No ffmpeg$EXE in $URL"
+    exit 1
+fi
+
+mkdir -p "$(dirname "$DEST")"
+cp "$BIN" "$DEST"
+chmod +x "$DEST"
+echo "Saved $DEST"
@@ -0,0 +1,150 @@
+/**
+ * Desktop app API: endpoints the app serves next to the core server's on
+ * the web port (recordings, exports, storage, ...) and the app events it
+ * adds to the live `/ws` stream.
+ */
+
+import { getTransport } from './transport-ws.js';
+import type { TransportEvents } from './transport.js';
+import type {
+	ArmingMode,
+	ModeStatus,
+	ProtectedClip,
+	ServerAlert,
+	StorageStatus,
+	TelemetryHistory,
+	TelemetryResolution,
+	TelemetrySample,
+} from './types.js';
+
+/** Base URL of the app API: the web server the UI was loaded from. */
+export function appApiBase(): string {
+	if (typeof window !== 'undefined' && window.location.protocol.startsWith('http')) {
+		return window.location.origin;
+	}
+	// Non-HTTP protocol (e.g. tauri://) — embedded server defaults
+	return 'http://localhost:3000';
+}
+
+export async function getStorageStatus(): Promise<StorageStatus> {
+	const res = await fetch(`${appApiBase()}/api/storage`);
+	if (!res.ok) throw new Error(`Failed to get storage status: ${res.status}`);
+	return res.json();
+}
+
+/** Keyframe thumbnail nearest `at` (epoch ms), for timeline previews. */
+export function thumbnailUrl(cameraId: string, at: number): string {
+	return `${appApiBase()}/api/recordings/${encodeURIComponent(cameraId)}/thumbnail?at=${Math.round(at)}`;
+}
+
+/**
+ * Recorded telemetry for a camera between `from` and `to` (epoch ms, up to
+ * an hour), at most one sample per `step` ms if given.
+ */
+export async function getTelemetryReplay(
+	cameraId: string,
+	from: number,
+	to: number,
+	step?: number,
+): Promise<TelemetrySample[]> {
+	const params = new URLSearchParams({ from: String(Math.round(from)), to: String(Math.round(to)) });
+	if (step) params.set('step', String(Math.round(step)));
+	const res = await fetch(`${appApiBase()}/api/recordings/${encodeURIComponent(cameraId)}/telemetry?${params}`);
+	if (!res.ok) throw new Error(`Failed to get telemetry replay: ${res.status}`);
+	return res.json();
+}
+
+/**
+ * Stored telemetry history for a camera, kept whether or not it's recorded.
+ * Defaults to the last hour; without `resolution`, the server picks one
+ * that suits the range.
+ */
+export async function getTelemetryHistory(
+	cameraId: string,
+	opts: { from?: number; to?: number; resolution?: TelemetryResolution } = {},
+): Promise<TelemetryHistory> {
+	const params = new URLSearchParams();
+	if (opts.from !== undefined) params.set('from', String(Math.round(opts.from)));
+	if (opts.to !== undefined) params.set('to', String(Math.round(opts.to)));
+	if (opts.resolution) params.set('resolution', opts.resolution);
+	const res = await fetch(`${appApiBase()}/api/cameras/${encodeURIComponent(cameraId)}/telemetry?${params}`);
+	if (!res.ok) throw new Error(`Failed to get telemetry history: ${res.status}`);
+	return res.json();
+}
+
+export async function listProtectedClips(cameraId?: string): Promise<ProtectedClip[]> {
+	const query = cameraId ? `?camera=${encodeURIComponent(cameraId)}` : '';
+	const res = await fetch(`${appApiBase()}/api/protected${query}`);
+	if (!res.ok) throw new Error(`Failed to list protected clips: ${res.status}`);
+	return res.json();
+}
+
+/** Protect a camera's recordings between `start` and `end` (epoch ms) from retention. */
+export async function protectClip(clip: {
+	camera: string;
+	start: number;
+	end: number;
+	label: string;
+	note?: string;
+}): Promise<ProtectedClip> {
+	const res = await fetch(`${appApiBase()}/api/protected`, {
+		method: 'POST',
+		headers: { 'Content-Type': 'application/json' },
+		body: JSON.stringify(clip),
+	});
+	if (!res.ok) throw new Error(await res.text() || `Failed to protect clip: ${res.status}`);
+	return res.json();
+}
+
+/** Release a protected clip, returning its recordings to normal retention. */
+export async function releaseProtectedClip(id: string): Promise<void> {
+	const res = await fetch(`${appApiBase()}/api/protected/${encodeURIComponent(id)}`, { method: 'DELETE' });
+	if (!res.ok) throw new Error(`Failed to release protected clip: ${res.status}`);
+}
+
+/** Recent alerts, newest first, optionally for one camera. */
+export async function listAlerts(cameraId?: string, limit?: number): Promise<ServerAlert[]> {
+	const params = new URLSearchParams();
+	if (cameraId) params.set('camera', cameraId);
+	if (limit) params.set('limit', String(limit));
+	const res = await fetch(`${appApiBase()}/api/alerts?${params}`);
+	if (!res.ok) throw new Error(`Failed to list alerts: ${res.status}`);
+	return res.json();
+}
+
+/** Clear the server's alert history. */
+export async function clearAlerts(): Promise<void> {
+	const res = await fetch(`${appApiBase()}/api/alerts`, { method: 'DELETE' });
+	if (!res.ok) throw new Error(`Failed to clear alerts: ${res.status}`);
+}
+
+export async function getMode(): Promise<ModeStatus> {
+	const res = await fetch(`${appApiBase()}/api/mode`);
+	if (!res.ok) throw new Error(`Failed to get arming mode: ${res.status}`);
+	return res.json();
+}
+
+/** Switch the arming mode until the next scheduled switch. */
+export async function setMode(mode: ArmingMode): Promise<ModeStatus> {
+	const res = await fetch(`${appApiBase()}/api/mode`, {
+		method: 'PUT',
+		headers: { 'Content-Type': 'application/json' },
+		body: JSON.stringify({ mode }),
+	});
+	if (!res.ok) throw new Error(await res.text() || `Failed to set arming mode: ${res.status}`);
+	return res.json();
+}
+
+export type AppEvents = Pick<TransportEvents, 'storage-status' | 'alert' | 'mode'>;
+
+export type AppEventName = keyof AppEvents;
+
+/**
+ * App events, sent by the app as text messages on the live `/ws` stream.
+ * Headless servers without the app simply never deliver them.
+ */
+export const appEvents = {
+	on<E extends AppEventName>(event: E, cb: (data: AppEvents[E]) => void): () => void {
+		return getTransport().on(event, cb);
+	},
+};
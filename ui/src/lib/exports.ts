@@ -0,0 +1,91 @@
+/** Clip and time-lapse export via the desktop app's REST API (served on the web port). */
+
+import { appApiBase } from './appApi.js';
+
+export type ExportJob = {
+	id: string;
+	camera_id: string;
+	start: number;
+	end: number;
+} & ({ kind: 'clip' } | { kind: 'timelapse'; interval: number }) & (
+	| { status: 'queued' }
+	| { status: 'running'; progress: number }
+	| { status: 'done'; size: number; clip_start: number; clip_end: number; file_name: string }
+	| { status: 'failed'; error: string }
+);
+
+const POLL_INTERVAL_MS = 500;
+
+async function errorText(res: Response): Promise<string> {
+	return (await res.text()) || `HTTP ${res.status}`;
+}
+
+/** Start an export and resolve once it has finished. */
+export async function exportClip(
+	cameraId: string,
+	start: number,
+	end: number,
+	onProgress?: (progress: number) => void,
+): Promise<ExportJob> {
+	return runJob('/api/exports', { camera: cameraId, start: Math.round(start), end: Math.round(end) }, onProgress);
+}
+
+/**
+ * Start a time-lapse taking one keyframe per `interval` ms between `start`
+ * and `end` (up to a week), and resolve once it has finished.
+ */
+export async function exportTimelapse(
+	cameraId: string,
+	start: number,
+	end: number,
+	interval: number,
+	onProgress?: (progress: number) => void,
+): Promise<ExportJob> {
+	return runJob(
+		'/api/exports/timelapse',
+		{ camera: cameraId, start: Math.round(start), end: Math.round(end), interval: Math.round(interval) },
+		onProgress,
+	);
+}
+
+async function runJob(
+	path: string,
+	body: Record<string, unknown>,
+	onProgress?: (progress: number) => void,
+): Promise<ExportJob> {
+	const base = appApiBase();
+	const res = await fetch(`${base}${path}`, {
+		method: 'POST',
+		headers: { 'Content-Type': 'application/json' },
+		body: JSON.stringify(body),
+	});
+	if (!res.ok) throw new Error(await errorText(res));
+	let job: ExportJob = await res.json();
+
+	while (job.status === 'queued' || job.status === 'running') {
+		onProgress?.(job.status === 'running' ? job.progress : 0);
+		await new Promise((r) => setTimeout(r, POLL_INTERVAL_MS));
+		const poll = await fetch(`${base}/api/exports/${job.id}`);
+		if (!poll.ok) throw new Error(await errorText(poll));
+		job = await poll.json();
+	}
+	if (job.status === 'failed') throw new Error(job.error);
+	return job;
+}
+
+/**
+ * Save a finished export. The desktop app asks where to save it; browsers
+ * download it.
+ */
+export async function saveExport(job: ExportJob): Promise<void> {
+	const tauri = (window as { __TAURI_INTERNALS__?: { invoke: (cmd: string, args: unknown) => Promise<unknown> } })
+		.__TAURI_INTERNALS__;
+	if (tauri) {
+		await tauri.invoke('save_export', { id: job.id });
+		return;
+	}
+	const a = document.createElement('a');
+	a.href = `${appApiBase()}/api/exports/${job.id}/download`;
+	a.download = job.status === 'done' ? job.file_name : '';
+	a.click();
+}
@@ -1,6 +1,22 @@
 import { describe, it, expect, beforeEach, vi } from 'vitest';
-import { alertsStore } from '../alerts.svelte.js';
 import { flushSync } from 'svelte';
+import type { AlertType, ServerAlert } from '$lib/types.js';
+
+vi.mock('$lib/appApi.js', () => ({
+	listAlerts: vi.fn(async () => []),
+	clearAlerts: vi.fn(async () => {}),
+	appEvents: { on: vi.fn(() => () => {}) },
+}));
+
+import { alertsStore } from '../alerts.svelte.js';
+import { cameraConfigStore } from '../cameraConfig.svelte.js';
+import { appEvents, clearAlerts, listAlerts } from '$lib/appApi.js';
+
+let nextId = 1;
+
+function serverAlert(type: AlertType, cameraId: string, message: string): ServerAlert {
+	return { id: nextId++, type, camera_id: cameraId, message, time: 1_700_000_000_000 + nextId };
+}
 
 describe('alertsStore', () => {
 	beforeEach(() => {
@@ -8,9 +24,9 @@ describe('alertsStore', () => {
 			alertsStore.clearAll();
 			alertsStore.enabled = true;
 			alertsStore.browserNotifications = false;
-			alertsStore.disconnectAlertEnabled = true;
-			alertsStore.motionThreshold = 0.6;
+			cameraConfigStore.overrides = {};
 		});
+		vi.clearAllMocks();
 	});
 
 	it('starts with no alerts', () => {
@@ -18,31 +34,51 @@ describe('alertsStore', () => {
 		expect(alertsStore.unreadCount).toBe(0);
 	});
 
-	it('adds an alert', () => {
+	it('receives a server alert', () => {
+		const alert = serverAlert('disconnect', 'cam1', 'Disconnected');
 		flushSync(() => {
-			alertsStore.addAlert('disconnect', 'cam1', 'Front Door', 'Front Door disconnected');
+			alertsStore.receive(alert);
 		});
 		expect(alertsStore.alerts).toHaveLength(1);
+		expect(alertsStore.alerts[0].id).toBe(alert.id);
 		expect(alertsStore.alerts[0].type).toBe('disconnect');
 		expect(alertsStore.alerts[0].cameraId).toBe('cam1');
-		expect(alertsStore.alerts[0].cameraName).toBe('Front Door');
-		expect(alertsStore.alerts[0].message).toBe('Front Door disconnected');
+		expect(alertsStore.alerts[0].cameraName).toBe('cam1');
+		expect(alertsStore.alerts[0].message).toBe('Disconnected');
+		expect(alertsStore.alerts[0].timestamp).toBe(alert.time);
 		expect(alertsStore.alerts[0].read).toBe(false);
 	});
 
+	it('names cameras by their display name', () => {
+		flushSync(() => {
+			cameraConfigStore.rename('cam1', 'Front Door');
+			alertsStore.receive(serverAlert('motion', 'cam1', 'Motion detected (72%)'));
+		});
+		expect(alertsStore.alerts[0].cameraName).toBe('Front Door');
+	});
+
 	it('prepends new alerts (newest first)', () => {
 		flushSync(() => {
-			alertsStore.addAlert('disconnect', 'cam1', 'Cam 1', 'Disconnected');
-			alertsStore.addAlert('motion', 'cam2', 'Cam 2', 'Motion detected');
+			alertsStore.receive(serverAlert('disconnect', 'cam1', 'Disconnected'));
+			alertsStore.receive(serverAlert('motion', 'cam2', 'Motion detected'));
 		});
 		expect(alertsStore.alerts[0].type).toBe('motion');
 		expect(alertsStore.alerts[1].type).toBe('disconnect');
 	});
 
+	it('ignores alerts it already has', () => {
+		const alert = serverAlert('reconnect', 'cam1', 'Reconnected');
+		flushSync(() => {
+			alertsStore.receive(alert);
+			alertsStore.receive(alert);
+		});
+		expect(alertsStore.alerts).toHaveLength(1);
+	});
+
 	it('tracks unread count', () => {
 		flushSync(() => {
-			alertsStore.addAlert('disconnect', 'cam1', 'Cam 1', 'Disconnected');
-			alertsStore.addAlert('reconnect', 'cam1', 'Cam 1', 'Reconnected');
+			alertsStore.receive(serverAlert('disconnect', 'cam1', 'Disconnected'));
+			alertsStore.receive(serverAlert('reconnect', 'cam1', 'Reconnected'));
 		});
 		expect(alertsStore.unreadCount).toBe(2);
 
@@ -54,26 +90,27 @@ describe('alertsStore', () => {
 
 	it('marks all read', () => {
 		flushSync(() => {
-			alertsStore.addAlert('disconnect', 'cam1', 'Cam 1', 'Disconnected');
-			alertsStore.addAlert('motion', 'cam2', 'Cam 2', 'Motion');
+			alertsStore.receive(serverAlert('disconnect', 'cam1', 'Disconnected'));
+			alertsStore.receive(serverAlert('motion', 'cam2', 'Motion'));
 			alertsStore.markAllRead();
 		});
 		expect(alertsStore.unreadCount).toBe(0);
 	});
 
-	it('clears all alerts', () => {
+	it('clears all alerts here and on the server', () => {
 		flushSync(() => {
-			alertsStore.addAlert('disconnect', 'cam1', 'Cam 1', 'Disconnected');
-			alertsStore.addAlert('motion', 'cam2', 'Cam 2', 'Motion');
+			alertsStore.receive(serverAlert('disconnect', 'cam1', 'Disconnected'));
+			alertsStore.receive(serverAlert('motion', 'cam2', 'Motion'));
 			alertsStore.clearAll();
 		});
 		expect(alertsStore.alerts).toHaveLength(0);
+		expect(clearAlerts).toHaveBeenCalled();
 	});
 
 	it('dismisses a single alert', () => {
 		flushSync(() => {
-			alertsStore.addAlert('disconnect', 'cam1', 'Cam 1', 'Disconnected');
-			alertsStore.addAlert('motion', 'cam2', 'Cam 2', 'Motion');
+			alertsStore.receive(serverAlert('disconnect', 'cam1', 'Disconnected'));
+			alertsStore.receive(serverAlert('motion', 'cam2', 'Motion'));
 		});
 		const id = alertsStore.alerts[1].id;
 		flushSync(() => {
@@ -83,10 +120,10 @@ describe('alertsStore', () => {
 		expect(alertsStore.alerts[0].type).toBe('motion');
 	});
 
-	it('does not add alerts when disabled', () => {
+	it('does not show alerts when disabled', () => {
 		flushSync(() => {
 			alertsStore.enabled = false;
-			alertsStore.addAlert('disconnect', 'cam1', 'Cam 1', 'Disconnected');
+			alertsStore.receive(serverAlert('disconnect', 'cam1', 'Disconnected'));
 		});
 		expect(alertsStore.alerts).toHaveLength(0);
 	});
@@ -94,20 +131,22 @@ describe('alertsStore', () => {
 	it('caps alerts at 100', () => {
 		flushSync(() => {
 			for (let i = 0; i < 110; i++) {
-				alertsStore.addAlert('motion', `cam${i}`, `Cam ${i}`, `Motion ${i}`);
+				alertsStore.receive(serverAlert('motion', `cam${i}`, `Motion ${i}`));
 			}
 		});
 		expect(alertsStore.alerts.length).toBeLessThanOrEqual(100);
 	});
 
-	it('assigns unique incrementing IDs', () => {
-		flushSync(() => {
-			alertsStore.addAlert('disconnect', 'cam1', 'Cam 1', 'D1');
-			alertsStore.addAlert('reconnect', 'cam1', 'Cam 1', 'R1');
-		});
-		const ids = alertsStore.alerts.map((a) => a.id);
-		expect(ids[0]).not.toBe(ids[1]);
-		// Newest first, so first has higher id
-		expect(ids[0]).toBeGreaterThan(ids[1]);
+	it('loads history as read and follows new alerts', async () => {
+		const older = serverAlert('disconnect', 'cam1', 'Disconnected');
+		const newer = serverAlert('reconnect', 'cam1', 'Reconnected');
+		vi.mocked(listAlerts).mockResolvedValueOnce([newer, older]);
+
+		const stop = alertsStore.start();
+		await vi.waitFor(() => expect(alertsStore.alerts).toHaveLength(2));
+		expect(alertsStore.alerts.map((a) => a.id)).toEqual([newer.id, older.id]);
+		expect(alertsStore.unreadCount).toBe(0);
+		expect(appEvents.on).toHaveBeenCalledWith('alert', expect.any(Function));
+		stop();
 	});
 });
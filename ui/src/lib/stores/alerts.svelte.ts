@@ -1,4 +1,9 @@
-export type AlertType = 'motion' | 'disconnect' | 'reconnect';
+import { appEvents, clearAlerts, listAlerts } from '$lib/appApi.js';
+import type { AlertType, ServerAlert } from '$lib/types.js';
+import { cameraStore } from './cameras.svelte.js';
+import { cameraConfigStore } from './cameraConfig.svelte.js';
+
+export type { AlertType };
 
 export interface Alert {
 	id: number;
@@ -12,37 +17,46 @@ export interface Alert {
 
 const MAX_ALERTS = 100;
 
+/**
+ * Alerts raised by the server's alert engine. Rules are evaluated
+ * server-side; this store only keeps what the UI shows and what it has read.
+ */
 class AlertsStore {
 	alerts = $state<Alert[]>([]);
 	enabled = $state(true);
 	browserNotifications = $state(false);
-	motionThreshold = $state(0.6);
-	disconnectAlertEnabled = $state(true);
-
-	private nextId = 1;
 
 	unreadCount = $derived(this.alerts.filter((a) => !a.read).length);
 
-	addAlert(type: AlertType, cameraId: string, cameraName: string, message: string) {
-		if (!this.enabled) return;
+	/** Load recent history, then follow new alerts. Returns an unsubscribe function. */
+	start(): () => void {
+		listAlerts(undefined, MAX_ALERTS)
+			.then((history) => {
+				const known = new Set(this.alerts.map((a) => a.id));
+				const older = history.filter((a) => !known.has(a.id)).map((a) => this.toAlert(a, true));
+				this.alerts = [...this.alerts, ...older]
+					.sort((a, b) => b.id - a.id)
+					.slice(0, MAX_ALERTS);
+			})
+			.catch(() => {
+				// Headless servers without the app API have no alert history
+			});
+		return appEvents.on('alert', (alert) => this.receive(alert));
+	}
 
-		const alert: Alert = {
-			id: this.nextId++,
-			type,
-			cameraId,
-			cameraName,
-			message,
-			timestamp: Date.now(),
-			read: false,
-		};
+	/** Show an alert pushed by the server. */
+	receive(serverAlert: ServerAlert) {
+		if (!this.enabled) return;
+		if (this.alerts.some((a) => a.id === serverAlert.id)) return;
 
+		const alert = this.toAlert(serverAlert, false);
 		// Prepend new alert and cap at MAX_ALERTS
 		this.alerts = [alert, ...this.alerts].slice(0, MAX_ALERTS);
 
 		// Browser notification
 		if (this.browserNotifications && typeof window !== 'undefined' && 'Notification' in window) {
 			if (Notification.permission === 'granted') {
-				new Notification(`${cameraName} - ${type}`, { body: message });
+				new Notification(`${alert.cameraName} - ${alert.type}`, { body: alert.message });
 			}
 		}
 	}
@@ -60,13 +74,28 @@ class AlertsStore {
 		}
 	}
 
+	/** Clear the list here and the history on the server. */
 	clearAll() {
 		this.alerts = [];
+		clearAlerts().catch((e) => console.error('[Alerts] Failed to clear history:', e));
 	}
 
 	dismiss(id: number) {
 		this.alerts = this.alerts.filter((a) => a.id !== id);
 	}
+
+	private toAlert(alert: ServerAlert, read: boolean): Alert {
+		const cam = cameraStore.cameras.find((c) => c.id === alert.camera_id);
+		return {
+			id: alert.id,
+			type: alert.type,
+			cameraId: alert.camera_id,
+			cameraName: cameraConfigStore.getDisplayName(alert.camera_id, cam?.name ?? alert.camera_id),
+			message: alert.message,
+			timestamp: alert.time,
+			read,
+		};
+	}
 }
 
 export const alertsStore = new AlertsStore();
@@ -0,0 +1,42 @@
+import { appEvents, getMode, setMode } from '$lib/appApi.js';
+import type { ArmingMode, ModeStatus } from '$lib/types.js';
+
+export type { ArmingMode };
+
+export const MODES: { value: ArmingMode; label: string }[] = [
+	{ value: 'home', label: 'Home' },
+	{ value: 'away', label: 'Away' },
+	{ value: 'night', label: 'Night' },
+	{ value: 'disarmed', label: 'Disarmed' },
+];
+
+/** The server's arming mode. `status` stays null without the app API. */
+class ModeStore {
+	status = $state<ModeStatus | null>(null);
+
+	label = $derived(MODES.find((m) => m.value === this.status?.mode)?.label ?? '');
+
+	/** Load the current mode, then follow changes. Returns an unsubscribe function. */
+	start(): () => void {
+		getMode()
+			.then((status) => {
+				this.status ??= status;
+			})
+			.catch(() => {
+				// Headless servers without the app API have no modes
+			});
+		return appEvents.on('mode', (status) => {
+			this.status = status;
+		});
+	}
+
+	async set(mode: ArmingMode) {
+		try {
+			this.status = await setMode(mode);
+		} catch (e) {
+			console.error('[Mode] Failed to set arming mode:', e);
+		}
+	}
+}
+
+export const modeStore = new ModeStore();
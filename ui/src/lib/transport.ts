@@ -15,6 +15,9 @@ import type {
   AudioLevelEvent,
   TelemetryEvent,
   ServerStatus,
+  StorageStatus,
+  ServerAlert,
+  ModeStatus,
 } from './types.js';
 
 export type Unsubscribe = () => void;
@@ -26,6 +29,10 @@ export type TransportEvents = {
   'audio-level': AudioLevelEvent;
   'telemetry': TelemetryEvent;
   'camera-event': CameraEvent;
+  // App events, sent as JSON text messages by the desktop app
+  'storage-status': StorageStatus;
+  alert: ServerAlert;
+  mode: ModeStatus;
 };
 
 export type TransportEventName = keyof TransportEvents;
@@ -69,8 +69,125 @@ export interface RecordingSegment {
   start: number; // epoch ms
   end: number;   // epoch ms
   type: 'motion' | 'alert' | 'continuous';
+  size?: number; // bytes
+  channels?: ('video' | 'audio' | 'telemetry')[];
+  tier?: 'hot' | 'cold';
+}
+
+/** A camera time range exempt from recording retention. */
+export interface ProtectedClip {
+  id: string;
+  camera_id: string;
+  start: number;   // epoch ms
+  end: number;     // epoch ms
+  label: string;
+  note: string;
+  created: number; // epoch ms
+}
+
+export type AlertType =
+  | 'motion'
+  | 'disconnect'
+  | 'reconnect'
+  | 'threshold'
+  | 'threshold_cleared'
+  | 'geofence_exit'
+  | 'geofence_return'
+  | 'gps_degraded';
+
+/** An alert raised by the server's alert engine. */
+export interface ServerAlert {
+  id: number;
+  type: AlertType;
+  camera_id: string;
+  message: string;
+  time: number; // epoch ms
+}
+
+/** Telemetry metrics in history, null where the camera didn't report one. */
+export interface TelemetryMetrics {
+  cpu_usage: number | null;
+  cpu_temp: number | null;
+  memory_usage: number | null;
+  disk_usage: number | null;
+  load_average: number | null; // one-minute
+  motion_level: number | null;
+}
+
+export type TelemetryResolution = 'raw' | '1m' | '1h';
+
+/** A point of telemetry history: a reading, or a rollup's average with its range. */
+export interface TelemetryPoint extends TelemetryMetrics {
+  time: number; // epoch ms; bucket start for rollups
+  min?: TelemetryMetrics;
+  max?: TelemetryMetrics;
+  count?: number;
+}
+
+export interface TelemetryHistory {
+  resolution: TelemetryResolution;
+  points: TelemetryPoint[];
+}
+
+/** The server's arming mode, from the app API. */
+export type ArmingMode = 'home' | 'away' | 'night' | 'disarmed';
+
+export interface ModeStatus {
+  mode: ArmingMode;
+  source: 'initial' | 'manual' | 'schedule';
+  since: number; // epoch ms
+  next: { mode: ArmingMode; time: number } | null; // next scheduled switch
+}
+
+/** A recorded telemetry reading, from the app API's telemetry replay. */
+export interface TelemetrySample extends Omit<TelemetryEvent, 'source_id'> {
+  time: number; // epoch ms
 }
 
 export type GridLayout = 'auto' | '1+5';
 export type MarkerMode = 'dot' | 'detailed' | 'pip';
 export type ViewMode = 'live' | 'map' | 'dashboard' | 'camera';
+
+export interface CameraStorageHealth {
+  frames_dropped: number;
+  store_errors: number;
+  last_error: string | null;
+  last_error_at: number | null; // epoch ms
+}
+
+export interface StorageRootStatus {
+  path: string;
+  mode: 'spillover' | 'mirror';
+  used_bytes: number;
+  max_bytes: number | null; // null for mirrors
+  disk: { path: string; free_bytes: number; total_bytes: number } | null;
+  failing: boolean;
+  last_error: string | null;
+  last_error_at: number | null; // epoch ms
+}
+
+export interface StorageStatus {
+  state: 'ok' | 'low_space' | 'failing' | 'degraded';
+  disk: { path: string; free_bytes: number; total_bytes: number } | null;
+  min_free_bytes: number;
+  used_bytes: number;
+  max_bytes: number;
+  segments: number;
+  lag_events: number;
+  frames_lagged: number;
+  emergency_removed: number;
+  scan: StorageScanReport | null;
+  cameras: Record<string, CameraStorageHealth>;
+  roots: StorageRootStatus[]; // empty with a single root
+}
+
+export interface StorageScanReport {
+  scanned: number;
+  recovered: number;
+  truncated: number;
+  truncated_bytes: number;
+  quarantined: number;
+  empty: number;
+  missing: number;
+  duration_ms: number;
+}
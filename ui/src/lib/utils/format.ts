@@ -20,3 +20,15 @@ export function formatUptime(secs: number): string {
 	if (d > 0) return `${d}d ${h}h ${m}m`;
 	return `${h}h ${m}m`;
 }
+
+/** Format a byte count to a human-readable string (e.g. "1.5 GB") */
+export function formatBytes(bytes: number): string {
+	const units = ['B', 'KB', 'MB', 'GB', 'TB'];
+	let value = bytes;
+	let unit = 0;
+	while (value >= 1024 && unit < units.length - 1) {
+		value /= 1024;
+		unit++;
+	}
+	return unit === 0 ? `${value} B` : `${value.toFixed(1)} ${units[unit]}`;
+}